@@ -0,0 +1,56 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Feeds arbitrary bytes to the validation of the registration payloads,
+/// which parses whatever the open internet sends to POST /register and
+/// POST /v1/register/batch. Run with `cargo fuzz run registration_payload`.
+
+#![no_main]
+#![allow(dead_code)]
+
+extern crate iron;
+extern crate libfuzzer_sys;
+#[macro_use]
+extern crate log;
+extern crate params;
+extern crate redis;
+extern crate rustc_serialize;
+
+use rustc_serialize::json::Json;
+
+// The server is a binary crate, so build the modules under test from their
+// sources, along with the few modules they use.
+#[path = "../../src/errors.rs"]
+mod errors;
+#[path = "../../src/metrics.rs"]
+mod metrics;
+#[path = "../../src/nat.rs"]
+mod nat;
+#[path = "../../src/signaling.rs"]
+mod signaling;
+#[path = "../../src/validation.rs"]
+mod validation;
+
+#[export_name="rust_fuzzer_test_input"]
+pub extern fn go(data: &[u8]) {
+    // Iron hands the body to the handlers as a string, which `extract`
+    // parses before validating it.
+    let payload = match std::str::from_utf8(data) {
+        Ok(payload) => payload,
+        Err(_) => return
+    };
+    let value = match Json::from_str(payload) {
+        Ok(value) => value,
+        Err(_) => return
+    };
+
+    for &strict in &[false, true] {
+        if let Err(error) = validation::registration(&value, strict) {
+            let _ = error.into_response();
+        }
+        if let Err(error) = validation::batch(&value, 100, strict) {
+            let _ = error.into_response();
+        }
+    }
+}
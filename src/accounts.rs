@@ -0,0 +1,634 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Optional user accounts, mounted under /v1/account when the server runs
+/// with `--accounts`. Boxes linked to an account are found from anywhere,
+/// rather than only from the public IP they registered from.
+///
+/// POST /v1/account => create an account, with { "email": ..., "password": ... }.
+/// POST /v1/account/session => log in with the same object, returning a
+///                             session `token`.
+/// DELETE /v1/account/session => log out.
+/// GET /v1/account/boxes => the latest registrations of the linked boxes.
+/// POST /v1/account/boxes => link the box which created a pairing code,
+///                           with { "code": ... }.
+/// DELETE /v1/account/boxes/<fingerprint> => unlink a box.
+/// POST /v1/account/boxes/<fingerprint>/guests => create a guest token
+///                                                giving access to a box,
+///                                                for { "expires_in": ... }
+///                                                seconds.
+/// GET /v1/account/subdomains => the subdomains reserved by the account.
+/// POST /v1/account/subdomains => reserve a friendly subdomain, with
+///                               { "name": ... }.
+/// DELETE /v1/account/subdomains/<name> => release a subdomain.
+/// GET /v1/account/guest => the latest registration of the box of the guest
+///                          token of the request.
+/// GET /v1/account/oidc/login => redirect to the OpenID Connect provider,
+///                               when one is configured.
+/// GET /v1/account/oidc/callback => where the provider sends the users
+///                                  back, returning a session `token`.
+///
+/// Except for the creation of an account, the log in and the guests,
+/// requests need the `Authorization: Bearer <session token>` header.
+
+use config::Config;
+use crypto::pbkdf2;
+use db::{ self, Pairing, RecordStatus };
+use discovery;
+use errors::*;
+use iron::headers::{ ContentType, Location };
+use iron::method::Method;
+use iron::prelude::*;
+use iron::status::{ self, Status };
+use labels;
+use oidc::Provider;
+use params::{ Params, Value };
+use privacy;
+use router::Router;
+use routing::Routes;
+use rustc_serialize::json;
+use storage::Storage;
+use tokens;
+use validation::{ self, MIN_PASSWORD_LENGTH };
+
+/// Limits of each account, none when not set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Quotas {
+    /// Number of boxes linked to an account.
+    pub boxes: Option<u64>,
+    /// Number of registrations of the boxes of an account per hour.
+    pub registrations_per_hour: Option<u64>,
+    /// Number of subdomains reserved by an account.
+    pub subdomains: Option<u64>,
+}
+
+/// Number of PBKDF2 iterations of the password hashes.
+static PBKDF2_ITERATIONS: u32 = 10_000;
+
+fn internal_error<E: ::std::fmt::Display>(e: E) -> IronError {
+    error!("{}", e);
+    EndpointError::build(status::InternalServerError, ErrNo::InternalError, None, None)
+}
+
+fn json_response(body: String) -> IronResult<Response> {
+    let mut response = Response::with(body);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+    Ok(response)
+}
+
+/// The user of the session of a request, and its token.
+fn session(req: &Request, db: &Storage) -> IronResult<(String, String)> {
+    let unauthorized = || {
+        EndpointError::build(status::Unauthorized, ErrNo::Unauthorized, None, None)
+    };
+    let token = try!(tokens::bearer(req).ok_or_else(&unauthorized));
+    match db.session_user(&token) {
+        Ok(Some(email)) => Ok((email, token)),
+        Ok(None) => Err(unauthorized()),
+        Err(e) => Err(internal_error(e))
+    }
+}
+
+fn create_account(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let (email, password) = try!(validation::extract(req, validation::credentials));
+    info!("POST /v1/account email={}", email);
+
+    if password.len() < MIN_PASSWORD_LENGTH {
+        return EndpointError::with_details(
+            status::BadRequest, ErrNo::BadRequest,
+            format!("`password` must be at least {} bytes long", MIN_PASSWORD_LENGTH));
+    }
+    let hash = try!(pbkdf2::pbkdf2_simple(&password, PBKDF2_ITERATIONS)
+                        .map_err(internal_error));
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    match db.create_user(&email, &hash) {
+        Ok(true) => json_response("{\"status\" : \"created\"}".to_owned()),
+        Ok(false) => EndpointError::with(status::Conflict, ErrNo::Conflict),
+        Err(e) => Err(internal_error(e))
+    }
+}
+
+fn log_in(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let (email, password) = try!(validation::extract(req, validation::credentials));
+    info!("POST /v1/account/session email={}", email);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let hash = match db.password_hash(&email) {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized),
+        Err(e) => return Err(internal_error(e))
+    };
+    match pbkdf2::pbkdf2_check(&password, &hash) {
+        Ok(true) => {},
+        Ok(false) => return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized),
+        Err(e) => return Err(internal_error(e))
+    }
+
+    session_response(&*db, &email)
+}
+
+fn session_response(db: &Storage, email: &str) -> IronResult<Response> {
+    let token = try!(db.create_session(email).map_err(internal_error));
+    json_response(format!("{{\"token\" : \"{}\", \"expires_in\" : {}}}",
+                          token, db::SESSION_TTL))
+}
+
+fn oidc_login(_: &mut Request, config: &Config, provider: &Provider)
+    -> IronResult<Response> {
+    info!("GET /v1/account/oidc/login");
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let state = try!(db.create_oidc_state().map_err(internal_error));
+    let url = match provider.authorization_url(&state) {
+        Ok(url) => url,
+        Err(e) => {
+            error!("{}", e);
+            return EndpointError::with(status::BadGateway, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(status::Found);
+    response.headers.set(Location(url));
+    Ok(response)
+}
+
+fn oidc_callback(req: &mut Request, config: &Config, provider: &Provider)
+    -> IronResult<Response> {
+    let (code, state) = match req.get_ref::<Params>() {
+        Ok(params) => match (params.find(&["code"]), params.find(&["state"])) {
+            (Some(&Value::String(ref code)), Some(&Value::String(ref state))) => {
+                (code.clone(), state.clone())
+            },
+            _ => return EndpointError::with(status::BadRequest, ErrNo::InvalidParameter)
+        },
+        Err(_) => return EndpointError::with(status::BadRequest, ErrNo::BadRequest)
+    };
+    info!("GET /v1/account/oidc/callback");
+
+    // The state ties the callback to a log in started here, so that nobody
+    // can log users in with a code of their own.
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    if !try!(db.take_oidc_state(&state).map_err(internal_error)) {
+        return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized);
+    }
+    let email = match provider.email(&code) {
+        Ok(Some(email)) => email,
+        Ok(None) => {
+            return EndpointError::with_details(status::Unauthorized, ErrNo::Unauthorized,
+                                               "No verified email".to_owned())
+        },
+        Err(e) => {
+            error!("{}", e);
+            return EndpointError::with(status::BadGateway, ErrNo::InternalError)
+        }
+    };
+
+    try!(db.ensure_user(&email).map_err(internal_error));
+    session_response(&*db, &email)
+}
+
+fn log_out(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let (email, token) = try!(session(req, &*db));
+    info!("DELETE /v1/account/session email={}", email);
+
+    try!(db.delete_session(&token).map_err(internal_error));
+    json_response("{\"status\" : \"logged out\"}".to_owned())
+}
+
+fn boxes(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let (email, _) = try!(session(req, &*db));
+    info!("GET /v1/account/boxes email={}", email);
+
+    let records = try!(db.user_boxes(&email).map_err(internal_error));
+    let public_ip = privacy::stored_ip(config, &req.remote_addr.ip());
+    let now = config.clock.now();
+    let records: Vec<RecordStatus> = records.into_iter().map(|record| {
+        let strategy = discovery::strategy(&record, &public_ip);
+        RecordStatus::new(record, now, config.ping_interval).with_strategy(strategy)
+    }).collect();
+    json_response(try!(json::encode(&records).map_err(internal_error)))
+}
+
+fn link_box(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let (email, _) = try!(session(req, &*db));
+    let code = try!(validation::extract(req, validation::link));
+    let public_ip = privacy::stored_ip(config, &req.remote_addr.ip());
+    info!("POST /v1/account/boxes email={} public_ip={}", email, public_ip);
+
+    if let Some(max) = config.quotas.boxes {
+        if try!(db.box_count(&email).map_err(internal_error)) >= max {
+            return EndpointError::with_details(status::Forbidden, ErrNo::TooManyBoxes,
+                                               format!("At most {} boxes per account", max));
+        }
+    }
+
+    // Pairing codes are only shown by the boxes, so whoever has one is
+    // next to the box.
+    let record = match db.redeem_pairing_code(&code, public_ip) {
+        Ok(Pairing::Paired(record)) => record,
+        Ok(Pairing::Unknown) => return EndpointError::with(status::NotFound, ErrNo::NotFound),
+        Ok(Pairing::Throttled(retry_after)) => {
+            return EndpointError::with_retry_after(status::TooManyRequests,
+                                                   ErrNo::TooManyRequests,
+                                                   retry_after)
+        },
+        Err(e) => return Err(internal_error(e))
+    };
+    try!(db.link_box(&email, record.client.clone()).map_err(internal_error));
+
+    let record = RecordStatus::new(record, config.clock.now(), config.ping_interval);
+    json_response(try!(json::encode(&record).map_err(internal_error)))
+}
+
+fn unlink_box(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let (email, _) = try!(session(req, &*db));
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    info!("DELETE /v1/account/boxes/{} email={}", fingerprint, email);
+    let fingerprint = privacy::stored_fingerprint(config, &fingerprint);
+
+    match db.unlink_box(&email, fingerprint) {
+        Ok(true) => json_response("{\"status\" : \"unlinked\"}".to_owned()),
+        Ok(false) => EndpointError::with(status::NotFound, ErrNo::NotFound),
+        Err(e) => Err(internal_error(e))
+    }
+}
+
+/// Let a guest find a box of the account, e.g. a friend house-sitting,
+/// until the token expires or the box is unlinked.
+fn create_guest_token(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let (email, _) = try!(session(req, &*db));
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    let expires_in = try!(validation::extract(req, validation::guest_token));
+    info!("POST /v1/account/boxes/{}/guests email={} expires_in={}",
+          fingerprint, email, expires_in);
+    let fingerprint = privacy::stored_fingerprint(config, &fingerprint);
+
+    if !try!(db.is_owner(&email, fingerprint.clone()).map_err(internal_error)) {
+        return EndpointError::with(status::NotFound, ErrNo::NotFound);
+    }
+    let token = try!(db.create_guest_token(&email, fingerprint, expires_in)
+                       .map_err(internal_error));
+    json_response(format!("{{\"token\" : \"{}\", \"expires_in\" : {}}}", token, expires_in))
+}
+
+fn subdomains(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let (email, _) = try!(session(req, &*db));
+    info!("GET /v1/account/subdomains email={}", email);
+
+    let names = try!(db.user_subdomains(&email).map_err(internal_error));
+    json_response(try!(json::encode(&names).map_err(internal_error)))
+}
+
+fn reserve_subdomain(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let (email, _) = try!(session(req, &*db));
+    let name = try!(validation::extract(req, validation::subdomain));
+    info!("POST /v1/account/subdomains email={} name={}", email, name);
+    if let Some(reserved) = labels::lookalike(&name, &config.subdomain_blocklist) {
+        return EndpointError::with_details(status::Forbidden, ErrNo::LabelNotAllowed,
+                                           format!("`{}` is, or looks like, the reserved \
+                                                    name `{}`", name, reserved));
+    }
+
+    if let Some(max) = config.quotas.subdomains {
+        let names = try!(db.user_subdomains(&email).map_err(internal_error));
+        if !names.contains(&name) && names.len() as u64 >= max {
+            return EndpointError::with_details(status::Forbidden, ErrNo::TooManySubdomains,
+                                               format!("At most {} subdomains per account",
+                                                       max));
+        }
+    }
+
+    match db.reserve_subdomain(&email, &name) {
+        Ok(true) => json_response(format!("{{\"name\" : \"{}\"}}", name)),
+        Ok(false) => EndpointError::with(status::Conflict, ErrNo::Conflict),
+        Err(e) => Err(internal_error(e))
+    }
+}
+
+fn release_subdomain(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let (email, _) = try!(session(req, &*db));
+    let name = req.extensions.get::<Router>().unwrap()
+                  .find("name").unwrap_or("").to_lowercase();
+    info!("DELETE /v1/account/subdomains/{} email={}", name, email);
+
+    match db.release_subdomain(&email, &name) {
+        Ok(true) => json_response("{\"status\" : \"released\"}".to_owned()),
+        Ok(false) => EndpointError::with(status::NotFound, ErrNo::NotFound),
+        Err(e) => Err(internal_error(e))
+    }
+}
+
+fn guest_box(req: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("GET /v1/account/guest");
+    let token = match tokens::bearer(req) {
+        Some(token) => token,
+        None => return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized)
+    };
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let client = match db.guest_client(&token) {
+        Ok(Some(client)) => client,
+        Ok(None) => return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized),
+        Err(e) => return Err(internal_error(e))
+    };
+    let record = match db.find_by_client(client) {
+        Ok(Some(record)) => record,
+        Ok(None) => return EndpointError::with(status::NotFound, ErrNo::NotFound),
+        Err(e) => return Err(internal_error(e))
+    };
+    let public_ip = privacy::stored_ip(config, &req.remote_addr.ip());
+    let strategy = discovery::strategy(&record, &public_ip);
+    let record = RecordStatus::new(record, config.clock.now(), config.ping_interval)
+        .with_strategy(strategy);
+    json_response(try!(json::encode(&record).map_err(internal_error)))
+}
+
+pub fn create(config: Config) -> Routes {
+    let mut router = Routes::with_metrics(config.metrics.clone());
+
+    let cfg = config.clone();
+    router.post("/", move |req: &mut Request| -> IronResult<Response> {
+        create_account(req, &cfg)
+    }, "account_create");
+
+    let cfg = config.clone();
+    router.post("session", move |req: &mut Request| -> IronResult<Response> {
+        log_in(req, &cfg)
+    }, "account_log_in");
+
+    let cfg = config.clone();
+    router.route(Method::Delete, "session", move |req: &mut Request| -> IronResult<Response> {
+        log_out(req, &cfg)
+    }, "account_log_out");
+
+    let cfg = config.clone();
+    router.get("boxes", move |req: &mut Request| -> IronResult<Response> {
+        boxes(req, &cfg)
+    }, "account_boxes");
+
+    let cfg = config.clone();
+    router.post("boxes", move |req: &mut Request| -> IronResult<Response> {
+        link_box(req, &cfg)
+    }, "account_link_box");
+
+    let cfg = config.clone();
+    router.route(Method::Delete, "boxes/:fingerprint",
+                 move |req: &mut Request| -> IronResult<Response> {
+        unlink_box(req, &cfg)
+    }, "account_unlink_box");
+
+    let cfg = config.clone();
+    router.post("boxes/:fingerprint/guests", move |req: &mut Request| -> IronResult<Response> {
+        create_guest_token(req, &cfg)
+    }, "account_create_guest_token");
+
+    let cfg = config.clone();
+    router.get("subdomains", move |req: &mut Request| -> IronResult<Response> {
+        subdomains(req, &cfg)
+    }, "account_subdomains");
+
+    let cfg = config.clone();
+    router.post("subdomains", move |req: &mut Request| -> IronResult<Response> {
+        reserve_subdomain(req, &cfg)
+    }, "account_reserve_subdomain");
+
+    let cfg = config.clone();
+    router.route(Method::Delete, "subdomains/:name",
+                 move |req: &mut Request| -> IronResult<Response> {
+        release_subdomain(req, &cfg)
+    }, "account_release_subdomain");
+
+    let cfg = config.clone();
+    router.get("guest", move |req: &mut Request| -> IronResult<Response> {
+        guest_box(req, &cfg)
+    }, "account_guest_box");
+
+    if let Some(provider) = config.oidc.clone() {
+        let cfg = config.clone();
+        let prv = provider.clone();
+        router.get("oidc/login", move |req: &mut Request| -> IronResult<Response> {
+            oidc_login(req, &cfg, &prv)
+        }, "account_oidc_login");
+
+        let cfg = config.clone();
+        router.get("oidc/callback", move |req: &mut Request| -> IronResult<Response> {
+            oidc_callback(req, &cfg, &provider)
+        }, "account_oidc_callback");
+    }
+
+    router
+}
+
+#[test]
+fn test_accounts() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+    use rustc_serialize::json::Json;
+
+    let server = TestServer::with_config(|config| {
+        config.accounts = true;
+        config.quotas = Quotas {
+            boxes: Some(1),
+            registrations_per_hour: Some(1),
+            subdomains: None,
+        };
+    });
+
+    let credentials = r#"{"email": "User@example.com", "password": "correct horse"}"#;
+    assert_eq!(server.post("/v1/account", credentials).0, StatusCode::Ok);
+    assert_eq!(server.post("/v1/account", credentials).0, StatusCode::Conflict);
+    assert_eq!(server.post("/v1/account", r#"{"email": "a@b", "password": "short"}"#).0,
+               StatusCode::BadRequest);
+    assert_eq!(server.post("/v1/account/session",
+                           r#"{"email": "user@example.com", "password": "wrong"}"#).0,
+               StatusCode::Unauthorized);
+
+    let (status, body) = server.post("/v1/account/session", credentials);
+    assert_eq!(status, StatusCode::Ok);
+    let session = token_from(&body);
+
+    let (_, body) = server.post("/register", r#"{"client": "<fingerprint>", "message": "m"}"#);
+    let box_token = token_from(&body);
+    let (_, _, body) = server.request("POST", "/v1/pairing", bearer(&box_token),
+                                      Some(r#"{"client": "<fingerprint>"}"#));
+    let code = Json::from_str(&body).unwrap()
+                   .find("code").and_then(Json::as_string).unwrap().to_owned();
+
+    let link = format!(r#"{{"code": "{}"}}"#, code);
+    let (status, _, _) = server.request("POST", "/v1/account/boxes", bearer(&session),
+                                        Some(&link[..]));
+    assert_eq!(status, StatusCode::Ok);
+
+    let (status, _, body) = server.request("GET", "/v1/account/boxes", bearer(&session), None);
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""client":"<fingerprint>""#));
+
+    let (status, _, body) = server.request("POST", "/v1/account/boxes", bearer(&session),
+                                           Some(r#"{"code": "000000"}"#));
+    assert_eq!(status, StatusCode::Forbidden);
+    assert!(body.contains(&format!(r#""errno":{}"#, ErrNo::TooManyBoxes.code())));
+
+    // Only the registrations of linked boxes count.
+    let registration = r#"{"client": "<fingerprint>", "message": "m"}"#;
+    let (status, headers, _) = server.request("POST", "/register", Headers::new(),
+                                              Some(registration));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(headers.get_raw("X-RateLimit-Remaining"), Some(&[b"0".to_vec()][..]));
+    let (status, headers, body) = server.request("POST", "/register", Headers::new(),
+                                                 Some(registration));
+    assert_eq!(status, StatusCode::TooManyRequests);
+    assert!(body.contains(&format!(r#""errno":{}"#, ErrNo::TooManyRegistrations.code())));
+    assert_eq!(headers.get_raw("X-RateLimit-Limit"), Some(&[b"1".to_vec()][..]));
+    assert!(headers.get_raw("Retry-After").is_some());
+
+    let (status, _, _) = server.request("DELETE", "/v1/account/boxes/<fingerprint>",
+                                        bearer(&session), None);
+    assert_eq!(status, StatusCode::Ok);
+    let (_, _, body) = server.request("GET", "/v1/account/boxes", bearer(&session), None);
+    assert_eq!(body, "[]");
+
+    let (status, _, _) = server.request("DELETE", "/v1/account/session", bearer(&session), None);
+    assert_eq!(status, StatusCode::Ok);
+    let (status, _, _) = server.request("GET", "/v1/account/boxes", bearer(&session), None);
+    assert_eq!(status, StatusCode::Unauthorized);
+}
+
+#[test]
+fn test_accounts_storage() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::status::StatusCode;
+    use redis::ErrorKind;
+
+    let (server, storage) = TestServer::with_mock_storage_config(|config| {
+        config.accounts = true;
+    });
+
+    let credentials = r#"{"email": "user@example.com", "password": "correct horse"}"#;
+    assert_eq!(server.post("/v1/account", credentials).0, StatusCode::Ok);
+    let (status, body) = server.post("/v1/account/session", credentials);
+    assert_eq!(status, StatusCode::Ok);
+    let session = token_from(&body);
+
+    storage.fail("user_boxes", ErrorKind::IoError, "connection lost");
+    let (status, _, _) = server.request("GET", "/v1/account/boxes", bearer(&session), None);
+    assert_eq!(status, StatusCode::InternalServerError);
+
+    assert_eq!(storage.calls(), vec!["create_user user@example.com",
+                                     "password_hash user@example.com",
+                                     "create_session user@example.com",
+                                     "session_user ", "user_boxes user@example.com"]);
+}
+
+#[test]
+fn test_subdomains() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::status::StatusCode;
+
+    let (server, storage) = TestServer::with_mock_storage_config(|config| {
+        config.accounts = true;
+        config.quotas.subdomains = Some(1);
+        config.subdomain_blocklist = vec!["paypal".to_owned()];
+    });
+    storage.add_session("other-session", "other@example.com");
+
+    let credentials = r#"{"email": "user@example.com", "password": "correct horse"}"#;
+    assert_eq!(server.post("/v1/account", credentials).0, StatusCode::Ok);
+    let session = token_from(&server.post("/v1/account/session", credentials).1);
+
+    let reserve = |session: &str, name: &str| {
+        let body = format!(r#"{{"name": "{}"}}"#, name);
+        server.request("POST", "/v1/account/subdomains", bearer(session), Some(&body[..]))
+    };
+    let (status, _, body) = reserve(&session, "Kitchen");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, r#"{"name" : "kitchen"}"#);
+    // Reserving it again doesn't count against the quota.
+    assert_eq!(reserve(&session, "kitchen").0, StatusCode::Ok);
+    let (status, _, body) = reserve(&session, "garage");
+    assert_eq!(status, StatusCode::Forbidden);
+    assert!(body.contains(&format!(r#""errno":{}"#, ErrNo::TooManySubdomains.code())));
+    assert_eq!(reserve("other-session", "kitchen").0, StatusCode::Conflict);
+    assert_eq!(reserve(&session, "-garage").0, StatusCode::BadRequest);
+    for name in &["Admin", "adm1n", "paypa1"] {
+        let (status, _, body) = reserve(&session, name);
+        assert_eq!(status, StatusCode::Forbidden);
+        assert!(body.contains(&format!(r#""errno":{}"#, ErrNo::LabelNotAllowed.code())));
+    }
+
+    let (status, _, body) = server.request("GET", "/v1/account/subdomains", bearer(&session),
+                                           None);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, r#"["kitchen"]"#);
+
+    let (status, _, _) = server.request("DELETE", "/v1/account/subdomains/kitchen",
+                                        bearer("other-session"), None);
+    assert_eq!(status, StatusCode::NotFound);
+    let (status, _, _) = server.request("DELETE", "/v1/account/subdomains/kitchen",
+                                        bearer(&session), None);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(reserve("other-session", "kitchen").0, StatusCode::Ok);
+}
+
+#[test]
+fn test_guest_tokens() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+    use rustc_serialize::json::Json;
+
+    let server = TestServer::with_config(|config| config.accounts = true);
+
+    let credentials = r#"{"email": "user@example.com", "password": "correct horse"}"#;
+    server.post("/v1/account", credentials);
+    let session = token_from(&server.post("/v1/account/session", credentials).1);
+    let (_, body) = server.post("/register", r#"{"client": "<fingerprint>", "message": "m"}"#);
+    let box_token = token_from(&body);
+    let (_, _, body) = server.request("POST", "/v1/pairing", bearer(&box_token),
+                                      Some(r#"{"client": "<fingerprint>"}"#));
+    let code = Json::from_str(&body).unwrap()
+                   .find("code").and_then(Json::as_string).unwrap().to_owned();
+    let link = format!(r#"{{"code": "{}"}}"#, code);
+    server.request("POST", "/v1/account/boxes", bearer(&session), Some(&link[..]));
+
+    let path = "/v1/account/boxes/<fingerprint>/guests";
+    let (status, _, body) = server.request("POST", path, bearer(&session),
+                                           Some(r#"{"expires_in": 600}"#));
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""expires_in" : 600"#));
+    let guest = token_from(&body);
+    assert_eq!(server.request("POST", path, bearer(&session),
+                              Some(r#"{"expires_in": 0}"#)).0,
+               StatusCode::BadRequest);
+    assert_eq!(server.request("POST", "/v1/account/boxes/other/guests", bearer(&session),
+                              None).0,
+               StatusCode::NotFound);
+    assert_eq!(server.request("POST", path, Headers::new(), None).0, StatusCode::Unauthorized);
+
+    let (status, _, body) = server.request("GET", "/v1/account/guest", bearer(&guest), None);
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""client":"<fingerprint>""#));
+    // The guest token is only good for finding the box.
+    assert_eq!(server.request("GET", "/v1/account/boxes", bearer(&guest), None).0,
+               StatusCode::Unauthorized);
+    assert_eq!(server.request("GET", "/v1/account/guest", bearer("wrong"), None).0,
+               StatusCode::Unauthorized);
+
+    // Unlinking the box ends the access of its guests.
+    server.request("DELETE", "/v1/account/boxes/<fingerprint>", bearer(&session), None);
+    assert_eq!(server.request("GET", "/v1/account/guest", bearer(&guest), None).0,
+               StatusCode::Unauthorized);
+}
@@ -0,0 +1,919 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Administration endpoints, mounted under /admin.
+/// All of them require the `Authorization: Bearer <admin token>` header, with
+/// the token of `--admin-token` or one created with `keys create admin`.
+///
+/// GET /admin/records => list the records matching the optional `public_ip`,
+///                       `fingerprint`, `since` and `until` query parameters.
+/// POST /admin/lookup => bulk lookup of a list of public IPs and/or
+///                       fingerprints, e.g.
+///                       { "public_ips": ["88.22.170.96"],
+///                         "fingerprints": ["e7ce02ea..."] }
+/// GET /admin/export => dump all the records, as JSON Lines by default or
+///                       as CSV with `format=csv`.
+/// GET /admin/integrity => check the consistency of the database, answering
+///                          with a 503 if problems were found.
+/// POST /admin/backup => write a backup of all the records to the backup
+///                       directory.
+/// GET /admin/metrics => the requests and errors of each route, the UDP
+///                        datagrams, the eviction runs, the database health
+///                        checks and the latency and retries of the storage
+///                        operations, since this instance started.
+/// GET /admin/dashboard => a page showing the current boxes, the stats and
+///                          the metrics.
+/// DELETE /admin/records/<fingerprint> => delete the latest record of a
+///                                        client.
+/// GET /admin/archive/<fingerprint> => the latest registration of a box, and
+///                                     when it was evicted, with
+///                                     --retain-archive.
+/// GET /admin/flapping => the boxes whose public IP changed implausibly
+///                        often during the last day.
+/// GET /admin/public_ips => the public IPs with their number of clients and
+///                          latest registration, those with the most clients
+///                          first, optionally only those with at least
+///                          `min_clients` clients.
+/// GET /admin/pinned => the fingerprints of the pinned boxes.
+/// PUT /admin/records/<fingerprint>/pinned => exempt the latest record of a
+///                                           box from expiration and
+///                                           eviction.
+/// DELETE /admin/records/<fingerprint>/pinned => let it expire again.
+/// POST /admin/evict => drop the expired clients right away, returning
+///                      how many were dropped.
+/// GET /admin/stats => the number of public IPs and of clients, the
+///                     registration intervals and the churn rate.
+/// GET /admin/usage => the anonymized daily usage aggregates, with
+///                     --usage-aggregates.
+/// GET /admin/read_only => whether the server is read-only.
+/// PUT /admin/read_only => turn the read-only mode on or off, e.g.
+///                         { "read_only": true, "retry_after": 300 }
+/// GET /admin/features => whether each optional feature is enabled.
+/// PUT /admin/features => turn features on or off, e.g. { "push": false }
+/// GET /admin/revoked => the hashes of the revoked credentials, and when
+///                       they were revoked, the latest first.
+/// POST /admin/revoked => revoke a box token or API key, or the tokens of a
+///                        box, e.g. { "credential": "..." } or
+///                        { "fingerprint": "e7ce02ea..." }
+/// DELETE /admin/revoked/<hash> => accept a revoked credential again.
+
+use backup;
+use cache;
+use config::Config;
+use export::{ ExportBody, Format };
+use features::Feature;
+use db::{ Filter, RecordStatus, ADMIN_CREDENTIAL };
+use errors::*;
+use iron::{ BeforeMiddleware, Chain };
+use iron::headers::ContentType;
+use iron::method::Method;
+use iron::prelude::*;
+use iron::status::{ self, Status };
+use params::{ Map, Params, Value };
+use privacy;
+use read_only::DEFAULT_RETRY_AFTER;
+use redis::RedisResult;
+use revocation;
+use router::Router;
+use routing::Routes;
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::Encodable;
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use tokens;
+use validation;
+
+/// Maximum number of entries accepted by a single bulk lookup.
+static MAX_LOOKUP_ENTRIES: usize = 1000;
+
+struct AdminAuth {
+    config: Config,
+}
+
+/// The token an `Authorization` header carries, either as a bearer token or
+/// as the password of HTTP basic authentication, which lets browsers open
+/// the dashboard.
+fn presented_token(value: &[u8]) -> Option<Vec<u8>> {
+    if value.starts_with(b"Bearer ") {
+        return Some(value[7..].to_vec());
+    }
+    if !value.starts_with(b"Basic ") {
+        return None;
+    }
+    match value[6..].from_base64() {
+        Ok(credentials) => credentials.iter().position(|&byte| byte == b':').map(|colon| {
+            credentials[colon + 1..].to_vec()
+        }),
+        Err(_) => None
+    }
+}
+
+/// Whether an `Authorization` header carries the admin token.
+fn is_authorized(token: &str, value: &[u8]) -> bool {
+    presented_token(value).and_then(|given| String::from_utf8(given).ok())
+                          .map_or(false, |given| tokens::matches(token, &given))
+}
+
+impl AdminAuth {
+    /// Whether `value` carries one of the admin tokens created from the
+    /// command line.
+    fn has_admin_credential(&self, value: &[u8]) -> bool {
+        let token = match presented_token(value).and_then(|given| String::from_utf8(given).ok()) {
+            Some(token) => token,
+            None => return false
+        };
+        let found = self.config.storage.connect(&self.config).and_then(|db| {
+            db.find_credential(revocation::hash(&token))
+        });
+        match found {
+            Ok(Some(credential)) => credential.kind == ADMIN_CREDENTIAL,
+            Ok(None) => false,
+            Err(e) => {
+                error!("Can't check the admin credentials: {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl BeforeMiddleware for AdminAuth {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let authorized = match req.headers.get_raw("Authorization") {
+            Some(values) if values.len() == 1 => {
+                self.config.admin_token.as_ref().map_or(false, |token| {
+                    is_authorized(token, &values[0])
+                }) || self.has_admin_credential(&values[0])
+            },
+            _ => false
+        };
+
+        if authorized {
+            Ok(())
+        } else {
+            let mut error = EndpointError::build(status::Unauthorized, ErrNo::Unauthorized,
+                                                 None, None);
+            error.response.headers.set_raw("WWW-Authenticate",
+                                           vec![b"Basic realm=\"admin\"".to_vec()]);
+            Err(error)
+        }
+    }
+}
+
+/// Parameters helpers return the name of the parameter in error.
+fn string_param(params: &Map, name: &str) -> Result<Option<String>, String> {
+    match params.find(&[name]) {
+        Some(&Value::String(ref value)) => Ok(Some(value.clone())),
+        Some(_) => Err(name.to_owned()),
+        None => Ok(None)
+    }
+}
+
+fn time_param(params: &Map, name: &str) -> Result<Option<u64>, String> {
+    match try!(string_param(params, name)) {
+        Some(value) => value.parse().map(Some).map_err(|_| name.to_owned()),
+        None => Ok(None)
+    }
+}
+
+fn invalid_param(name: String) -> IronResult<Response> {
+    EndpointError::with_details(status::BadRequest, ErrNo::InvalidParameter,
+                                format!("Invalid `{}` parameter", name))
+}
+
+fn filter_from_params(params: &Map) -> Result<Filter, String> {
+    Ok(Filter {
+        public_ip: try!(string_param(params, "public_ip")),
+        client: try!(string_param(params, "fingerprint")),
+        since: try!(time_param(params, "since")),
+        until: try!(time_param(params, "until")),
+    })
+}
+
+fn records(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let filter = match req.get_ref::<Params>() {
+        Ok(params) => match filter_from_params(params) {
+            Ok(filter) => filter,
+            Err(name) => return invalid_param(name)
+        },
+        Err(_) => Filter::default()
+    };
+    info!("GET /admin/records {:?}", filter);
+    let filter = Filter {
+        client: filter.client.map(|fingerprint| privacy::stored_fingerprint(config, &fingerprint)),
+        .. filter
+    };
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let records = match db.find(&filter) {
+        Ok(records) => records,
+        Err(e) => {
+            error!("{}", e);
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let now = config.clock.now();
+    let records: Vec<RecordStatus> = records.into_iter().map(|record| {
+        RecordStatus::new(record, now, config.ping_interval)
+    }).collect();
+    let serialized = match json::encode(&records) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+fn lookup(req: &mut Request, config: &Config) -> IronResult<Response> {
+    #[derive(RustcDecodable, Debug)]
+    struct LookupBody {
+        public_ips: Option<Vec<String>>,
+        fingerprints: Option<Vec<String>>,
+    }
+
+    #[derive(RustcEncodable, Debug)]
+    struct LookupResult {
+        public_ips: BTreeMap<String, Vec<RecordStatus>>,
+        fingerprints: BTreeMap<String, Option<RecordStatus>>,
+    }
+
+    let payload = try!(validation::json_body(req));
+    let body: LookupBody = match json::decode(&payload) {
+        Ok(body) => body,
+        Err(error) => {
+            error!("{:?}", error);
+            return from_decoder_error(error);
+        }
+    };
+
+    let public_ips = body.public_ips.unwrap_or(vec![]);
+    let fingerprints = body.fingerprints.unwrap_or(vec![]);
+    info!("POST /admin/lookup {} public IPs, {} fingerprints",
+          public_ips.len(), fingerprints.len());
+
+    if public_ips.len() + fingerprints.len() > MAX_LOOKUP_ENTRIES {
+        return EndpointError::with_details(status::BadRequest,
+                                           ErrNo::TooManyEntries,
+                                           format!("At most {} entries are accepted",
+                                                   MAX_LOOKUP_ENTRIES))
+    }
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let mut result = LookupResult {
+        public_ips: BTreeMap::new(),
+        fingerprints: BTreeMap::new(),
+    };
+
+    for public_ip in public_ips {
+        match db.get(public_ip.clone()) {
+            Ok(records) => {
+                let records = records.into_iter().map(|record| {
+                    RecordStatus::new(record, config.clock.now(), config.ping_interval)
+                }).collect();
+                result.public_ips.insert(public_ip, records);
+            },
+            Err(e) => {
+                error!("{}", e);
+                return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+            }
+        }
+    }
+
+    for fingerprint in fingerprints {
+        match db.find_by_client(privacy::stored_fingerprint(config, &fingerprint)) {
+            Ok(record) => {
+                let record = record.map(|record| {
+                    RecordStatus::new(record, config.clock.now(), config.ping_interval)
+                });
+                result.fingerprints.insert(fingerprint, record);
+            },
+            Err(e) => {
+                error!("{}", e);
+                return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+            }
+        }
+    }
+
+    let serialized = match json::encode(&result) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+fn export(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let format = match req.get_ref::<Params>() {
+        Ok(params) => match string_param(params, "format") {
+            Ok(Some(name)) => match Format::from_name(&name) {
+                Some(format) => format,
+                None => return invalid_param("format".to_owned())
+            },
+            Ok(None) => Format::JsonLines,
+            Err(name) => return invalid_param(name)
+        },
+        Err(_) => Format::JsonLines
+    };
+    info!("GET /admin/export {:?}", format);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let records = match db.find(&Filter::default()) {
+        Ok(records) => records,
+        Err(e) => {
+            error!("{}", e);
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::new();
+    response.status = Some(Status::Ok);
+    response.headers.set_raw("Content-Type",
+                             vec![format.content_type().as_bytes().to_vec()]);
+    response.body = Some(Box::new(ExportBody {
+        records: records,
+        format: format,
+    }));
+
+    Ok(response)
+}
+
+/// Maximum number of problems reported by the integrity check.
+static MAX_INTEGRITY_PROBLEMS: usize = 100;
+
+fn integrity(_: &mut Request, config: &Config) -> IronResult<Response> {
+    #[derive(RustcEncodable, Debug)]
+    struct IntegrityResult {
+        ok: bool,
+        problems: Vec<String>,
+    }
+
+    info!("GET /admin/integrity");
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let problems = match db.check_integrity(MAX_INTEGRITY_PROBLEMS) {
+        Ok(problems) => problems,
+        Err(e) => {
+            error!("{}", e);
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    for problem in &problems {
+        warn!("Integrity check: {}", problem);
+    }
+
+    let result = IntegrityResult {
+        ok: problems.is_empty(),
+        problems: problems,
+    };
+    let serialized = match json::encode(&result) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(if result.ok {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    });
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+fn backup(_: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("POST /admin/backup");
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let path = match backup::backup(&*db, config.clock.now(), &config.backup_dir) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("Backup failed: {}", e);
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(
+        json::encode(&path.to_string_lossy().into_owned())
+            .map(|path| format!("{{\"status\" : \"ok\", \"path\" : {}}}", path))
+            .unwrap()
+    );
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+/// Reply with `value` as JSON, or with a 500 when `result` is an error.
+fn json_response<T: Encodable>(result: RedisResult<T>) -> IronResult<Response> {
+    let serialized = match result.map_err(|e| e.to_string())
+                                 .and_then(|value| {
+                                     json::encode(&value).map_err(|e| e.to_string())
+                                 }) {
+        Ok(serialized) => serialized,
+        Err(e) => {
+            error!("{}", e);
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+fn delete(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    info!("DELETE /admin/records/{}", fingerprint);
+    let fingerprint = privacy::stored_fingerprint(config, &fingerprint);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let record = db.find_by_client(fingerprint.clone()).unwrap_or(None);
+    let result = db.delete(fingerprint);
+    if let Some(record) = record {
+        cache::invalidate(config, &record.public_ip);
+    }
+    match result {
+        Ok(false) => EndpointError::with(status::NotFound, ErrNo::NotFound),
+        result => json_response(result.map(|deleted| {
+            let mut result = BTreeMap::new();
+            result.insert("deleted", deleted);
+            result
+        }))
+    }
+}
+
+fn archived(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    info!("GET /admin/archive/{}", fingerprint);
+    let fingerprint = privacy::stored_fingerprint(config, &fingerprint);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    match db.archived(fingerprint) {
+        Ok(None) => EndpointError::with(status::NotFound, ErrNo::NotFound),
+        result => json_response(result.map(Option::unwrap))
+    }
+}
+
+fn flapping(_: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("GET /admin/flapping");
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    json_response(db.flapping())
+}
+
+fn public_ips(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let min_clients = match req.get_ref::<Params>() {
+        Ok(params) => match string_param(params, "min_clients") {
+            Ok(None) => 1,
+            Ok(Some(value)) => match value.parse::<usize>() {
+                Ok(min_clients) => min_clients,
+                Err(_) => return invalid_param("min_clients".to_owned())
+            },
+            Err(name) => return invalid_param(name)
+        },
+        Err(_) => 1
+    };
+    info!("GET /admin/public_ips min_clients={}", min_clients);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    json_response(db.public_ips(min_clients))
+}
+
+fn pinned(_: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("GET /admin/pinned");
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    json_response(db.pinned())
+}
+
+fn pin(req: &mut Request, config: &Config, pinned: bool) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    info!("{} /admin/records/{}/pinned", if pinned { "PUT" } else { "DELETE" }, fingerprint);
+    let fingerprint = privacy::stored_fingerprint(config, &fingerprint);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let record = db.find_by_client(fingerprint.clone()).unwrap_or(None);
+    let result = if pinned { db.pin(fingerprint) } else { db.unpin(fingerprint) };
+    if let Some(record) = record {
+        cache::invalidate(config, &record.public_ip);
+    }
+    match result {
+        Ok(false) => EndpointError::with(status::NotFound, ErrNo::NotFound),
+        result => json_response(result.map(|_| {
+            let mut result = BTreeMap::new();
+            result.insert("pinned", pinned);
+            result
+        }))
+    }
+}
+
+fn evict(_: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("POST /admin/evict");
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    json_response(db.evict_clients().map(|evicted| {
+        let count = evicted.len();
+        config.metrics.record_eviction(count, config.clock.now());
+        for &(ref public_ip, _) in &evicted {
+            cache::invalidate(config, public_ip);
+        }
+        config.events.publish_evictions(config, &*db, evicted);
+        let mut result = BTreeMap::new();
+        result.insert("evicted", count);
+        result
+    }))
+}
+
+fn stats(_: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("GET /admin/stats");
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    json_response(db.stats())
+}
+
+fn usage(_: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("GET /admin/usage");
+    if !config.usage_aggregates {
+        return EndpointError::with(status::NotFound, ErrNo::NotFound)
+    }
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    json_response(db.usage())
+}
+
+fn metrics(_: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("GET /admin/metrics");
+    json_response(Ok(config.metrics.snapshot()))
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+struct ReadOnlyState {
+    read_only: bool,
+    retry_after: Option<u64>,
+}
+
+fn read_only_state(config: &Config) -> IronResult<Response> {
+    let retry_after = config.read_only.retry_after();
+    json_response(Ok(ReadOnlyState {
+        read_only: retry_after.is_some(),
+        retry_after: retry_after,
+    }))
+}
+
+fn get_read_only(_: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("GET /admin/read_only");
+    read_only_state(config)
+}
+
+fn set_read_only(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let payload = try!(validation::json_body(req));
+    let body: ReadOnlyState = match json::decode(&payload) {
+        Ok(body) => body,
+        Err(error) => {
+            error!("{:?}", error);
+            return from_decoder_error(error);
+        }
+    };
+    info!("PUT /admin/read_only {:?}", body);
+
+    if body.read_only {
+        let retry_after = body.retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+        warn!("Entering read-only mode, clients retry after {} seconds", retry_after);
+        config.read_only.enable(retry_after);
+    } else {
+        warn!("Leaving read-only mode");
+        config.read_only.disable();
+    }
+    read_only_state(config)
+}
+
+fn get_features(_: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("GET /admin/features");
+    json_response(Ok(config.features.snapshot()))
+}
+
+fn set_features(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let payload = try!(validation::json_body(req));
+    let body: BTreeMap<String, bool> = match json::decode(&payload) {
+        Ok(body) => body,
+        Err(error) => {
+            error!("{:?}", error);
+            return from_decoder_error(error);
+        }
+    };
+    info!("PUT /admin/features {:?}", body);
+
+    // Check every change before applying any.
+    let mut changes = Vec::with_capacity(body.len());
+    for (name, enabled) in body {
+        match Feature::from_name(&name) {
+            Some(feature) => changes.push((feature, enabled)),
+            None => {
+                return EndpointError::with_details(status::BadRequest, ErrNo::BadRequest,
+                                                   format!("Unknown feature `{}`", name))
+            }
+        }
+    }
+    for &(feature, enabled) in &changes {
+        if enabled && !config.features.is_available(feature) {
+            return EndpointError::with_details(status::BadRequest, ErrNo::BadRequest,
+                                               format!("The `{}` feature isn't configured",
+                                                       feature.name()))
+        }
+    }
+    for (feature, enabled) in changes {
+        warn!("Turning the {} feature {}", feature.name(), if enabled { "on" } else { "off" });
+        config.features.set(feature, enabled);
+    }
+    json_response(Ok(config.features.snapshot()))
+}
+
+fn revoked(_: &mut Request, config: &Config) -> IronResult<Response> {
+    info!("GET /admin/revoked");
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    json_response(db.revoked())
+}
+
+fn revoke(req: &mut Request, config: &Config) -> IronResult<Response> {
+    #[derive(RustcDecodable)]
+    struct RevokeBody {
+        credential: Option<String>,
+        fingerprint: Option<String>,
+    }
+
+    let payload = try!(validation::json_body(req));
+    let body: RevokeBody = match json::decode(&payload) {
+        Ok(body) => body,
+        Err(error) => {
+            error!("{:?}", error);
+            return from_decoder_error(error);
+        }
+    };
+    // The credentials aren't logged, since they are secrets.
+    info!("POST /admin/revoked fingerprint={:?}", body.fingerprint);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let mut hashes: Vec<String> = body.credential.iter()
+                                      .map(|credential| revocation::hash(credential))
+                                      .collect();
+    if let Some(fingerprint) = body.fingerprint {
+        let client = privacy::stored_fingerprint(config, &fingerprint);
+        match db.tokens(&client) {
+            Ok(ref tokens) if tokens.is_empty() => {
+                return EndpointError::with(status::NotFound, ErrNo::NotFound)
+            },
+            Ok(tokens) => hashes.extend(tokens.iter().map(|token| revocation::hash(token))),
+            Err(e) => return Err(database_error(e))
+        }
+    }
+    if hashes.is_empty() {
+        let details = "Expected a `credential` or a `fingerprint`".to_owned();
+        return EndpointError::with_details(status::BadRequest, ErrNo::BadRequest, details)
+    }
+
+    warn!("Revoking {} credentials", hashes.len());
+    json_response(config.revocations.revoke(&*db, &hashes).map(|_| {
+        let mut result = BTreeMap::new();
+        result.insert("revoked", hashes);
+        result
+    }))
+}
+
+fn restore(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let hash = req.extensions.get::<Router>().unwrap()
+                  .find("hash").unwrap_or("").to_owned();
+    info!("DELETE /admin/revoked/{}", hash);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    match config.revocations.restore(&*db, &hash) {
+        Ok(false) => EndpointError::with(status::NotFound, ErrNo::NotFound),
+        result => json_response(result.map(|_| {
+            let mut result = BTreeMap::new();
+            result.insert("revoked", false);
+            result
+        }))
+    }
+}
+
+fn dashboard(_: &mut Request) -> IronResult<Response> {
+    let mut response = Response::with(include_str!("../static/dashboard.html"));
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::html());
+
+    Ok(response)
+}
+
+pub fn create(config: Config) -> Chain {
+    let mut router = Routes::with_metrics(config.metrics.clone());
+
+    let cfg = config.clone();
+    router.get("records", move |req: &mut Request| -> IronResult<Response> {
+        records(req, &cfg)
+    }, "admin_records");
+
+    let cfg = config.clone();
+    router.post("lookup", move |req: &mut Request| -> IronResult<Response> {
+        lookup(req, &cfg)
+    }, "admin_lookup");
+
+    let cfg = config.clone();
+    router.get("export", move |req: &mut Request| -> IronResult<Response> {
+        export(req, &cfg)
+    }, "admin_export");
+
+    let cfg = config.clone();
+    router.get("integrity", move |req: &mut Request| -> IronResult<Response> {
+        integrity(req, &cfg)
+    }, "admin_integrity");
+
+    let cfg = config.clone();
+    router.post("backup", move |req: &mut Request| -> IronResult<Response> {
+        backup(req, &cfg)
+    }, "admin_backup");
+
+    let cfg = config.clone();
+    router.route(Method::Delete, "records/:fingerprint",
+                 move |req: &mut Request| -> IronResult<Response> {
+        delete(req, &cfg)
+    }, "admin_delete");
+
+    let cfg = config.clone();
+    router.get("archive/:fingerprint", move |req: &mut Request| -> IronResult<Response> {
+        archived(req, &cfg)
+    }, "admin_archive");
+
+    let cfg = config.clone();
+    router.get("flapping", move |req: &mut Request| -> IronResult<Response> {
+        flapping(req, &cfg)
+    }, "admin_flapping");
+
+    let cfg = config.clone();
+    router.get("public_ips", move |req: &mut Request| -> IronResult<Response> {
+        public_ips(req, &cfg)
+    }, "admin_public_ips");
+
+    let cfg = config.clone();
+    router.get("pinned", move |req: &mut Request| -> IronResult<Response> {
+        pinned(req, &cfg)
+    }, "admin_pinned");
+
+    let cfg = config.clone();
+    router.route(Method::Put, "records/:fingerprint/pinned",
+                 move |req: &mut Request| -> IronResult<Response> {
+        pin(req, &cfg, true)
+    }, "admin_pin");
+
+    let cfg = config.clone();
+    router.route(Method::Delete, "records/:fingerprint/pinned",
+                 move |req: &mut Request| -> IronResult<Response> {
+        pin(req, &cfg, false)
+    }, "admin_unpin");
+
+    let cfg = config.clone();
+    router.post("evict", move |req: &mut Request| -> IronResult<Response> {
+        evict(req, &cfg)
+    }, "admin_evict");
+
+    let cfg = config.clone();
+    router.get("stats", move |req: &mut Request| -> IronResult<Response> {
+        stats(req, &cfg)
+    }, "admin_stats");
+
+    let cfg = config.clone();
+    router.get("usage", move |req: &mut Request| -> IronResult<Response> {
+        usage(req, &cfg)
+    }, "admin_usage");
+
+    let cfg = config.clone();
+    router.get("metrics", move |req: &mut Request| -> IronResult<Response> {
+        metrics(req, &cfg)
+    }, "admin_metrics");
+
+    let cfg = config.clone();
+    router.get("read_only", move |req: &mut Request| -> IronResult<Response> {
+        get_read_only(req, &cfg)
+    }, "admin_read_only");
+
+    let cfg = config.clone();
+    router.route(Method::Put, "read_only", move |req: &mut Request| -> IronResult<Response> {
+        set_read_only(req, &cfg)
+    }, "admin_set_read_only");
+
+    let cfg = config.clone();
+    router.get("features", move |req: &mut Request| -> IronResult<Response> {
+        get_features(req, &cfg)
+    }, "admin_features");
+
+    let cfg = config.clone();
+    router.route(Method::Put, "features", move |req: &mut Request| -> IronResult<Response> {
+        set_features(req, &cfg)
+    }, "admin_set_features");
+
+    let cfg = config.clone();
+    router.get("revoked", move |req: &mut Request| -> IronResult<Response> {
+        revoked(req, &cfg)
+    }, "admin_revoked");
+
+    let cfg = config.clone();
+    router.post("revoked", move |req: &mut Request| -> IronResult<Response> {
+        revoke(req, &cfg)
+    }, "admin_revoke");
+
+    let cfg = config.clone();
+    router.route(Method::Delete, "revoked/:hash",
+                 move |req: &mut Request| -> IronResult<Response> {
+        restore(req, &cfg)
+    }, "admin_restore");
+
+    router.get("dashboard", dashboard, "admin_dashboard");
+
+    let mut chain = Chain::new(router);
+    chain.link_before(AdminAuth { config: config.clone() });
+    chain
+}
+
+#[test]
+fn test_is_authorized() {
+    use rustc_serialize::base64::{ ToBase64, STANDARD };
+
+    let basic = |credentials: &str| {
+        format!("Basic {}", credentials.as_bytes().to_base64(STANDARD))
+    };
+    assert!(is_authorized("secret", b"Bearer secret"));
+    assert!(is_authorized("secret", basic("admin:secret").as_bytes()));
+    assert!(is_authorized("secret", basic(":secret").as_bytes()));
+    assert!(!is_authorized("secret", b"Bearer other"));
+    assert!(!is_authorized("secret", basic("admin:other").as_bytes()));
+    assert!(!is_authorized("secret", basic("secret").as_bytes()));
+    assert!(!is_authorized("secret", b"Basic !!!"));
+}
+
+#[test]
+fn test_admin_storage() {
+    use super::test_server::{ ADMIN_TOKEN, bearer, TestServer };
+    use hyper::status::StatusCode;
+    use redis::ErrorKind;
+
+    let (server, storage) = TestServer::with_mock_storage();
+    let (status, _) = server.post("/register", r#"{"client": "a", "message": "m"}"#);
+    assert_eq!(status, StatusCode::Ok);
+
+    let (status, _, _) = server.request("PUT", "/admin/records/a/pinned", bearer(ADMIN_TOKEN),
+                                        None);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(server.admin_get("/pinned"), (StatusCode::Ok, r#"["a"]"#.to_owned()));
+
+    storage.fail("stats", ErrorKind::IoError, "connection lost");
+    assert_eq!(server.admin_get("/stats").0, StatusCode::InternalServerError);
+
+    let calls = storage.calls();
+    assert_eq!(calls[calls.len() - 4..].to_vec(),
+               vec!["find_by_client a", "pin a", "pinned ", "stats "]);
+}
+
+#[test]
+fn test_admin_credentials() {
+    use super::db::{ Credential, API_CREDENTIAL };
+    use super::test_server::{ bearer, TestServer };
+    use hyper::status::StatusCode;
+
+    let server = TestServer::new();
+    for &(name, kind) in &[("ops", ADMIN_CREDENTIAL), ("app", API_CREDENTIAL)] {
+        server.db.add_credential(&Credential {
+            name: name.to_owned(),
+            kind: kind.to_owned(),
+            hash: revocation::hash(&format!("{}-token", name)),
+            created_at: 0,
+        }).unwrap();
+    }
+    let stats = |token: &str| server.request("GET", "/admin/stats", bearer(token), None).0;
+    assert_eq!(stats("ops-token"), StatusCode::Ok);
+    assert_eq!(stats("app-token"), StatusCode::Unauthorized);
+    assert_eq!(stats("other"), StatusCode::Unauthorized);
+
+    server.db.remove_credential("ops").unwrap();
+    assert_eq!(stats("ops-token"), StatusCode::Unauthorized);
+}
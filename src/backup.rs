@@ -0,0 +1,227 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Logical backups of the registrations.
+///
+/// A backup is a JSON file named `registrations-<timestamp>.json` holding
+/// every current record, which doesn't depend on the Redis persistence
+/// settings of the database server.
+
+use db::{ Db, Filter, Record };
+use export;
+use redis::RedisError;
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{ self, Json };
+use std::error::Error;
+use std::fmt;
+use std::fs::{ self, File };
+use std::io::{ self, Read, Write };
+use std::net::IpAddr;
+use std::path::{ Path, PathBuf };
+use storage::Storage;
+
+/// Version of the backup format, bumped on incompatible changes.
+/// Version 1 had a single timestamp per record instead of first_seen and
+/// last_seen, it is upgraded when loaded.
+pub static BACKUP_VERSION: u32 = 2;
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct Backup {
+    pub version: u32,
+    pub created: u64,
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    Db(RedisError),
+    Io(io::Error),
+    Json(json::EncoderError),
+    Decode(json::DecoderError),
+    Parse(json::ParserError),
+    Invalid(String),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BackupError::Db(ref err) => write!(f, "Database error: {}", err),
+            BackupError::Io(ref err) => write!(f, "I/O error: {}", err),
+            BackupError::Json(ref err) => write!(f, "JSON error: {}", err),
+            BackupError::Decode(ref err) => write!(f, "JSON error: {}", err),
+            BackupError::Parse(ref err) => write!(f, "JSON error: {}", err),
+            BackupError::Invalid(ref msg) => write!(f, "Invalid backup: {}", msg),
+        }
+    }
+}
+
+impl Error for BackupError {
+    fn description(&self) -> &str {
+        match *self {
+            BackupError::Db(ref err) => err.description(),
+            BackupError::Io(ref err) => err.description(),
+            BackupError::Json(ref err) => err.description(),
+            BackupError::Decode(ref err) => err.description(),
+            BackupError::Parse(ref err) => err.description(),
+            BackupError::Invalid(ref msg) => msg,
+        }
+    }
+}
+
+impl From<RedisError> for BackupError {
+    fn from(err: RedisError) -> BackupError {
+        BackupError::Db(err)
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> BackupError {
+        BackupError::Io(err)
+    }
+}
+
+impl From<json::EncoderError> for BackupError {
+    fn from(err: json::EncoderError) -> BackupError {
+        BackupError::Json(err)
+    }
+}
+
+impl From<json::DecoderError> for BackupError {
+    fn from(err: json::DecoderError) -> BackupError {
+        BackupError::Decode(err)
+    }
+}
+
+impl From<json::ParserError> for BackupError {
+    fn from(err: json::ParserError) -> BackupError {
+        BackupError::Parse(err)
+    }
+}
+
+/// Write a backup of all the records to `directory`, creating it if needed,
+/// and return the path of the backup file, named after `created`.
+pub fn backup(db: &Storage, created: u64, directory: &Path)
+    -> Result<PathBuf, BackupError> {
+    let records = try!(db.find(&Filter::default()));
+    let backup = Backup {
+        version: BACKUP_VERSION,
+        created: created,
+        records: records,
+    };
+    let serialized = try!(json::encode(&backup));
+
+    try!(fs::create_dir_all(directory));
+    let path = directory.join(format!("registrations-{}.json", created));
+    // Write to a temporary file first, so that a failed backup doesn't
+    // leave a truncated file around.
+    let temporary = directory.join(format!(".registrations-{}.json.tmp", created));
+    {
+        let mut file = try!(File::create(&temporary));
+        try!(file.write_all(serialized.as_bytes()));
+        try!(file.sync_all());
+    }
+    try!(fs::rename(&temporary, &path));
+
+    info!("Backed up {} records to {:?}", backup.records.len(), path);
+    Ok(path)
+}
+
+/// Read a backup file.
+pub fn load(path: &Path) -> Result<Backup, BackupError> {
+    let mut content = String::new();
+    let mut file = try!(File::open(path));
+    try!(file.read_to_string(&mut content));
+
+    let mut backup = try!(Json::from_str(&content));
+    if let Json::Object(ref mut object) = backup {
+        let legacy = object.get("version").and_then(|v| v.as_u64()) == Some(1);
+        if legacy {
+            if let Some(&mut Json::Array(ref mut records)) = object.get_mut("records") {
+                for record in records.iter_mut() {
+                    export::upgrade_legacy_record(record);
+                }
+            }
+            object.insert("version".to_owned(), Json::U64(BACKUP_VERSION as u64));
+        }
+    }
+
+    Ok(try!(Backup::decode(&mut json::Decoder::new(backup))))
+}
+
+/// Check that a backup can be restored: it has a known version and all its
+/// records are well formed.
+pub fn validate(backup: &Backup) -> Result<(), BackupError> {
+    if backup.version != BACKUP_VERSION {
+        return Err(BackupError::Invalid(
+            format!("unsupported version {}", backup.version)));
+    }
+
+    for (index, record) in backup.records.iter().enumerate() {
+        if let Err(msg) = validate_record(record) {
+            return Err(BackupError::Invalid(format!("record {} {}", index, msg)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a record read from a backup or an export can be stored.
+pub fn validate_record(record: &Record) -> Result<(), &'static str> {
+    if record.public_ip.parse::<IpAddr>().is_err() {
+        return Err("has an invalid public IP");
+    }
+    if record.client.is_empty() {
+        return Err("has no client");
+    }
+
+    Ok(())
+}
+
+/// Number of records written per transaction when restoring.
+static RESTORE_BATCH_SIZE: usize = 500;
+
+/// Load a validated backup into `db`, which has to be empty.
+/// Restored records get a fresh TTL, as if they just registered.
+pub fn restore(db: &Db, backup: &Backup) -> Result<usize, BackupError> {
+    try!(validate(backup));
+
+    if !try!(db.is_empty()) {
+        return Err(BackupError::Invalid(
+            "the database isn't empty".to_owned()));
+    }
+
+    for batch in backup.records.chunks(RESTORE_BATCH_SIZE) {
+        try!(db.add_many(batch));
+    }
+
+    info!("Restored {} records", backup.records.len());
+    Ok(backup.records.len())
+}
+
+#[test]
+fn test_validate() {
+    let record = |public_ip: &str, client: &str| {
+        Record::new(public_ip.to_owned(), client.to_owned(),
+                    "<message>".to_owned(), 0)
+    };
+
+    let mut backup = Backup {
+        version: BACKUP_VERSION,
+        created: 0,
+        records: vec![record("127.0.0.1", "<fingerprint>"),
+                      record("::1", "<another_fingerprint>")],
+    };
+    assert!(validate(&backup).is_ok());
+
+    backup.records.push(record("localhost", "<fingerprint>"));
+    assert!(validate(&backup).is_err());
+
+    backup.records.pop();
+    backup.records.push(record("127.0.0.1", ""));
+    assert!(validate(&backup).is_err());
+
+    backup.records.pop();
+    backup.version = BACKUP_VERSION + 1;
+    assert!(validate(&backup).is_err());
+}
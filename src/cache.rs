@@ -0,0 +1,227 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// A small least-recently-used cache with a per-entry time to live, used to
+/// avoid hitting the database for every discovery request while a client is
+/// waiting for its box to show up.
+
+use config::Config;
+use db::Record;
+use std::collections::{ BTreeMap, HashMap };
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{ Arc, Mutex };
+
+struct Entry<V> {
+    value: V,
+    tick: u64,
+    expires: u64,
+}
+
+pub struct LruCache<K, V> {
+    capacity: usize,
+    ttl: u64,
+    tick: u64,
+    entries: HashMap<K, Entry<V>>,
+    // Access order, least recently used first.
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    /// Create a cache holding up to `capacity` entries, each of them valid
+    /// for `ttl` seconds.
+    pub fn new(capacity: usize, ttl: u64) -> LruCache<K, V> {
+        LruCache {
+            capacity: capacity,
+            ttl: ttl,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Get a copy of the value cached for `key`, if it hasn't expired at
+    /// time `now`.
+    pub fn get(&mut self, key: &K, now: u64) -> Option<V> {
+        let expired = match self.entries.get(key) {
+            Some(entry) => entry.expires <= now,
+            None => return None
+        };
+        if expired {
+            self.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key).unwrap();
+        self.order.remove(&entry.tick);
+        self.order.insert(tick, key.clone());
+        entry.tick = tick;
+        Some(entry.value.clone())
+    }
+
+    /// Cache `value` for `key` at time `now`, evicting the least recently
+    /// used entry if the cache is full.
+    pub fn insert(&mut self, key: K, value: V, now: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(&key);
+        if self.entries.len() >= self.capacity {
+            let oldest = self.order.keys().next().cloned();
+            if let Some(tick) = oldest {
+                if let Some(key) = self.order.remove(&tick) {
+                    self.entries.remove(&key);
+                }
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, Entry {
+            value: value,
+            tick: self.tick,
+            expires: now + self.ttl,
+        });
+    }
+
+    /// Drop the entry for `key`, typically because the underlying data
+    /// changed.
+    pub fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Number of seconds a discovery lookup stays cached. Registrations
+/// invalidate the cache immediately, this only bounds how long an expired
+/// record can still be served.
+static HIT_TTL: u64 = 5;
+
+/// Number of seconds we remember that a public IP has no registration.
+/// Kept short so that a box showing up is noticed quickly even if it
+/// registered through another server instance.
+static MISS_TTL: u64 = 2;
+
+/// Discovery results keyed by public IP. Empty results are kept apart so
+/// that a client polling before its box registers doesn't evict the
+/// useful entries.
+pub struct DiscoveryCache {
+    hits: LruCache<String, Vec<Record>>,
+    misses: LruCache<String, ()>,
+}
+
+impl DiscoveryCache {
+    pub fn new(hit_capacity: usize, miss_capacity: usize) -> DiscoveryCache {
+        DiscoveryCache {
+            hits: LruCache::new(hit_capacity, HIT_TTL),
+            misses: LruCache::new(miss_capacity, MISS_TTL),
+        }
+    }
+
+    pub fn get(&mut self, public_ip: &String, now: u64) -> Option<Vec<Record>> {
+        if self.misses.get(public_ip, now).is_some() {
+            return Some(vec![]);
+        }
+        self.hits.get(public_ip, now)
+    }
+
+    pub fn insert(&mut self, public_ip: String, records: Vec<Record>, now: u64) {
+        if records.is_empty() {
+            self.misses.insert(public_ip, (), now);
+        } else {
+            self.hits.insert(public_ip, records, now);
+        }
+    }
+
+    /// Forget anything we know about `public_ip`, because a box registered
+    /// from there.
+    pub fn invalidate(&mut self, public_ip: &String) {
+        self.hits.remove(public_ip);
+        self.misses.remove(public_ip);
+    }
+}
+
+impl fmt::Debug for DiscoveryCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DiscoveryCache {{ hits: {}, misses: {} }}",
+               self.hits.entries.len(), self.misses.entries.len())
+    }
+}
+
+/// The discovery cache of an instance, shared by the route handlers, the
+/// admin endpoints and the eviction job, which all change the boxes.
+pub type SharedCache = Arc<Mutex<DiscoveryCache>>;
+
+/// A discovery cache of these capacities. Registrations received by other
+/// instances wouldn't invalidate it, so it caches nothing in cluster mode.
+pub fn shared(cluster: bool, hit_capacity: usize, miss_capacity: usize) -> SharedCache {
+    let cache = if cluster {
+        DiscoveryCache::new(0, 0)
+    } else {
+        DiscoveryCache::new(hit_capacity, miss_capacity)
+    };
+    Arc::new(Mutex::new(cache))
+}
+
+/// The key of the discovery results of a public IP in the cache: its subnet
+/// when subnets are matched, since they are shared by all its public IPs.
+pub fn discovery_key(config: &Config, public_ip: &str) -> String {
+    config.subnet.network(public_ip).unwrap_or(public_ip.to_owned())
+}
+
+/// Forget the cached discovery results of `public_ip`, because one of its
+/// boxes was deleted, pinned, unpinned or evicted.
+pub fn invalidate(config: &Config, public_ip: &str) {
+    config.discovery_cache.lock().unwrap().invalidate(&discovery_key(config, public_ip));
+}
+
+#[test]
+fn test_lru_cache() {
+    let mut cache = LruCache::new(2, 10);
+
+    cache.insert("a", 1, 0);
+    cache.insert("b", 2, 0);
+    assert_eq!(cache.get(&"a", 1), Some(1));
+
+    // "b" is now the least recently used entry.
+    cache.insert("c", 3, 1);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get(&"b", 1), None);
+    assert_eq!(cache.get(&"a", 1), Some(1));
+    assert_eq!(cache.get(&"c", 1), Some(3));
+
+    // Invalidation.
+    cache.remove(&"a");
+    assert_eq!(cache.get(&"a", 1), None);
+
+    // Expiration.
+    assert_eq!(cache.get(&"c", 11), None);
+    assert_eq!(cache.len(), 0);
+}
+
+#[test]
+fn test_discovery_cache() {
+    let mut cache = DiscoveryCache::new(10, 10);
+    let ip = "127.0.0.1".to_owned();
+
+    assert!(cache.get(&ip, 0).is_none());
+    cache.insert(ip.clone(), vec![], 0);
+    assert_eq!(cache.get(&ip, 1).map(|r| r.len()), Some(0));
+
+    // Negative entries expire quickly.
+    assert!(cache.get(&ip, MISS_TTL).is_none());
+
+    cache.insert(ip.clone(), vec![], 10);
+    cache.invalidate(&ip);
+    assert!(cache.get(&ip, 10).is_none());
+}
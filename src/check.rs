@@ -0,0 +1,215 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Validation of the whole configuration with `--check-config`, for deploy
+/// pipelines: the paths exist, the port can be bound, the databases are
+/// reachable and the URLs and domains are well-formed.
+
+use config::Config;
+use hyper::Url;
+use logging::Target;
+use redis::{ Client, cmd, ConnectionAddr, ConnectionInfo };
+use reporting::Destination;
+use server;
+use std::net::TcpListener;
+use std::path::Path;
+use tenants::Tenant;
+
+/// The settings of the listening socket and of the process, which aren't
+/// part of the `Config`.
+pub struct Process<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub reuse_port: bool,
+    pub cert_directory: Option<&'a Path>,
+    pub pidfile: Option<&'a Path>,
+    pub log: &'a str,
+}
+
+/// The outcome of one check, with what was found or what is wrong.
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub result: Result<String, String>,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<String, String>) -> Check {
+        Check {
+            name: name.to_owned(),
+            result: result,
+        }
+    }
+}
+
+/// Whether `domain` is a well-formed host name: dot-separated labels of
+/// at most 63 letters, digits and hyphens, which don't start or end with a
+/// hyphen, and at most 253 bytes in total.
+pub fn is_valid_domain(domain: &str) -> bool {
+    if domain.is_empty() || domain.len() > 253 {
+        return false;
+    }
+    domain.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63 &&
+        !label.starts_with('-') && !label.ends_with('-') &&
+        label.chars().all(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' => true,
+            _ => false
+        })
+    })
+}
+
+fn check_url(url: &str) -> Result<String, String> {
+    match Url::parse(url) {
+        Ok(ref parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => {
+            Ok(url.to_owned())
+        },
+        Ok(_) => Err(format!("{} isn't an http or https URL", url)),
+        Err(e) => Err(format!("{} isn't a valid URL: {}", url, e))
+    }
+}
+
+fn check_database(config: &Config) -> Result<String, String> {
+    let location = format!("{}:{} database {}", config.db_host, config.db_port,
+                           config.db_index);
+    let client = try!(Client::open(ConnectionInfo {
+        addr: Box::new(ConnectionAddr::Tcp(config.db_host.clone(), config.db_port)),
+        db: config.db_index,
+        passwd: config.db_password.clone()
+    }).map_err(|e| format!("Invalid Redis server {}: {}", location, e)));
+    let connection = try!(client.get_connection().map_err(|e| {
+        format!("Can't connect to {}: {}", location, e)
+    }));
+    let _: String = try!(cmd("PING").query(&connection).map_err(|e| {
+        format!("{} doesn't answer: {}", location, e)
+    }));
+    Ok(location)
+}
+
+fn check_listen(process: &Process) -> Result<String, String> {
+    let addr = format!("{}:{}", process.host, process.port);
+    // With SO_REUSEPORT, the instance being replaced can keep the port.
+    let bound = if process.reuse_port {
+        server::bind(&addr).map(|_| ())
+    } else {
+        TcpListener::bind(&addr as &str).map(|_| ())
+    };
+    bound.map(|_| addr.clone()).map_err(|e| format!("Can't bind {}: {}", addr, e))
+}
+
+/// The directory which will hold `path` exists.
+fn check_parent(path: &Path) -> Result<String, String> {
+    match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() || parent.is_dir() => {
+            Ok(path.display().to_string())
+        },
+        _ => Err(format!("The directory of {} doesn't exist", path.display()))
+    }
+}
+
+fn check_certificates(directory: &Path) -> Result<String, String> {
+    for name in &["privkey.pem", "fullchain.pem"] {
+        if !directory.join(name).is_file() {
+            return Err(format!("{} has no {}", directory.display(), name));
+        }
+    }
+    Ok(directory.display().to_string())
+}
+
+fn check_backup_dir(directory: &Path) -> Result<String, String> {
+    if directory.is_dir() {
+        Ok(directory.display().to_string())
+    } else if directory.exists() {
+        Err(format!("{} isn't a directory", directory.display()))
+    } else {
+        // Backups create the directory itself, not its parents.
+        check_parent(directory).map(|path| format!("{} (will be created)", path))
+    }
+}
+
+fn check_tenant(tenant: &Tenant) -> Result<String, String> {
+    match tenant.domain_suffix {
+        Some(ref suffix) if !is_valid_domain(suffix) => {
+            Err(format!("The domain suffix {} of {} is malformed", suffix, tenant.name))
+        },
+        _ => Ok(tenant.name.clone())
+    }
+}
+
+/// Run every check of the configuration. `tenants` come with their own
+/// configuration.
+pub fn run(config: &Config, tenants: &[(Tenant, Config)], process: &Process) -> Vec<Check> {
+    let mut checks = vec![
+        Check::new("listen", check_listen(process)),
+        Check::new("database", check_database(config)),
+        Check::new("backup directory", check_backup_dir(&config.backup_dir)),
+    ];
+
+    if let Some(directory) = process.cert_directory {
+        checks.push(Check::new("certificates", check_certificates(directory)));
+    }
+    if let Some(pidfile) = process.pidfile {
+        checks.push(Check::new("pidfile", check_parent(pidfile)));
+    }
+    match Target::from_name(process.log) {
+        Ok(Target::File(ref path)) => checks.push(Check::new("log file", check_parent(path))),
+        Ok(_) => {},
+        Err(message) => checks.push(Check::new("log", Err(message)))
+    }
+
+    let mut urls = vec![];
+    if let Some(ref url) = config.zipkin_url {
+        urls.push(("zipkin url", url.clone()));
+    }
+    if let Some(Destination::Webhook(ref url)) = config.error_reporting {
+        urls.push(("error webhook", url.clone()));
+    }
+    if let Some(ref url) = config.push.gateway {
+        urls.push(("push gateway", url.clone()));
+    }
+    if let Some(ref provider) = config.oidc {
+        urls.push(("oidc issuer", provider.issuer.clone()));
+        urls.push(("oidc redirect uri", provider.redirect_uri.clone()));
+    }
+    for (name, url) in urls {
+        checks.push(Check::new(name, check_url(&url)));
+    }
+
+    for &(ref tenant, ref tenant_config) in tenants {
+        checks.push(Check::new(&format!("tenant {}", tenant.name), check_tenant(tenant)));
+        checks.push(Check::new(&format!("tenant {} database", tenant.name),
+                               check_database(tenant_config)));
+    }
+
+    checks
+}
+
+#[test]
+fn test_is_valid_domain() {
+    use std::iter;
+
+    assert!(is_valid_domain("acme.example.com"));
+    assert!(is_valid_domain("box-1.example.com"));
+    assert!(is_valid_domain("localhost"));
+    assert!(!is_valid_domain(""));
+    assert!(!is_valid_domain("acme..example.com"));
+    assert!(!is_valid_domain("-acme.example.com"));
+    assert!(!is_valid_domain("acme_1.example.com"));
+    assert!(!is_valid_domain("acmé.example.com"));
+    let label: String = iter::repeat('a').take(64).collect();
+    assert!(!is_valid_domain(&format!("{}.com", label)));
+}
+
+#[test]
+fn test_checks() {
+    use std::path::PathBuf;
+
+    assert!(check_url("https://zipkin:9411/api/v2/spans").is_ok());
+    assert!(check_url("ftp://example.com").is_err());
+    assert!(check_url("not a url").is_err());
+    assert!(check_parent(Path::new("registration_server.pid")).is_ok());
+    assert!(check_parent(Path::new("/nonexistent/registration_server.pid")).is_err());
+    assert!(check_backup_dir(&PathBuf::from("/nonexistent/backups")).is_err());
+    assert!(check_certificates(Path::new("/nonexistent")).is_err());
+}
@@ -0,0 +1,54 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Source of the current time, injected through the `Config` so that the
+/// time based logic (timestamps, expiration, scheduling) can be tested
+/// without sleeping.
+
+use std::fmt::Debug;
+#[cfg(test)]
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+pub trait Clock: Debug + Send + Sync {
+    /// Number of seconds since the epoch.
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => 0
+        }
+    }
+}
+
+/// A clock which only moves when told to.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ManualClock {
+    time: AtomicUsize,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(time: u64) -> ManualClock {
+        ManualClock { time: AtomicUsize::new(time as usize) }
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.time.fetch_add(seconds as usize, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.time.load(Ordering::SeqCst) as u64
+    }
+}
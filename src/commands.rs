@@ -0,0 +1,276 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Command line operations which run against the database, or against a
+/// running server, and exit instead of starting the server. Errors are
+/// returned as messages for the user.
+
+use backup;
+use check;
+use config::Config;
+use ctl::{ Backend, Operation };
+use db::{ Credential, Db, Filter, Record };
+use e2e;
+use export::{ self, Format };
+use loadtest;
+use revocation;
+use seed::{ self, Distribution };
+use tenants::Tenant;
+use std::fs::File;
+use std::io::{ self, Read };
+use std::path::Path;
+use tokens;
+
+/// Number of records written per transaction when importing.
+static IMPORT_BATCH_SIZE: usize = 500;
+
+fn connect(config: &Config) -> Result<Db, String> {
+    Db::from_config(config).map_err(|e| {
+        format!("Can't connect to the database at {}:{}: {}", config.db_host, config.db_port, e)
+    })
+}
+
+fn format_from_name(name: &str) -> Result<Format, String> {
+    Format::from_name(name).ok_or(format!("Unknown format {}", name))
+}
+
+pub fn backup(config: &Config) -> Result<(), String> {
+    let db = try!(connect(config));
+    let path = try!(backup::backup(&db, db.now(), &config.backup_dir)
+                          .map_err(|e| format!("Backup failed: {}", e)));
+    println!("Backup written to {}", path.display());
+    Ok(())
+}
+
+pub fn restore(config: &Config, path: &Path, dry_run: bool) -> Result<(), String> {
+    let error = |e: backup::BackupError| {
+        format!("Can't restore {}: {}", path.display(), e)
+    };
+
+    let backup = try!(backup::load(path).map_err(&error));
+    try!(backup::validate(&backup).map_err(&error));
+
+    if dry_run {
+        println!("{} is valid, {} records would be restored",
+                 path.display(), backup.records.len());
+        return Ok(());
+    }
+
+    let db = try!(connect(config));
+    let count = try!(backup::restore(&db, &backup).map_err(&error));
+    println!("Restored {} records from {}", count, path.display());
+    Ok(())
+}
+
+pub fn export(config: &Config, format: &str) -> Result<(), String> {
+    let format = try!(format_from_name(format));
+
+    let db = try!(connect(config));
+    let records = try!(db.find(&Filter::default())
+                         .map_err(|e| format!("Export failed: {}", e)));
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    export::write_records(&records, format, &mut out)
+        .map_err(|e| format!("Export failed: {}", e))
+}
+
+pub fn import(config: &Config, path: &Path, format: &str, dry_run: bool)
+    -> Result<(), String> {
+    let format = try!(format_from_name(format));
+    let error = |e: String| format!("Can't import {}: {}", path.display(), e);
+
+    let mut content = String::new();
+    try!(File::open(path).and_then(|mut file| file.read_to_string(&mut content))
+                         .map_err(|e| error(e.to_string())));
+
+    let records = try!(export::read_records(&content, format).map_err(&error));
+    for (index, record) in records.iter().enumerate() {
+        try!(backup::validate_record(record).map_err(|msg| {
+            error(format!("record {} {}", index + 1, msg))
+        }));
+    }
+
+    if dry_run {
+        println!("{} is valid, {} records would be imported",
+                 path.display(), records.len());
+        return Ok(());
+    }
+
+    let db = try!(connect(config));
+    for batch in records.chunks(IMPORT_BATCH_SIZE) {
+        try!(db.add_many(batch).map_err(|e| error(e.to_string())));
+    }
+
+    println!("Imported {} records from {}", records.len(), path.display());
+    Ok(())
+}
+
+pub fn seed(config: &Config, count: usize, networks: usize, distribution: &str)
+    -> Result<(), String> {
+    let distribution = try!(Distribution::from_name(distribution).ok_or(
+        format!("Unknown distribution {}", distribution)));
+    if networks == 0 {
+        return Err("The records need at least one network".to_owned());
+    }
+
+    let db = try!(connect(config));
+    let records = seed::records(count, networks, distribution, db.now());
+    for batch in records.chunks(IMPORT_BATCH_SIZE) {
+        try!(db.add_many(batch).map_err(|e| format!("Seeding failed: {}", e)));
+    }
+
+    println!("Added {} fake records", records.len());
+    Ok(())
+}
+
+/// Print the outcome of every check of the configuration, failing if any
+/// of them did.
+pub fn check_config(config: &Config, tenants: &[(Tenant, Config)], process: &check::Process)
+    -> Result<(), String> {
+    let checks = check::run(config, tenants, process);
+    let mut failures = 0;
+    for check in &checks {
+        match check.result {
+            Ok(ref found) => println!("ok    {}: {}", check.name, found),
+            Err(ref problem) => {
+                failures += 1;
+                println!("FAIL  {}: {}", check.name, problem);
+            }
+        }
+    }
+
+    if failures > 0 {
+        Err(format!("{} of {} checks failed", failures, checks.len()))
+    } else {
+        println!("The configuration is valid");
+        Ok(())
+    }
+}
+
+/// Print a new pairing secret for end-to-end encrypted discovery.
+pub fn e2e_secret() -> Result<(), String> {
+    println!("{}", e2e::pairing_secret());
+    Ok(())
+}
+
+/// Print the `encrypted` field of the box `fingerprint` holding `data`.
+pub fn e2e_seal(fingerprint: &str, secret: &str, data: &str) -> Result<(), String> {
+    let key = try!(e2e::derive_key(secret, fingerprint));
+    println!("{}", e2e::seal(&key, fingerprint, data.as_bytes()));
+    Ok(())
+}
+
+/// Print the data of the `encrypted` field of the box `fingerprint`.
+pub fn e2e_open(fingerprint: &str, secret: &str, blob: &str) -> Result<(), String> {
+    let key = try!(e2e::derive_key(secret, fingerprint));
+    let data = try!(e2e::open(&key, fingerprint, blob));
+    println!("{}", String::from_utf8_lossy(&data));
+    Ok(())
+}
+
+/// Create an admin token or API key named `name`, printing it. Only its
+/// hash is stored, so it can't be shown again.
+pub fn keys_create(config: &Config, kind: &str, name: &str) -> Result<(), String> {
+    let db = try!(connect(config));
+    let key = tokens::generate();
+    let credential = Credential {
+        name: name.to_owned(),
+        kind: kind.to_owned(),
+        hash: revocation::hash(&key),
+        created_at: config.clock.now(),
+    };
+    if !try!(db.add_credential(&credential).map_err(|e| e.to_string())) {
+        return Err(format!("There is already a key named {}", name));
+    }
+    println!("{}", key);
+    Ok(())
+}
+
+pub fn keys_list(config: &Config) -> Result<(), String> {
+    let db = try!(connect(config));
+    for credential in try!(db.credentials().map_err(|e| e.to_string())) {
+        println!("{}\t{}\t{}\t{}", credential.name, credential.kind, credential.created_at,
+                 credential.hash);
+    }
+    Ok(())
+}
+
+/// Delete the key named `name`, and add it to the revocation list so that
+/// the servers which already accepted it stop doing so.
+pub fn keys_revoke(config: &Config, name: &str) -> Result<(), String> {
+    let db = try!(connect(config));
+    let credential = match try!(db.remove_credential(name).map_err(|e| e.to_string())) {
+        Some(credential) => credential,
+        None => return Err(format!("There is no key named {}", name))
+    };
+    try!(db.revoke(credential.hash).map_err(|e| e.to_string()));
+    println!("Revoked {}", name);
+    Ok(())
+}
+
+pub fn loadtest(url: &str, settings: &loadtest::Settings) -> Result<(), String> {
+    println!("Simulating {} boxes against {} for {} seconds",
+             settings.boxes, url, settings.duration);
+    let report = try!(loadtest::run(url, settings)
+                        .map_err(|e| format!("Load test failed: {}", e)));
+    print!("{}", report);
+    Ok(())
+}
+
+fn write_records(records: &[Record]) -> Result<(), String> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    export::write_records(records, Format::JsonLines, &mut out).map_err(|e| e.to_string())
+}
+
+/// Run an operator command against the database, or against the admin API
+/// at `admin_url`.
+pub fn ctl(config: &Config, admin_url: Option<String>, operation: Operation)
+    -> Result<(), String> {
+    let backend = match admin_url {
+        Some(url) => Backend::Api {
+            url: url,
+            token: try!(config.admin_token.clone().ok_or(
+                "--admin-url needs the --admin-token of the server".to_owned())),
+        },
+        None => Backend::Db(try!(connect(config)))
+    };
+    let not_registered = |client: &str| format!("{} is not registered", client);
+
+    match operation {
+        Operation::List(filter) => {
+            let records = try!(backend.list(&filter));
+            write_records(&records)
+        },
+        Operation::Find(client) => {
+            match try!(backend.find(&client)) {
+                Some(record) => write_records(&[record]),
+                None => Err(not_registered(&client))
+            }
+        },
+        Operation::Delete(client) => {
+            if !try!(backend.delete(&client)) {
+                return Err(not_registered(&client));
+            }
+            println!("Deleted {}", client);
+            Ok(())
+        },
+        Operation::Evict => {
+            println!("Evicted {} expired clients", try!(backend.evict()));
+            Ok(())
+        },
+        Operation::Stats => {
+            let stats = try!(backend.stats());
+            println!("Public IPs: {}", stats.public_ips);
+            println!("Clients: {}", stats.clients);
+            println!("Largest network: {} clients", stats.largest_network);
+            if let (Some(p50), Some(p95)) = (stats.interval_p50, stats.interval_p95) {
+                println!("Registration interval: {} s (p50), {} s (p95)", p50, p95);
+            }
+            println!("Churn rate: {:.1} % over 24 hours", 100.0 * stats.churn_rate);
+            Ok(())
+        }
+    }
+}
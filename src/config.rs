@@ -0,0 +1,136 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Runtime configuration shared by the route handlers.
+
+use accounts::Quotas;
+use cache::SharedCache;
+use clock::Clock;
+use events::Bus;
+use features::Features;
+use jwt;
+use metrics::Metrics;
+use oidc;
+use push;
+use read_only::ReadOnly;
+use reporting::Destination;
+use retention::Policy;
+use revocation::Revocations;
+use std::path::PathBuf;
+use storage::Connector;
+use std::sync::Arc;
+use subnet::Prefixes;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub db_host: String,
+    pub db_port: u16,
+    pub db_password: Option<String>,
+    /// Number of the Redis database, which differs for each tenant.
+    pub db_index: i64,
+    /// Token expected in the `Authorization: Bearer` header of admin
+    /// requests. The admin API is disabled when not set.
+    pub admin_token: Option<String>,
+    /// Number of public IPs whose discovery results are kept in memory.
+    pub cache_size: usize,
+    /// Number of public IPs remembered as having no registration.
+    pub negative_cache_size: usize,
+    /// Whether other instances share the same database. In that case the
+    /// in-process caches are disabled and background jobs are coordinated
+    /// through leases.
+    pub cluster: bool,
+    /// Identifies this instance in the leases it holds.
+    pub instance_id: String,
+    /// Where backups are written.
+    pub backup_dir: PathBuf,
+    /// How long the registrations, usage aggregates and backups are kept.
+    pub retention: Policy,
+    /// Number of seconds between two runs of the database maintenance job,
+    /// 0 to disable it.
+    pub maintenance_interval: u64,
+    /// Whether registrations with unknown fields are rejected, to catch
+    /// firmware bugs in staging deployments.
+    pub strict: bool,
+    /// Where handler panics and 5xx responses are reported, if anywhere.
+    pub error_reporting: Option<Destination>,
+    /// URL of the Zipkin collector receiving the trace spans.
+    pub zipkin_url: Option<String>,
+    /// Number of threads handling the HTTP requests.
+    pub threads: usize,
+    /// Number of seconds allowed to read a request.
+    pub read_timeout: u64,
+    /// Number of seconds allowed to write a response.
+    pub write_timeout: u64,
+    /// Number of seconds a request waits for each database command before
+    /// failing with a 504, 0 to wait forever.
+    pub handler_timeout: u64,
+    /// Number of milliseconds after which a storage operation is logged as
+    /// slow, 0 to never log them.
+    pub slow_query_threshold: u64,
+    /// Number of seconds an idle connection is kept open, 0 to close
+    /// connections after each response.
+    pub keep_alive: u64,
+    /// Source of the current time.
+    pub clock: Arc<Clock>,
+    /// Opens the storage used by the public endpoints.
+    pub storage: Arc<Connector>,
+    /// Counters of this instance shown on the admin dashboard.
+    pub metrics: Arc<Metrics>,
+    /// Subnets matched together during discovery, for carrier-grade NATs.
+    pub subnet: Prefixes,
+    /// Number of seconds between two registrations or heartbeats of a box,
+    /// which tells when a box is shown offline.
+    pub ping_interval: u64,
+    /// Where the push notifications about boxes coming online or changing
+    /// address are sent. Push subscriptions are refused when disabled.
+    pub push: push::Settings,
+    /// Whether users can create accounts and link boxes to them.
+    pub accounts: bool,
+    /// Limits of each account.
+    pub quotas: Quotas,
+    /// Names no account can reserve as a subdomain, on top of
+    /// `labels::RESERVED`, nor anything looking like them.
+    pub subdomain_blocklist: Vec<String>,
+    /// The OpenID Connect provider users can log in with, if any.
+    pub oidc: Option<oidc::Provider>,
+    /// Number of public IP changes of a box within an hour above which it
+    /// is flagged as flapping, 0 to never flag boxes.
+    pub max_ip_changes: u64,
+    /// Whether the registrations of the flapping boxes need the token of
+    /// their latest registration.
+    pub flapping_auth: bool,
+    /// Whether the anonymized daily usage aggregates are computed, which
+    /// tracks the activity of the boxes without their IPs.
+    pub usage_aggregates: bool,
+    /// Key of the hash stored instead of the public IPs, in privacy mode.
+    pub ip_hash_key: Option<String>,
+    /// Salt of the hash stored instead of the fingerprints of the boxes.
+    pub fingerprint_salt: Option<String>,
+    /// Whether the writes are rejected and the background jobs paused,
+    /// shared by all the tenants.
+    pub read_only: Arc<ReadOnly>,
+    /// The optional subsystems turned on, which can change at runtime.
+    pub features: Arc<Features>,
+    /// The keys signing the tokens as JWTs, random tokens being issued
+    /// when not set.
+    pub jwt: Option<jwt::Keys>,
+    /// The revoked box tokens and API keys, which differ for each tenant.
+    pub revocations: Arc<Revocations>,
+    /// The subscribers to the registrations and evictions of the boxes.
+    pub events: Arc<Bus>,
+    /// The discovery results cached by this instance, which differ for
+    /// each tenant.
+    pub discovery_cache: SharedCache,
+    /// Port the server connects to on the public IP of the boxes to verify
+    /// that they are reachable, if it does.
+    pub probe_port: Option<u16>,
+    /// Number of open discovery requests accepted from a public IP per
+    /// minute.
+    pub open_discovery_limit: u64,
+    /// Number of mailbox messages accepted from a public IP per minute.
+    pub mailbox_limit: u64,
+    /// The API keys of the tenants file selecting this tenant, which like
+    /// the API keys of its database give access to the full discovery.
+    pub api_keys: Vec<String>,
+}
@@ -0,0 +1,150 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Operator commands on the records, run either directly against the
+/// database or through the admin API of a running server, e.g. when the
+/// database isn't reachable from the operator's machine.
+
+use db::{ Db, Filter, Record, Stats };
+use hyper::Client;
+use hyper::header::Headers;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use rustc_serialize::Decodable;
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+#[derive(Debug, Clone)]
+pub enum Operation {
+    List(Filter),
+    Find(String),
+    Delete(String),
+    Evict,
+    Stats,
+}
+
+pub enum Backend {
+    Db(Db),
+    /// The admin API at `url`, e.g. `https://knilxof.org:4443/admin`.
+    Api { url: String, token: String },
+}
+
+pub fn percent_encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+            (byte as char).to_string()
+        },
+        _ => format!("%{:02X}", byte)
+    }).collect()
+}
+
+fn filter_query(filter: &Filter) -> String {
+    let mut params = vec![];
+    if let Some(ref public_ip) = filter.public_ip {
+        params.push(format!("public_ip={}", percent_encode(public_ip)));
+    }
+    if let Some(ref client) = filter.client {
+        params.push(format!("fingerprint={}", percent_encode(client)));
+    }
+    if let Some(since) = filter.since {
+        params.push(format!("since={}", since));
+    }
+    if let Some(until) = filter.until {
+        params.push(format!("until={}", until));
+    }
+    params.join("&")
+}
+
+impl Backend {
+    /// Send a request to the admin API, returning `None` for a 404.
+    fn request<T: Decodable>(url: &str, token: &str, method: Method, path: &str)
+        -> Result<Option<T>, String> {
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![format!("Bearer {}", token).into_bytes()]);
+
+        let url = format!("{}/{}", url.trim_right_matches('/'), path);
+        let mut response = try!(Client::new().request(method, &url)
+                                             .headers(headers)
+                                             .send()
+                                             .map_err(|e| format!("{}: {}", url, e)));
+        let mut body = String::new();
+        try!(response.read_to_string(&mut body).map_err(|e| format!("{}: {}", url, e)));
+
+        match response.status {
+            StatusCode::NotFound => Ok(None),
+            status if status.is_success() => {
+                json::decode(&body).map(Some).map_err(|e| format!("{}: {}", url, e))
+            },
+            status => Err(format!("{}: {} {}", url, status, body))
+        }
+    }
+
+    pub fn list(&self, filter: &Filter) -> Result<Vec<Record>, String> {
+        match *self {
+            Backend::Db(ref db) => db.find(filter).map_err(|e| e.to_string()),
+            Backend::Api { ref url, ref token } => {
+                let path = format!("records?{}", filter_query(filter));
+                Backend::request(url, token, Method::Get, &path)
+                    .map(|records| records.unwrap_or(vec![]))
+            }
+        }
+    }
+
+    pub fn find(&self, client: &str) -> Result<Option<Record>, String> {
+        let filter = Filter {
+            client: Some(client.to_owned()),
+            .. Filter::default()
+        };
+        self.list(&filter).map(|records| records.into_iter().next())
+    }
+
+    /// Returns whether the client was registered.
+    pub fn delete(&self, client: &str) -> Result<bool, String> {
+        match *self {
+            Backend::Db(ref db) => db.delete(client.to_owned()).map_err(|e| e.to_string()),
+            Backend::Api { ref url, ref token } => {
+                let path = format!("records/{}", percent_encode(client));
+                Backend::request::<BTreeMap<String, bool>>(url, token, Method::Delete, &path)
+                    .map(|deleted| deleted.is_some())
+            }
+        }
+    }
+
+    /// Returns the number of clients evicted.
+    pub fn evict(&self) -> Result<usize, String> {
+        match *self {
+            Backend::Db(ref db) => db.evict().map_err(|e| e.to_string()),
+            Backend::Api { ref url, ref token } => {
+                Backend::request::<BTreeMap<String, usize>>(url, token, Method::Post, "evict")
+                    .map(|result| {
+                        result.and_then(|result| result.get("evicted").cloned()).unwrap_or(0)
+                    })
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Result<Stats, String> {
+        match *self {
+            Backend::Db(ref db) => db.stats().map_err(|e| e.to_string()),
+            Backend::Api { ref url, ref token } => {
+                Backend::request(url, token, Method::Get, "stats")
+                    .map(|stats| stats.unwrap_or(Stats::default()))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_filter_query() {
+    let filter = Filter {
+        public_ip: Some("1.2.3.4".to_owned()),
+        client: Some("a b&c".to_owned()),
+        since: Some(1481900000),
+        until: None,
+    };
+    assert_eq!(filter_query(&filter),
+               "public_ip=1.2.3.4&fingerprint=a%20b%26c&since=1481900000");
+    assert_eq!(filter_query(&Filter::default()), "");
+}
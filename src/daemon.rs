@@ -0,0 +1,124 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Running as a traditional daemon on hosts without systemd: detaching
+/// from the terminal, writing a pidfile and removing it on termination.
+
+use libc;
+use std::ffi::CString;
+use std::fs::{ self, File };
+use std::io::{ self, Read, Write };
+use std::mem;
+use std::path::{ Path, PathBuf };
+use std::process;
+use std::thread;
+
+fn last_error(action: &str) -> String {
+    format!("Can't {}: {}", action, io::Error::last_os_error())
+}
+
+fn fork() -> Result<(), String> {
+    match unsafe { libc::fork() } {
+        -1 => Err(last_error("fork")),
+        // The child continues, the parent is done.
+        0 => Ok(()),
+        _ => process::exit(0)
+    }
+}
+
+/// Detach from the terminal: fork twice around setsid() so that the
+/// daemon can't acquire a controlling terminal again, move to / and send
+/// the standard streams to /dev/null.
+/// This has to happen before any thread is started.
+pub fn daemonize() -> Result<(), String> {
+    try!(fork());
+    if unsafe { libc::setsid() } == -1 {
+        return Err(last_error("create a new session"));
+    }
+    try!(fork());
+
+    unsafe { libc::umask(0o027) };
+    let root = CString::new("/").unwrap();
+    if unsafe { libc::chdir(root.as_ptr()) } == -1 {
+        return Err(last_error("change to /"));
+    }
+
+    let null = CString::new("/dev/null").unwrap();
+    let fd = unsafe { libc::open(null.as_ptr(), libc::O_RDWR) };
+    if fd == -1 {
+        return Err(last_error("open /dev/null"));
+    }
+    for stream in &[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(fd, *stream) } == -1 {
+            return Err(last_error("redirect the standard streams"));
+        }
+    }
+    unsafe { libc::close(fd) };
+
+    Ok(())
+}
+
+fn is_running(pid: libc::pid_t) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+/// Write our pid to `path`, unless it belongs to a process still running.
+pub fn write_pidfile(path: &Path) -> Result<(), String> {
+    let error = |e: io::Error| format!("Can't write {}: {}", path.display(), e);
+
+    if let Ok(mut file) = File::open(path) {
+        let mut content = String::new();
+        try!(file.read_to_string(&mut content).map_err(&error));
+        if let Ok(pid) = content.trim().parse() {
+            if is_running(pid) {
+                return Err(format!("Already running with pid {} according to {}",
+                                   pid, path.display()));
+            }
+        }
+    }
+
+    let mut file = try!(File::create(path).map_err(&error));
+    let pid = unsafe { libc::getpid() };
+    writeln!(file, "{}", pid).map_err(&error)
+}
+
+/// Handle SIGTERM and SIGINT by calling `before_exit` and removing the
+/// pidfile, if any, before exiting. The signals are blocked in every thread
+/// started afterwards and waited for in a dedicated thread, so this has to
+/// be called before starting any other thread.
+pub fn handle_signals<F>(pidfile: Option<PathBuf>, before_exit: F) -> Result<(), String>
+    where F: FnOnce() + Send + 'static {
+    let mut signals: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+    }
+    let result = unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ::std::ptr::null_mut())
+    };
+    if result != 0 {
+        return Err(format!("Can't block signals: {}",
+                           io::Error::from_raw_os_error(result)));
+    }
+
+    thread::spawn(move || {
+        let mut signal: libc::c_int = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            error!("Can't wait for signals");
+            return;
+        }
+
+        info!("Received signal {}, exiting", signal);
+        before_exit();
+        if let Some(ref pidfile) = pidfile {
+            if let Err(e) = fs::remove_file(pidfile) {
+                error!("Can't remove {}: {}", pidfile.display(), e);
+            }
+        }
+        process::exit(0);
+    });
+
+    Ok(())
+}
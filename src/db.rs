@@ -2,53 +2,703 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use redis::{ Client, cmd, Connection, ConnectionAddr, ConnectionInfo,
-             pipe, RedisResult };
-use std::time::Duration;
+use clock::{ Clock, SystemClock };
+use config::Config;
+use jwt;
+use push::Subscription;
+use redis::{ Client, cmd, Connection, ConnectionAddr, ConnectionInfo, ErrorKind,
+             pipe, Pipeline, RedisResult, Script };
+use rustc_serialize::{ Encodable, Encoder };
+use signaling::{ self, Candidate };
+use std::cmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
 use std::thread::sleep;
+use retention::Policy;
+use subnet::Prefixes;
+use tokens;
 
-static RECORD_TTL: i32 = 2 * 60; // 2 minutes
+/// Registrations expire after 2 minutes by default, see `retention`.
+pub static RECORD_TTL: i32 = 2 * 60;
+/// Pairing codes expire after 5 minutes.
+pub static PAIRING_TTL: i32 = 5 * 60;
+/// Number of unknown pairing codes accepted from a public IP within
+/// PAIRING_TTL, which makes guessing the codes of other boxes impractical.
+pub static MAX_PAIRING_FAILURES: u64 = 10;
+/// Account sessions expire after 30 days.
+pub static SESSION_TTL: i32 = 30 * 24 * 60 * 60;
+/// Users have 10 minutes to log in with an OpenID Connect provider.
+pub static OIDC_STATE_TTL: i32 = 10 * 60;
+/// Push subscriptions expire when not renewed for 30 days.
+pub static PUSH_TTL: i32 = 30 * 24 * 60 * 60;
+/// Messages left in the mailbox of a box expire after a day.
+pub static MAILBOX_TTL: i32 = 24 * 60 * 60;
+/// Number of messages kept in the mailbox of a box, the oldest ones being
+/// dropped first.
+pub static MAX_MAILBOX_MESSAGES: isize = 16;
+/// Number of seconds the previous token of a box keeps working after it
+/// rotated its token, while the requests using it finish.
+pub static TOKEN_GRACE_PERIOD: u64 = 5 * 60;
+/// Number of seconds to wait at startup for a Redis server which refuses
+/// connections, e.g. because it is starting as well.
+pub static CONNECT_TIMEOUT: u64 = 30;
+/// Number of the latest intervals between two registrations or heartbeats
+/// of a box kept in the "intervals" list, for the stats.
+pub static INTERVAL_SAMPLES: isize = 10_000;
+/// Number of days the anonymized usage aggregates are kept by default.
+pub static USAGE_RETENTION: u64 = 90;
+/// Public IP changes of a box are counted over this number of seconds to
+/// detect flapping.
+pub static FLAPPING_WINDOW: u64 = 3600;
+/// Number of seconds a box stays flagged as flapping.
+pub static FLAPPING_TTL: u64 = 24 * 3600;
+/// Registrations and evictions are counted per hour in
+/// "registrations:<hour>" and "evictions:<hour>" keys, kept for this number
+/// of hours for the stats.
+pub static HOURLY_WINDOW: u64 = 7 * 24;
+/// Number of hours of evictions the churn rate is computed over.
+static CHURN_WINDOW: u64 = 24;
 
-#[derive(RustcEncodable, Debug)]
+/// Reads all the records of the public IP KEYS[1], dropping the clients
+/// whose message expired along the way. Returns a list of (client, fields
+/// of the client's hash) pairs.
+static GET_SCRIPT: &'static str = r"
+local result = {}
+local members = redis.call('SMEMBERS', KEYS[1])
+for _, member in ipairs(members) do
+    local fields = redis.call('HGETALL', KEYS[1] .. ':' .. member)
+    if #fields > 0 then
+        table.insert(result, { member, fields })
+    else
+        redis.call('SREM', KEYS[1], member)
+    end
+end
+return result
+";
+
+/// Converts the records of the public IP KEYS[1] which are still strings
+/// holding their message, as the first versions stored them, into hashes
+/// timestamped ARGV[1], keeping their time to live, and adds them to the
+/// indexes described in `Db::set`. A record whose box registered from
+/// another public IP since is dropped instead. Returns the number of
+/// records converted.
+static MIGRATE_SCRIPT: &'static str = r"
+if redis.call('TYPE', KEYS[1]).ok ~= 'set' then
+    return 0
+end
+local migrated = 0
+for _, member in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+    local key = KEYS[1] .. ':' .. member
+    local box_key = 'box:' .. member
+    if redis.call('TYPE', key).ok == 'string' then
+        local public_ip = redis.call('GET', box_key)
+        if public_ip and public_ip ~= KEYS[1] then
+            redis.call('DEL', key)
+            redis.call('SREM', KEYS[1], member)
+        else
+            local message = redis.call('GET', key)
+            local ttl = redis.call('PTTL', key)
+            redis.call('DEL', key)
+            redis.call('HMSET', key, 'message', message, 'timestamp', ARGV[1])
+            redis.call('SET', box_key, KEYS[1])
+            if ttl > 0 then
+                redis.call('PEXPIRE', key, ttl)
+                redis.call('PEXPIRE', box_key, ttl)
+            end
+            redis.call('SADD', 'public_ips', KEYS[1])
+            migrated = migrated + 1
+        end
+    end
+end
+return migrated
+";
+
+/// The version of the data layout of a database, which `migrate_records`
+/// brings up to `SCHEMA_VERSION`. Databases without it predate versions.
+static SCHEMA_VERSION_KEY: &'static str = "schema_version";
+/// Version 1: the records are hashes.
+static SCHEMA_VERSION: u64 = 1;
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
 pub struct Record {
+    pub public_ip:  String,
+    pub client:     String,
+    pub message:    String,
+    /// When the client first registered, kept across re-registrations
+    /// and public IP changes as long as the record doesn't expire.
+    pub first_seen: u64,
+    /// When the client last registered.
+    pub last_seen:  u64,
+    /// Incremented on every update of the record, starting at 1.
+    /// 0 for records which haven't been stored yet.
+    pub revision:   u64,
+    /// Address of the box on its local network, if it gave it, used to
+    /// rank the discovery results.
+    pub local_ip:   Option<String>,
+    /// Data the box encrypted for its clients, in base64, which is stored
+    /// and returned as is.
+    pub encrypted:  Option<String>,
+    /// The mapping behavior of the NAT of the box, if it gave it, one of
+    /// `nat::MAPPINGS`.
+    pub nat:        Option<String>,
+    /// Whether the server could connect back to the box at its public IP,
+    /// if it tried. Set by the probes rather than the box, and dropped when
+    /// the box moves to another public IP.
+    pub verified:   Option<bool>,
+}
+
+impl Record {
+    /// A record for a registration happening at time `now`.
+    pub fn new(public_ip: String, client: String, message: String, now: u64)
+        -> Record {
+        Record {
+            public_ip: public_ip,
+            client: client,
+            message: message,
+            first_seen: now,
+            last_seen: now,
+            revision: 0,
+            local_ip: None,
+            encrypted: None,
+            nat: None,
+            verified: None,
+        }
+    }
+
+    /// The entity tag of the record, which changes with every update of the
+    /// registration but not with heartbeats. The first_seen time tells apart
+    /// the records of a box which expired and registered again.
+    pub fn etag(&self) -> String {
+        format!("{}-{}", self.first_seen, self.revision)
+    }
+
+    /// Whether the client didn't register again within `ttl` seconds at
+    /// time `now`. Redis drops the entries after the same delay, but using
+    /// the clock of the Db as well keeps the expiration testable.
+    pub fn is_expired(&self, now: u64, ttl: u64) -> bool {
+        self.last_seen + ttl <= now
+    }
+
+    /// Whether the box is expected to be reachable at time `now`, when it
+    /// should register or send a heartbeat every `ping_interval` seconds.
+    /// Missing a single one doesn't make it offline yet.
+    pub fn is_online(&self, now: u64, ping_interval: u64) -> bool {
+        self.last_seen + 2 * ping_interval >= now
+    }
+
+    /// Build a record from the fields of its "publicIP:clientID" hash.
+    /// Entries written before first_seen and last_seen existed only have a
+    /// timestamp, which is used for both.
+    fn from_fields(public_ip: &str, client: &str, fields: &HashMap<String, String>)
+        -> Option<Record> {
+        let number = |name: &str| fields.get(name).and_then(|v| v.parse().ok());
+
+        fields.get("message").map(|message| {
+            let timestamp = number("timestamp").unwrap_or(0);
+            Record {
+                public_ip: public_ip.to_owned(),
+                client: client.to_owned(),
+                message: message.clone(),
+                first_seen: number("first_seen").unwrap_or(timestamp),
+                last_seen: number("last_seen").unwrap_or(timestamp),
+                revision: number("revision").unwrap_or(0),
+                local_ip: fields.get("local_ip").cloned(),
+                encrypted: fields.get("encrypted").cloned(),
+                nat: fields.get("nat").cloned(),
+                verified: fields.get("verified").map(|verified| verified == "1"),
+            }
+        })
+    }
+}
+
+/// The latest registration of a box, kept after its eviction when the
+/// archive is enabled.
+#[derive(RustcEncodable, Debug, Clone)]
+pub struct Archived {
+    pub record: Record,
+    /// When the record was evicted, if it was.
+    pub evicted: Option<u64>,
+}
+
+/// A box whose public IP changed implausibly often, which usually means
+/// that its fingerprint is spoofed or that its NAT is broken.
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, PartialEq)]
+pub struct Flapping {
+    pub client: String,
+    /// When the box was last flagged.
+    pub flagged: u64,
+}
+
+/// A public IP along with the number of boxes currently registered from it,
+/// e.g. to spot NATs hosting suspiciously many fingerprints.
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, PartialEq)]
+pub struct PublicIp {
     pub public_ip: String,
-    pub client:    String,
-    pub message:   String,
+    pub clients: usize,
+    /// The latest registration or heartbeat of its boxes.
+    pub last_seen: u64,
+}
+
+/// A revoked box token or API key, by its hash.
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, PartialEq)]
+pub struct Revocation {
+    pub hash: String,
+    pub revoked_at: u64,
+}
+
+/// An admin token or API key created from the command line, by its hash.
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, PartialEq)]
+pub struct Credential {
+    /// Tells the credentials apart, e.g. the name of their holder.
+    pub name: String,
+    /// `ADMIN_CREDENTIAL` or `API_CREDENTIAL`.
+    pub kind: String,
+    pub hash: String,
+    pub created_at: u64,
+}
+
+/// The kind of the admin tokens.
+pub static ADMIN_CREDENTIAL: &'static str = "admin";
+/// The kind of the API keys.
+pub static API_CREDENTIAL: &'static str = "api";
+
+/// A record along with whether the box looks online, as the API returns it,
+/// and in discovery responses how to connect to it.
+#[derive(Debug, Clone)]
+pub struct RecordStatus {
+    pub record: Record,
+    pub online: bool,
+    /// One of the strategies of `discovery::strategy`.
+    pub strategy: Option<&'static str>,
+}
+
+impl RecordStatus {
+    pub fn new(record: Record, now: u64, ping_interval: u64) -> RecordStatus {
+        RecordStatus {
+            online: record.is_online(now, ping_interval),
+            record: record,
+            strategy: None,
+        }
+    }
+
+    pub fn with_strategy(mut self, strategy: &'static str) -> RecordStatus {
+        self.strategy = Some(strategy);
+        self
+    }
+}
+
+/// Encoded as the fields of the record, plus `online`, and `strategy` when
+/// there is one.
+impl Encodable for RecordStatus {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let record = &self.record;
+        let length = if self.strategy.is_some() { 12 } else { 11 };
+        s.emit_struct("RecordStatus", length, |s| {
+            try!(s.emit_struct_field("public_ip", 0, |s| record.public_ip.encode(s)));
+            try!(s.emit_struct_field("client", 1, |s| record.client.encode(s)));
+            try!(s.emit_struct_field("message", 2, |s| record.message.encode(s)));
+            try!(s.emit_struct_field("first_seen", 3, |s| record.first_seen.encode(s)));
+            try!(s.emit_struct_field("last_seen", 4, |s| record.last_seen.encode(s)));
+            try!(s.emit_struct_field("revision", 5, |s| record.revision.encode(s)));
+            try!(s.emit_struct_field("local_ip", 6, |s| record.local_ip.encode(s)));
+            try!(s.emit_struct_field("encrypted", 7, |s| record.encrypted.encode(s)));
+            try!(s.emit_struct_field("nat", 8, |s| record.nat.encode(s)));
+            try!(s.emit_struct_field("verified", 9, |s| record.verified.encode(s)));
+            try!(s.emit_struct_field("online", 10, |s| self.online.encode(s)));
+            match self.strategy {
+                Some(strategy) => s.emit_struct_field("strategy", 11, |s| strategy.encode(s)),
+                None => Ok(())
+            }
+        })
+    }
+}
+
+/// Condition on the current record of a client for an update to apply, so
+/// that devices sharing a fingerprint don't silently overwrite each other.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Precondition {
+    /// The current record has this revision, 0 if there is none.
+    Revision(u64),
+    /// The current record, if any, wasn't updated after this time.
+    UnmodifiedSince(u64),
+    /// The current record has one of these entity tags.
+    Matches(Vec<String>),
+    /// There is a current record, whatever it is.
+    Exists,
+}
+
+impl Precondition {
+    /// Whether the condition holds for the `current` record of the client.
+    pub fn holds(&self, current: Option<&Record>) -> bool {
+        match *self {
+            Precondition::Revision(revision) => {
+                current.map_or(0, |record| record.revision) == revision
+            },
+            Precondition::UnmodifiedSince(time) => {
+                current.map_or(true, |record| record.last_seen <= time)
+            },
+            Precondition::Matches(ref etags) => {
+                current.map_or(false, |record| etags.contains(&record.etag()))
+            },
+            Precondition::Exists => current.is_some()
+        }
+    }
+}
+
+/// Outcome of a heartbeat.
+#[derive(Debug, Clone)]
+pub enum Heartbeat {
+    /// The client isn't registered, or its registration expired.
+    Unknown,
+    /// The token isn't the one of the client's latest registration.
+    InvalidToken,
+    /// The registration was kept alive, and now looks like this.
+    Alive(Record),
+}
+
+/// Outcome of the redemption of a pairing code.
+#[derive(Debug, Clone)]
+pub enum Pairing {
+    /// The code doesn't exist, expired or was already used.
+    Unknown,
+    /// Too many unknown codes were tried from this public IP, retry after
+    /// this number of seconds.
+    Throttled(u64),
+    /// The latest registration of the box which created the code.
+    Paired(Record),
+}
+
+/// Size of the database.
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub public_ips:      usize,
+    pub clients:         usize,
+    /// Number of clients of the public IP with the most clients.
+    pub largest_network: usize,
+    /// Median and 95th percentile of the number of seconds between two
+    /// registrations or heartbeats of a box, over the latest
+    /// `INTERVAL_SAMPLES`.
+    pub interval_p50: Option<u64>,
+    pub interval_p95: Option<u64>,
+    /// Fraction of the boxes evicted over the last 24 hours, out of the
+    /// current ones and the evicted ones.
+    pub churn_rate: f64,
+    /// Registrations and evictions of each of the last `HOURLY_WINDOW`
+    /// hours, oldest first.
+    pub hourly: Vec<Hourly>,
+}
+
+/// Registrations and evictions of an hour.
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, Default, PartialEq)]
+pub struct Hourly {
+    /// Start of the hour, in seconds since the epoch.
+    pub hour: u64,
+    pub registrations: u64,
+    pub evictions: u64,
+}
+
+/// Anonymized aggregates of the activity of a day, without any IP or
+/// client.
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    /// Start of the day, in seconds since the epoch.
+    pub day: u64,
+    /// Number of boxes which registered or sent a heartbeat.
+    pub active_boxes: u64,
+    /// Number of registrations of boxes which weren't registered, because
+    /// they are new or their registration expired.
+    pub new_boxes: u64,
+    pub evictions: u64,
+    /// Number of active boxes which sent their local IP.
+    pub local_ip_boxes: u64,
+}
+
+/// Queue the command setting the optional `field` of the hash `key` to
+/// `value`, or removing it.
+fn set_optional(pipeline: &mut Pipeline, key: &str, field: &str, value: &Option<String>) {
+    match *value {
+        Some(ref value) => {
+            pipeline.cmd("HSET").arg(key).arg(field).arg(value.clone()).ignore();
+        },
+        None => {
+            pipeline.cmd("HDEL").arg(key).arg(field).ignore();
+        }
+    }
+}
+
+/// The `quantile` of `sorted` values, by the nearest-rank method.
+fn percentile(sorted: &[u64], quantile: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    Some(sorted[cmp::max(rank, 1) - 1])
+}
+
+/// Criteria used to look records up. Unset fields match every record.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub public_ip: Option<String>,
+    pub client:    Option<String>,
+    /// Only match records registered at or after this time.
+    pub since:     Option<u64>,
+    /// Only match records registered at or before this time.
+    pub until:     Option<u64>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        if let Some(ref public_ip) = self.public_ip {
+            if *public_ip != record.public_ip {
+                return false;
+            }
+        }
+        if let Some(ref client) = self.client {
+            if *client != record.client {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if record.last_seen < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if record.last_seen > until {
+                return false;
+            }
+        }
+        true
+    }
 }
 
 pub struct Db {
-    connection: Connection
+    /// Kept to open a new connection when this one breaks.
+    client: Client,
+    connection: Connection,
+    /// How long commands may wait for Redis, applied to new connections too.
+    timeout: Option<Duration>,
+    get_script: Script,
+    clock: Arc<Clock>,
+    prefixes: Prefixes,
+    /// Whether the activity is tracked for the usage aggregates.
+    usage: bool,
+    retention: Policy,
+    /// Number of public IP changes of a box within `FLAPPING_WINDOW` above
+    /// which it is flagged as flapping, 0 to never flag boxes.
+    max_ip_changes: u64,
+    /// The keys signing the tokens, which are random when not set.
+    jwt: Option<jwt::Keys>,
 }
 
 impl Db {
+    /// Connect to a Redis database, failing right away if it can't be
+    /// reached.
     pub fn new(db_host: String,
                db_port: u16,
-               db_password: Option<String>) -> Db {
-        let client = Client::open(ConnectionInfo {
-            addr: Box::new(ConnectionAddr::Tcp(db_host.clone(), db_port)),
-            db: 0,
-            passwd: db_password.clone()
-        }).unwrap();
+               db_password: Option<String>,
+               db_index: i64) -> RedisResult<Db> {
+        let client = try!(Client::open(ConnectionInfo {
+            addr: Box::new(ConnectionAddr::Tcp(db_host, db_port)),
+            db: db_index,
+            passwd: db_password
+        }));
+        let connection = try!(client.get_connection());
+
+        Ok(Db {
+            client: client,
+            connection: connection,
+            timeout: None,
+            get_script: Script::new(GET_SCRIPT),
+            clock: Arc::new(SystemClock),
+            prefixes: Prefixes::default(),
+            usage: false,
+            retention: Policy::default(),
+            max_ip_changes: 0,
+            jwt: None,
+        })
+    }
+
+    /// Connect to the database of the configuration, whose commands time
+    /// out after `handler_timeout` seconds.
+    pub fn from_config(config: &Config) -> RedisResult<Db> {
+        let timeout = if config.handler_timeout > 0 {
+            Some(Duration::from_secs(config.handler_timeout))
+        } else {
+            None
+        };
+        Db::new(config.db_host.clone(), config.db_port, config.db_password.clone(),
+                config.db_index)
+            .map(|db| {
+                db.with_clock(config.clock.clone())
+                  .with_prefixes(config.subnet)
+                  .with_usage(config.usage_aggregates)
+                  .with_retention(config.retention)
+                  .with_flapping(config.max_ip_changes)
+                  .with_jwt(config.jwt.clone())
+            })
+            .and_then(|db| db.with_timeout(timeout))
+    }
 
+    /// Call `connect` until the server stops refusing the connection, for at
+    /// most `timeout`. Other errors aren't retried.
+    pub fn wait_for<F>(connect: F, timeout: Duration) -> RedisResult<Db>
+        where F: Fn() -> RedisResult<Db> {
+        let start = Instant::now();
         loop {
-            match client.get_connection() {
-                Err(err) => {
-                    if err.is_connection_refusal() {
-                        warn!("Could not connect: {} (Will retry)", err);
-                        sleep(Duration::from_millis(1));
-                    } else {
-                        panic!("Could not connect: {}", err);
-                    }
-                },
-                Ok(connection) => {
-                    return Db {
-                        connection: connection
-                    }
+            match connect() {
+                Err(ref err) if err.is_connection_refusal() && start.elapsed() < timeout => {
+                    warn!("Could not connect: {} (Will retry)", err);
+                    sleep(Duration::from_millis(1));
                 },
+                result => return result
             }
         }
     }
 
+    /// Replace the connection, e.g. after Redis closed it.
+    pub fn reconnect(&mut self) -> RedisResult<()> {
+        let connection = try!(self.client.get_connection());
+        try!(connection.set_read_timeout(self.timeout));
+        try!(connection.set_write_timeout(self.timeout));
+        self.connection = connection;
+        Ok(())
+    }
+
+    /// Fail the commands for which Redis doesn't answer within `timeout`,
+    /// with an error whose `is_timeout()` is true, rather than waiting
+    /// forever. `None` waits forever.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> RedisResult<Db> {
+        try!(self.connection.set_read_timeout(timeout));
+        try!(self.connection.set_write_timeout(timeout));
+        self.timeout = timeout;
+        Ok(self)
+    }
+
+    /// Check that the connection works.
+    pub fn ping(&self) -> RedisResult<()> {
+        let _: String = try!(cmd("PING").query(&self.connection));
+        Ok(())
+    }
+
+    /// Use `clock` to tell which records expired.
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> Db {
+        self.clock = clock;
+        self
+    }
+
+    /// Index the public IPs by the subnets of these prefixes, for the
+    /// discovery within subnets.
+    pub fn with_prefixes(mut self, prefixes: Prefixes) -> Db {
+        self.prefixes = prefixes;
+        self
+    }
+
+    /// Track the active boxes, new boxes and evictions of each day, for
+    /// `aggregate_usage`. Only HyperLogLogs of the clients and counters are
+    /// kept, for two days.
+    pub fn with_usage(mut self, usage: bool) -> Db {
+        self.usage = usage;
+        self
+    }
+
+    /// Expire the registrations and the usage aggregates as `retention`
+    /// says.
+    pub fn with_retention(mut self, retention: Policy) -> Db {
+        self.retention = retention;
+        self
+    }
+
+    /// Flag the boxes whose public IP changes more than `max_ip_changes`
+    /// times within `FLAPPING_WINDOW`, 0 to never flag boxes.
+    pub fn with_flapping(mut self, max_ip_changes: u64) -> Db {
+        self.max_ip_changes = max_ip_changes;
+        self
+    }
+
+    /// Issue the tokens as JWTs signed with `keys`.
+    pub fn with_jwt(mut self, keys: Option<jwt::Keys>) -> Db {
+        self.jwt = keys;
+        self
+    }
+
+    /// Queue the commands counting a public IP change of `client`, and
+    /// flagging it when it changes too often.
+    fn track_move(&self, connection: &Connection, pipeline: &mut Pipeline, client: &str,
+                  public_ip: &str) -> RedisResult<()> {
+        if self.max_ip_changes == 0 {
+            return Ok(());
+        }
+        let now = self.now();
+        let key = format!("moves:{}", client);
+        let since = now.saturating_sub(FLAPPING_WINDOW);
+        let moves: u64 = try!(
+            cmd("ZCOUNT").arg(key.clone()).arg(format!("({}", since)).arg("+inf")
+                         .query(connection)
+        );
+        pipeline.cmd("ZREMRANGEBYSCORE").arg(key.clone()).arg("-inf").arg(since).ignore()
+                .cmd("ZADD").arg(key.clone()).arg(now).arg(format!("{}:{}", now, public_ip))
+                            .ignore()
+                .cmd("EXPIRE").arg(key).arg(FLAPPING_WINDOW).ignore();
+        if moves + 1 > self.max_ip_changes {
+            warn!("{} changed public IP {} times within {} seconds", client, moves + 1,
+                  FLAPPING_WINDOW);
+            pipeline.cmd("ZADD").arg("flapping").arg(now).arg(client).ignore();
+        }
+        Ok(())
+    }
+
+    /// Queue the commands keeping a copy of `record` in "archive:<client>",
+    /// which outlives the record by the archive retention.
+    fn archive(&self, pipeline: &mut Pipeline, record: &Record) {
+        if self.retention.archive == 0 {
+            return;
+        }
+        let key = format!("archive:{}", record.client);
+        pipeline.cmd("HMSET").arg(key.clone())
+                             .arg("public_ip").arg(record.public_ip.clone())
+                             .arg("message").arg(record.message.clone())
+                             .arg("first_seen").arg(record.first_seen)
+                             .arg("last_seen").arg(record.last_seen)
+                             .arg("revision").arg(record.revision)
+                             .ignore()
+                .cmd("HDEL").arg(key.clone()).arg("evicted").ignore()
+                .cmd("EXPIRE").arg(key.clone())
+                              .arg(self.retention.records + self.retention.archive * 86400)
+                              .ignore();
+        set_optional(pipeline, &key, "local_ip", &record.local_ip);
+        set_optional(pipeline, &key, "encrypted", &record.encrypted);
+        set_optional(pipeline, &key, "nat", &record.nat);
+    }
+
+    /// Queue the commands tracking the activity of `client` today.
+    fn track_usage(&self, pipeline: &mut Pipeline, client: &str, new: bool, local_ip: bool) {
+        if !self.usage {
+            return;
+        }
+        let day = self.now() / 86400;
+        let mut keys = vec![format!("usage:active:{}", day)];
+        if local_ip {
+            keys.push(format!("usage:local_ip:{}", day));
+        }
+        for key in &keys {
+            pipeline.cmd("PFADD").arg(key.clone()).arg(client).ignore()
+                    .cmd("EXPIRE").arg(key.clone()).arg(2 * 86400).ignore();
+        }
+        if new {
+            let key = format!("usage:new:{}", day);
+            pipeline.cmd("INCR").arg(key.clone()).ignore()
+                    .cmd("EXPIRE").arg(key).arg(2 * 86400).ignore();
+        }
+    }
+
+    /// Number of seconds since the epoch, according to the clock of the Db.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
     ///
     /// Add or update a DB record.
     /// We keep a set with the record's public IP as key containing the list
@@ -61,116 +711,1641 @@ impl Db {
     ///     "2b3e83cca3ee12c8b41d86bfeca6034ea8cb9056"
     /// ]
     ///
-    /// "88.22.170.96:e7ce02eaa73da35bddea00c82124c7fbbe49b731": "message1"
-    /// "88.22.170.96:2b3e83cca3ee12c8b41d86bfeca6034ea8cb9056": "message2"
+    /// "88.22.170.96:e7ce02eaa73da35bddea00c82124c7fbbe49b731":
+    ///     { message: "message1", first_seen: 1481800000, last_seen: 1481900000 }
+    /// "88.22.170.96:2b3e83cca3ee12c8b41d86bfeca6034ea8cb9056":
+    ///     { message: "message2", first_seen: 1481900042, last_seen: 1481900042 }
     ///
     /// Each "publicIP:clientID" tuple has a ttl of 2 minutes.
     ///
-    pub fn set(&self, record: Record) -> RedisResult<()> {
-        let key = format!("{}:{}", record.public_ip, record.client);
+    /// We also keep track of the last public IP each client registered from,
+    /// so that a client can be found by its ID only:
+    ///
+    /// "box:e7ce02eaa73da35bddea00c82124c7fbbe49b731": "88.22.170.96"
+    ///
+    /// And the list of public IPs with registered clients, for the eviction
+    /// job:
+    ///
+    /// "public_ips": [ "88.22.170.96" ]
+    ///
+    /// When a client registers from a new public IP, its entry for the
+    /// previous public IP is removed in the same transaction, and the new
+    /// entry keeps its first_seen time and revision counter.
+    ///
+    /// Returns the new revision of the record.
+    ///
+    pub fn set(&self, record: Record) -> RedisResult<u64> {
+        self.add_many(&[record]).map(|revisions| revisions[0])
+    }
 
-        // We need to start watching the keys we care about (public_ip and
-        // public_ip:client) so that our exec fails if the key changes.
-        let _: () = try!(
-            cmd("WATCH").arg(key.clone())
-                        .arg(record.public_ip.clone())
-                        .query(&self.connection)
+    ///
+    /// Add or update a DB record like `set`, if the current record of the
+    /// client satisfies `precondition`, in the same transaction.
+    ///
+    /// Returns the new revision of the record, or `None` if it wasn't
+    /// updated.
+    ///
+    pub fn set_if(&self, record: Record, precondition: Precondition)
+        -> RedisResult<Option<u64>> {
+        self.update(&[record], Some(precondition))
+            .map(|revisions| revisions.map(|revisions| revisions[0]))
+    }
+
+    ///
+    /// Add or update several DB records at once, in a single transaction.
+    /// Returns the new revision of each record.
+    ///
+    pub fn add_many(&self, records: &[Record]) -> RedisResult<Vec<u64>> {
+        // Without a precondition, the records are always updated.
+        self.update(records, None).map(|revisions| revisions.unwrap_or(vec![]))
+    }
+
+    fn update(&self, records: &[Record], precondition: Option<Precondition>)
+        -> RedisResult<Option<Vec<u64>>> {
+        if records.is_empty() {
+            return Ok(Some(vec![]));
+        }
+
+        // We watch the "box:clientID" keys so that the transaction is
+        // retried if the client registers from somewhere else meanwhile.
+        let box_keys: Vec<String> = records.iter()
+            .map(|record| format!("box:{}", record.client))
+            .collect();
+
+        self.with_transaction(&box_keys, |connection, pipeline| {
+            let mut revisions = Vec::with_capacity(records.len());
+            for (record, box_key) in records.iter().zip(box_keys.iter()) {
+                let previous_ip: Option<String> = try!(
+                    cmd("GET").arg(box_key.clone()).query(connection)
+                );
+                let new = previous_ip.is_none();
+                // Keep the first_seen time and revision of the current
+                // entry, wherever it is. Imported records may come with a
+                // higher revision, which we keep.
+                let mut first_seen = record.first_seen;
+                let mut revision = cmp::max(record.revision, 1);
+                let mut interval = None;
+                let mut pinned = false;
+                let mut current = None;
+                if let Some(ref previous_ip) = previous_ip {
+                    let fields: HashMap<String, String> = try!(
+                        cmd("HGETALL").arg(format!("{}:{}", previous_ip,
+                                                   record.client))
+                                      .query(connection)
+                    );
+                    pinned = fields.contains_key("pinned");
+                    current = Record::from_fields(previous_ip, &record.client, &fields);
+                    if let Some(ref previous) = current {
+                        first_seen = cmp::min(first_seen, previous.first_seen);
+                        revision = cmp::max(revision, previous.revision + 1);
+                        if record.last_seen > previous.last_seen {
+                            interval = Some(record.last_seen - previous.last_seen);
+                        }
+                    }
+                }
+                if let Some(ref precondition) = precondition {
+                    if !precondition.holds(current.as_ref()) {
+                        info!("The update of {} doesn't match {:?}", record.client,
+                              precondition);
+                        let _: () = try!(cmd("UNWATCH").query(connection));
+                        return Ok(Some(None));
+                    }
+                }
+
+                if let Some(previous_ip) = previous_ip {
+                    if previous_ip != record.public_ip {
+                        info!("{} moved from {} to {}", record.client,
+                              previous_ip, record.public_ip);
+                        try!(self.track_move(connection, pipeline, &record.client,
+                                             &record.public_ip));
+                        pipeline
+                            .cmd("SREM").arg(previous_ip.clone())
+                                        .arg(record.client.clone())
+                                        .ignore()
+                            .cmd("DEL").arg(format!("{}:{}", previous_ip,
+                                                    record.client))
+                                       .ignore();
+                    }
+                }
+
+                let key = format!("{}:{}", record.public_ip, record.client);
+                pipeline
+                    .cmd("SADD").arg(record.public_ip.clone())
+                                .arg(record.client.clone())
+                                .ignore()
+                    .cmd("HMSET").arg(key.clone())
+                                 .arg("message").arg(record.message.clone())
+                                 .arg("first_seen").arg(first_seen)
+                                 .arg("last_seen").arg(record.last_seen)
+                                 .arg("revision").arg(revision)
+                                 .ignore()
+                    .cmd("HDEL").arg(key.clone())
+                                .arg("timestamp")
+                                .ignore()
+                    .cmd("SADD").arg("public_ips")
+                                .arg(record.public_ip.clone())
+                                .ignore();
+                // Remember where this client was last seen, with the same
+                // TTL, unless it is pinned.
+                if pinned {
+                    pipeline
+                        .cmd("HSET").arg(key.clone()).arg("pinned").arg(1).ignore()
+                        .cmd("PERSIST").arg(key.clone()).ignore()
+                        .cmd("SET").arg(box_key.clone())
+                                   .arg(record.public_ip.clone())
+                                   .ignore();
+                } else {
+                    pipeline
+                        .cmd("EXPIRE").arg(key.clone())
+                                      .arg(self.retention.records)
+                                      .ignore()
+                        .cmd("SETEX").arg(box_key.clone())
+                                     .arg(self.retention.records)
+                                     .arg(record.public_ip.clone())
+                                     .ignore();
+                }
+                let hourly_key = format!("registrations:{}", record.last_seen / 3600);
+                pipeline.cmd("INCR").arg(hourly_key.clone()).ignore()
+                        .cmd("EXPIRE").arg(hourly_key).arg(HOURLY_WINDOW * 3600).ignore();
+                if let Some(interval) = interval {
+                    pipeline.cmd("LPUSH").arg("intervals").arg(interval).ignore()
+                            .cmd("LTRIM").arg("intervals").arg(0).arg(INTERVAL_SAMPLES - 1)
+                                         .ignore();
+                }
+                self.track_usage(pipeline, &record.client, new, record.local_ip.is_some());
+                self.archive(pipeline, &Record {
+                    first_seen: first_seen,
+                    revision: revision,
+                    .. record.clone()
+                });
+                set_optional(pipeline, &key, "local_ip", &record.local_ip);
+                set_optional(pipeline, &key, "encrypted", &record.encrypted);
+                set_optional(pipeline, &key, "nat", &record.nat);
+                // The public IPs of a subnet, expiring along with their
+                // latest registration.
+                if let Some(network) = self.prefixes.network(&record.public_ip) {
+                    let subnet_key = format!("subnet:{}", network);
+                    pipeline
+                        .cmd("SADD").arg(subnet_key.clone())
+                                    .arg(record.public_ip.clone())
+                                    .ignore()
+                        .cmd("EXPIRE").arg(subnet_key)
+                                      .arg(self.retention.records)
+                                      .ignore();
+                }
+                revisions.push(revision);
+            }
+
+            let executed: Option<()> = try!(pipeline.query(connection));
+            Ok(executed.map(|_| Some(revisions)))
+        })
+    }
+
+    ///
+    /// Run `func` as a transaction: the `keys` are watched while `func`
+    /// reads what it needs from the connection and queues its writes in the
+    /// atomic pipeline, which it then executes. If one of the keys changed
+    /// meanwhile the pipeline isn't applied, `func` gets `None` back from
+    /// the execution and returns it, and we start over.
+    ///
+    pub fn with_transaction<T, F>(&self, keys: &[String], mut func: F)
+        -> RedisResult<T>
+        where F: FnMut(&Connection, &mut Pipeline) -> RedisResult<Option<T>> {
+        loop {
+            let mut watch = cmd("WATCH");
+            for key in keys {
+                watch.arg(key.clone());
+            }
+            let _: () = try!(watch.query(&self.connection));
+
+            let mut pipeline = pipe();
+            pipeline.atomic();
+            match func(&self.connection, &mut pipeline) {
+                Ok(Some(result)) => return Ok(result),
+                Ok(None) => {
+                    debug!("Transaction on {:?} aborted, retrying", keys);
+                },
+                Err(err) => {
+                    let _: RedisResult<()> =
+                        cmd("UNWATCH").query(&self.connection);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Get the registration entries for a given public IP.
+    ///
+    pub fn get(&self, public_ip: String) -> RedisResult<Vec<Record>> {
+        let entries: Vec<(String, HashMap<String, String>)> = try!(
+            self.get_script.key(public_ip.clone())
+                           .invoke(&self.connection)
         );
 
-        // We check if there's already an entry for this public IP.
-        let is_member: isize = try!(
-            cmd("SISMEMBER").arg(record.public_ip.clone())
-                            .arg(record.client.clone())
-                            .query(&self.connection)
+        let now = self.now();
+        let result: Vec<Record> = entries.iter().filter_map(|&(ref client, ref fields)| {
+            self.unexpired(Record::from_fields(&public_ip, client, fields), fields, now)
+        }).collect();
+
+        info!("Records of {}: {:?}", public_ip, result);
+
+        Ok(result)
+    }
+
+    ///
+    /// Get the registration entries for discovery from a public IP: those of
+    /// every public IP of its subnet when subnets are matched, or only its
+    /// own otherwise.
+    ///
+    pub fn discover(&self, public_ip: String) -> RedisResult<Vec<Record>> {
+        let network = match self.prefixes.network(&public_ip) {
+            Some(network) => network,
+            None => return self.get(public_ip)
+        };
+
+        let mut public_ips: Vec<String> = try!(
+            cmd("SMEMBERS").arg(format!("subnet:{}", network))
+                           .query(&self.connection)
         );
+        if !public_ips.contains(&public_ip) {
+            public_ips.push(public_ip);
+        }
 
-        if is_member == 0 {
-            // If there is no previous entry for this public IP, we add one
-            // and add the message corresponding to this key (IP:user tuple)
-            info!("{} is not a member of {} yet",
-                  record.client.clone(), record.public_ip.clone());
-            let _: () = try!(
-                pipe().atomic()
-                      .cmd("SADD").arg(record.public_ip.clone())
-                                  .arg(record.client.clone())
-                                  .ignore()
-                      .cmd("SET").arg(key.clone())
-                                 .arg(record.message.clone())
-                                 .query(&self.connection)
+        let mut result = Vec::new();
+        for public_ip in public_ips {
+            result.extend(try!(self.get(public_ip)));
+        }
+        Ok(result)
+    }
+
+    ///
+    /// Drop the clients whose message expired from the public IP sets, and
+    /// the public IPs left without clients. Returns the number of clients
+    /// removed.
+    ///
+    pub fn evict(&self) -> RedisResult<usize> {
+        self.evict_clients().map(|evicted| evicted.len())
+    }
+
+    ///
+    /// Like `evict`, returning the public IP and client of each registration
+    /// removed.
+    ///
+    pub fn evict_clients(&self) -> RedisResult<Vec<(String, String)>> {
+        let public_ips: Vec<String> = try!(
+            cmd("SMEMBERS").arg("public_ips")
+                           .query(&self.connection)
+        );
+
+        let mut evicted = vec![];
+        for public_ip in public_ips {
+            let members: Vec<String> = try!(
+                cmd("SMEMBERS").arg(public_ip.clone())
+                               .query(&self.connection)
             );
-        } else {
-            // Otherwise, we just update the message from the existing
-            // entry.
-            info!("{} is already a member of {}",
-                  record.client.clone(), record.public_ip.clone());
+
+            for member in members {
+                if try!(self.read(&public_ip, &member)).is_none() {
+                    info!("Evicting {} from {}", member, public_ip);
+                    let box_key = format!("box:{}", member);
+                    let latest: Option<String> = try!(
+                        cmd("GET").arg(box_key.clone()).query(&self.connection)
+                    );
+
+                    let mut pipeline = pipe();
+                    pipeline.cmd("SREM").arg(public_ip.clone())
+                                        .arg(member.clone()).ignore()
+                            .cmd("DEL").arg(format!("{}:{}", public_ip, member))
+                                       .ignore();
+                    if latest.as_ref() == Some(&public_ip) {
+                        pipeline.cmd("DEL").arg(box_key).ignore();
+                    }
+                    // Stamp the archived copy, unless the box registered
+                    // from elsewhere since.
+                    if self.retention.archive > 0 &&
+                       latest.as_ref().map_or(true, |latest| *latest == public_ip) {
+                        let archive_key = format!("archive:{}", member);
+                        let archived_ip: Option<String> = try!(
+                            cmd("HGET").arg(archive_key.clone()).arg("public_ip")
+                                       .query(&self.connection)
+                        );
+                        if archived_ip.as_ref() == Some(&public_ip) {
+                            pipeline.cmd("HSET").arg(archive_key).arg("evicted")
+                                                .arg(self.now()).ignore();
+                        }
+                    }
+                    let _: () = try!(pipeline.query(&self.connection));
+                    evicted.push((public_ip.clone(), member));
+                }
+            }
+
+            let remaining: usize = try!(
+                cmd("SCARD").arg(public_ip.clone())
+                            .query(&self.connection)
+            );
+            if remaining == 0 {
+                let _: () = try!(
+                    cmd("SREM").arg("public_ips")
+                               .arg(public_ip.clone())
+                               .query(&self.connection)
+                );
+            }
+        }
+
+        if !evicted.is_empty() {
+            let key = format!("evictions:{}", self.now() / 3600);
             let _: () = try!(
-                cmd("SET").arg(key.clone())
-                          .arg(record.message.clone())
-                          .query(&self.connection)
+                pipe().cmd("INCRBY").arg(key.clone()).arg(evicted.len()).ignore()
+                      .cmd("EXPIRE").arg(key).arg(HOURLY_WINDOW * 3600).ignore()
+                      .query(&self.connection)
             );
+            if self.usage {
+                let key = format!("usage:evictions:{}", self.now() / 86400);
+                let _: () = try!(
+                    pipe().cmd("INCRBY").arg(key.clone()).arg(evicted.len()).ignore()
+                          .cmd("EXPIRE").arg(key).arg(2 * 86400).ignore()
+                          .query(&self.connection)
+                );
+            }
         }
 
-        // And set the TTL of the message.
-        // The entry in the list of clients for this public IP will be
-        // cleaned up during the .get call. It does no harm to keep it
-        // around until that point.
-        let _: () = try!(
-            cmd("EXPIRE").arg(key.clone())
-                         .arg(RECORD_TTL) // 2 min.
-                         .query(&self.connection)
+        Ok(evicted)
+    }
+
+    ///
+    /// Try to take the `name` lease for `ttl` seconds. Used to make sure that
+    /// a background job only runs on one instance of a cluster at a time.
+    ///
+    pub fn acquire_lease(&self, name: &str, owner: &str, ttl: u64)
+        -> RedisResult<bool> {
+        let result: Option<String> = try!(
+            cmd("SET").arg(format!("lease:{}", name))
+                      .arg(owner)
+                      .arg("NX")
+                      .arg("EX").arg(ttl)
+                      .query(&self.connection)
+        );
+
+        Ok(result.is_some())
+    }
+
+    ///
+    /// Get the most recent registration entry for a given client, whatever
+    /// the public IP it registered from.
+    ///
+    pub fn find_by_client(&self, client: String) -> RedisResult<Option<Record>> {
+        let public_ip: Option<String> = try!(
+            cmd("GET").arg(format!("box:{}", client))
+                      .query(&self.connection)
+        );
+
+        match public_ip {
+            Some(public_ip) => self.read(&public_ip, &client),
+            None => Ok(None)
+        }
+    }
+
+    ///
+    /// Store the token returned by the latest registration of a client, which
+    /// expires along with the registration.
+    ///
+    pub fn set_token(&self, client: String, token: String) -> RedisResult<()> {
+        pipe().atomic()
+              .cmd("SETEX").arg(format!("token:{}", client))
+                           .arg(self.retention.records)
+                           .arg(token)
+                           .ignore()
+              .cmd("DEL").arg(format!("previous_token:{}", client)).ignore()
+              .query(&self.connection)
+    }
+
+    ///
+    /// Whether `token` is the one of the latest registration of a client,
+    /// or its previous one during the grace period of a rotation.
+    ///
+    pub fn token_matches(&self, client: &str, token: &str) -> RedisResult<bool> {
+        let tokens = try!(self.tokens(client));
+        Ok(tokens.iter().any(|expected| tokens::matches(expected, token)))
+    }
+
+    ///
+    /// The tokens a client can use: the one of its latest registration, and
+    /// the previous one during the grace period of a rotation.
+    ///
+    pub fn tokens(&self, client: &str) -> RedisResult<Vec<String>> {
+        let (current, previous): (Option<String>, Option<String>) = try!(
+            cmd("MGET").arg(format!("token:{}", client))
+                       .arg(format!("previous_token:{}", client))
+                       .query(&self.connection)
         );
+        Ok(current.into_iter().chain(previous).collect())
+    }
+
+    ///
+    /// Replace the token of a client by a new one, if `token` is its
+    /// current one. The previous token keeps working for
+    /// `TOKEN_GRACE_PERIOD` seconds. Returns the new token, or `None` when
+    /// the client isn't registered or `token` doesn't match.
+    ///
+    pub fn rotate_token(&self, client: String, token: &str) -> RedisResult<Option<String>> {
+        let token_key = format!("token:{}", client);
+        self.with_transaction(&[token_key.clone()], |connection, pipeline| {
+            let current: Option<String> = try!(
+                cmd("GET").arg(token_key.clone()).query(connection)
+            );
+            if !current.map_or(false, |current| tokens::matches(&current, token)) {
+                let _: () = try!(cmd("UNWATCH").query(connection));
+                return Ok(Some(None));
+            }
+            let new_token = jwt::issue(self.jwt.as_ref(), &client, jwt::BOX_SCOPE,
+                                       jwt::BOX_TOKEN_LIFETIME, self.now());
+            pipeline.cmd("SETEX").arg(token_key.clone())
+                                 .arg(self.retention.records)
+                                 .arg(new_token.clone())
+                                 .ignore()
+                    .cmd("SETEX").arg(format!("previous_token:{}", client))
+                                 .arg(TOKEN_GRACE_PERIOD)
+                                 .arg(token)
+                                 .ignore();
+            let executed: Option<()> = try!(pipeline.query(connection));
+            Ok(executed.map(|_| Some(new_token)))
+        })
+    }
+
+    ///
+    /// Bump the last_seen time of the latest registration of a client, and
+    /// push back its expiration, if `token` is the one of this registration.
+    ///
+    pub fn heartbeat(&self, client: String, token: &str) -> RedisResult<Heartbeat> {
+        let record = match try!(self.find_by_client(client.clone())) {
+            Some(record) => record,
+            None => return Ok(Heartbeat::Unknown)
+        };
+        if !try!(self.token_matches(&client, token)) {
+            return Ok(Heartbeat::InvalidToken);
+        }
+        let token_key = format!("token:{}", client);
+        let key = format!("{}:{}", record.public_ip, client);
+
+        let pinned: bool = try!(
+            cmd("HEXISTS").arg(key.clone()).arg("pinned").query(&self.connection)
+        );
+
+        let now = self.now();
+        let ttl = self.retention.records;
+        let mut pipeline = pipe();
+        pipeline.atomic()
+                .cmd("HSET").arg(key.clone()).arg("last_seen").arg(now).ignore()
+                .cmd("EXPIRE").arg(token_key).arg(ttl).ignore();
+        if !pinned {
+            pipeline.cmd("EXPIRE").arg(key).arg(ttl).ignore()
+                    .cmd("EXPIRE").arg(format!("box:{}", client)).arg(ttl).ignore();
+        }
+        self.track_usage(&mut pipeline, &client, false, record.local_ip.is_some());
+        self.archive(&mut pipeline, &Record { last_seen: now, .. record.clone() });
+        if now > record.last_seen {
+            pipeline.cmd("LPUSH").arg("intervals").arg(now - record.last_seen).ignore()
+                    .cmd("LTRIM").arg("intervals").arg(0).arg(INTERVAL_SAMPLES - 1).ignore();
+        }
+        if let Some(network) = self.prefixes.network(&record.public_ip) {
+            pipeline.cmd("EXPIRE").arg(format!("subnet:{}", network))
+                                  .arg(self.retention.records).ignore();
+        }
+        let _: () = try!(pipeline.query(&self.connection));
+
+        Ok(Heartbeat::Alive(Record {
+            last_seen: now,
+            .. record
+        }))
+    }
+
+    ///
+    /// Create a pairing code for a client, which a user enters in an app to
+    /// find the box without typing its fingerprint.
+    ///
+    pub fn create_pairing_code(&self, client: String) -> RedisResult<String> {
+        // Codes are short, so retry on the rare collisions with the codes
+        // of other boxes.
+        for _ in 0..10 {
+            let code = tokens::pairing_code();
+            let created: Option<String> = try!(
+                cmd("SET").arg(format!("pairing:{}", code))
+                          .arg(client.clone())
+                          .arg("NX").arg("EX").arg(PAIRING_TTL)
+                          .query(&self.connection)
+            );
+            if created.is_some() {
+                return Ok(code);
+            }
+        }
+        Err((ErrorKind::ResponseError, "No pairing code available").into())
+    }
+
+    ///
+    /// Redeem a pairing code entered by a user from `public_ip`. A code can
+    /// only be used once.
+    ///
+    pub fn redeem_pairing_code(&self, code: &str, public_ip: String)
+        -> RedisResult<Pairing> {
+        let failures_key = format!("pairing_failures:{}", public_ip);
+        let failures: Option<u64> = try!(
+            cmd("GET").arg(failures_key.clone()).query(&self.connection)
+        );
+        if failures.unwrap_or(0) >= MAX_PAIRING_FAILURES {
+            let ttl: i64 = try!(cmd("TTL").arg(failures_key).query(&self.connection));
+            return Ok(Pairing::Throttled(cmp::max(ttl, 1) as u64));
+        }
+
+        let key = format!("pairing:{}", code);
+        let (client, _): (Option<String>, u64) = try!(
+            pipe().atomic()
+                  .cmd("GET").arg(key.clone())
+                  .cmd("DEL").arg(key)
+                  .query(&self.connection)
+        );
+        let client = match client {
+            Some(client) => client,
+            None => {
+                let _: () = try!(
+                    pipe().atomic()
+                          .cmd("INCR").arg(failures_key.clone()).ignore()
+                          .cmd("EXPIRE").arg(failures_key).arg(PAIRING_TTL).ignore()
+                          .query(&self.connection)
+                );
+                return Ok(Pairing::Unknown);
+            }
+        };
+
+        Ok(match try!(self.find_by_client(client)) {
+            Some(record) => Pairing::Paired(record),
+            None => Pairing::Unknown
+        })
+    }
+
+    ///
+    /// Create a user account, returning false if the email is already taken.
+    ///
+    pub fn create_user(&self, email: &str, password_hash: &str) -> RedisResult<bool> {
+        let key = format!("user:{}", email);
+        let created: bool = try!(
+            cmd("HSETNX").arg(key.clone()).arg("password").arg(password_hash)
+                         .query(&self.connection)
+        );
+        if created {
+            let _: () = try!(
+                cmd("HSET").arg(key).arg("created").arg(self.now()).query(&self.connection)
+            );
+        }
+        Ok(created)
+    }
+
+    ///
+    /// Create the account of a user logging in through an OpenID Connect
+    /// provider, unless it exists already. Such accounts have no password.
+    ///
+    pub fn ensure_user(&self, email: &str) -> RedisResult<()> {
+        cmd("HSETNX").arg(format!("user:{}", email)).arg("created").arg(self.now())
+                     .query(&self.connection)
+    }
+
+    ///
+    /// Remember the state of a log in through an OpenID Connect provider, which
+    /// comes back with the user.
+    ///
+    pub fn create_oidc_state(&self) -> RedisResult<String> {
+        let state = tokens::generate();
+        let _: () = try!(
+            cmd("SETEX").arg(format!("oidc_state:{}", state))
+                        .arg(OIDC_STATE_TTL)
+                        .arg(1)
+                        .query(&self.connection)
+        );
+        Ok(state)
+    }
+
+    ///
+    /// Forget the state of a log in, returning whether it was known. Each
+    /// state can only be used once.
+    ///
+    pub fn take_oidc_state(&self, state: &str) -> RedisResult<bool> {
+        cmd("DEL").arg(format!("oidc_state:{}", state)).query(&self.connection)
+    }
+
+    ///
+    /// The password hash of a user, if the account exists.
+    ///
+    pub fn password_hash(&self, email: &str) -> RedisResult<Option<String>> {
+        cmd("HGET").arg(format!("user:{}", email)).arg("password").query(&self.connection)
+    }
+
+    ///
+    /// Open a session for a user, returning its token.
+    ///
+    pub fn create_session(&self, email: &str) -> RedisResult<String> {
+        let token = jwt::issue(self.jwt.as_ref(), email, jwt::USER_SCOPE, SESSION_TTL as u64,
+                               self.now());
+        let _: () = try!(
+            cmd("SETEX").arg(format!("session:{}", token))
+                        .arg(SESSION_TTL)
+                        .arg(email)
+                        .query(&self.connection)
+        );
+        Ok(token)
+    }
+
+    ///
+    /// The user of a session, if it didn't expire.
+    ///
+    pub fn session_user(&self, token: &str) -> RedisResult<Option<String>> {
+        cmd("GET").arg(format!("session:{}", token)).query(&self.connection)
+    }
+
+    ///
+    /// Close a session.
+    ///
+    pub fn delete_session(&self, token: &str) -> RedisResult<()> {
+        cmd("DEL").arg(format!("session:{}", token)).query(&self.connection)
+    }
+
+    ///
+    /// Link a box to a user account. A box has a single owner, so it is
+    /// unlinked from the account of its previous owner, if any.
+    ///
+    pub fn link_box(&self, email: &str, client: String) -> RedisResult<()> {
+        let owner_key = format!("box_owner:{}", client);
+        let previous: Option<String> = try!(
+            cmd("GETSET").arg(owner_key).arg(email).query(&self.connection)
+        );
+        let mut pipeline = pipe();
+        pipeline.atomic()
+                .cmd("SADD").arg(format!("user_boxes:{}", email)).arg(client.clone()).ignore();
+        if let Some(previous) = previous.and_then(|p| if p != email { Some(p) } else { None }) {
+            pipeline.cmd("SREM").arg(format!("user_boxes:{}", previous)).arg(client).ignore();
+        }
+        pipeline.query(&self.connection)
+    }
+
+    ///
+    /// Unlink a box from a user account, returning whether it was linked.
+    ///
+    pub fn unlink_box(&self, email: &str, client: String) -> RedisResult<bool> {
+        let unlinked: bool = try!(
+            cmd("SREM").arg(format!("user_boxes:{}", email)).arg(client.clone())
+                       .query(&self.connection)
+        );
+        if unlinked {
+            let _: () = try!(cmd("DEL").arg(format!("box_owner:{}", client))
+                                       .query(&self.connection));
+        }
+        Ok(unlinked)
+    }
+
+    ///
+    /// Number of boxes linked to a user account.
+    ///
+    pub fn box_count(&self, email: &str) -> RedisResult<u64> {
+        cmd("SCARD").arg(format!("user_boxes:{}", email)).query(&self.connection)
+    }
+
+    ///
+    /// Count a registration of a box against the hourly quota of its owner.
+    /// Returns the owner and the number of registrations of their boxes
+    /// during the current hour, or `None` if the box isn't linked to any
+    /// account.
+    ///
+    pub fn count_registration(&self, client: String) -> RedisResult<Option<(String, u64)>> {
+        let owner: Option<String> = try!(
+            cmd("GET").arg(format!("box_owner:{}", client)).query(&self.connection)
+        );
+        let owner = match owner {
+            Some(owner) => owner,
+            None => return Ok(None)
+        };
+        let key = format!("registrations:{}:{}", owner, self.now() / 3600);
+        let (count, _): (u64, ()) = try!(
+            pipe().atomic()
+                  .cmd("INCR").arg(key.clone())
+                  .cmd("EXPIRE").arg(key).arg(3600)
+                  .query(&self.connection)
+        );
+        Ok(Some((owner, count)))
+    }
+
+    ///
+    /// The latest registrations of the boxes linked to a user account,
+    /// whatever public IP they registered from. The boxes which aren't
+    /// registered anymore are left out, but stay linked.
+    ///
+    pub fn user_boxes(&self, email: &str) -> RedisResult<Vec<Record>> {
+        let clients: Vec<String> = try!(
+            cmd("SMEMBERS").arg(format!("user_boxes:{}", email)).query(&self.connection)
+        );
+        let mut records = Vec::with_capacity(clients.len());
+        for client in clients {
+            if let Some(record) = try!(self.find_by_client(client)) {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    ///
+    /// Whether a box is linked to a user account.
+    ///
+    pub fn is_owner(&self, email: &str, client: String) -> RedisResult<bool> {
+        let owner: Option<String> = try!(
+            cmd("GET").arg(format!("box_owner:{}", client)).query(&self.connection)
+        );
+        Ok(owner.map_or(false, |owner| owner == email))
+    }
+
+    ///
+    /// Create a guest token, which lets whoever holds it find a box linked to
+    /// the account of `email` for `ttl` seconds, returning the token.
+    ///
+    pub fn create_guest_token(&self, email: &str, client: String, ttl: u64)
+        -> RedisResult<String> {
+        let token = jwt::issue(self.jwt.as_ref(), &client, jwt::GUEST_SCOPE, ttl, self.now());
+        let key = format!("guest:{}", token);
+        let _: () = try!(
+            pipe().atomic()
+                  .cmd("HMSET").arg(key.clone()).arg("email").arg(email)
+                                                .arg("client").arg(client).ignore()
+                  .cmd("EXPIRE").arg(key).arg(ttl).ignore()
+                  .query(&self.connection)
+        );
+        Ok(token)
+    }
+
+    ///
+    /// The client a guest token gives access to, if it didn't expire and the
+    /// box is still linked to the account which created the token.
+    ///
+    pub fn guest_client(&self, token: &str) -> RedisResult<Option<String>> {
+        let (email, client): (Option<String>, Option<String>) = try!(
+            cmd("HMGET").arg(format!("guest:{}", token)).arg("email").arg("client")
+                        .query(&self.connection)
+        );
+        match (email, client) {
+            (Some(email), Some(client)) => {
+                let linked = try!(self.is_owner(&email, client.clone()));
+                Ok(if linked { Some(client) } else { None })
+            },
+            _ => Ok(None)
+        }
+    }
+
+    ///
+    /// Reserve a friendly subdomain for a user account, returning false if
+    /// another account reserved it already. Reserving it again is harmless.
+    ///
+    pub fn reserve_subdomain(&self, email: &str, name: &str) -> RedisResult<bool> {
+        let key = format!("subdomain:{}", name);
+        let reserved: bool = try!(
+            cmd("SETNX").arg(key.clone()).arg(email).query(&self.connection)
+        );
+        if !reserved {
+            let owner: Option<String> = try!(cmd("GET").arg(key).query(&self.connection));
+            if owner.map_or(true, |owner| owner != email) {
+                return Ok(false);
+            }
+        }
+        let _: () = try!(
+            cmd("SADD").arg(format!("user_subdomains:{}", email)).arg(name)
+                       .query(&self.connection)
+        );
+        Ok(true)
+    }
+
+    ///
+    /// Release a subdomain of a user account, returning whether it was
+    /// reserved by the account.
+    ///
+    pub fn release_subdomain(&self, email: &str, name: &str) -> RedisResult<bool> {
+        let released: bool = try!(
+            cmd("SREM").arg(format!("user_subdomains:{}", email)).arg(name)
+                       .query(&self.connection)
+        );
+        if released {
+            let _: () = try!(cmd("DEL").arg(format!("subdomain:{}", name))
+                                       .query(&self.connection));
+        }
+        Ok(released)
+    }
+
+    ///
+    /// The subdomains reserved by a user account, sorted.
+    ///
+    pub fn user_subdomains(&self, email: &str) -> RedisResult<Vec<String>> {
+        let mut names: Vec<String> = try!(
+            cmd("SMEMBERS").arg(format!("user_subdomains:{}", email)).query(&self.connection)
+        );
+        names.sort();
+        Ok(names)
+    }
+
+    ///
+    /// Subscribe a mobile client to the push notifications about a box, or
+    /// renew its subscription.
+    ///
+    pub fn subscribe(&self, client: String, subscription: &Subscription) -> RedisResult<()> {
+        let key = format!("push:{}", client);
+        pipe().atomic()
+              .cmd("SADD").arg(key.clone()).arg(subscription.to_key()).ignore()
+              .cmd("EXPIRE").arg(key).arg(PUSH_TTL).ignore()
+              .query(&self.connection)
+    }
+
+    ///
+    /// Stop the push notifications about a box to a mobile client. Returns
+    /// whether it was subscribed.
+    ///
+    pub fn unsubscribe(&self, client: String, subscription: &Subscription)
+        -> RedisResult<bool> {
+        cmd("SREM").arg(format!("push:{}", client))
+                   .arg(subscription.to_key())
+                   .query(&self.connection)
+    }
+
+    ///
+    /// The mobile clients subscribed to the push notifications about a box.
+    ///
+    pub fn subscriptions(&self, client: String) -> RedisResult<Vec<Subscription>> {
+        let keys: Vec<String> = try!(
+            cmd("SMEMBERS").arg(format!("push:{}", client)).query(&self.connection)
+        );
+        Ok(keys.iter().filter_map(|key| Subscription::from_key(key)).collect())
+    }
+
+    ///
+    /// Leave a message in the mailbox of a box, for it to get with the
+    /// response to its next registration or heartbeat. Returns the number of
+    /// messages waiting.
+    ///
+    pub fn leave_message(&self, client: String, message: String) -> RedisResult<usize> {
+        let key = format!("mailbox:{}", client);
+        let (count,): (usize,) = try!(
+            pipe().atomic()
+                  .cmd("RPUSH").arg(key.clone()).arg(message)
+                  .cmd("LTRIM").arg(key.clone()).arg(-MAX_MAILBOX_MESSAGES).arg(-1).ignore()
+                  .cmd("EXPIRE").arg(key).arg(MAILBOX_TTL).ignore()
+                  .query(&self.connection)
+        );
+        Ok(cmp::min(count, MAX_MAILBOX_MESSAGES as usize))
+    }
+
+    ///
+    /// Empty the mailbox of a box, returning its messages, oldest first.
+    ///
+    pub fn take_messages(&self, client: String) -> RedisResult<Vec<String>> {
+        let key = format!("mailbox:{}", client);
+        let (messages,): (Vec<String>,) = try!(
+            pipe().atomic()
+                  .cmd("LRANGE").arg(key.clone()).arg(0).arg(-1)
+                  .cmd("DEL").arg(key).ignore()
+                  .query(&self.connection)
+        );
+        Ok(messages)
+    }
+
+    ///
+    /// Replace the NAT traversal candidates of a box, which expire after
+    /// `signaling::CANDIDATES_TTL` seconds.
+    ///
+    pub fn set_candidates(&self, client: String, candidates: &[Candidate])
+        -> RedisResult<()> {
+        cmd("SETEX").arg(format!("candidates:{}", client))
+                    .arg(signaling::CANDIDATES_TTL)
+                    .arg(signaling::to_key(candidates))
+                    .query(&self.connection)
+    }
+
+    ///
+    /// The NAT traversal candidates of a box, if it has any left.
+    ///
+    pub fn candidates(&self, client: String) -> RedisResult<Option<Vec<Candidate>>> {
+        let key: Option<String> = try!(
+            cmd("GET").arg(format!("candidates:{}", client)).query(&self.connection)
+        );
+        Ok(key.map(|key| signaling::from_key(&key)))
+    }
+
+    ///
+    /// Leave the candidates of a client which wants to connect to a box,
+    /// for the box to get with its next candidates.
+    ///
+    pub fn add_peer(&self, client: String, candidates: &[Candidate]) -> RedisResult<()> {
+        let key = format!("peers:{}", client);
+        pipe().atomic()
+              .cmd("RPUSH").arg(key.clone()).arg(signaling::to_key(candidates)).ignore()
+              .cmd("LTRIM").arg(key.clone()).arg(-signaling::MAX_PEERS).arg(-1).ignore()
+              .cmd("EXPIRE").arg(key).arg(signaling::CANDIDATES_TTL).ignore()
+              .query(&self.connection)
+    }
+
+    ///
+    /// Take the candidates the clients left for a box, oldest first.
+    ///
+    pub fn take_peers(&self, client: String) -> RedisResult<Vec<Vec<Candidate>>> {
+        let key = format!("peers:{}", client);
+        let (peers,): (Vec<String>,) = try!(
+            pipe().atomic()
+                  .cmd("LRANGE").arg(key.clone()).arg(0).arg(-1)
+                  .cmd("DEL").arg(key).ignore()
+                  .query(&self.connection)
+        );
+        Ok(peers.iter().map(|peer| signaling::from_key(peer)).collect())
+    }
+
+    ///
+    /// Record whether the box could be reached at the public IP of its
+    /// registration, if it is still registered from there. Returns whether
+    /// it was.
+    ///
+    pub fn set_verified(&self, public_ip: String, client: String, verified: bool)
+        -> RedisResult<bool> {
+        let key = format!("{}:{}", public_ip, client);
+        self.with_transaction(&[key.clone()], |connection, pipeline| {
+            let exists: bool = try!(cmd("EXISTS").arg(key.clone()).query(connection));
+            if !exists {
+                let _: () = try!(cmd("UNWATCH").query(connection));
+                return Ok(Some(false));
+            }
+            pipeline.cmd("HSET").arg(key.clone())
+                                .arg("verified").arg(if verified { 1 } else { 0 })
+                                .ignore();
+            let executed: Option<()> = try!(pipeline.query(connection));
+            Ok(executed.map(|_| Some(true)))
+        })
+    }
+
+    ///
+    /// Delete the latest registration entry of a client, e.g. at the request
+    /// of its owner. Returns whether the client was registered.
+    ///
+    pub fn delete(&self, client: String) -> RedisResult<bool> {
+        let box_key = format!("box:{}", client);
+        let public_ip: Option<String> = try!(
+            cmd("GET").arg(box_key.clone()).query(&self.connection)
+        );
+        let public_ip = match public_ip {
+            Some(public_ip) => public_ip,
+            None => return Ok(false)
+        };
+
+        info!("Deleting {} from {}", client, public_ip);
+        let _: () = try!(
+            pipe().atomic()
+                  .cmd("SREM").arg(public_ip.clone()).arg(client.clone()).ignore()
+                  .cmd("DEL").arg(format!("{}:{}", public_ip, client)).ignore()
+                  .cmd("DEL").arg(box_key).ignore()
+                  .cmd("DEL").arg(format!("token:{}", client)).ignore()
+                  .cmd("DEL").arg(format!("previous_token:{}", client)).ignore()
+                  .cmd("DEL").arg(format!("push:{}", client)).ignore()
+                  .cmd("DEL").arg(format!("archive:{}", client)).ignore()
+                  .query(&self.connection)
+        );
+
+        let remaining: usize = try!(
+            cmd("SCARD").arg(public_ip.clone()).query(&self.connection)
+        );
+        if remaining == 0 {
+            let _: () = try!(
+                cmd("SREM").arg("public_ips").arg(public_ip).query(&self.connection)
+            );
+        }
+
+        Ok(true)
+    }
+
+    ///
+    /// The archived latest registration of a client, which is kept for the
+    /// archive retention after its eviction.
+    ///
+    pub fn archived(&self, client: String) -> RedisResult<Option<Archived>> {
+        let fields: HashMap<String, String> = try!(
+            cmd("HGETALL").arg(format!("archive:{}", client)).query(&self.connection)
+        );
+        let public_ip = match fields.get("public_ip") {
+            Some(public_ip) => public_ip.clone(),
+            None => return Ok(None)
+        };
+        Ok(Record::from_fields(&public_ip, &client, &fields).map(|record| {
+            Archived {
+                record: record,
+                evicted: fields.get("evicted").and_then(|evicted| evicted.parse().ok()),
+            }
+        }))
+    }
+
+    ///
+    /// The boxes flagged as flapping within the last `FLAPPING_TTL`
+    /// seconds, most recent first.
+    ///
+    pub fn flapping(&self) -> RedisResult<Vec<Flapping>> {
+        let since = self.now().saturating_sub(FLAPPING_TTL);
+        let (flagged, _): (Vec<(String, u64)>, ()) = try!(
+            pipe().cmd("ZREVRANGEBYSCORE").arg("flapping").arg("+inf").arg(format!("({}", since))
+                                          .arg("WITHSCORES")
+                  .cmd("ZREMRANGEBYSCORE").arg("flapping").arg("-inf").arg(since)
+                  .query(&self.connection)
+        );
+        Ok(flagged.into_iter().map(|(client, flagged)| {
+            Flapping {
+                client: client,
+                flagged: flagged,
+            }
+        }).collect())
+    }
+
+    ///
+    /// Whether an update of a client is allowed: always, unless the client
+    /// is flagged as flapping and `token` isn't the one of its latest
+    /// registration.
+    ///
+    pub fn check_flapping(&self, client: String, token: Option<String>) -> RedisResult<bool> {
+        let flagged: Option<u64> = try!(
+            cmd("ZSCORE").arg("flapping").arg(client.clone()).query(&self.connection)
+        );
+        if !flagged.map_or(false, |flagged| flagged + FLAPPING_TTL > self.now()) {
+            return Ok(true);
+        }
+        match token {
+            Some(token) => self.token_matches(&client, &token),
+            None => Ok(false)
+        }
+    }
+
+    ///
+    /// The response kept for the idempotency `key` of a request, if a
+    /// request with the same key succeeded within the lifetime of a
+    /// registration.
+    ///
+    pub fn find_response(&self, key: String) -> RedisResult<Option<String>> {
+        cmd("GET").arg(format!("idempotency:{}", key)).query(&self.connection)
+    }
+
+    ///
+    /// Keep the response of a request with the idempotency `key` for the
+    /// lifetime of a registration, unless one is kept already.
+    ///
+    pub fn keep_response(&self, key: String, response: String) -> RedisResult<()> {
+        cmd("SET").arg(format!("idempotency:{}", key))
+                  .arg(response)
+                  .arg("EX").arg(self.retention.records)
+                  .arg("NX")
+                  .query(&self.connection)
+    }
+
+    ///
+    /// Pin the latest record of a client, which then never expires nor gets
+    /// evicted, e.g. for demo boxes and monitoring canaries. Returns false
+    /// if the client isn't registered.
+    ///
+    /// The `pinned` field of the record is the only mark of a pinned box,
+    /// and moves along with it when it registers from another public IP.
+    ///
+    pub fn pin(&self, client: String) -> RedisResult<bool> {
+        let record = match try!(self.find_by_client(client.clone())) {
+            Some(record) => record,
+            None => return Ok(false)
+        };
+        let key = format!("{}:{}", record.public_ip, client);
+        let _: () = try!(
+            pipe().atomic()
+                  .cmd("HSET").arg(key.clone()).arg("pinned").arg(1).ignore()
+                  .cmd("PERSIST").arg(key).ignore()
+                  .cmd("PERSIST").arg(format!("box:{}", client)).ignore()
+                  .query(&self.connection)
+        );
+        Ok(true)
+    }
+
+    ///
+    /// Let the record of a client expire again, returning whether it was
+    /// pinned. A box which stopped registering isn't discovered anymore,
+    /// and the next eviction drops it.
+    ///
+    pub fn unpin(&self, client: String) -> RedisResult<bool> {
+        let box_key = format!("box:{}", client);
+        let public_ip: Option<String> = try!(
+            cmd("GET").arg(box_key.clone()).query(&self.connection)
+        );
+        let public_ip = match public_ip {
+            Some(public_ip) => public_ip,
+            None => return Ok(false)
+        };
+        let key = format!("{}:{}", public_ip, client);
+        let ttl = self.retention.records;
+        let (unpinned,): (bool,) = try!(
+            pipe().atomic()
+                  .cmd("HDEL").arg(key.clone()).arg("pinned")
+                  .cmd("EXPIRE").arg(key).arg(ttl).ignore()
+                  .cmd("EXPIRE").arg(box_key).arg(ttl).ignore()
+                  .query(&self.connection)
+        );
+        Ok(unpinned)
+    }
+
+    ///
+    /// The pinned clients, sorted. This reads every record, like the stats.
+    ///
+    pub fn pinned(&self) -> RedisResult<Vec<String>> {
+        let public_ips: Vec<String> = try!(
+            cmd("SMEMBERS").arg("public_ips").query(&self.connection)
+        );
+        let mut clients = vec![];
+        for public_ip in public_ips {
+            let members: Vec<String> = try!(
+                cmd("SMEMBERS").arg(public_ip.clone()).query(&self.connection)
+            );
+            if members.is_empty() {
+                continue;
+            }
+            let mut pipeline = pipe();
+            for member in &members {
+                pipeline.cmd("HEXISTS").arg(format!("{}:{}", public_ip, member)).arg("pinned");
+            }
+            let pinned: Vec<bool> = try!(pipeline.query(&self.connection));
+            clients.extend(members.into_iter().zip(pinned).filter(|&(_, pinned)| pinned)
+                                  .map(|(client, _)| client));
+        }
+        clients.sort();
+        Ok(clients)
+    }
+
+    ///
+    /// Count the public IPs and the clients registered from them, including
+    /// the clients which expired but haven't been evicted yet.
+    ///
+    pub fn stats(&self) -> RedisResult<Stats> {
+        let public_ips: Vec<String> = try!(
+            cmd("SMEMBERS").arg("public_ips").query(&self.connection)
+        );
+
+        let mut stats = Stats {
+            public_ips: public_ips.len(),
+            .. Stats::default()
+        };
+        for public_ip in public_ips {
+            let clients: usize = try!(
+                cmd("SCARD").arg(public_ip).query(&self.connection)
+            );
+            stats.clients += clients;
+            stats.largest_network = cmp::max(stats.largest_network, clients);
+        }
+
+        let mut intervals: Vec<u64> = try!(
+            cmd("LRANGE").arg("intervals").arg(0).arg(-1).query(&self.connection)
+        );
+        intervals.sort();
+        stats.interval_p50 = percentile(&intervals, 0.5);
+        stats.interval_p95 = percentile(&intervals, 0.95);
+
+        let hour = self.now() / 3600;
+        let hours: Vec<u64> = (hour.saturating_sub(HOURLY_WINDOW - 1)..hour + 1).collect();
+        let counts = |name: &str| -> RedisResult<Vec<u64>> {
+            let keys: Vec<String> = hours.iter().map(|hour| {
+                format!("{}:{}", name, hour)
+            }).collect();
+            let counts: Vec<Option<u64>> = try!(cmd("MGET").arg(keys).query(&self.connection));
+            Ok(counts.into_iter().map(|count| count.unwrap_or(0)).collect())
+        };
+        let registrations = try!(counts("registrations"));
+        let evictions = try!(counts("evictions"));
+        stats.hourly = hours.iter().zip(registrations.into_iter().zip(evictions.iter()))
+                            .map(|(hour, (registrations, evictions))| {
+            Hourly {
+                hour: hour * 3600,
+                registrations: registrations,
+                evictions: *evictions,
+            }
+        }).collect();
+
+        let churn_hours = cmp::min(CHURN_WINDOW as usize, evictions.len());
+        let evicted = evictions[evictions.len() - churn_hours..].iter().sum::<u64>() as usize;
+        if evicted > 0 {
+            stats.churn_rate = evicted as f64 / (stats.clients + evicted) as f64;
+        }
+
+        Ok(stats)
+    }
+
+    /// Compute the usage aggregates of `day`, counted in days since the
+    /// epoch, from the activity tracked that day, and store them in the
+    /// "usage:<day>" hash for the days of the retention policy.
+    pub fn aggregate_usage(&self, day: u64) -> RedisResult<Usage> {
+        let (active_boxes, new_boxes, evictions, local_ip_boxes):
+            (u64, Option<u64>, Option<u64>, u64) = try!(
+            pipe().cmd("PFCOUNT").arg(format!("usage:active:{}", day))
+                  .cmd("GET").arg(format!("usage:new:{}", day))
+                  .cmd("GET").arg(format!("usage:evictions:{}", day))
+                  .cmd("PFCOUNT").arg(format!("usage:local_ip:{}", day))
+                  .query(&self.connection)
+        );
+        let usage = Usage {
+            day: day * 86400,
+            active_boxes: active_boxes,
+            new_boxes: new_boxes.unwrap_or(0),
+            evictions: evictions.unwrap_or(0),
+            local_ip_boxes: local_ip_boxes,
+        };
+
+        let key = format!("usage:{}", day);
+        let _: () = try!(
+            pipe().cmd("HMSET").arg(key.clone())
+                               .arg("active_boxes").arg(usage.active_boxes)
+                               .arg("new_boxes").arg(usage.new_boxes)
+                               .arg("evictions").arg(usage.evictions)
+                               .arg("local_ip_boxes").arg(usage.local_ip_boxes)
+                               .ignore()
+                  .cmd("EXPIRE").arg(key).arg(self.retention.usage * 86400).ignore()
+                  .query(&self.connection)
+        );
+        Ok(usage)
+    }
+
+    /// The stored usage aggregates, oldest first.
+    pub fn usage(&self) -> RedisResult<Vec<Usage>> {
+        let today = self.now() / 86400;
+        let mut aggregates = vec![];
+        for day in today.saturating_sub(self.retention.usage - 1)..today + 1 {
+            let fields: HashMap<String, u64> = try!(
+                cmd("HGETALL").arg(format!("usage:{}", day)).query(&self.connection)
+            );
+            if fields.is_empty() {
+                continue;
+            }
+            let field = |name: &str| fields.get(name).cloned().unwrap_or(0);
+            aggregates.push(Usage {
+                day: day * 86400,
+                active_boxes: field("active_boxes"),
+                new_boxes: field("new_boxes"),
+                evictions: field("evictions"),
+                local_ip_boxes: field("local_ip_boxes"),
+            });
+        }
+        Ok(aggregates)
+    }
+
+    ///
+    /// Revoke the credential with this hash.
+    ///
+    pub fn revoke(&self, hash: String) -> RedisResult<()> {
+        cmd("HSET").arg("revoked").arg(hash).arg(self.now()).query(&self.connection)
+    }
+
+    ///
+    /// Accept the credential with this hash again, returning whether it was
+    /// revoked.
+    ///
+    pub fn unrevoke(&self, hash: String) -> RedisResult<bool> {
+        cmd("HDEL").arg("revoked").arg(hash).query(&self.connection)
+    }
+
+    ///
+    /// The revoked credentials, the latest first.
+    ///
+    pub fn revoked(&self) -> RedisResult<Vec<Revocation>> {
+        let revoked: HashMap<String, u64> = try!(
+            cmd("HGETALL").arg("revoked").query(&self.connection)
+        );
+        let mut revoked: Vec<Revocation> = revoked.into_iter().map(|(hash, revoked_at)| {
+            Revocation { hash: hash, revoked_at: revoked_at }
+        }).collect();
+        revoked.sort_by(|a, b| (b.revoked_at, &a.hash).cmp(&(a.revoked_at, &b.hash)));
+        Ok(revoked)
+    }
+
+    ///
+    /// Store a credential, returning false if its name is taken.
+    ///
+    pub fn add_credential(&self, credential: &Credential) -> RedisResult<bool> {
+        let key = format!("credential:{}", credential.name);
+        let created: bool = try!(
+            cmd("SADD").arg("credentials").arg(credential.name.clone()).query(&self.connection)
+        );
+        if !created {
+            return Ok(false);
+        }
+        let _: () = try!(
+            pipe().atomic()
+                  .cmd("HMSET").arg(key).arg("kind").arg(credential.kind.clone())
+                                        .arg("hash").arg(credential.hash.clone())
+                                        .arg("created_at").arg(credential.created_at).ignore()
+                  .cmd("HSET").arg("credential_hashes").arg(credential.hash.clone())
+                              .arg(credential.name.clone()).ignore()
+                  .query(&self.connection)
+        );
+        Ok(true)
+    }
+
+    fn credential(&self, name: String) -> RedisResult<Option<Credential>> {
+        let (kind, hash, created_at): (Option<String>, Option<String>, Option<u64>) = try!(
+            cmd("HMGET").arg(format!("credential:{}", name)).arg("kind").arg("hash")
+                        .arg("created_at").query(&self.connection)
+        );
+        Ok(match (kind, hash, created_at) {
+            (Some(kind), Some(hash), Some(created_at)) => Some(Credential {
+                name: name,
+                kind: kind,
+                hash: hash,
+                created_at: created_at,
+            }),
+            _ => None
+        })
+    }
+
+    ///
+    /// The credentials, by name.
+    ///
+    pub fn credentials(&self) -> RedisResult<Vec<Credential>> {
+        let mut names: Vec<String> = try!(
+            cmd("SMEMBERS").arg("credentials").query(&self.connection)
+        );
+        names.sort();
+        let mut credentials = Vec::with_capacity(names.len());
+        for name in names {
+            if let Some(credential) = try!(self.credential(name)) {
+                credentials.push(credential);
+            }
+        }
+        Ok(credentials)
+    }
+
+    ///
+    /// The credential with this hash, if any.
+    ///
+    pub fn find_credential(&self, hash: &str) -> RedisResult<Option<Credential>> {
+        let name: Option<String> = try!(
+            cmd("HGET").arg("credential_hashes").arg(hash).query(&self.connection)
+        );
+        match name {
+            Some(name) => self.credential(name),
+            None => Ok(None)
+        }
+    }
+
+    ///
+    /// Delete a credential, returning it if it existed.
+    ///
+    pub fn remove_credential(&self, name: &str) -> RedisResult<Option<Credential>> {
+        let credential = match try!(self.credential(name.to_owned())) {
+            Some(credential) => credential,
+            None => return Ok(None)
+        };
+        let _: () = try!(
+            pipe().atomic()
+                  .cmd("SREM").arg("credentials").arg(name).ignore()
+                  .cmd("DEL").arg(format!("credential:{}", name)).ignore()
+                  .cmd("HDEL").arg("credential_hashes").arg(credential.hash.clone()).ignore()
+                  .query(&self.connection)
+        );
+        Ok(Some(credential))
+    }
+
+    ///
+    /// The public IPs with at least `min_clients` unexpired clients, those
+    /// with the most clients first.
+    ///
+    pub fn public_ips(&self, min_clients: usize) -> RedisResult<Vec<PublicIp>> {
+        let public_ips: Vec<String> = try!(
+            cmd("SMEMBERS").arg("public_ips").query(&self.connection)
+        );
+
+        let mut result = Vec::new();
+        for public_ip in public_ips {
+            let records = try!(self.get(public_ip.clone()));
+            if records.is_empty() || records.len() < min_clients {
+                continue;
+            }
+            result.push(PublicIp {
+                public_ip: public_ip,
+                clients: records.len(),
+                last_seen: records.iter().map(|record| record.last_seen).max().unwrap_or(0),
+            });
+        }
+        result.sort_by(|a, b| (b.clients, &a.public_ip).cmp(&(a.clients, &b.public_ip)));
+        Ok(result)
+    }
+
+    ///
+    /// Get all the registration entries matching a filter.
+    ///
+    pub fn find(&self, filter: &Filter) -> RedisResult<Vec<Record>> {
+        let candidates = match (&filter.public_ip, &filter.client) {
+            (&Some(ref public_ip), _) => try!(self.get(public_ip.clone())),
+            (&None, &Some(ref client)) => {
+                try!(self.find_by_client(client.clone())).into_iter().collect()
+            },
+            (&None, &None) => try!(self.all())
+        };
+
+        Ok(candidates.into_iter().filter(|record| filter.matches(record)).collect())
+    }
+
+    /// Walk the "box:clientID" keys to get the latest entry of every client.
+    fn all(&self) -> RedisResult<Vec<Record>> {
+        let keys: Vec<String> = try!(
+            cmd("SCAN").cursor_arg(0)
+                       .arg("MATCH").arg("box:*")
+                       .iter(&self.connection)
+        ).collect();
+
+        let mut result = Vec::new();
+        for key in keys {
+            if let Some(record) = try!(self.find_by_client(key[4..].to_owned())) {
+                result.push(record);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Read the "publicIP:clientID" entry, if it hasn't expired yet.
+    fn read(&self, public_ip: &str, client: &str) -> RedisResult<Option<Record>> {
+        let key = format!("{}:{}", public_ip, client);
+        info!("Key {}", key.clone());
+        let fields: HashMap<String, String> = try!(
+            cmd("HGETALL").arg(key.clone())
+                          .query(&self.connection)
+        );
+
+        Ok(self.unexpired(Record::from_fields(public_ip, client, &fields), &fields,
+                          self.now()))
+    }
+
+    /// The record read from `fields`, unless it expired at time `now`.
+    /// Pinned records never expire.
+    fn unexpired(&self, record: Option<Record>, fields: &HashMap<String, String>, now: u64)
+        -> Option<Record> {
+        record.and_then(|record| {
+            if !fields.contains_key("pinned") && record.is_expired(now, self.retention.records) {
+                None
+            } else {
+                Some(record)
+            }
+        })
+    }
+
+    ///
+    /// Compact the append-only file of the Redis server, which otherwise
+    /// keeps growing with every registration even though the records
+    /// expire. Returns whether a rewrite was started: nothing happens if
+    /// the server doesn't use an AOF or is already rewriting it.
+    ///
+    pub fn maintenance(&self) -> RedisResult<bool> {
+        let info: String = try!(
+            cmd("INFO").arg("persistence").query(&self.connection)
+        );
+        let field = |name: &str| {
+            info.lines()
+                .find(|line| line.starts_with(name))
+                .and_then(|line| line.split(':').nth(1))
+                .map(|value| value.trim() == "1")
+                .unwrap_or(false)
+        };
+
+        if !field("aof_enabled") || field("aof_rewrite_in_progress") ||
+           field("aof_rewrite_scheduled") {
+            return Ok(false);
+        }
+
+        let _: () = try!(
+            cmd("BGREWRITEAOF").query(&self.connection)
+        );
+
+        Ok(true)
+    }
+
+    ///
+    /// Convert the records the first versions stored as strings holding
+    /// their message into the hashes described in `set`, since reading or
+    /// updating them would otherwise fail with WRONGTYPE errors. The whole
+    /// database is only scanned once: afterwards its schema version tells
+    /// that there is nothing left to convert. Returns the number of records
+    /// converted.
+    ///
+    pub fn migrate_records(&self) -> RedisResult<usize> {
+        let version: Option<u64> = try!(
+            cmd("GET").arg(SCHEMA_VERSION_KEY).query(&self.connection)
+        );
+        if version.unwrap_or(0) >= SCHEMA_VERSION {
+            return Ok(0);
+        }
+
+        // The public IPs weren't hashed yet, nor listed in "public_ips".
+        let public_ips: Vec<String> = try!(
+            cmd("SCAN").cursor_arg(0).iter(&self.connection)
+        ).filter(|key: &String| key.parse::<IpAddr>().is_ok()).collect();
+        let script = Script::new(MIGRATE_SCRIPT);
+        let mut count = 0;
+        for public_ip in public_ips {
+            let converted: usize = try!(
+                script.key(public_ip).arg(self.now()).invoke(&self.connection)
+            );
+            count += converted;
+        }
+
+        let _: () = try!(
+            cmd("SET").arg(SCHEMA_VERSION_KEY).arg(SCHEMA_VERSION).query(&self.connection)
+        );
+        Ok(count)
+    }
+
+    ///
+    /// Look for inconsistencies in the data layout described in `set`, and
+    /// for persistence errors reported by the Redis server. Returns a
+    /// description of each problem found, up to `max_problems`.
+    ///
+    pub fn check_integrity(&self, max_problems: usize) -> RedisResult<Vec<String>> {
+        let mut problems = Vec::new();
+
+        let info: String = try!(
+            cmd("INFO").arg("persistence").query(&self.connection)
+        );
+        for line in info.lines() {
+            let mut parts = line.trim().splitn(2, ':');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                if name.ends_with("_status") && value != "ok" {
+                    problems.push(format!("Redis reports {}:{}", name, value));
+                }
+            }
+        }
+
+        let box_keys: Vec<String> = try!(
+            cmd("SCAN").cursor_arg(0)
+                       .arg("MATCH").arg("box:*")
+                       .iter(&self.connection)
+        ).collect();
+
+        for box_key in box_keys {
+            if problems.len() >= max_problems {
+                return Ok(problems);
+            }
+
+            let client = &box_key[4..];
+            let public_ip: Option<String> = match cmd("GET").arg(box_key.clone())
+                                                          .query(&self.connection) {
+                Ok(public_ip) => public_ip,
+                Err(_) => {
+                    problems.push(format!("{} isn't a string", box_key));
+                    continue;
+                }
+            };
+            // The key expired since we listed it.
+            let public_ip = match public_ip {
+                Some(public_ip) => public_ip,
+                None => continue
+            };
+
+            let key = format!("{}:{}", public_ip, client);
+            let ttl: i64 = try!(cmd("TTL").arg(key.clone()).query(&self.connection));
+            // Pinned records are persisted on purpose.
+            let pinned: bool = try!(
+                cmd("HEXISTS").arg(key.clone()).arg("pinned").query(&self.connection)
+            );
+            if ttl == -1 && !pinned {
+                problems.push(format!("{} never expires", key));
+            }
+
+            match self.read(&public_ip, client) {
+                Ok(Some(_)) => {},
+                Ok(None) => {
+                    problems.push(format!("{} points to the missing entry {}",
+                                          box_key, key));
+                },
+                Err(_) => problems.push(format!("{} isn't a hash", key))
+            }
+
+            match cmd("SISMEMBER").arg(public_ip.clone())
+                                  .arg(client)
+                                  .query::<isize>(&self.connection) {
+                Ok(1) => {},
+                Ok(_) => {
+                    problems.push(format!("{} isn't a member of {}",
+                                          client, public_ip));
+                },
+                Err(_) => problems.push(format!("{} isn't a set", public_ip))
+            }
+        }
 
-        Ok(())
+        problems.truncate(max_problems);
+        Ok(problems)
     }
 
     ///
-    /// Get the registration entries for a given public IP.
+    /// Whether the database holds no key at all, but its schema version.
     ///
-    pub fn get(&self, public_ip: String) -> RedisResult<Vec<Record>> {
-        let _: () = try!(
-            cmd("WATCH").arg(public_ip.clone())
-                        .query(&self.connection)
-        );
-
-        // Get the clients for the given public IP.
-        let members: Vec<String> = try!(
-            cmd("SMEMBERS").arg(public_ip.clone())
-                           .query(&self.connection)
+    pub fn is_empty(&self) -> RedisResult<bool> {
+        let (size, versioned): (usize, usize) = try!(
+            pipe().cmd("DBSIZE")
+                  .cmd("EXISTS").arg(SCHEMA_VERSION_KEY)
+                  .query(&self.connection)
         );
 
-        info!("Members of {}: {:?}", public_ip.clone(), members.clone());
-
-        let mut result = Vec::new();
-
-        // For each client we get the associated message.
-        for member in members {
-            let key = format!("{}:{}", public_ip.clone(), member);
-            info!("Key {}", key.clone());
-            match cmd("GET").arg(key.clone())
-                                    .query(&self.connection) {
-                Ok(message) => {
-                    info!("Message for {}: {}", key.clone(), message);
-
-                    result.push(Record {
-                        public_ip: public_ip.clone(),
-                        client: member.clone(),
-                        message: message
-                    });
-                },
-                Err(_) => {
-                    // Remove the client id from the list of clients of this public
-                    // IP that has no associated message.
-                    info!("Removing {} from {}", member.clone(), public_ip.clone());
-                    let _: () = try!(
-                        cmd("SREM").arg(public_ip.clone())
-                                   .arg(member.clone())
-                                   .query(&self.connection)
-                    );
-                }
-            };
-        }
-
-        Ok(result)
+        Ok(size == versioned)
     }
 
     #[cfg(test)]
@@ -185,10 +2360,12 @@ impl Db {
 
 #[test]
 fn test_db() {
+    use super::clock::ManualClock;
     use super::db_test_context::TestContext;
 
     let ctx = TestContext::new();
-    let db = ctx.db;
+    let clock = Arc::new(ManualClock::new(1481900000));
+    let db = ctx.db.with_clock(clock.clone());
 
     // Look for a record, but the db is empty.
     match db.get("127.0.0.1".to_owned()) {
@@ -196,11 +2373,10 @@ fn test_db() {
         Err(err) => { println!("Unexpected error: {}", err); assert!(false); }
     };
 
-    let mut r = Record {
-        public_ip: "127.0.0.1".to_owned(),
-        message: "<message>".to_owned(),
-        client: "<fingerprint>".to_owned()
-    };
+    let mut r = Record::new("127.0.0.1".to_owned(),
+                            "<fingerprint>".to_owned(),
+                            "<message>".to_owned(),
+                            clock.now());
 
     // Add this new record.
     match db.set(r) {
@@ -213,16 +2389,16 @@ fn test_db() {
         Ok(records) => {
             assert_eq!(records.len(), 1);
             assert_eq!(records[0].message, "<message>");
+            assert_eq!(records[0].revision, 1);
         },
         Err(err) => { println!("Unexpected error: {}", err); assert!(false); }
     }
 
     // Add another record with the same public IP, but a different local one.
-    r = Record {
-        public_ip: "127.0.0.1".to_owned(),
-        message: "<another_message>".to_owned(),
-        client:  "<another_fingerprint>".to_owned()
-    };
+    r = Record::new("127.0.0.1".to_owned(),
+                    "<another_fingerprint>".to_owned(),
+                    "<another_message>".to_owned(),
+                    clock.now());
 
     match db.set(r) {
         Ok(_) => { assert!(true); },
@@ -245,6 +2421,739 @@ fn test_db() {
         Err(err) => { println!("Unexpected error: {}", err); assert!(false); }
     }
 
-    // Fake travelling in the future, and evict both records.
+    // Look the first record up by its fingerprint only.
+    match db.find_by_client("<fingerprint>".to_owned()) {
+        Ok(Some(record)) => {
+            assert_eq!(record.public_ip, "127.0.0.1");
+            assert_eq!(record.message, "<message>");
+        },
+        Ok(None) => { assert!(false); },
+        Err(err) => { println!("Unexpected error: {}", err); assert!(false); }
+    }
+
+    match db.find_by_client("<unknown>".to_owned()) {
+        Ok(record) => { assert!(record.is_none()); },
+        Err(err) => { println!("Unexpected error: {}", err); assert!(false); }
+    }
+
+    // Time range queries.
+    let filter = Filter { since: Some(clock.now() + 3600), ..Filter::default() };
+    assert!(db.find(&filter).unwrap().is_empty());
+    let filter = Filter { until: Some(clock.now() + 3600), ..Filter::default() };
+    assert_eq!(db.find(&filter).unwrap().len(), 2);
+
+    // Several records at once.
+    let earlier = clock.now() - 100;
+    let records: Vec<Record> = (0..3).map(|i| {
+        Record::new("127.0.0.2".to_owned(),
+                    format!("<fingerprint{}>", i),
+                    format!("<message{}>", i),
+                    earlier)
+    }).collect();
+    db.add_many(&records).unwrap();
+    assert_eq!(db.get("127.0.0.2".to_owned()).unwrap().len(), 3);
+
+    // Moving to another public IP removes the previous entry.
+    // It keeps its first_seen time.
+    db.set(Record::new("127.0.0.3".to_owned(),
+                       "<fingerprint0>".to_owned(),
+                       "<moved_message>".to_owned(),
+                       clock.now())).unwrap();
+    assert_eq!(db.get("127.0.0.2".to_owned()).unwrap().len(), 2);
+    let moved = db.find_by_client("<fingerprint0>".to_owned()).unwrap().unwrap();
+    assert_eq!(moved.public_ip, "127.0.0.3");
+    assert_eq!(moved.first_seen, earlier);
+    assert_eq!(moved.revision, 2);
+    assert!(moved.last_seen > earlier);
+
+    // The data is consistent.
+    assert!(db.check_integrity(10).unwrap().is_empty());
+
+    // Nothing to evict yet.
+    assert_eq!(db.evict().unwrap(), 0);
+
+    // Only one instance can hold a lease.
+    assert!(db.acquire_lease("test", "instance1", 10).unwrap());
+    assert!(!db.acquire_lease("test", "instance2", 10).unwrap());
+
+    // The records registered earlier expire first.
+    clock.advance(30);
+    assert_eq!(db.get("127.0.0.2".to_owned()).unwrap().len(), 0);
+    assert!(db.find_by_client("<fingerprint1>".to_owned()).unwrap().is_none());
+    assert_eq!(db.get("127.0.0.1".to_owned()).unwrap().len(), 2);
+    assert_eq!(db.evict().unwrap(), 2);
+
+    // Travel in the future, and evict all the other records.
+    clock.advance(RECORD_TTL as u64);
+    assert_eq!(db.get("127.0.0.1".to_owned()).unwrap().len(), 0);
+    assert_eq!(db.evict().unwrap(), 3);
+    assert!(db.check_integrity(10).unwrap().is_empty());
+
+    db.flush().unwrap();
+}
+
+#[test]
+fn test_delete() {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let now = ctx.db.now();
+    for &(public_ip, client) in &[("1.2.3.4", "a"), ("1.2.3.4", "b"), ("5.6.7.8", "c")] {
+        ctx.db.set(Record::new(public_ip.to_owned(), client.to_owned(),
+                               "<message>".to_owned(), now)).unwrap();
+    }
+    let stats = ctx.db.stats().unwrap();
+    assert_eq!((stats.public_ips, stats.clients, stats.largest_network), (2, 3, 2));
+
+    assert_eq!(ctx.db.delete("c".to_owned()).unwrap(), true);
+    assert_eq!(ctx.db.delete("c".to_owned()).unwrap(), false);
+    assert!(ctx.db.find_by_client("c".to_owned()).unwrap().is_none());
+    let stats = ctx.db.stats().unwrap();
+    assert_eq!((stats.public_ips, stats.clients, stats.largest_network), (1, 2, 2));
+    assert!(ctx.db.check_integrity(10).unwrap().is_empty());
+}
+
+#[test]
+fn test_migrate_records() {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let db = &ctx.db;
+    db.set(Record::new("1.2.3.4".to_owned(), "b".to_owned(),
+                       "<message>".to_owned(), db.now())).unwrap();
+    // A record as the first versions stored it.
+    let _: () = pipe().cmd("SADD").arg("1.2.3.4").arg("a").ignore()
+                      .cmd("SETEX").arg("1.2.3.4:a").arg(RECORD_TTL).arg("<old>").ignore()
+                      .cmd("SADD").arg("5.6.7.8").arg("b").ignore()
+                      .cmd("SETEX").arg("5.6.7.8:b").arg(RECORD_TTL).arg("<moved>").ignore()
+                      .query(&db.connection).unwrap();
+    assert!(db.get("1.2.3.4".to_owned()).is_err());
+
+    assert_eq!(db.migrate_records().unwrap(), 1);
+    assert!(db.find_by_client("a".to_owned()).unwrap().is_some());
+    let public_ips = db.public_ips(0).unwrap();
+    assert_eq!(public_ips.len(), 1);
+    assert_eq!(public_ips[0].public_ip, "1.2.3.4");
+    // "b" registered from "1.2.3.4" since.
+    let exists: bool = cmd("EXISTS").arg("5.6.7.8:b").query(&db.connection).unwrap();
+    assert!(!exists);
+
+    // The schema version now spares the scan.
+    let _: () = pipe().cmd("SADD").arg("1.2.3.4").arg("c").ignore()
+                      .cmd("SET").arg("1.2.3.4:c").arg("<old>").ignore()
+                      .query(&db.connection).unwrap();
+    assert_eq!(db.migrate_records().unwrap(), 0);
+    let _: () = pipe().cmd("SREM").arg("1.2.3.4").arg("c").ignore()
+                      .cmd("DEL").arg("1.2.3.4:c").ignore()
+                      .query(&db.connection).unwrap();
+    let mut records = db.get("1.2.3.4".to_owned()).unwrap();
+    records.sort_by(|a, b| a.client.cmp(&b.client));
+    assert_eq!(records.len(), 2);
+    assert_eq!((&records[0].client[..], &records[0].message[..]), ("a", "<old>"));
+    let ttl: i32 = cmd("TTL").arg("1.2.3.4:a").query(&db.connection).unwrap();
+    assert!(ttl > 0 && ttl <= RECORD_TTL);
+
+    // And can be updated again.
+    assert_eq!(db.set(Record::new("1.2.3.4".to_owned(), "a".to_owned(),
+                                  "<new>".to_owned(), db.now())).unwrap(), 1);
+}
+
+#[test]
+fn test_pairing() {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    ctx.db.set(Record::new("1.2.3.4".to_owned(), "a".to_owned(),
+                           "<message>".to_owned(), ctx.db.now())).unwrap();
+
+    let code = ctx.db.create_pairing_code("a".to_owned()).unwrap();
+    match ctx.db.redeem_pairing_code(&code, "5.6.7.8".to_owned()).unwrap() {
+        Pairing::Paired(record) => assert_eq!(record.client, "a"),
+        pairing => panic!("Unexpected {:?}", pairing)
+    }
+
+    let code = ctx.db.create_pairing_code("a".to_owned()).unwrap();
+    for _ in 0..MAX_PAIRING_FAILURES {
+        match ctx.db.redeem_pairing_code("wrong", "5.6.7.8".to_owned()).unwrap() {
+            Pairing::Unknown => {},
+            pairing => panic!("Unexpected {:?}", pairing)
+        }
+    }
+    // Even the right code is refused once throttled.
+    match ctx.db.redeem_pairing_code(&code, "5.6.7.8".to_owned()).unwrap() {
+        Pairing::Throttled(retry_after) => assert!(retry_after <= PAIRING_TTL as u64),
+        pairing => panic!("Unexpected {:?}", pairing)
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    use super::db_test_context::TestContext;
+    use quickcheck::{ Arbitrary, StdGen };
+    use rand;
+    use std::iter;
+
+    let ctx = TestContext::new();
+    let db = ctx.db;
+
+    // Arbitrary strings cover the whole unicode range, quotes and control
+    // characters, which all have to be stored as they are.
+    let mut gen = StdGen::new(rand::thread_rng(), 256);
+    let mut samples: Vec<(String, String)> = (0..200).map(|_| {
+        (String::arbitrary(&mut gen), String::arbitrary(&mut gen))
+    }).collect();
+    samples.push(("\"quoted\" 'client'".to_owned(), "{\"json\": [\"message\"]}".to_owned()));
+    samples.push(("box:*".to_owned(), "\\\r\n\0%s".to_owned()));
+    samples.push(("ünicode ☃".to_owned(), iter::repeat('x').take(100000).collect()));
+
+    for (index, (client, message)) in samples.into_iter().enumerate() {
+        if client.is_empty() {
+            continue;
+        }
+        let public_ip = format!("10.0.{}.{}", index / 256, index % 256);
+
+        db.set(Record::new(public_ip.clone(), client.clone(), message.clone(), db.now()))
+          .unwrap();
+
+        let found = db.find_by_client(client.clone()).unwrap()
+                      .expect(&format!("{:?} wasn't found", client));
+        assert_eq!(found.public_ip, public_ip);
+        assert_eq!(found.message, message);
+
+        let records = db.get(public_ip).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].client, client);
+        assert_eq!(records[0].message, message);
+    }
+}
+
+/// The time to read the records of a public IP with 10 boxes, through
+/// `GET_SCRIPT`. Compare it to `bench_get_pipelined`.
+#[cfg(test)]
+#[bench]
+fn bench_get(b: &mut ::test::Bencher) {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let db = ctx.db;
+    populate_public_ip(&db, "127.0.0.1", 10);
+
+    b.iter(|| db.get("127.0.0.1".to_owned()).unwrap());
+
+    db.flush().unwrap();
+}
+
+/// The baseline of `bench_get`: the same records, read without the script
+/// in two round trips, the members of the public IP then a pipeline of the
+/// hashes of its clients.
+#[cfg(test)]
+#[bench]
+fn bench_get_pipelined(b: &mut ::test::Bencher) {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let db = ctx.db;
+    populate_public_ip(&db, "127.0.0.1", 10);
+
+    b.iter(|| {
+        let public_ip = "127.0.0.1".to_owned();
+        let clients: Vec<String> = cmd("SMEMBERS").arg(public_ip.clone())
+                                                  .query(&db.connection).unwrap();
+        let mut pipeline = pipe();
+        for client in &clients {
+            pipeline.cmd("HGETALL").arg(format!("{}:{}", public_ip, client));
+        }
+        let fields: Vec<HashMap<String, String>> = pipeline.query(&db.connection).unwrap();
+        let now = db.now();
+        clients.iter().zip(fields.iter()).filter_map(|(client, fields)| {
+            db.unexpired(Record::from_fields(&public_ip, client, fields), fields, now)
+        }).collect::<Vec<Record>>()
+    });
+
     db.flush().unwrap();
 }
+
+/// Register `count` boxes from `public_ip`.
+#[cfg(test)]
+fn populate_public_ip(db: &Db, public_ip: &str, count: usize) {
+    for i in 0..count {
+        db.set(Record::new(public_ip.to_owned(),
+                           format!("<fingerprint{}>", i),
+                           "<message>".to_owned(),
+                           db.now())).unwrap();
+    }
+}
+
+/// Fill the database with `count` records, registered by boxes 4 by 4 from
+/// the same public IPs.
+#[cfg(test)]
+fn populate(db: &Db, count: usize) {
+    let now = db.now();
+    let records: Vec<Record> = (0..count).map(|i| {
+        Record::new(format!("10.{}.{}.{}", i / 4 / 65536, i / 4 / 256 % 256, i / 4 % 256),
+                    format!("<fingerprint{}>", i),
+                    "<message>".to_owned(),
+                    now)
+    }).collect();
+
+    for batch in records.chunks(1000) {
+        db.add_many(batch).unwrap();
+    }
+}
+
+/// Register a box like the handler does, connecting to the database first.
+#[cfg(test)]
+fn bench_register(b: &mut ::test::Bencher, count: usize) {
+    use super::db_test_context::{ TestContext, SERVER_HOST };
+
+    let ctx = TestContext::new();
+    populate(&ctx.db, count);
+
+    b.iter(|| {
+        let db = Db::new(SERVER_HOST.to_owned(),
+                         ctx.server.port,
+                         None,
+                         0).unwrap();
+        db.set(Record::new("127.0.0.1".to_owned(),
+                           "<fingerprint>".to_owned(),
+                           "<message>".to_owned(),
+                           db.now())).unwrap()
+    });
+
+    ctx.db.flush().unwrap();
+}
+
+#[cfg(test)]
+fn bench_discovery(b: &mut ::test::Bencher, count: usize) {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    populate(&ctx.db, count);
+
+    b.iter(|| ctx.db.get("10.0.0.1".to_owned()).unwrap());
+
+    ctx.db.flush().unwrap();
+}
+
+/// Sweep the whole database, finding nothing to evict.
+#[cfg(test)]
+fn bench_evict(b: &mut ::test::Bencher, count: usize) {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    populate(&ctx.db, count);
+
+    b.iter(|| ctx.db.evict().unwrap());
+
+    ctx.db.flush().unwrap();
+}
+
+#[cfg(test)]
+#[bench]
+fn bench_register_10k(b: &mut ::test::Bencher) { bench_register(b, 10_000) }
+
+#[cfg(test)]
+#[bench]
+fn bench_register_100k(b: &mut ::test::Bencher) { bench_register(b, 100_000) }
+
+#[cfg(test)]
+#[bench]
+fn bench_register_1m(b: &mut ::test::Bencher) { bench_register(b, 1_000_000) }
+
+#[cfg(test)]
+#[bench]
+fn bench_discovery_10k(b: &mut ::test::Bencher) { bench_discovery(b, 10_000) }
+
+#[cfg(test)]
+#[bench]
+fn bench_discovery_100k(b: &mut ::test::Bencher) { bench_discovery(b, 100_000) }
+
+#[cfg(test)]
+#[bench]
+fn bench_discovery_1m(b: &mut ::test::Bencher) { bench_discovery(b, 1_000_000) }
+
+#[cfg(test)]
+#[bench]
+fn bench_evict_10k(b: &mut ::test::Bencher) { bench_evict(b, 10_000) }
+
+#[cfg(test)]
+#[bench]
+fn bench_evict_100k(b: &mut ::test::Bencher) { bench_evict(b, 100_000) }
+
+#[cfg(test)]
+#[bench]
+fn bench_evict_1m(b: &mut ::test::Bencher) { bench_evict(b, 1_000_000) }
+
+#[test]
+fn test_percentile() {
+    assert_eq!(percentile(&[], 0.5), None);
+    assert_eq!(percentile(&[30], 0.95), Some(30));
+    let intervals: Vec<u64> = (1..101).collect();
+    assert_eq!(percentile(&intervals, 0.5), Some(50));
+    assert_eq!(percentile(&intervals, 0.95), Some(95));
+    assert_eq!(percentile(&intervals, 0.0), Some(1));
+}
+
+#[test]
+fn test_usage() {
+    use super::clock::ManualClock;
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let clock = Arc::new(ManualClock::new(1481900000));
+    let db = ctx.db.with_clock(clock.clone()).with_usage(true);
+    let day = clock.now() / 86400;
+
+    let mut record = Record::new("1.2.3.4".to_owned(), "a".to_owned(),
+                                 "<message>".to_owned(), clock.now());
+    record.local_ip = Some("192.168.0.2".to_owned());
+    db.set(record.clone()).unwrap();
+    db.set(record).unwrap();
+    db.set(Record::new("1.2.3.4".to_owned(), "b".to_owned(),
+                       "<message>".to_owned(), clock.now())).unwrap();
+
+    let usage = db.aggregate_usage(day).unwrap();
+    assert_eq!(usage, Usage { day: day * 86400, active_boxes: 2, new_boxes: 2, evictions: 0,
+                              local_ip_boxes: 1 });
+    assert_eq!(db.usage().unwrap(), vec![usage]);
+}
+
+#[test]
+fn test_pinned() {
+    use super::clock::ManualClock;
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let clock = Arc::new(ManualClock::new(1481900000));
+    let db = ctx.db.with_clock(clock.clone());
+    for client in &["demo", "other"] {
+        db.set(Record::new("1.2.3.4".to_owned(), client.to_string(),
+                           "<message>".to_owned(), clock.now())).unwrap();
+    }
+    assert!(db.pin("demo".to_owned()).unwrap());
+    assert!(!db.pin("unknown".to_owned()).unwrap());
+    assert_eq!(db.pinned().unwrap(), vec!["demo".to_owned()]);
+
+    // Registering again keeps the record pinned.
+    db.set(Record::new("5.6.7.8".to_owned(), "demo".to_owned(),
+                       "<message>".to_owned(), clock.now())).unwrap();
+    assert_eq!(db.pinned().unwrap(), vec!["demo".to_owned()]);
+    // Pinned records never expire, which isn't a problem.
+    assert!(db.check_integrity(10).unwrap().is_empty());
+
+    clock.advance(RECORD_TTL as u64);
+    assert_eq!(db.evict().unwrap(), 1);
+    let records = db.get("5.6.7.8".to_owned()).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].client, "demo");
+    assert!(db.find_by_client("demo".to_owned()).unwrap().is_some());
+
+    assert!(db.unpin("demo".to_owned()).unwrap());
+    assert!(!db.unpin("demo".to_owned()).unwrap());
+    assert!(db.pinned().unwrap().is_empty());
+    assert!(db.get("5.6.7.8".to_owned()).unwrap().is_empty());
+}
+
+#[test]
+fn test_archive() {
+    use super::clock::ManualClock;
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let clock = Arc::new(ManualClock::new(1481900000));
+    let retention = Policy { archive: 30, .. Policy::default() };
+    let db = ctx.db.with_clock(clock.clone()).with_retention(retention);
+    let registered = clock.now();
+    db.set(Record::new("1.2.3.4".to_owned(), "a".to_owned(),
+                       "<message>".to_owned(), registered)).unwrap();
+    let archived = db.archived("a".to_owned()).unwrap().unwrap();
+    assert_eq!(archived.record.public_ip, "1.2.3.4");
+    assert_eq!(archived.evicted, None);
+
+    clock.advance(RECORD_TTL as u64);
+    assert_eq!(db.evict().unwrap(), 1);
+    assert!(db.find_by_client("a".to_owned()).unwrap().is_none());
+    let archived = db.archived("a".to_owned()).unwrap().unwrap();
+    assert_eq!(archived.record.last_seen, registered);
+    assert_eq!(archived.evicted, Some(clock.now()));
+    assert!(db.archived("b".to_owned()).unwrap().is_none());
+}
+
+#[test]
+fn test_flapping() {
+    use super::clock::ManualClock;
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let clock = Arc::new(ManualClock::new(1481900000));
+    let db = ctx.db.with_clock(clock.clone()).with_flapping(2);
+    for public_ip in &["1.1.1.1", "2.2.2.2", "3.3.3.3"] {
+        db.set(Record::new(public_ip.to_string(), "a".to_owned(),
+                           "<message>".to_owned(), clock.now())).unwrap();
+        clock.advance(60);
+    }
+    assert!(db.flapping().unwrap().is_empty());
+    assert!(db.check_flapping("a".to_owned(), None).unwrap());
+
+    db.set(Record::new("4.4.4.4".to_owned(), "a".to_owned(),
+                       "<message>".to_owned(), clock.now())).unwrap();
+    assert_eq!(db.flapping().unwrap(),
+               vec![Flapping { client: "a".to_owned(), flagged: clock.now() }]);
+    db.set_token("a".to_owned(), "token".to_owned()).unwrap();
+    assert!(!db.check_flapping("a".to_owned(), None).unwrap());
+    assert!(!db.check_flapping("a".to_owned(), Some("wrong".to_owned())).unwrap());
+    assert!(db.check_flapping("a".to_owned(), Some("token".to_owned())).unwrap());
+
+    clock.advance(FLAPPING_TTL);
+    assert!(db.flapping().unwrap().is_empty());
+    assert!(db.check_flapping("a".to_owned(), None).unwrap());
+}
+
+#[test]
+fn test_set_if() {
+    use super::clock::ManualClock;
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let clock = Arc::new(ManualClock::new(1481900000));
+    let db = ctx.db.with_clock(clock.clone());
+    let record = |message: &str| {
+        Record::new("1.2.3.4".to_owned(), "a".to_owned(), message.to_owned(), clock.now())
+    };
+
+    assert_eq!(db.set_if(record("first"), Precondition::Revision(1)).unwrap(), None);
+    assert_eq!(db.set_if(record("first"), Precondition::Revision(0)).unwrap(), Some(1));
+    assert_eq!(db.set_if(record("second"), Precondition::Revision(0)).unwrap(), None);
+    assert_eq!(db.set_if(record("second"), Precondition::Revision(1)).unwrap(), Some(2));
+
+    let registered = clock.now();
+    clock.advance(60);
+    assert_eq!(db.set_if(record("third"), Precondition::UnmodifiedSince(registered - 1))
+                 .unwrap(), None);
+    assert_eq!(db.set_if(record("third"), Precondition::UnmodifiedSince(registered))
+                 .unwrap(), Some(3));
+    let current = db.find_by_client("a".to_owned()).unwrap().unwrap();
+    assert_eq!(current.message, "third");
+
+    assert_eq!(db.set_if(record("fourth"), Precondition::Matches(vec!["1-1".to_owned()]))
+                 .unwrap(), None);
+    assert_eq!(db.set_if(record("fourth"), Precondition::Matches(vec![current.etag()]))
+                 .unwrap(), Some(4));
+    assert_eq!(db.set_if(Record::new("1.2.3.4".to_owned(), "b".to_owned(),
+                                     "<message>".to_owned(), clock.now()),
+                         Precondition::Exists).unwrap(), None);
+}
+
+#[test]
+fn test_keep_response() {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let db = &ctx.db;
+    assert_eq!(db.find_response("1.2.3.4:key".to_owned()).unwrap(), None);
+    db.keep_response("1.2.3.4:key".to_owned(), "first".to_owned()).unwrap();
+    db.keep_response("1.2.3.4:key".to_owned(), "second".to_owned()).unwrap();
+    assert_eq!(db.find_response("1.2.3.4:key".to_owned()).unwrap(), Some("first".to_owned()));
+    assert_eq!(db.find_response("5.6.7.8:key".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn test_public_ips() {
+    use super::clock::ManualClock;
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let clock = Arc::new(ManualClock::new(1481900000));
+    let db = ctx.db.with_clock(clock.clone());
+    for &(public_ip, client) in &[("1.1.1.1", "a"), ("2.2.2.2", "b"), ("2.2.2.2", "c")] {
+        db.set(Record::new(public_ip.to_owned(), client.to_owned(),
+                           "<message>".to_owned(), clock.now())).unwrap();
+        clock.advance(10);
+    }
+
+    assert_eq!(db.public_ips(1).unwrap(), vec![
+        PublicIp { public_ip: "2.2.2.2".to_owned(), clients: 2, last_seen: 1481900020 },
+        PublicIp { public_ip: "1.1.1.1".to_owned(), clients: 1, last_seen: 1481900000 },
+    ]);
+    assert_eq!(db.public_ips(2).unwrap().len(), 1);
+    assert!(db.public_ips(3).unwrap().is_empty());
+
+    clock.advance(RECORD_TTL as u64);
+    assert!(db.public_ips(1).unwrap().is_empty());
+}
+
+#[test]
+fn test_hourly() {
+    use super::clock::ManualClock;
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let clock = Arc::new(ManualClock::new(1481900400));
+    let db = ctx.db.with_clock(clock.clone());
+    for client in &["a", "b", "a"] {
+        db.set(Record::new("1.2.3.4".to_owned(), client.to_string(),
+                           "<message>".to_owned(), clock.now())).unwrap();
+    }
+    clock.advance(3600);
+    assert_eq!(db.evict().unwrap(), 2);
+
+    let stats = db.stats().unwrap();
+    assert_eq!(stats.hourly.len(), HOURLY_WINDOW as usize);
+    let latest = &stats.hourly[stats.hourly.len() - 2..];
+    assert_eq!(latest, &[Hourly { hour: 1481900400, registrations: 3, evictions: 0 },
+                         Hourly { hour: 1481904000, registrations: 0, evictions: 2 }]);
+    assert_eq!(stats.churn_rate, 1.0);
+}
+
+#[test]
+fn test_rotate_token() {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let db = &ctx.db;
+    db.set(Record::new("1.2.3.4".to_owned(), "a".to_owned(),
+                       "<message>".to_owned(), db.now())).unwrap();
+    db.set_token("a".to_owned(), "old".to_owned()).unwrap();
+
+    assert_eq!(db.rotate_token("a".to_owned(), "wrong").unwrap(), None);
+    assert_eq!(db.rotate_token("b".to_owned(), "old").unwrap(), None);
+    let new_token = db.rotate_token("a".to_owned(), "old").unwrap().unwrap();
+    assert!(new_token != "old");
+
+    // Both tokens work during the grace period, but only the new one
+    // rotates again.
+    let alive = |token: &str| match db.heartbeat("a".to_owned(), token).unwrap() {
+        Heartbeat::Alive(_) => true,
+        _ => false
+    };
+    assert!(alive(&new_token));
+    assert!(alive("old"));
+    assert!(!alive("wrong"));
+    assert_eq!(db.rotate_token("a".to_owned(), "old").unwrap(), None);
+
+    // A new registration ends the grace period.
+    db.set_token("a".to_owned(), "newer".to_owned()).unwrap();
+    assert!(!alive("old"));
+    assert!(!alive(&new_token));
+    assert!(alive("newer"));
+}
+
+#[test]
+fn test_revoked() {
+    use super::clock::ManualClock;
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let clock = Arc::new(ManualClock::new(1481900000));
+    let db = ctx.db.with_clock(clock.clone());
+    assert!(db.revoked().unwrap().is_empty());
+
+    db.revoke("a".to_owned()).unwrap();
+    clock.advance(10);
+    db.revoke("b".to_owned()).unwrap();
+    assert_eq!(db.revoked().unwrap(), vec![
+        Revocation { hash: "b".to_owned(), revoked_at: 1481900010 },
+        Revocation { hash: "a".to_owned(), revoked_at: 1481900000 },
+    ]);
+
+    assert!(db.unrevoke("a".to_owned()).unwrap());
+    assert!(!db.unrevoke("a".to_owned()).unwrap());
+    assert_eq!(db.revoked().unwrap().len(), 1);
+
+    assert!(db.tokens("c").unwrap().is_empty());
+    db.set_token("c".to_owned(), "token".to_owned()).unwrap();
+    assert_eq!(db.tokens("c").unwrap(), vec!["token".to_owned()]);
+}
+
+#[test]
+fn test_credentials() {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let credential = |name: &str, kind: &str| Credential {
+        name: name.to_owned(),
+        kind: kind.to_owned(),
+        hash: format!("hash of {}", name),
+        created_at: 1481900000,
+    };
+    assert!(ctx.db.add_credential(&credential("ops", ADMIN_CREDENTIAL)).unwrap());
+    assert!(ctx.db.add_credential(&credential("app", API_CREDENTIAL)).unwrap());
+    assert!(!ctx.db.add_credential(&credential("app", ADMIN_CREDENTIAL)).unwrap());
+    assert_eq!(ctx.db.credentials().unwrap(),
+               vec![credential("app", API_CREDENTIAL), credential("ops", ADMIN_CREDENTIAL)]);
+    assert_eq!(ctx.db.find_credential("hash of ops").unwrap(),
+               Some(credential("ops", ADMIN_CREDENTIAL)));
+    assert_eq!(ctx.db.find_credential("other").unwrap(), None);
+
+    assert_eq!(ctx.db.remove_credential("ops").unwrap(),
+               Some(credential("ops", ADMIN_CREDENTIAL)));
+    assert_eq!(ctx.db.remove_credential("ops").unwrap(), None);
+    assert_eq!(ctx.db.find_credential("hash of ops").unwrap(), None);
+    assert_eq!(ctx.db.credentials().unwrap().len(), 1);
+}
+
+#[test]
+fn test_mailbox() {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let db = &ctx.db;
+    assert!(db.take_messages("a".to_owned()).unwrap().is_empty());
+
+    assert_eq!(db.leave_message("a".to_owned(), "wake up".to_owned()).unwrap(), 1);
+    assert_eq!(db.leave_message("a".to_owned(), "again".to_owned()).unwrap(), 2);
+    let ttl: i32 = cmd("TTL").arg("mailbox:a").query(&db.connection).unwrap();
+    assert!(ttl > 0 && ttl <= MAILBOX_TTL);
+    assert_eq!(db.take_messages("a".to_owned()).unwrap(), vec!["wake up", "again"]);
+    assert!(db.take_messages("a".to_owned()).unwrap().is_empty());
+
+    // Only the latest messages are kept.
+    for i in 0..MAX_MAILBOX_MESSAGES + 2 {
+        db.leave_message("b".to_owned(), i.to_string()).unwrap();
+    }
+    let messages = db.take_messages("b".to_owned()).unwrap();
+    assert_eq!(messages.len(), MAX_MAILBOX_MESSAGES as usize);
+    assert_eq!(messages[0], "2");
+}
+
+#[test]
+fn test_candidates() {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let db = &ctx.db;
+    assert_eq!(db.candidates("a".to_owned()).unwrap(), None);
+    let candidates = vec![Candidate::host("192.168.1.2".to_owned(), 8443)];
+    db.set_candidates("a".to_owned(), &candidates).unwrap();
+    assert_eq!(db.candidates("a".to_owned()).unwrap(), Some(candidates.clone()));
+
+    assert!(db.take_peers("a".to_owned()).unwrap().is_empty());
+    for port in 0..signaling::MAX_PEERS as u16 + 1 {
+        db.add_peer("a".to_owned(), &[Candidate::host("10.0.0.1".to_owned(), port)]).unwrap();
+    }
+    let peers = db.take_peers("a".to_owned()).unwrap();
+    assert_eq!(peers.len(), signaling::MAX_PEERS as usize);
+    assert_eq!(peers[0], vec![Candidate::host("10.0.0.1".to_owned(), 1)]);
+    assert!(db.take_peers("a".to_owned()).unwrap().is_empty());
+}
+
+#[test]
+fn test_set_verified() {
+    use super::db_test_context::TestContext;
+
+    let ctx = TestContext::new();
+    let db = &ctx.db;
+    let record = Record::new("1.2.3.4".to_owned(), "a".to_owned(), "m".to_owned(), db.now());
+    assert!(!db.set_verified("1.2.3.4".to_owned(), "a".to_owned(), true).unwrap());
+    db.set(record.clone()).unwrap();
+    assert_eq!(db.find_by_client("a".to_owned()).unwrap().unwrap().verified, None);
+    assert!(db.set_verified("1.2.3.4".to_owned(), "a".to_owned(), true).unwrap());
+    assert_eq!(db.find_by_client("a".to_owned()).unwrap().unwrap().verified, Some(true));
+
+    // Registering again from the same public IP keeps it, moving drops it.
+    db.set(record.clone()).unwrap();
+    assert_eq!(db.find_by_client("a".to_owned()).unwrap().unwrap().verified, Some(true));
+    db.set(Record { public_ip: "5.6.7.8".to_owned(), .. record }).unwrap();
+    assert_eq!(db.find_by_client("a".to_owned()).unwrap().unwrap().verified, None);
+    assert!(!db.set_verified("1.2.3.4".to_owned(), "a".to_owned(), false).unwrap());
+}
@@ -2,28 +2,38 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::net::TcpListener;
 use std::process;
+use std::time::Duration;
 use super::db::Db;
 
-static SERVER_PORT: u16 = 38991;
-static SERVER_HOST: &'static str = "127.0.0.1";
+pub static SERVER_HOST: &'static str = "127.0.0.1";
+
+/// A port nobody listens on at the moment.
+pub fn free_port() -> u16 {
+    TcpListener::bind((SERVER_HOST, 0)).unwrap().local_addr().unwrap().port()
+}
 
 pub struct RedisServer {
     pub process: process::Child,
+    pub port: u16,
 }
 
 impl RedisServer {
 
+    /// Start a Redis server on a free port, so that tests can run in
+    /// parallel.
     pub fn new() -> RedisServer {
+        let port = free_port();
         let mut cmd = process::Command::new("redis-server");
         cmd
             .stdout(process::Stdio::null())
             .stderr(process::Stdio::null())
-            .arg("--port").arg(SERVER_PORT.to_string())
+            .arg("--port").arg(port.to_string())
             .arg("--bind").arg(SERVER_HOST.to_string());
 
         let process = cmd.spawn().unwrap();
-        RedisServer { process: process }
+        RedisServer { process: process, port: port }
     }
 }
 
@@ -43,9 +53,12 @@ impl TestContext {
     pub fn new() -> TestContext {
         let server = RedisServer::new();
 
-        let db = Db::new(SERVER_HOST.to_string(),
-                         SERVER_PORT,
-                         None /* password */);
+        let port = server.port;
+        let db = Db::wait_for(|| Db::new(SERVER_HOST.to_string(),
+                                         port,
+                                         None /* password */,
+                                         0 /* database */),
+                              Duration::from_secs(10)).unwrap();
 
         db.flush().unwrap();
 
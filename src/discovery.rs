@@ -0,0 +1,286 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Shaping of the discovery results returned to clients: ordering,
+/// deduplication and truncation of the records matching a public IP,
+/// ranking of the boxes on the same local network as the client first, and
+/// advice on how to connect to each box.
+
+use db::Record;
+use nat;
+use params::{ Map, Value };
+use rustc_serialize::json::Json;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use subnet;
+
+/// The box is behind the same public IP as the client: connect to it on the
+/// local network.
+pub static LOCAL: &'static str = "local";
+/// Connect to the public address of the box, hole punching as needed.
+pub static DIRECT: &'static str = "direct";
+/// Go through the tunnel the box advertises.
+pub static TUNNEL: &'static str = "tunnel";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Newest,
+    Oldest,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub order: Order,
+    /// Only keep the freshest record of each fingerprint, hiding the older
+    /// registrations of boxes whose public IP changed within the subnet.
+    pub unique: bool,
+    pub limit: Option<usize>,
+    /// Address of the client on its local network.
+    pub local_ip: Option<IpAddr>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            order: Order::Newest,
+            unique: true,
+            limit: None,
+            local_ip: None,
+        }
+    }
+}
+
+impl Options {
+    /// Read the discovery options from the query string parameters:
+    ///   sort=newest|oldest
+    ///   unique=true|false
+    ///   all=true|false, the opposite of unique, which can't be given too
+    ///   limit=<n>
+    ///   local_ip=<address>
+    /// Returns the name of the first invalid parameter on failure.
+    pub fn from_params(params: &Map) -> Result<Options, &'static str> {
+        let mut options = Options::default();
+
+        if let Some(value) = params.find(&["sort"]) {
+            options.order = match *value {
+                Value::String(ref s) if s == "newest" => Order::Newest,
+                Value::String(ref s) if s == "oldest" => Order::Oldest,
+                _ => return Err("sort")
+            };
+        }
+
+        if let Some(value) = params.find(&["unique"]) {
+            options.unique = match *value {
+                Value::String(ref s) if s == "true" => true,
+                Value::String(ref s) if s == "false" => false,
+                _ => return Err("unique")
+            };
+        }
+
+        if let Some(value) = params.find(&["all"]) {
+            if params.find(&["unique"]).is_some() {
+                return Err("all");
+            }
+            options.unique = match *value {
+                Value::String(ref s) if s == "true" => false,
+                Value::String(ref s) if s == "false" => true,
+                _ => return Err("all")
+            };
+        }
+
+        if let Some(value) = params.find(&["limit"]) {
+            options.limit = match *value {
+                Value::String(ref s) => match s.parse() {
+                    Ok(limit) => Some(limit),
+                    Err(_) => return Err("limit")
+                },
+                _ => return Err("limit")
+            };
+        }
+
+        if let Some(value) = params.find(&["local_ip"]) {
+            options.local_ip = match *value {
+                Value::String(ref s) => match s.parse() {
+                    Ok(local_ip) => Some(local_ip),
+                    Err(_) => return Err("local_ip")
+                },
+                _ => return Err("local_ip")
+            };
+        }
+
+        Ok(options)
+    }
+}
+
+/// Order the records by registration time and, if requested, only keep the
+/// most recent record for each fingerprint. With a `local_ip`, the boxes
+/// sharing the longest prefix with it come first, which picks the boxes of
+/// the client's home among those of a large network.
+pub fn rank(mut records: Vec<Record>, options: &Options) -> Vec<Record> {
+    // Always put the newest records first so that deduplication keeps the
+    // freshest entry, and reverse afterwards if needed.
+    records.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+
+    if options.unique {
+        let mut seen = HashSet::new();
+        records.retain(|record| seen.insert(record.client.clone()));
+    }
+
+    if options.order == Order::Oldest {
+        records.reverse();
+    }
+
+    if let Some(ref local_ip) = options.local_ip {
+        let overlap = |record: &Record| {
+            record.local_ip.as_ref()
+                  .and_then(|ip| ip.parse().ok())
+                  .map_or(0, |ip| subnet::common_prefix(local_ip, &ip))
+        };
+        // The sort is stable, keeping the time order for equal overlaps.
+        records.sort_by(|a, b| overlap(b).cmp(&overlap(a)));
+    }
+
+    if let Some(limit) = options.limit {
+        records.truncate(limit);
+    }
+
+    records
+}
+
+/// A record as the open discovery returns it: which boxes are there and how
+/// to connect to them, without their messages or addresses.
+#[derive(Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Summary {
+    pub client: String,
+    pub online: bool,
+    pub strategy: String,
+}
+
+impl Summary {
+    /// The summary of `record` for a client at `public_ip`, as stored.
+    pub fn new(record: &Record, public_ip: &str, now: u64, ping_interval: u64) -> Summary {
+        Summary {
+            client: record.client.clone(),
+            online: record.is_online(now, ping_interval),
+            strategy: strategy(record, public_ip).to_owned(),
+        }
+    }
+}
+
+/// Whether the box advertises a tunnel, as the non-empty `tunnel_origin`
+/// of a JSON message like the one of the foxbox.
+fn has_tunnel(record: &Record) -> bool {
+    Json::from_str(&record.message).ok()
+        .and_then(|message| message.find("tunnel_origin").and_then(Json::as_string)
+                                   .map(|origin| !origin.is_empty()))
+        .unwrap_or(false)
+}
+
+/// How a client at `public_ip`, as stored, should first try to connect to
+/// the box of `record`: `LOCAL` on the same public IP, else `DIRECT` when
+/// a probe reached it or its NAT mapping lets the client in, `TUNNEL` when
+/// it can't or isn't known and the box advertises a tunnel, and `DIRECT`
+/// otherwise, there being nothing better to try. This is advice: clients still fall back on
+/// the other ways, which the server can't check.
+pub fn strategy(record: &Record, public_ip: &str) -> &'static str {
+    if record.public_ip == public_ip {
+        return LOCAL;
+    }
+    let reachable = record.verified == Some(true) ||
+                    record.nat.as_ref().map_or(false, |mapping| nat::lets_clients_in(mapping));
+    if !reachable && has_tunnel(record) {
+        TUNNEL
+    } else {
+        DIRECT
+    }
+}
+
+#[test]
+fn test_rank() {
+    let record = |client: &str, timestamp: u64| {
+        Record::new("127.0.0.1".to_owned(),
+                    client.to_owned(),
+                    format!("{}@{}", client, timestamp),
+                    timestamp)
+    };
+    let records = vec![record("a", 10), record("b", 30), record("a", 20)];
+
+    let ranked = rank(records.clone(), &Options::default());
+    let stamps: Vec<u64> = ranked.iter().map(|r| r.last_seen).collect();
+    assert_eq!(stamps, vec![30, 20]);
+
+    let ranked = rank(records.clone(), &Options {
+        unique: false,
+        .. Options::default()
+    });
+    let stamps: Vec<u64> = ranked.iter().map(|r| r.last_seen).collect();
+    assert_eq!(stamps, vec![30, 20, 10]);
+
+    let ranked = rank(records.clone(), &Options {
+        order: Order::Oldest,
+        unique: true,
+        limit: None,
+        local_ip: None
+    });
+    let messages: Vec<String> = ranked.iter().map(|r| r.message.clone()).collect();
+    assert_eq!(messages, vec!["a@20".to_owned(), "b@30".to_owned()]);
+
+    let ranked = rank(records.clone(), &Options {
+        order: Order::Newest,
+        unique: false,
+        limit: Some(1),
+        local_ip: None
+    });
+    assert_eq!(ranked.len(), 1);
+    assert_eq!(ranked[0].client, "b");
+
+    let mut records = records;
+    records[0].local_ip = Some("192.168.1.20".to_owned());
+    records[1].local_ip = Some("192.168.7.20".to_owned());
+    let ranked = rank(records, &Options {
+        unique: false,
+        local_ip: Some("192.168.1.10".parse().unwrap()),
+        .. Options::default()
+    });
+    let messages: Vec<String> = ranked.iter().map(|r| r.message.clone()).collect();
+    assert_eq!(messages, vec!["a@10".to_owned(), "b@30".to_owned(), "a@20".to_owned()]);
+}
+
+#[test]
+fn test_strategy() {
+    let mut record = Record::new("1.2.3.4".to_owned(), "a".to_owned(),
+                                 r#"{"local_origin":"https://a.local","tunnel_origin":null}"#
+                                     .to_owned(),
+                                 0);
+    assert_eq!(strategy(&record, "1.2.3.4"), LOCAL);
+    assert_eq!(strategy(&record, "5.6.7.8"), DIRECT);
+
+    record.message = r#"{"tunnel_origin":"https://a.tunnel"}"#.to_owned();
+    assert_eq!(strategy(&record, "1.2.3.4"), LOCAL);
+    assert_eq!(strategy(&record, "5.6.7.8"), TUNNEL);
+    record.nat = Some("address-and-port-dependent".to_owned());
+    assert_eq!(strategy(&record, "5.6.7.8"), TUNNEL);
+    record.verified = Some(true);
+    assert_eq!(strategy(&record, "5.6.7.8"), DIRECT);
+    record.verified = Some(false);
+    record.nat = Some("endpoint-independent".to_owned());
+    assert_eq!(strategy(&record, "5.6.7.8"), DIRECT);
+    record.nat = Some("none".to_owned());
+    assert_eq!(strategy(&record, "5.6.7.8"), DIRECT);
+
+    record.message = "not json".to_owned();
+    record.nat = None;
+    assert_eq!(strategy(&record, "5.6.7.8"), DIRECT);
+}
+
+#[test]
+fn test_summary() {
+    let record = Record::new("1.2.3.4".to_owned(), "a".to_owned(), "m".to_owned(), 100);
+    assert_eq!(Summary::new(&record, "1.2.3.4", 130, 30), Summary {
+        client: "a".to_owned(),
+        online: true,
+        strategy: LOCAL.to_owned(),
+    });
+    assert!(!Summary::new(&record, "1.2.3.4", 200, 30).online);
+}
@@ -0,0 +1,134 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// End-to-end encrypted discovery, which makes the registration server a
+/// zero-knowledge rendezvous point: it stores the `encrypted` field of the
+/// registrations and hands it to whoever asks, but only the paired clients
+/// can read it. The server itself never encrypts nor decrypts anything;
+/// this module is the reference implementation of the protocol, used by
+/// the `e2e` commands to help writing boxes and apps.
+///
+/// 1. The box generates a random pairing secret of 32 bytes once, and keeps
+///    it. It shares the secret with the apps it pairs with without going
+///    through the server, e.g. by appending `#secret=<base64url>` to the
+///    payload of its QR code before showing it.
+/// 2. The box and the apps derive the key of the box with HKDF-SHA256, from
+///    the secret, with the fingerprint of the box as the salt and
+///    `KEY_INFO` as the info.
+/// 3. The box encrypts its local data, e.g. a JSON object with its local
+///    addresses, with ChaCha20-Poly1305, a random nonce of 8 bytes and the
+///    fingerprint as additional data, so that a blob can't be passed off as
+///    another box's.
+/// 4. It registers with the `encrypted` field set to the URL-safe base64,
+///    without padding, of the version byte (1), the nonce, the ciphertext
+///    and the tag of 16 bytes.
+/// 5. The apps discover the box as usual, and decrypt its `encrypted` field
+///    with the key they derived.
+///
+/// Rotating the secret means pairing the apps again.
+
+use crypto::aead::{ AeadDecryptor, AeadEncryptor };
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::hkdf::{ hkdf_expand, hkdf_extract };
+use crypto::sha2::Sha256;
+use rand::{ OsRng, Rng };
+use rustc_serialize::base64::{ FromBase64, ToBase64, URL_SAFE };
+
+/// Version byte of the blobs of this protocol.
+pub static VERSION: u8 = 1;
+/// The HKDF info of the key derivation.
+pub static KEY_INFO: &'static [u8] = b"fxbox e2e discovery v1";
+/// Length, in bytes, of the pairing secrets and of the keys.
+pub static KEY_LENGTH: usize = 32;
+static NONCE_LENGTH: usize = 8;
+static TAG_LENGTH: usize = 16;
+
+fn random_bytes(length: usize) -> Vec<u8> {
+    let mut bytes = vec![0; length];
+    OsRng::new().expect("No source of randomness").fill_bytes(&mut bytes);
+    bytes
+}
+
+/// A new random pairing secret, in URL-safe base64.
+pub fn pairing_secret() -> String {
+    random_bytes(KEY_LENGTH).to_base64(URL_SAFE)
+}
+
+/// The key of the box `fingerprint`, from its base64 pairing `secret`.
+pub fn derive_key(secret: &str, fingerprint: &str) -> Result<Vec<u8>, String> {
+    let secret = try!(secret.from_base64().map_err(|_| "The secret isn't base64".to_owned()));
+    if secret.len() != KEY_LENGTH {
+        return Err(format!("The secret must be {} bytes long", KEY_LENGTH));
+    }
+    let mut prk = vec![0; KEY_LENGTH];
+    hkdf_extract(Sha256::new(), fingerprint.as_bytes(), &secret, &mut prk);
+    let mut key = vec![0; KEY_LENGTH];
+    hkdf_expand(Sha256::new(), &prk, KEY_INFO, &mut key);
+    Ok(key)
+}
+
+/// Encrypt the local data of the box `fingerprint` into the value of its
+/// `encrypted` field.
+pub fn seal(key: &[u8], fingerprint: &str, plaintext: &[u8]) -> String {
+    let nonce = random_bytes(NONCE_LENGTH);
+    let mut ciphertext = vec![0; plaintext.len()];
+    let mut tag = vec![0; TAG_LENGTH];
+    ChaCha20Poly1305::new(key, &nonce, fingerprint.as_bytes())
+        .encrypt(plaintext, &mut ciphertext, &mut tag);
+
+    let mut blob = vec![VERSION];
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&ciphertext);
+    blob.extend_from_slice(&tag);
+    blob.to_base64(URL_SAFE)
+}
+
+/// Decrypt the `encrypted` field of the box `fingerprint`.
+pub fn open(key: &[u8], fingerprint: &str, blob: &str) -> Result<Vec<u8>, String> {
+    let blob = try!(blob.from_base64().map_err(|_| "The data isn't base64".to_owned()));
+    if blob.len() < 1 + NONCE_LENGTH + TAG_LENGTH {
+        return Err("The data is too short".to_owned());
+    }
+    if blob[0] != VERSION {
+        return Err(format!("Unknown version {}", blob[0]));
+    }
+    let nonce = &blob[1..1 + NONCE_LENGTH];
+    let ciphertext = &blob[1 + NONCE_LENGTH..blob.len() - TAG_LENGTH];
+    let tag = &blob[blob.len() - TAG_LENGTH..];
+
+    let mut plaintext = vec![0; ciphertext.len()];
+    if ChaCha20Poly1305::new(key, nonce, fingerprint.as_bytes())
+           .decrypt(ciphertext, &mut plaintext, tag) {
+        Ok(plaintext)
+    } else {
+        Err("The data can't be decrypted with this key".to_owned())
+    }
+}
+
+#[test]
+fn test_seal_open() {
+    let secret = pairing_secret();
+    assert!(secret != pairing_secret());
+    let key = derive_key(&secret, "<fingerprint>").unwrap();
+    assert_eq!(key.len(), KEY_LENGTH);
+    assert_eq!(key, derive_key(&secret, "<fingerprint>").unwrap());
+    assert!(key != derive_key(&secret, "<other>").unwrap());
+    assert!(derive_key("c2hvcnQ", "<fingerprint>").is_err());
+
+    let plaintext = br#"{"local_ip": "192.168.1.2"}"#;
+    let blob = seal(&key, "<fingerprint>", plaintext);
+    assert!(blob.bytes().all(|byte| byte != b'+' && byte != b'/' && byte != b'='));
+    assert!(blob != seal(&key, "<fingerprint>", plaintext));
+    assert_eq!(open(&key, "<fingerprint>", &blob).unwrap(), plaintext.to_vec());
+
+    // Another key, another box, or a modified blob don't decrypt.
+    let other_key = derive_key(&pairing_secret(), "<fingerprint>").unwrap();
+    assert!(open(&other_key, "<fingerprint>", &blob).is_err());
+    assert!(open(&key, "<other>", &blob).is_err());
+    let mut tampered = blob.from_base64().unwrap();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    assert!(open(&key, "<fingerprint>", &tampered.to_base64(URL_SAFE)).is_err());
+    assert!(open(&key, "<fingerprint>", "AQ").is_err());
+}
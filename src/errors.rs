@@ -2,8 +2,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use iron::headers::{ ContentType, Headers };
 use iron::status;
 use iron::prelude::*;
+use redis::RedisError;
 use rustc_serialize::json;
 use std::error::Error;
 use std::fmt::{ self, Debug };
@@ -23,42 +25,280 @@ impl Error for StringError {
     }
 }
 
+/// Every error number returned in the `errno` field of error responses.
+/// Client developers can get the list from GET /errors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrNo {
+    MissingClient = 100,
+    MissingMessage = 101,
+    InvalidParameter = 102,
+    TooManyEntries = 103,
+    UnknownPath = 104,
+    InvalidClient = 105,
+    InvalidMessage = 106,
+    UnknownField = 107,
+    TooManyBoxes = 108,
+    TooManyRegistrations = 109,
+    ReadOnly = 110,
+    FeatureDisabled = 111,
+    DatabaseUnavailable = 112,
+    Timeout = 113,
+    Flapping = 114,
+    Revoked = 115,
+    TooManySubdomains = 116,
+    LabelNotAllowed = 117,
+    Conflict = 409,
+    PreconditionFailed = 412,
+    UnsupportedMediaType = 415,
+    TooManyRequests = 429,
+    BadRequest = 400,
+    Unauthorized = 401,
+    NotFound = 404,
+    MethodNotAllowed = 405,
+    InternalError = 501,
+}
+
+impl ErrNo {
+    pub fn all() -> Vec<ErrNo> {
+        vec![
+            ErrNo::MissingClient,
+            ErrNo::MissingMessage,
+            ErrNo::InvalidParameter,
+            ErrNo::TooManyEntries,
+            ErrNo::UnknownPath,
+            ErrNo::InvalidClient,
+            ErrNo::InvalidMessage,
+            ErrNo::UnknownField,
+            ErrNo::TooManyBoxes,
+            ErrNo::TooManyRegistrations,
+            ErrNo::ReadOnly,
+            ErrNo::FeatureDisabled,
+            ErrNo::DatabaseUnavailable,
+            ErrNo::Timeout,
+            ErrNo::Flapping,
+            ErrNo::Revoked,
+            ErrNo::TooManySubdomains,
+            ErrNo::LabelNotAllowed,
+            ErrNo::Conflict,
+            ErrNo::PreconditionFailed,
+            ErrNo::UnsupportedMediaType,
+            ErrNo::TooManyRequests,
+            ErrNo::BadRequest,
+            ErrNo::Unauthorized,
+            ErrNo::NotFound,
+            ErrNo::MethodNotAllowed,
+            ErrNo::InternalError,
+        ]
+    }
+
+    pub fn code(&self) -> u16 {
+        *self as u16
+    }
+
+    pub fn description(&self) -> &'static str {
+        match *self {
+            ErrNo::MissingClient => "The `client` field is missing.",
+            ErrNo::MissingMessage => "The `message` field is missing.",
+            ErrNo::InvalidParameter => "A query string parameter is invalid.",
+            ErrNo::TooManyEntries => "The request has too many entries.",
+            ErrNo::UnknownPath => "There is no endpoint at this path.",
+            ErrNo::InvalidClient => "The `client` field isn't a non-empty string of at most 256 bytes.",
+            ErrNo::InvalidMessage => "The `message` field isn't a non-empty string of at most 4096 bytes.",
+            ErrNo::UnknownField => "The registration has a field the server doesn't know.",
+            ErrNo::TooManyBoxes => "The account has as many boxes as its quota allows.",
+            ErrNo::TooManyRegistrations => "The boxes of the account registered as many times this hour as its quota allows.",
+            ErrNo::ReadOnly => "The server is read-only for maintenance, retry after `retry_after` seconds.",
+            ErrNo::FeatureDisabled => "The feature of this endpoint is disabled on this server.",
+            ErrNo::DatabaseUnavailable => "The server can't reach its database, retry later.",
+            ErrNo::Timeout => "The database didn't answer in time, retry later.",
+            ErrNo::Flapping => "The public IP of the box changed too often, its registrations need the token of its latest one.",
+            ErrNo::Revoked => "The token or API key of the request was revoked.",
+            ErrNo::TooManySubdomains => "The account has reserved as many subdomains as its quota allows.",
+            ErrNo::LabelNotAllowed => "The subdomain is, or looks like, a reserved or blocked name.",
+            ErrNo::Conflict => "The resource already exists.",
+            ErrNo::PreconditionFailed => "The record changed since the revision or time the update expected.",
+            ErrNo::UnsupportedMediaType => "The body of the request must be JSON, as its Content-Type should say.",
+            ErrNo::TooManyRequests => "Too many attempts, retry after `retry_after` seconds.",
+            ErrNo::BadRequest => "The request is malformed.",
+            ErrNo::Unauthorized => "Missing or invalid credentials.",
+            ErrNo::NotFound => "The requested resource doesn't exist.",
+            ErrNo::MethodNotAllowed => "The method isn't allowed for this resource.",
+            ErrNo::InternalError => "The server failed to process the request.",
+        }
+    }
+}
+
+#[derive(Debug, RustcEncodable)]
+pub struct ErrNoDescription {
+    pub errno: u16,
+    pub description: &'static str,
+}
+
+/// The registry of errors, as served by GET /errors.
+pub fn describe_all() -> Vec<ErrNoDescription> {
+    ErrNo::all().iter().map(|errno| ErrNoDescription {
+        errno: errno.code(),
+        description: errno.description(),
+    }).collect()
+}
+
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 pub struct ErrorBody {
     pub code: u16,
     pub errno: u16,
-    pub error: String
+    pub error: String,
+    /// Number of seconds after which the request can be retried, also sent
+    /// as a Retry-After header.
+    pub retry_after: Option<u64>,
+    /// Human readable explanation, e.g. which field failed validation.
+    pub details: Option<String>,
+    /// Identifies the request in the logs and traces, given when the
+    /// server failed unexpectedly.
+    pub request_id: Option<String>,
+}
+
+/// The state of a rate limit or quota after counting a request, sent as
+/// `X-RateLimit-*` headers so that clients can slow down before reaching it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LimitState {
+    /// Number of requests accepted per window.
+    pub limit: u64,
+    /// Number of requests left in the current window.
+    pub remaining: u64,
+    /// Number of seconds before the window resets.
+    pub reset: u64,
+}
+
+impl LimitState {
+    pub fn new(limit: u64, count: u64, reset: u64) -> LimitState {
+        LimitState {
+            limit: limit,
+            remaining: limit.saturating_sub(count),
+            reset: reset,
+        }
+    }
+
+    pub fn set_headers(&self, headers: &mut Headers) {
+        headers.set_raw("X-RateLimit-Limit", vec![self.limit.to_string().into_bytes()]);
+        headers.set_raw("X-RateLimit-Remaining",
+                        vec![self.remaining.to_string().into_bytes()]);
+        headers.set_raw("X-RateLimit-Reset", vec![self.reset.to_string().into_bytes()]);
+    }
 }
 
 pub struct EndpointError;
 
 impl EndpointError {
-    pub fn with(status: status::Status, errno: u16)
+    pub fn with(status: status::Status, errno: ErrNo)
+        -> IronResult<Response> {
+        Err(EndpointError::build(status, errno, None, None))
+    }
+
+    pub fn with_details(status: status::Status, errno: ErrNo, details: String)
         -> IronResult<Response> {
+        Err(EndpointError::build(status, errno, None, Some(details)))
+    }
+
+    pub fn with_retry_after(status: status::Status, errno: ErrNo, retry_after: u64)
+        -> IronResult<Response> {
+        Err(EndpointError::build(status, errno, Some(retry_after), None))
+    }
+
+    /// The 429 of a request over a rate limit or quota, which can be retried
+    /// once its window resets.
+    pub fn with_limit(errno: ErrNo, state: LimitState, details: Option<String>) -> IronError {
+        let mut error = EndpointError::build(status::TooManyRequests, errno,
+                                             Some(state.reset), details);
+        state.set_headers(&mut error.response.headers);
+        error
+    }
+
+    /// The 500 of a request which failed unexpectedly, e.g. because its
+    /// handler panicked.
+    pub fn with_request_id(status: status::Status, errno: ErrNo, request_id: String)
+        -> IronResult<Response> {
+        Err(EndpointError::build_body(status, errno, None, None, Some(request_id)))
+    }
+
+    pub fn build(status: status::Status,
+                 errno: ErrNo,
+                 retry_after: Option<u64>,
+                 details: Option<String>) -> IronError {
+        EndpointError::build_body(status, errno, retry_after, details, None)
+    }
+
+    fn build_body(status: status::Status,
+                  errno: ErrNo,
+                  retry_after: Option<u64>,
+                  details: Option<String>,
+                  request_id: Option<String>) -> IronError {
         let error = status.canonical_reason().unwrap().to_owned();
         let body = ErrorBody {
             code: status.to_u16(),
-            errno: errno,
-            error: error.clone()
+            errno: errno.code(),
+            error: error.clone(),
+            retry_after: retry_after,
+            details: details,
+            request_id: request_id,
         };
 
-        Err(
-            IronError::new(StringError(error),
-            (status, json::encode(&body).unwrap()))
-        )
+        let mut error = IronError::new(StringError(error),
+                                       (status, json::encode(&body).unwrap()));
+        error.response.headers.set(ContentType::json());
+        if let Some(retry_after) = retry_after {
+            error.response.headers.set_raw("Retry-After",
+                                           vec![retry_after.to_string().into_bytes()]);
+        }
+        error
+    }
+}
+
+/// The 503 of a request which couldn't connect to the database.
+pub fn database_unavailable(error: RedisError) -> IronError {
+    error!("Database unavailable: {}", error);
+    EndpointError::build(status::ServiceUnavailable, ErrNo::DatabaseUnavailable, None, None)
+}
+
+/// The error of a request whose database command failed: a 504 if the
+/// database didn't answer within the handler timeout, a 500 otherwise.
+pub fn database_error(error: RedisError) -> IronError {
+    error!("{}", error);
+    if error.is_timeout() {
+        EndpointError::build(status::GatewayTimeout, ErrNo::Timeout, None, None)
+    } else {
+        EndpointError::build(status::InternalServerError, ErrNo::InternalError, None, None)
     }
 }
 
 pub fn from_decoder_error(error: json::DecoderError) -> IronResult<Response> {
-    match error {
+    let details = match error {
         json::DecoderError::MissingFieldError(field) => {
-            let errno = match field.as_ref() {
-                "local_ip" => 100,
-                "tunnel_url" => 101,
-                _ => 400
-            };
-            EndpointError::with(status::BadRequest, errno)
+            format!("Missing field `{}`", field)
         },
-        _ => EndpointError::with(status::BadRequest, 400)
-    }
+        json::DecoderError::ExpectedError(expected, found) => {
+            format!("Expected {}, found {}", expected, found)
+        },
+        error => format!("{}", error)
+    };
+    EndpointError::with_details(status::BadRequest, ErrNo::BadRequest, details)
+}
+
+#[test]
+fn test_errnos_are_unique() {
+    let mut codes: Vec<u16> = ErrNo::all().iter().map(|e| e.code()).collect();
+    let count = codes.len();
+    codes.sort();
+    codes.dedup();
+    assert_eq!(codes.len(), count);
+}
+
+#[test]
+fn test_database_error() {
+    use redis::ErrorKind;
+    use std::io;
+
+    let error = database_error(io::Error::new(io::ErrorKind::TimedOut, "timed out").into());
+    assert_eq!(error.response.status, Some(status::GatewayTimeout));
+    let error = database_error((ErrorKind::TypeError, "corrupt record").into());
+    assert_eq!(error.response.status, Some(status::InternalServerError));
 }
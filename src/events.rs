@@ -0,0 +1,118 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Internal event bus: the handlers publish what happened to the boxes, and
+/// the integrations subscribe to it, rather than being called one by one
+/// from every handler which registers or evicts a box.
+///
+/// Subscribers are set up once at startup, and called in turn from the
+/// thread which published the event, so they shouldn't block: the push
+/// notifier, the only subscriber so far, sends its notifications from a
+/// thread of its own. Failures of a subscriber are its own to log, and
+/// never fail the request.
+
+use config::Config;
+use db::Record;
+use std::fmt;
+use storage::Storage;
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A box registered, with its registration from before if it had one.
+    Registered { previous: Option<Record>, record: Record },
+    /// The registration of a box was modified in place.
+    Updated { previous: Option<Record>, record: Record },
+    /// The registration of `client` from `public_ip` expired, `public_ip`
+    /// being hashed in privacy mode.
+    Evicted { public_ip: String, client: String },
+}
+
+pub trait Subscriber: Send + Sync {
+    /// The name of the subscriber, for the logs.
+    fn name(&self) -> &'static str;
+
+    /// Whether the events should carry the previous registration of the
+    /// boxes, which costs a read per box.
+    fn wants_previous(&self, _config: &Config) -> bool {
+        false
+    }
+
+    fn handle(&self, config: &Config, db: &Storage, event: &Event);
+}
+
+/// The subscribers, in the order they are called.
+#[derive(Default)]
+pub struct Bus {
+    subscribers: Vec<Box<Subscriber>>,
+}
+
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = self.subscribers.iter().map(|subscriber| subscriber.name())
+                                                      .collect();
+        write!(f, "Bus {{ subscribers: {:?} }}", names)
+    }
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus::default()
+    }
+
+    pub fn with(mut self, subscriber: Box<Subscriber>) -> Bus {
+        self.subscribers.push(subscriber);
+        self
+    }
+
+    /// Whether one of the subscribers wants the previous registrations.
+    pub fn wants_previous(&self, config: &Config) -> bool {
+        self.subscribers.iter().any(|subscriber| subscriber.wants_previous(config))
+    }
+
+    pub fn publish(&self, config: &Config, db: &Storage, event: &Event) {
+        for subscriber in &self.subscribers {
+            subscriber.handle(config, db, event);
+        }
+    }
+
+    /// Publish the eviction of these clients, as returned by
+    /// `Db::evict_clients`.
+    pub fn publish_evictions(&self, config: &Config, db: &Storage,
+                             evicted: Vec<(String, String)>) {
+        for (public_ip, client) in evicted {
+            self.publish(config, db, &Event::Evicted { public_ip: public_ip, client: client });
+        }
+    }
+}
+
+#[test]
+fn test_publish() {
+    use super::test_server::test_config;
+    use std::sync::{ Arc, Mutex };
+    use storage::MockStorage;
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Subscriber for Recorder {
+        fn name(&self) -> &'static str { "recorder" }
+
+        fn handle(&self, _: &Config, _: &Storage, event: &Event) {
+            if let Event::Evicted { ref client, .. } = *event {
+                self.0.lock().unwrap().push(client.clone());
+            }
+        }
+    }
+
+    let config = test_config(6379);
+    let db = MockStorage::new();
+    let received = Arc::new(Mutex::new(vec![]));
+    let bus = Bus::new().with(Box::new(Recorder(received.clone())))
+                        .with(Box::new(Recorder(received.clone())));
+    assert!(!bus.wants_previous(&config));
+    assert_eq!(format!("{:?}", bus), r#"Bus { subscribers: ["recorder", "recorder"] }"#);
+
+    let event = Event::Evicted { public_ip: "1.2.3.4".to_owned(), client: "abcd".to_owned() };
+    bus.publish(&config, &db, &event);
+    assert_eq!(*received.lock().unwrap(), vec!["abcd", "abcd"]);
+}
@@ -0,0 +1,258 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Export of the records for offline analysis or migrations, either as
+/// JSON Lines (one JSON record per line) or as CSV with a header line, and
+/// parsing of these exports for the import tool.
+
+use db::Record;
+use iron::response::{ ResponseBody, WriteBody };
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{ self, Json };
+use std::io::{ self, Write };
+use std::mem;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    JsonLines,
+    Csv,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "jsonl" => Some(Format::JsonLines),
+            "csv" => Some(Format::Csv),
+            _ => None
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            Format::JsonLines => "application/x-ndjson",
+            Format::Csv => "text/csv",
+        }
+    }
+}
+
+static CSV_HEADER: &'static str = "public_ip,client,message,first_seen,last_seen";
+
+/// Header of the exports made before first_seen and last_seen existed.
+static LEGACY_CSV_HEADER: &'static str = "public_ip,client,message,timestamp";
+
+/// Quote a CSV field if it contains a separator, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') ||
+       field.contains('\n') || field.contains('\r') {
+        format!("\"{}\"", field.replace("\"", "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Write the records to `out`, one per line.
+pub fn write_records<W: Write>(records: &[Record],
+                               format: Format,
+                               out: &mut W) -> io::Result<()> {
+    if format == Format::Csv {
+        try!(writeln!(out, "{}", CSV_HEADER));
+    }
+
+    for record in records {
+        match format {
+            Format::JsonLines => {
+                let line = try!(json::encode(record).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, e)
+                }));
+                try!(writeln!(out, "{}", line));
+            },
+            Format::Csv => {
+                try!(writeln!(out, "{},{},{},{},{}",
+                              csv_field(&record.public_ip),
+                              csv_field(&record.client),
+                              csv_field(&record.message),
+                              record.first_seen,
+                              record.last_seen));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Give a JSON record written before first_seen and last_seen existed both
+/// fields, set to its timestamp, and a revision if it doesn't have one.
+pub fn upgrade_legacy_record(record: &mut Json) {
+    if let Json::Object(ref mut object) = *record {
+        if let Some(timestamp) = object.remove("timestamp") {
+            object.entry("first_seen".to_owned()).or_insert(timestamp.clone());
+            object.entry("last_seen".to_owned()).or_insert(timestamp);
+        }
+        object.entry("revision".to_owned()).or_insert(Json::U64(0));
+    }
+}
+
+fn decode_record(line: &str) -> Result<Record, String> {
+    let mut record = try!(Json::from_str(line).map_err(|e| e.to_string()));
+    upgrade_legacy_record(&mut record);
+    Record::decode(&mut json::Decoder::new(record)).map_err(|e| e.to_string())
+}
+
+/// Split CSV content into rows of fields, handling quoted fields.
+fn parse_csv(content: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                },
+                '"' => quoted = false,
+                _ => field.push(c)
+            }
+            continue;
+        }
+
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => row.push(mem::replace(&mut field, String::new())),
+            '\r' => {},
+            '\n' => {
+                row.push(mem::replace(&mut field, String::new()));
+                rows.push(mem::replace(&mut row, Vec::new()));
+            },
+            _ => field.push(c)
+        }
+    }
+
+    if quoted {
+        return Err("unterminated quoted field".to_owned());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+/// Parse an export back into records. Errors mention the offending line.
+pub fn read_records(content: &str, format: Format) -> Result<Vec<Record>, String> {
+    match format {
+        Format::JsonLines => {
+            content.lines().enumerate()
+                   .filter(|&(_, line)| !line.trim().is_empty())
+                   .map(|(index, line)| {
+                       decode_record(line).map_err(|e| {
+                           format!("line {}: {}", index + 1, e)
+                       })
+                   })
+                   .collect()
+        },
+        Format::Csv => {
+            let rows = try!(parse_csv(content));
+            let mut rows = rows.into_iter().enumerate();
+            let fields = match rows.next() {
+                Some((_, ref header)) if header.join(",") == CSV_HEADER => 5,
+                Some((_, ref header)) if header.join(",") == LEGACY_CSV_HEADER => 4,
+                _ => return Err("line 1: missing CSV header".to_owned())
+            };
+
+            rows.map(|(index, row)| {
+                if row.len() != fields {
+                    return Err(format!("row {}: expected {} fields",
+                                       index + 1, fields));
+                }
+                let time = |field: &String| field.parse().map_err(|_| {
+                    format!("row {}: invalid time", index + 1)
+                });
+                let first_seen = try!(time(&row[3]));
+                let last_seen = if fields == 5 {
+                    try!(time(&row[4]))
+                } else {
+                    first_seen
+                };
+                Ok(Record {
+                    public_ip: row[0].clone(),
+                    client: row[1].clone(),
+                    message: row[2].clone(),
+                    first_seen: first_seen,
+                    last_seen: last_seen,
+                    // The stored revision keeps counting from where it is.
+                    revision: 0,
+                    local_ip: None,
+                    encrypted: None,
+                    nat: None,
+                    verified: None
+                })
+            }).collect()
+        }
+    }
+}
+
+/// Response body writing the records as they are encoded, rather than
+/// building the whole export in memory first.
+pub struct ExportBody {
+    pub records: Vec<Record>,
+    pub format: Format,
+}
+
+impl WriteBody for ExportBody {
+    fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
+        write_records(&self.records, self.format, res)
+    }
+}
+
+#[test]
+fn test_write_records() {
+    let records = vec![
+        Record {
+            public_ip: "127.0.0.1".to_owned(),
+            client: "<fingerprint>".to_owned(),
+            message: "a \"quoted\", message".to_owned(),
+            first_seen: 42,
+            last_seen: 43,
+            revision: 3,
+            local_ip: None,
+            encrypted: None,
+            nat: None,
+            verified: None
+        }
+    ];
+
+    let mut out = Vec::new();
+    write_records(&records, Format::Csv, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out,
+               "public_ip,client,message,first_seen,last_seen\n\
+                127.0.0.1,<fingerprint>,\"a \"\"quoted\"\", message\",42,43\n");
+    let read = read_records(&out, Format::Csv).unwrap();
+    assert_eq!(read.len(), 1);
+    assert_eq!(read[0].message, records[0].message);
+    assert_eq!(read[0].first_seen, 42);
+    assert_eq!(read[0].last_seen, 43);
+
+    let read = read_records("public_ip,client,message,timestamp\n\
+                             127.0.0.1,<fingerprint>,<message>,42\n",
+                            Format::Csv).unwrap();
+    assert_eq!(read[0].first_seen, 42);
+    assert_eq!(read[0].last_seen, 42);
+
+    let mut out = Vec::new();
+    write_records(&records, Format::JsonLines, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().count(), 1);
+    let read = read_records(&out, Format::JsonLines).unwrap();
+    assert_eq!(read.len(), 1);
+    assert_eq!(read[0].message, records[0].message);
+
+    assert!(read_records("127.0.0.1,a,b,1\n", Format::Csv).is_err());
+    assert!(read_records("{}\n", Format::JsonLines).is_err());
+}
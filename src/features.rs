@@ -0,0 +1,153 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Feature flags turning the optional subsystems off and on at runtime,
+/// through the admin API, without restarting the server.
+///
+/// Only the subsystems configured at startup can be turned on: the accounts
+/// need `--accounts`, and the push notifications a destination. The
+/// endpoints of a disabled subsystem answer with a 503.
+
+use errors::*;
+use iron::BeforeMiddleware;
+use iron::prelude::*;
+use iron::status;
+use std::collections::BTreeMap;
+use std::sync::{ Arc, Mutex };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Feature {
+    /// User accounts, under /v1/account.
+    Accounts,
+    /// Push subscriptions, and the notifications sent on registration.
+    Push,
+    /// Pairing codes and QR codes.
+    Pairing,
+}
+
+impl Feature {
+    pub fn all() -> Vec<Feature> {
+        vec![Feature::Accounts, Feature::Push, Feature::Pairing]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Feature::Accounts => "accounts",
+            Feature::Push => "push",
+            Feature::Pairing => "pairing",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Feature> {
+        Feature::all().into_iter().find(|feature| feature.name() == name)
+    }
+
+    /// The feature serving a request path, if it belongs to one.
+    fn of_path(path: &[&str]) -> Option<Feature> {
+        if path.len() < 2 || path[0] != "v1" {
+            return None;
+        }
+        match path[1] {
+            "account" => Some(Feature::Accounts),
+            "push" => Some(Feature::Push),
+            "pairing" => Some(Feature::Pairing),
+            "box" if path.len() == 4 && path[3] == "qr" => Some(Feature::Pairing),
+            _ => None
+        }
+    }
+}
+
+/// Whether each of the configured features is enabled, shared by the
+/// handlers and the admin endpoint toggling them.
+#[derive(Debug, Default)]
+pub struct Features {
+    /// Only the features configured at startup have an entry.
+    enabled: Mutex<BTreeMap<Feature, bool>>,
+}
+
+impl Features {
+    /// Enable the `available` features, except the `disabled` ones.
+    pub fn new(available: &[Feature], disabled: &[Feature]) -> Features {
+        Features {
+            enabled: Mutex::new(available.iter().map(|feature| {
+                (*feature, !disabled.contains(feature))
+            }).collect()),
+        }
+    }
+
+    /// Whether a feature was configured at startup, and can be turned on.
+    pub fn is_available(&self, feature: Feature) -> bool {
+        self.enabled.lock().unwrap().contains_key(&feature)
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.enabled.lock().unwrap().get(&feature).cloned().unwrap_or(false)
+    }
+
+    /// Turn a feature on or off, returning false if it isn't configured.
+    pub fn set(&self, feature: Feature, enabled: bool) -> bool {
+        match self.enabled.lock().unwrap().get_mut(&feature) {
+            Some(state) => {
+                *state = enabled;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Every feature by name, with whether it is enabled.
+    pub fn snapshot(&self) -> BTreeMap<String, bool> {
+        Feature::all().into_iter().map(|feature| {
+            (feature.name().to_owned(), self.is_enabled(feature))
+        }).collect()
+    }
+}
+
+/// Parse a comma-separated list of feature names, as given on the command
+/// line.
+pub fn parse_list(list: &str) -> Result<Vec<Feature>, String> {
+    list.split(',').map(str::trim).filter(|name| !name.is_empty()).map(|name| {
+        Feature::from_name(name).ok_or_else(|| format!("Unknown feature {}", name))
+    }).collect()
+}
+
+/// Rejects the requests to the endpoints of the disabled features.
+pub struct Gate {
+    pub features: Arc<Features>,
+}
+
+impl BeforeMiddleware for Gate {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match Feature::of_path(&req.url.path()) {
+            Some(feature) if !self.features.is_enabled(feature) => {
+                Err(EndpointError::build(status::ServiceUnavailable, ErrNo::FeatureDisabled,
+                                         None,
+                                         Some(format!("The {} feature is disabled",
+                                                      feature.name()))))
+            },
+            _ => Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_features() {
+    assert_eq!(parse_list("push, accounts"), Ok(vec![Feature::Push, Feature::Accounts]));
+    assert!(parse_list("tunnels").is_err());
+
+    assert_eq!(Feature::of_path(&["v1", "account", "boxes"]), Some(Feature::Accounts));
+    assert_eq!(Feature::of_path(&["v1", "box", "abcd", "qr"]), Some(Feature::Pairing));
+    assert_eq!(Feature::of_path(&["v1", "box", "abcd"]), None);
+    assert_eq!(Feature::of_path(&["ping"]), None);
+
+    let features = Features::new(&[Feature::Push, Feature::Pairing], &[Feature::Pairing]);
+    assert!(features.is_enabled(Feature::Push));
+    assert!(!features.is_enabled(Feature::Pairing));
+    assert!(!features.is_enabled(Feature::Accounts));
+    assert!(!features.is_available(Feature::Accounts));
+    assert!(features.set(Feature::Pairing, true));
+    assert!(features.is_enabled(Feature::Pairing));
+    assert!(!features.set(Feature::Accounts, true));
+    assert!(!features.is_enabled(Feature::Accounts));
+}
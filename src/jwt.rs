@@ -0,0 +1,290 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Signed tokens: with `--jwt-keys`, the box, session and guest tokens are
+/// JSON Web Tokens signed with HMAC-SHA256 (HS256), so that the other
+/// services of a deployment sharing the keys can check them, and tell whose
+/// they are, without calling this server.
+///
+/// The tokens carry the standard `iss`, `sub`, `iat`, `exp` and `jti`
+/// claims, along with a `scope`: "box" for the tokens of a box, whose
+/// subject is its fingerprint, "user" for the sessions, whose subject is
+/// the email of the user, and "guest" for the guest tokens, whose subject
+/// is the fingerprint of the box they give access to. Their header names
+/// the key which signed them in `kid`.
+///
+/// Keys are given as a comma-separated list of `<kid>:<secret>`. The first
+/// one signs the new tokens, and all of them are accepted, so that keys are
+/// rotated by putting a new key first and dropping the old one once the
+/// tokens it signed have expired.
+///
+/// The server still checks the tokens against the database as well, which
+/// keeps rotation, revocation and logging out working; it rejects the
+/// tokens which are expired or aren't signed with one of its keys upfront.
+///
+/// ES256 isn't implemented, since it would need an ECDSA implementation,
+/// which neither rust-crypto nor the OpenSSL bindings of the server have:
+/// `--jwt-algorithm ES256` refuses to start, and tokens whose header asks
+/// for it are rejected with `ES256_UNSUPPORTED`.
+
+use clock::Clock;
+use crypto::hmac::Hmac;
+use crypto::mac::{ Mac, MacResult };
+use crypto::sha2::Sha256;
+use errors::*;
+use iron::BeforeMiddleware;
+use iron::prelude::*;
+use iron::status;
+use rustc_serialize::base64::{ FromBase64, ToBase64, URL_SAFE };
+use rustc_serialize::json;
+use std::fmt;
+use std::sync::Arc;
+use tokens;
+
+/// The only signature algorithm supported.
+pub static ALGORITHM: &'static str = "HS256";
+/// Why ES256 is refused, both on the command line and in token headers.
+pub static ES256_UNSUPPORTED: &'static str =
+    "ES256 isn't supported: it needs an ECDSA implementation, which the server lacks";
+/// The scope of the tokens of a box.
+pub static BOX_SCOPE: &'static str = "box";
+/// The scope of the session tokens of a user.
+pub static USER_SCOPE: &'static str = "user";
+/// The scope of the guest tokens of a box.
+pub static GUEST_SCOPE: &'static str = "guest";
+/// Box tokens expire after a day, after which boxes register again to get
+/// a new one, or rotate it before.
+pub static BOX_TOKEN_LIFETIME: u64 = 24 * 60 * 60;
+
+#[derive(Debug, PartialEq, RustcDecodable, RustcEncodable)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Claims {
+    pub iss: String,
+    /// The fingerprint of a box, or the email of a user.
+    pub sub: String,
+    /// What the token is for: `BOX_SCOPE`, `USER_SCOPE` or `GUEST_SCOPE`.
+    pub scope: String,
+    pub iat: u64,
+    pub exp: u64,
+    /// Unique identifier of the token.
+    pub jti: String,
+}
+
+/// The signing keys, and the issuer of the tokens.
+#[derive(Clone, PartialEq)]
+pub struct Keys {
+    pub issuer: String,
+    /// Each key by id, the one signing the new tokens first.
+    keys: Vec<(String, String)>,
+}
+
+// Leaves the secrets out of the logged configuration.
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ids: Vec<&str> = self.keys.iter().map(|&(ref id, _)| &id[..]).collect();
+        write!(f, "Keys {{ issuer: {:?}, ids: {:?} }}", self.issuer, ids)
+    }
+}
+
+/// Check the algorithm given with `--jwt-algorithm`.
+pub fn check_algorithm(name: &str) -> Result<(), String> {
+    if name == ALGORITHM {
+        Ok(())
+    } else if name == "ES256" {
+        Err(ES256_UNSUPPORTED.to_owned())
+    } else {
+        Err(format!("Unsupported JWT algorithm {}, expected {}", name, ALGORITHM))
+    }
+}
+
+fn signature(secret: &str, input: &str) -> MacResult {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(input.as_bytes());
+    hmac.result()
+}
+
+impl Keys {
+    /// Parse a comma-separated list of `<kid>:<secret>`, as given on the
+    /// command line.
+    pub fn parse(list: &str, issuer: &str) -> Result<Keys, String> {
+        let mut keys: Vec<(String, String)> = vec![];
+        for key in list.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+            let mut parts = key.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(id), Some(secret)) if !id.is_empty() && !secret.is_empty() => {
+                    if keys.iter().any(|&(ref other, _)| other == id) {
+                        return Err(format!("The JWT key id {} is used twice", id));
+                    }
+                    keys.push((id.to_owned(), secret.to_owned()));
+                },
+                _ => return Err(format!("Invalid JWT key {}, expected <kid>:<secret>", key))
+            }
+        }
+        if keys.is_empty() {
+            return Err("Expected at least one JWT key".to_owned());
+        }
+        Ok(Keys {
+            issuer: issuer.to_owned(),
+            keys: keys,
+        })
+    }
+
+    /// A new token of `scope` for `subject`, valid for `lifetime` seconds
+    /// from `now`.
+    pub fn issue(&self, subject: &str, scope: &str, lifetime: u64, now: u64) -> String {
+        self.sign(&Claims {
+            iss: self.issuer.clone(),
+            sub: subject.to_owned(),
+            scope: scope.to_owned(),
+            iat: now,
+            exp: now + lifetime,
+            jti: tokens::generate(),
+        })
+    }
+
+    /// Sign `claims` with the current key.
+    pub fn sign(&self, claims: &Claims) -> String {
+        let (ref id, ref secret) = self.keys[0];
+        let header = Header {
+            alg: ALGORITHM.to_owned(),
+            typ: "JWT".to_owned(),
+            kid: id.clone(),
+        };
+        let input = format!("{}.{}",
+                            json::encode(&header).unwrap().as_bytes().to_base64(URL_SAFE),
+                            json::encode(claims).unwrap().as_bytes().to_base64(URL_SAFE));
+        let signature = signature(secret, &input).code().to_base64(URL_SAFE);
+        format!("{}.{}", input, signature)
+    }
+
+    /// The claims of a token, if one of the keys signed it, it was issued
+    /// by this issuer and it didn't expire at `now`.
+    pub fn verify(&self, token: &str, now: u64) -> Result<Claims, String> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err("Not a JWT".to_owned());
+        }
+        let decode = |part: &str| -> Result<String, String> {
+            let bytes = try!(part.from_base64().map_err(|_| "Invalid base64".to_owned()));
+            String::from_utf8(bytes).map_err(|_| "Invalid UTF-8".to_owned())
+        };
+        let header: Header = try!(json::decode(&try!(decode(parts[0])))
+                                      .map_err(|_| "Invalid header".to_owned()));
+        try!(check_algorithm(&header.alg));
+        let secret = match self.keys.iter().find(|&&(ref id, _)| *id == header.kid) {
+            Some(&(_, ref secret)) => secret,
+            None => return Err(format!("Unknown key {}", header.kid))
+        };
+        let given = try!(parts[2].from_base64().map_err(|_| "Invalid signature".to_owned()));
+        let input = format!("{}.{}", parts[0], parts[1]);
+        // MacResult compares in constant time.
+        if signature(secret, &input) != MacResult::new(&given) {
+            return Err("Invalid signature".to_owned());
+        }
+
+        let claims: Claims = try!(json::decode(&try!(decode(parts[1])))
+                                      .map_err(|_| "Invalid claims".to_owned()));
+        if claims.iss != self.issuer {
+            return Err(format!("Unknown issuer {}", claims.iss));
+        }
+        if claims.exp <= now {
+            return Err("Expired".to_owned());
+        }
+        Ok(claims)
+    }
+}
+
+/// A new token: a JWT when `jwt` has keys, a random token otherwise.
+pub fn issue(jwt: Option<&Keys>, subject: &str, scope: &str, lifetime: u64, now: u64)
+    -> String {
+    match jwt {
+        Some(keys) => keys.issue(subject, scope, lifetime, now),
+        None => tokens::generate()
+    }
+}
+
+/// Rejects the requests whose bearer token is a JWT which isn't valid, e.g.
+/// expired or signed with a dropped key. Other tokens go through, to be
+/// checked by the endpoints. The admin API is left out.
+pub struct Check {
+    pub keys: Keys,
+    pub clock: Arc<Clock>,
+}
+
+impl BeforeMiddleware for Check {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if req.url.path().first() == Some(&"admin") {
+            return Ok(());
+        }
+        let token = match tokens::bearer(req) {
+            Some(ref token) if token.split('.').count() == 3 => token.clone(),
+            _ => return Ok(())
+        };
+        match self.keys.verify(&token, self.clock.now()) {
+            Ok(_) => Ok(()),
+            Err(reason) => {
+                info!("Rejecting a JWT: {}", reason);
+                Err(EndpointError::build(status::Unauthorized, ErrNo::Unauthorized,
+                                         None, None))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_jwt() {
+    let keys = Keys::parse("2:new, 1:old", "fxbox").unwrap();
+    let token = keys.issue("abcd", BOX_SCOPE, 60, 1481900000);
+    assert_eq!(token.split('.').count(), 3);
+    let claims = keys.verify(&token, 1481900010).unwrap();
+    assert_eq!(claims.sub, "abcd");
+    assert_eq!(claims.scope, BOX_SCOPE);
+    assert_eq!(claims.iss, "fxbox");
+    assert_eq!((claims.iat, claims.exp), (1481900000, 1481900060));
+    assert!(keys.verify(&token, 1481900060).is_err());
+
+    // Tokens signed with the previous key stay valid until it is dropped.
+    let previous = Keys::parse("1:old", "fxbox").unwrap().issue("abcd", BOX_SCOPE, 60, 0);
+    assert!(keys.verify(&previous, 10).is_ok());
+    assert!(Keys::parse("2:new", "fxbox").unwrap().verify(&previous, 10).is_err());
+    assert!(Keys::parse("1:old", "other").unwrap().verify(&previous, 10).is_err());
+    assert!(Keys::parse("1:other", "fxbox").unwrap().verify(&previous, 10).is_err());
+
+    // Changing the claims breaks the signature.
+    let parts: Vec<&str> = token.split('.').collect();
+    let forged = Claims { sub: "efgh".to_owned(), .. claims };
+    let forged = format!("{}.{}.{}", parts[0],
+                         json::encode(&forged).unwrap().as_bytes().to_base64(URL_SAFE),
+                         parts[2]);
+    assert!(keys.verify(&forged, 1481900010).is_err());
+    assert!(keys.verify("abcd", 0).is_err());
+
+    assert!(Keys::parse("", "fxbox").is_err());
+    assert!(Keys::parse("nosecret", "fxbox").is_err());
+    assert!(Keys::parse("1:a,1:b", "fxbox").is_err());
+    assert_eq!(issue(None, "abcd", BOX_SCOPE, 60, 0).len(), 32);
+}
+
+#[test]
+fn test_es256() {
+    assert!(check_algorithm("HS256").is_ok());
+    assert_eq!(check_algorithm("ES256"), Err(ES256_UNSUPPORTED.to_owned()));
+    assert!(check_algorithm("none").is_err());
+
+    // A token claiming ES256 is rejected before looking at its signature.
+    let keys = Keys::parse("1:secret", "fxbox").unwrap();
+    let token = keys.issue("abcd", BOX_SCOPE, 60, 0);
+    let header = Header { alg: "ES256".to_owned(), typ: "JWT".to_owned(), kid: "1".to_owned() };
+    let parts: Vec<&str> = token.split('.').collect();
+    let forged = format!("{}.{}.{}",
+                         json::encode(&header).unwrap().as_bytes().to_base64(URL_SAFE),
+                         parts[1], parts[2]);
+    assert_eq!(keys.verify(&forged, 10), Err(ES256_UNSUPPORTED.to_owned()));
+}
@@ -0,0 +1,76 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Homograph checks of the friendly subdomains: on top of being DNS labels,
+/// which `validation::subdomain` checks, the names accounts reserve mustn't
+/// look like the `RESERVED` names of the service or the names blocked with
+/// `--blocked-subdomains`, e.g. "adm1n" or "rnail".
+///
+/// Names are compared by their skeleton, where the characters which are
+/// easily mistaken for one another map to the same one, as in Unicode's
+/// confusables. Subdomains are ASCII, so only the ASCII lookalikes matter,
+/// and punycode labels, which could hide any Unicode lookalike, are
+/// refused altogether.
+
+/// The names of the service, which no account can reserve, nor anything
+/// looking like them.
+pub static RESERVED: [&'static str; 14] = ["www", "api", "admin", "account", "accounts", "auth",
+                                           "login", "mail", "ns", "root", "static", "status",
+                                           "support", "tunnel"];
+
+/// Parse a comma-separated list of blocked names, as given on the command
+/// line.
+pub fn parse_blocklist(list: &str) -> Vec<String> {
+    list.split(',').map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// The skeleton of a lowercase label: hyphens are dropped, "rn" becomes
+/// "m", "vv" becomes "w", and the digits become the letter they look like.
+pub fn skeleton(label: &str) -> String {
+    let label = label.replace("-", "").replace("rn", "m").replace("vv", "w");
+    label.chars().map(|c| match c {
+        '0' => 'o',
+        '1' | 'i' => 'l',
+        '3' => 'e',
+        '4' => 'a',
+        '5' => 's',
+        '6' => 'b',
+        '7' => 't',
+        '8' => 'b',
+        '9' => 'g',
+        c => c
+    }).collect()
+}
+
+/// The reserved or blocked name `label` is, or looks like, if any.
+pub fn lookalike(label: &str, blocklist: &[String]) -> Option<String> {
+    if label.starts_with("xn--") {
+        return Some(label.to_owned());
+    }
+    let target = skeleton(label);
+    RESERVED.iter().map(|name| *name)
+            .chain(blocklist.iter().map(|name| &name[..]))
+            .find(|name| skeleton(name) == target)
+            .map(str::to_owned)
+}
+
+#[test]
+fn test_lookalike() {
+    let blocklist = parse_blocklist(" Paypal, ,bank ");
+    assert_eq!(blocklist, vec!["paypal".to_owned(), "bank".to_owned()]);
+
+    assert_eq!(lookalike("admin", &[]), Some("admin".to_owned()));
+    assert_eq!(lookalike("adm1n", &[]), Some("admin".to_owned()));
+    assert_eq!(lookalike("rnail", &[]), Some("mail".to_owned()));
+    assert_eq!(lookalike("vvvvw", &[]), Some("www".to_owned()));
+    assert_eq!(lookalike("ro0t", &[]), Some("root".to_owned()));
+    assert_eq!(lookalike("a-p-i", &[]), Some("api".to_owned()));
+    assert_eq!(lookalike("xn--80ak6aa92e", &[]), Some("xn--80ak6aa92e".to_owned()));
+    assert_eq!(lookalike("paypa1", &blocklist), Some("paypal".to_owned()));
+    assert_eq!(lookalike("paypa1", &[]), None);
+    assert_eq!(lookalike("kitchen", &blocklist), None);
+    assert_eq!(lookalike("administrator", &[]), None);
+}
@@ -0,0 +1,234 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Load generation against a running server, for capacity planning:
+/// simulated boxes register and ping at regular intervals, and the rates
+/// and latencies of both are reported at the end.
+///
+/// The boxes are shared between a fixed number of connections, each served
+/// by its own thread, so that thousands of boxes can be simulated.
+
+use hyper::Client;
+use hyper::header::{ ContentType, Headers };
+use std::cmp;
+use std::fmt;
+use std::io::Read;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Number of simulated boxes.
+    pub boxes: usize,
+    /// Seconds between two registrations of a box.
+    pub register_interval: u64,
+    /// Seconds between two pings of a box.
+    pub ping_interval: u64,
+    /// Seconds the test runs for.
+    pub duration: u64,
+    /// Number of concurrent connections to the server.
+    pub connections: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Latencies of the successful requests, in microseconds, sorted once
+    /// the test is over.
+    latencies: Vec<u64>,
+    errors: usize,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.latencies.extend(other.latencies);
+        self.errors += other.errors;
+    }
+
+    pub fn requests(&self) -> usize {
+        self.latencies.len() + self.errors
+    }
+
+    /// The latency under which `percent` of the successful requests were
+    /// answered, in microseconds.
+    pub fn percentile(&self, percent: usize) -> u64 {
+        if self.latencies.is_empty() {
+            return 0;
+        }
+        let rank = (self.latencies.len() * percent + 99) / 100;
+        self.latencies[cmp::max(rank, 1) - 1]
+    }
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub register: Stats,
+    pub ping: Stats,
+    pub elapsed: Duration,
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_nanos() as u64 / 1000
+}
+
+fn write_stats(f: &mut fmt::Formatter, name: &str, stats: &Stats, elapsed: Duration)
+    -> fmt::Result {
+    let ms = |micros: u64| micros as f64 / 1000.0;
+    let seconds = micros(elapsed) as f64 / 1_000_000.0;
+    writeln!(f, "{}: {} requests, {} errors, {:.1} requests/s, \
+                 latency p50 {:.1}ms p95 {:.1}ms p99 {:.1}ms max {:.1}ms",
+             name, stats.requests(), stats.errors,
+             stats.requests() as f64 / seconds,
+             ms(stats.percentile(50)), ms(stats.percentile(95)),
+             ms(stats.percentile(99)), ms(stats.percentile(100)))
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write_stats(f, "register", &self.register, self.elapsed));
+        write_stats(f, "ping", &self.ping, self.elapsed)
+    }
+}
+
+struct SimulatedBox {
+    body: String,
+    /// When the next requests are due, in microseconds since the start.
+    next_register: u64,
+    next_ping: u64,
+}
+
+fn send(client: &Client, url: &str, body: Option<&str>) -> Result<(), String> {
+    let request = match body {
+        Some(body) => {
+            let mut headers = Headers::new();
+            headers.set(ContentType::json());
+            client.post(url).headers(headers).body(body)
+        },
+        None => client.get(url)
+    };
+    let mut response = try!(request.send().map_err(|e| e.to_string()));
+    // Reading the whole response lets the connection be reused.
+    let mut content = String::new();
+    try!(response.read_to_string(&mut content).map_err(|e| e.to_string()));
+    if !response.status.is_success() {
+        return Err(response.status.to_string());
+    }
+    Ok(())
+}
+
+/// Simulate the boxes `first`, `first + step`, `first + 2 * step`... on
+/// one connection.
+fn simulate(url: &str, settings: &Settings, first: usize, step: usize, start: Instant)
+    -> (Stats, Stats) {
+    let register_interval = settings.register_interval * 1_000_000;
+    let ping_interval = settings.ping_interval * 1_000_000;
+    let end = settings.duration * 1_000_000;
+
+    // Spread the boxes over the intervals rather than starting them all
+    // at once.
+    let mut boxes: Vec<SimulatedBox> = (0..settings.boxes).filter(|index| {
+        index % step == first
+    }).map(|index| {
+        let offset = |interval: u64| interval * index as u64 / settings.boxes as u64;
+        SimulatedBox {
+            body: format!(r#"{{"client": "loadtest-{}", "message": "loadtest box {}"}}"#,
+                          index, index),
+            next_register: offset(register_interval),
+            next_ping: offset(register_interval) + offset(ping_interval),
+        }
+    }).collect();
+
+    let register_url = format!("{}/register", url);
+    let ping_url = format!("{}/ping", url);
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(30)));
+    let (mut registers, mut pings) = (Stats::default(), Stats::default());
+
+    loop {
+        let next = boxes.iter_mut().min_by_key(|simulated| {
+            cmp::min(simulated.next_register, simulated.next_ping)
+        });
+        let simulated = match next {
+            Some(simulated) => simulated,
+            None => break
+        };
+        let register = simulated.next_register <= simulated.next_ping;
+        let due = cmp::min(simulated.next_register, simulated.next_ping);
+        if due >= end {
+            break;
+        }
+
+        let now = micros(start.elapsed());
+        if due > now {
+            thread::sleep(Duration::from_millis((due - now) / 1000));
+        }
+
+        let sent = Instant::now();
+        let (result, stats) = if register {
+            simulated.next_register += register_interval;
+            (send(&client, &register_url, Some(&simulated.body)), &mut registers)
+        } else {
+            simulated.next_ping += ping_interval;
+            (send(&client, &ping_url, None), &mut pings)
+        };
+        match result {
+            Ok(()) => stats.latencies.push(micros(sent.elapsed())),
+            Err(e) => {
+                debug!("Load test request failed: {}", e);
+                stats.errors += 1;
+            }
+        }
+    }
+
+    (registers, pings)
+}
+
+/// Run the load test against the server at `url`, e.g.
+/// `http://localhost:4242`, and return once `settings.duration` is over.
+pub fn run(url: &str, settings: &Settings) -> Result<Report, String> {
+    if settings.boxes == 0 || settings.connections == 0 {
+        return Err("The load test needs at least one box and one connection".to_owned());
+    }
+    if settings.register_interval == 0 || settings.ping_interval == 0 {
+        return Err("The load test intervals must be at least 1 second".to_owned());
+    }
+
+    let url = url.trim_right_matches('/').to_owned();
+    let connections = cmp::min(settings.connections, settings.boxes);
+    let start = Instant::now();
+
+    let threads: Vec<_> = (0..connections).map(|first| {
+        let (url, settings) = (url.clone(), settings.clone());
+        thread::spawn(move || simulate(&url, &settings, first, connections, start))
+    }).collect();
+
+    let (mut register, mut ping) = (Stats::default(), Stats::default());
+    for thread in threads {
+        let (registers, pings) = try!(thread.join().map_err(|_| {
+            "A load test thread panicked".to_owned()
+        }));
+        register.merge(registers);
+        ping.merge(pings);
+    }
+    register.latencies.sort();
+    ping.latencies.sort();
+
+    Ok(Report {
+        register: register,
+        ping: ping,
+        elapsed: start.elapsed(),
+    })
+}
+
+#[test]
+fn test_percentile() {
+    let stats = Stats {
+        latencies: (1..101).collect(),
+        errors: 3,
+    };
+    assert_eq!(stats.requests(), 103);
+    assert_eq!(stats.percentile(50), 50);
+    assert_eq!(stats.percentile(99), 99);
+    assert_eq!(stats.percentile(100), 100);
+    assert_eq!(Stats::default().percentile(50), 0);
+}
@@ -0,0 +1,270 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Log outputs, selected with `--log`:
+///   console => the default env_logger output, on stderr.
+///   syslog => the local syslog daemon, through /dev/log.
+///   syslog://<host>:<port> => a remote syslog server, over UDP.
+///   file:<path> => a file, rotated according to a `Rotation` policy.
+/// In every case the levels are filtered according to RUST_LOG.
+
+use env_logger::{ self, LogBuilder };
+use log::{ self, Log, LogLevel, LogMetadata, LogRecord };
+use std::env;
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, Write };
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+static SYSLOG_SOCKET: &'static str = "/dev/log";
+/// The `daemon` syslog facility.
+static SYSLOG_FACILITY: u8 = 3;
+static SYSLOG_TAG: &'static str = "registration_server";
+
+#[derive(Debug, PartialEq)]
+pub enum Target {
+    Console,
+    LocalSyslog,
+    RemoteSyslog(String),
+    File(PathBuf),
+}
+
+impl Target {
+    pub fn from_name(name: &str) -> Result<Target, String> {
+        match name {
+            "console" => Ok(Target::Console),
+            "syslog" => Ok(Target::LocalSyslog),
+            _ if name.starts_with("syslog://") && name.len() > 9 => {
+                Ok(Target::RemoteSyslog(name[9..].to_owned()))
+            },
+            _ if name.starts_with("file:") && name.len() > 5 => {
+                Ok(Target::File(PathBuf::from(&name[5..])))
+            },
+            _ => Err(format!("Unknown log target {}", name))
+        }
+    }
+}
+
+/// When log files are rotated, and how many rotated files are kept.
+#[derive(Clone, Copy, Debug)]
+pub struct Rotation {
+    /// Maximum size of a log file in bytes, 0 for no limit.
+    pub max_size: u64,
+    /// Maximum number of seconds a log file is written to, 0 for no limit.
+    pub max_age: u64,
+    /// Number of rotated files kept, as <path>.1 (the most recent) to
+    /// <path>.<keep>.
+    pub keep: usize,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    opened: u64,
+}
+
+impl LogFile {
+    fn open(path: &Path, rotation: Rotation) -> io::Result<LogFile> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        let size = try!(file.metadata()).len();
+        Ok(LogFile {
+            path: path.to_owned(),
+            rotation: rotation,
+            file: file,
+            size: size,
+            opened: now(),
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn needs_rotation(&self, now: u64) -> bool {
+        (self.rotation.max_size > 0 && self.size >= self.rotation.max_size) ||
+        (self.rotation.max_age > 0 && now >= self.opened + self.rotation.max_age)
+    }
+
+    /// Shift <path>.<n> to <path>.<n + 1>, dropping the oldest file, and
+    /// start a new file at <path>.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.rotation.keep == 0 {
+            try!(fs::remove_file(&self.path));
+        } else {
+            let oldest = self.rotated_path(self.rotation.keep);
+            if oldest.exists() {
+                try!(fs::remove_file(&oldest));
+            }
+            for index in (1..self.rotation.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    try!(fs::rename(&from, self.rotated_path(index + 1)));
+                }
+            }
+            try!(fs::rename(&self.path, self.rotated_path(1)));
+        }
+
+        let path = self.path.clone();
+        *self = try!(LogFile::open(&path, self.rotation));
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.needs_rotation(now()) {
+            try!(self.rotate());
+        }
+        try!(self.file.write_all(line.as_bytes()));
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+enum Output {
+    LocalSyslog(UnixDatagram),
+    RemoteSyslog(UdpSocket),
+    File(Mutex<LogFile>),
+}
+
+struct Logger {
+    filter: env_logger::Logger,
+    output: Output,
+}
+
+fn severity(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::Error => 3,
+        LogLevel::Warn => 4,
+        LogLevel::Info => 6,
+        LogLevel::Debug | LogLevel::Trace => 7,
+    }
+}
+
+/// Format a message the way syslog(3) does, letting the daemon add the
+/// timestamp and hostname.
+fn syslog_message(level: LogLevel, message: &str) -> String {
+    format!("<{}>{}: {}",
+            SYSLOG_FACILITY * 8 + severity(level), SYSLOG_TAG, message)
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.filter.matches(record) {
+            return;
+        }
+
+        let message = format!("{}: {}", record.location().module_path(),
+                              record.args());
+        // There is nowhere left to report logging failures.
+        let _ = match self.output {
+            Output::LocalSyslog(ref socket) => {
+                socket.send(syslog_message(record.level(), &message).as_bytes())
+                      .map(|_| ())
+            },
+            Output::RemoteSyslog(ref socket) => {
+                socket.send(syslog_message(record.level(), &message).as_bytes())
+                      .map(|_| ())
+            },
+            Output::File(ref file) => {
+                let line = format!("{} {} {}\n", now(), record.level(), message);
+                file.lock().unwrap().write(&line)
+            }
+        };
+    }
+}
+
+fn filter() -> env_logger::Logger {
+    let mut builder = LogBuilder::new();
+    if let Ok(spec) = env::var("RUST_LOG") {
+        builder.parse(&spec);
+    }
+    builder.build()
+}
+
+pub fn init(target: &Target, rotation: Rotation) -> Result<(), String> {
+    let output = match *target {
+        Target::Console => return env_logger::init().map_err(|e| e.to_string()),
+        Target::LocalSyslog => {
+            let socket = try!(UnixDatagram::unbound().and_then(|socket| {
+                try!(socket.connect(SYSLOG_SOCKET));
+                Ok(socket)
+            }).map_err(|e| format!("Can't connect to {}: {}", SYSLOG_SOCKET, e)));
+            Output::LocalSyslog(socket)
+        },
+        Target::RemoteSyslog(ref address) => {
+            let socket = try!(UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+                try!(socket.connect(&address[..]));
+                Ok(socket)
+            }).map_err(|e| format!("Can't connect to {}: {}", address, e)));
+            Output::RemoteSyslog(socket)
+        },
+        Target::File(ref path) => {
+            let file = try!(LogFile::open(path, rotation).map_err(|e| {
+                format!("Can't open {}: {}", path.display(), e)
+            }));
+            Output::File(Mutex::new(file))
+        }
+    };
+
+    let logger = Logger {
+        filter: filter(),
+        output: output,
+    };
+    log::set_logger(|max_level| {
+        max_level.set(logger.filter.filter());
+        Box::new(logger)
+    }).map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_syslog_message() {
+    assert_eq!(Target::from_name("syslog://logs:514"),
+               Ok(Target::RemoteSyslog("logs:514".to_owned())));
+    assert!(Target::from_name("syslog://").is_err());
+    assert!(Target::from_name("stderr").is_err());
+
+    assert_eq!(syslog_message(LogLevel::Error, "failed"),
+               "<27>registration_server: failed");
+    assert_eq!(syslog_message(LogLevel::Info, "started"),
+               "<30>registration_server: started");
+}
+
+#[test]
+fn test_rotation() {
+    let dir = env::temp_dir().join(format!("registration_server_logs_{}", now()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.log");
+    let rotation = Rotation { max_size: 10, max_age: 0, keep: 2 };
+
+    let mut file = LogFile::open(&path, rotation).unwrap();
+    for line in &["first line\n", "second line\n", "third line\n", "fourth line\n"] {
+        file.write(line).unwrap();
+    }
+
+    let read = |path: PathBuf| {
+        let mut content = String::new();
+        io::Read::read_to_string(&mut File::open(path).unwrap(), &mut content).unwrap();
+        content
+    };
+    assert_eq!(read(path.clone()), "fourth line\n");
+    assert_eq!(read(file.rotated_path(1)), "third line\n");
+    assert_eq!(read(file.rotated_path(2)), "second line\n");
+    assert!(!file.rotated_path(3).exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -2,71 +2,381 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+#![cfg_attr(test, feature(test))]
+
 /// Simple server that manages foxbox registrations.
-/// Two end points are available:
+/// The following end points are available:
 /// POST /register => to register a match between public IP and mesage.
+/// POST /v1/register/batch => to register several matches at once.
 /// GET /ping => to get the list of public IP matches.
+/// GET /v1/discovery => the same, rate limited, with minimal data.
+/// GET /v1/discovery/full => the same as /ping, with an API key.
+/// GET /v1/box/<fingerprint> => to get the latest registration of a box.
+/// POST /v1/box/<fingerprint>/token => to rotate the token of a box.
+/// POST /v1/box/<fingerprint>/mailbox => to leave a message for a box.
+/// PUT/POST /v1/box/<fingerprint>/candidates => to exchange NAT traversal
+/// candidates between a box and a client.
+/// GET /errors => to get the list of error numbers and their meaning.
+/// The admin API is mounted under /admin, see admin.rs.
 ///
 /// Boxes are supposed to register themselves at regular intervals so we
 /// discard data which is too old periodically.
 
 extern crate docopt;
+extern crate crypto;
 extern crate env_logger;
+extern crate hyper;
 extern crate iron;
 extern crate iron_cors;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate mount;
+extern crate net2;
+extern crate num_cpus;
 extern crate params;
+extern crate rand;
 extern crate redis;
+extern crate route_recognizer;
 extern crate router;
 extern crate rusqlite;
 extern crate rustc_serialize;
+#[cfg(test)]
+extern crate quickcheck;
+#[cfg(test)]
+extern crate test;
 
+use config::Config;
 use docopt::Docopt;
-use iron::{ Chain, Iron, Protocol };
-use iron::method::Method;
+use iron::{ Chain, Iron, Protocol, Timeouts };
 use iron_cors::CORS;
 use mount::Mount;
-use std::path::PathBuf;
+use std::path::{ Path, PathBuf };
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
+mod accounts;
+mod admin;
+mod backup;
+mod cache;
+mod check;
+mod clock;
+mod commands;
+mod config;
+mod ctl;
+mod daemon;
 mod errors;
+mod events;
+mod export;
+mod features;
+mod jwt;
+mod labels;
+mod loadtest;
+mod logging;
+mod metrics;
+mod nat;
+mod oidc;
+mod privacy;
+mod probe;
 mod db;
+mod discovery;
+mod e2e;
+mod pairing;
+mod push;
+mod read_only;
+mod retention;
+mod revocation;
+mod reporting;
 mod routes;
+mod routing;
+mod scheduler;
+mod seed;
+mod signaling;
+mod storage;
+mod stun;
+mod subnet;
+mod tenants;
+mod server;
+mod tokens;
+mod tracing;
+mod validation;
 
 #[cfg(test)]
 mod db_test_context;
+#[cfg(test)]
+mod test_server;
 
 const USAGE: &'static str = "
-Usage: registration_server [-d <db-hostname>] [--db-port <db-port>] [--db-pass <db-pass>] [-h <hostname>] [-p <port>] [--cert-directory <dir>]
+Usage: registration_server [options]
+       registration_server restore <backup-file> [--dry-run] [options]
+       registration_server export [--format <format>] [options]
+       registration_server import <file> [--format <format>] [--dry-run] [options]
+       registration_server seed [--count <n>] [--networks <n>] [--distribution <d>] [options]
+       registration_server ctl list [--public-ip <ip>] [--fingerprint <fingerprint>] [--since <t>] [--until <t>] [options]
+       registration_server ctl (find | delete) <fingerprint> [options]
+       registration_server ctl (evict | stats) [options]
+       registration_server keys create (admin | api) <name> [--tenant <tenant>] [options]
+       registration_server keys list [--tenant <tenant>] [options]
+       registration_server keys revoke <name> [--tenant <tenant>] [options]
+       registration_server e2e secret
+       registration_server e2e (seal | open) <fingerprint> <secret> <data>
+       registration_server loadtest <url> [--boxes <n>] [--register-interval <s>] [--ping-interval <s>] [--duration <s>] [--connections <n>] [options]
 
 Options:
-    -d, --db-host <host>          Set Redis database hostname.
-        --db-port <db-port>       Set Redis database port.
-        --db-pass <db-pass>       Set Redis database password.
-    -h, --host <host>             Set local hostname.
-    -p, --port <port>             Set port to listen on for http connections.
-        --cert-directory <dir>    Certificate directory.
+    -d, --db-host <host>              Set Redis database hostname.
+        --db-port <db-port>           Set Redis database port.
+        --db-pass <db-pass>           Set Redis database password.
+    -h, --host <host>                 Set local hostname.
+    -p, --port <port>                 Set port to listen on for http connections.
+        --cert-directory <dir>        Certificate directory.
+        --admin-token <token>         Enable the admin API, protected by this token.
+        --cache-size <n>              Number of discovery results cached in memory [default: 1024].
+        --negative-cache-size <n>     Number of public IPs without registrations cached in memory [default: 4096].
+        --cluster                     Run alongside other instances sharing the same database.
+        --instance-id <id>            Name of this instance in cluster mode (defaults to <hostname>:<port>).
+        --check-config                Validate the configuration, print a report and exit, failing if anything is wrong.
+        --backup                      Write a backup of the database to the backup directory and exit.
+        --backup-dir <dir>            Directory where backups are written [default: backups].
+        --dry-run                     With restore and import, only validate the file.
+        --format <format>             With export and import, jsonl or csv [default: jsonl].
+        --retain-records <s>          Seconds after which the registration of a box which stopped registering is evicted [default: 120].
+        --retain-archive <days>       Days the latest registration of an evicted box is archived, 0 to disable the archive [default: 0].
+        --retain-usage <days>         Days the usage aggregates are kept [default: 90].
+        --retain-backups <days>       Days the backup files are kept, 0 to keep them forever [default: 0].
+        --maintenance-interval <s>    Seconds between two database maintenance runs, 0 to disable [default: 86400].
+        --strict                      Reject registrations with unknown fields.
+        --sentry-dsn <dsn>            Report handler panics and 5xx responses to this Sentry project.
+        --error-webhook <url>         Report handler panics and 5xx responses as JSON POSTed to this URL.
+        --zipkin-url <url>            Send the trace spans to this Zipkin collector (v2 JSON API).
+        --log <target>                Where logs go: console, syslog, syslog://<host>:<port> or file:<path> [default: console].
+        --log-max-size <bytes>        Rotate the log file when it reaches this size, 0 for no limit [default: 10485760].
+        --log-max-age <s>             Rotate the log file after this many seconds, 0 for no limit [default: 86400].
+        --log-keep <n>                Number of rotated log files to keep [default: 7].
+        --daemonize                   Detach from the terminal and run in the background.
+        --pidfile <path>              Write the server pid to this file, removed on SIGTERM and SIGINT.
+        --threads <n>                 Number of worker threads (defaults to 8 per CPU).
+        --request-timeout <s>         Seconds allowed to read a request and to write its response [default: 30].
+        --handler-timeout <s>         Seconds a request waits for each database command before failing with a 504, 0 to wait forever [default: 10].
+        --slow-query-threshold <ms>   Log the storage operations slower than this, 0 to disable [default: 100].
+        --keep-alive <s>              Seconds an idle keep-alive connection is kept open, 0 to disable keep-alive [default: 5].
+        --read-timeout <s>            Seconds allowed to read a request (defaults to the request timeout).
+        --write-timeout <s>           Seconds allowed to write a response (defaults to the request timeout).
+        --reuse-port                  Bind with SO_REUSEPORT and drain on SIGTERM, for zero-downtime restarts.
+        --drain-timeout <s>           With --reuse-port, seconds to wait for requests in progress on SIGTERM [default: 30].
+        --boxes <n>                   With loadtest, number of simulated boxes [default: 100].
+        --register-interval <s>       With loadtest, seconds between two registrations of a box [default: 60].
+        --ping-interval <s>           With loadtest, seconds between two pings of a box [default: 10].
+        --duration <s>                With loadtest, seconds the test runs for [default: 60].
+        --connections <n>             With loadtest, number of concurrent connections to the server [default: 16].
+        --count <n>                   With seed, number of fake records to add [default: 1000].
+        --networks <n>                With seed, number of public IPs the records are spread over [default: 100].
+        --distribution <d>            With seed, uniform or skewed spread of the records over the public IPs [default: uniform].
+        --public-ip <ip>              With ctl list, only list the records of this public IP.
+        --fingerprint <fingerprint>   With ctl list, only list the records of this box.
+        --since <t>                   With ctl list, only list the records registered at or after this timestamp.
+        --until <t>                   With ctl list, only list the records registered at or before this timestamp.
+        --admin-url <url>             With ctl, go through the admin API at this URL rather than the database.
+        --tenant <tenant>             With keys, manage the keys of this tenant of --tenants rather than those of the default database.
+        --subnet-v4 <bits>            Discover the boxes of the whole IPv4 subnet of this prefix length, e.g. 24 behind a CGNAT.
+        --subnet-v6 <bits>            Discover the boxes of the whole IPv6 subnet of this prefix length, e.g. 56.
+        --expected-ping-interval <s>  Seconds between two registrations or heartbeats of a box, after twice which it is shown offline [default: 30].
+        --max-ip-changes <n>          Flag the boxes whose public IP changes more often than this per hour, 0 to disable [default: 10].
+        --flapping-auth               Require the token of their latest registration to register the flagged boxes again.
+        --hash-ips <key>              Store a keyed hash of the public IPs rather than the addresses, with this secret key.
+        --hash-fingerprints <salt>    Store a salted hash of the fingerprints of the boxes rather than the fingerprints.
+        --usage-aggregates            Compute anonymized daily aggregates of the activity of the boxes, without their IPs.
+        --fcm-key <key>               Send push notifications to FCM tokens with this server key.
+        --push-gateway <url>          POST the push notifications to APNs tokens (and to FCM tokens without --fcm-key) as JSON to this URL.
+        --accounts                    Let users create accounts and list the boxes linked to them from anywhere.
+        --oidc-issuer <url>           With --accounts, let users log in with this OpenID Connect provider, e.g. https://accounts.firefox.com.
+        --oidc-client-id <id>         Client id of this server at the OpenID Connect provider.
+        --oidc-client-secret <key>    Client secret of this server at the OpenID Connect provider.
+        --oidc-redirect-uri <url>     Public URL of /v1/account/oidc/callback, registered at the provider.
+        --tenants <file>              Serve the tenants of this JSON file, each with its own database, domain suffix, admin token and rate limit.
+        --max-boxes-per-account <n>   With --accounts, number of boxes an account can link.
+        --max-registrations <n>       With --accounts, number of registrations per hour of the boxes of an account.
+        --max-subdomains <n>          With --accounts, number of subdomains an account can reserve.
+        --blocked-subdomains <list>   With --accounts, comma-separated names no account can reserve as a subdomain, nor their lookalikes.
+        --disable-features <list>     Start with these comma-separated features off: accounts, push, pairing.
+        --read-only                   Start read-only: discovery works but writes get a 503, until turned off through the admin API.
+        --jwt-keys <list>             Issue the tokens as JWTs signed with the first of these comma-separated <kid>:<secret> keys, accepting all of them.
+        --jwt-issuer <name>           The issuer of the JWTs [default: fxbox-registration].
+        --jwt-algorithm <alg>         The signature algorithm of the JWTs, only HS256 is supported [default: HS256].
+        --udp-echo <addresses>        Answer UDP datagrams on these comma-separated <ip>:<port> with their source address, for NAT type detection.
+        --stun-port <port>            Answer STUN binding requests on this UDP port of the local hostname, e.g. 3478.
+        --probe-port <port>           Connect back to the boxes registering from a new public IP on this TCP port, flagging them as verified if they answer.
+        --open-discovery-limit <n>    Number of open discovery requests accepted from a public IP per minute [default: 10].
+        --mailbox-limit <n>           Number of mailbox messages accepted from a public IP per minute [default: 10].
 ";
 
 
 #[derive(RustcDecodable)]
 struct Args {
+    cmd_restore: bool,
+    arg_backup_file: Option<String>,
+    flag_dry_run: bool,
+    cmd_export: bool,
+    flag_format: String,
+    cmd_import: bool,
+    arg_file: Option<String>,
+    cmd_seed: bool,
+    flag_count: usize,
+    flag_networks: usize,
+    flag_distribution: String,
+    cmd_ctl: bool,
+    cmd_list: bool,
+    cmd_find: bool,
+    cmd_delete: bool,
+    cmd_evict: bool,
+    cmd_stats: bool,
+    arg_fingerprint: Option<String>,
+    flag_public_ip: Option<String>,
+    flag_fingerprint: Option<String>,
+    flag_since: Option<u64>,
+    flag_until: Option<u64>,
+    flag_admin_url: Option<String>,
+    cmd_keys: bool,
+    cmd_create: bool,
+    cmd_admin: bool,
+    cmd_api: bool,
+    cmd_revoke: bool,
+    arg_name: Option<String>,
+    flag_tenant: Option<String>,
+    cmd_e2e: bool,
+    cmd_secret: bool,
+    cmd_seal: bool,
+    cmd_open: bool,
+    arg_secret: Option<String>,
+    arg_data: Option<String>,
+    cmd_loadtest: bool,
+    arg_url: Option<String>,
+    flag_boxes: usize,
+    flag_register_interval: u64,
+    flag_ping_interval: u64,
+    flag_duration: u64,
+    flag_connections: usize,
     flag_db_host: Option<String>,
     flag_db_port: Option<u16>,
     flag_db_pass: Option<String>,
     flag_host: Option<String>,
     flag_port: Option<u16>,
     flag_cert_directory: Option<String>,
+    flag_admin_token: Option<String>,
+    flag_cache_size: usize,
+    flag_negative_cache_size: usize,
+    flag_cluster: bool,
+    flag_instance_id: Option<String>,
+    flag_backup: bool,
+    flag_check_config: bool,
+    flag_backup_dir: String,
+    flag_retain_records: u64,
+    flag_retain_archive: u64,
+    flag_retain_usage: u64,
+    flag_retain_backups: u64,
+    flag_maintenance_interval: u64,
+    flag_strict: bool,
+    flag_sentry_dsn: Option<String>,
+    flag_error_webhook: Option<String>,
+    flag_zipkin_url: Option<String>,
+    flag_log: String,
+    flag_log_max_size: u64,
+    flag_log_max_age: u64,
+    flag_log_keep: usize,
+    flag_daemonize: bool,
+    flag_pidfile: Option<String>,
+    flag_threads: Option<usize>,
+    flag_request_timeout: u64,
+    flag_read_timeout: Option<u64>,
+    flag_write_timeout: Option<u64>,
+    flag_keep_alive: u64,
+    flag_handler_timeout: u64,
+    flag_slow_query_threshold: u64,
+    flag_reuse_port: bool,
+    flag_drain_timeout: u64,
+    flag_subnet_v4: Option<u8>,
+    flag_subnet_v6: Option<u8>,
+    flag_expected_ping_interval: u64,
+    flag_max_ip_changes: u64,
+    flag_flapping_auth: bool,
+    flag_usage_aggregates: bool,
+    flag_hash_ips: Option<String>,
+    flag_hash_fingerprints: Option<String>,
+    flag_fcm_key: Option<String>,
+    flag_push_gateway: Option<String>,
+    flag_accounts: bool,
+    flag_oidc_issuer: Option<String>,
+    flag_oidc_client_id: Option<String>,
+    flag_oidc_client_secret: Option<String>,
+    flag_oidc_redirect_uri: Option<String>,
+    flag_tenants: Option<String>,
+    flag_max_boxes_per_account: Option<u64>,
+    flag_max_registrations: Option<u64>,
+    flag_max_subdomains: Option<u64>,
+    flag_blocked_subdomains: Option<String>,
+    flag_read_only: bool,
+    flag_disable_features: Option<String>,
+    flag_jwt_keys: Option<String>,
+    flag_jwt_issuer: String,
+    flag_jwt_algorithm: String,
+    flag_udp_echo: Option<String>,
+    flag_stun_port: Option<u16>,
+    flag_probe_port: Option<u16>,
+    flag_open_discovery_limit: u64,
+    flag_mailbox_limit: u64,
 }
 
 
-fn main() {
-    env_logger::init().unwrap();
+/// The complete request handler: the public and admin endpoints with their
+/// middlewares.
+fn create_chain(config: &Config) -> Chain {
+    let mut mount = Mount::new();
+    // Every public route gets the CORS headers, but the admin API.
+    let public = routes::create(config.clone());
+    let mut cors_routes = public.globs("/");
+    mount.mount("/", public);
+    mount.mount("/admin", admin::create(config.clone()));
+    if config.accounts {
+        let accounts = accounts::create(config.clone());
+        cors_routes.extend(accounts.globs("/v1/account"));
+        mount.mount("/v1/account", accounts);
+    }
 
+    let reporter = config.error_reporting.clone().map(|destination| {
+        reporting::Reporter::new(destination, config.instance_id.clone())
+    });
+    // Traced outside of the reporting, so that the 500s of panics carry the
+    // trace headers too.
+    let reported = reporting::Reporting::new(mount, reporter);
+    let mut chain = Chain::new(tracing::Tracing::new(reported, config.zipkin_url.clone()));
+    chain.link_before(read_only::Guard { state: config.read_only.clone() });
+    chain.link_before(features::Gate { features: config.features.clone() });
+    chain.link_before(revocation::Guard { config: config.clone() });
+    if let Some(ref keys) = config.jwt {
+        chain.link_before(jwt::Check { keys: keys.clone(), clock: config.clock.clone() });
+    }
+    chain.link_after(routing::JsonNotFound);
+    let cors = CORS::new(cors_routes);
+    chain.link_after(cors);
+    chain
+}
+
+fn main() {
     let args: Args = Docopt::new(USAGE).and_then(|d| d.decode())
         .unwrap_or_else(|e| e.exit());
 
+    let rotation = logging::Rotation {
+        max_size: args.flag_log_max_size,
+        max_age: args.flag_log_max_age,
+        keep: args.flag_log_keep,
+    };
+    let log_target = logging::Target::from_name(&args.flag_log)
+        .and_then(|target| logging::init(&target, rotation).map(|_| target));
+    if let Err(message) = log_target {
+        println!("{}", message);
+        process::exit(1);
+    }
+
     let port = args.flag_port.unwrap_or(4242);
     let host = args.flag_host.unwrap_or("0.0.0.0".to_string());
     let using_tls = args.flag_cert_directory.is_some();
@@ -76,22 +386,376 @@ fn main() {
 
     info!("Redis server on {}:{}", db_host, db_port);
 
-    let mut mount = Mount::new();
-    mount.mount("/", routes::create(db_host.clone(), db_port, db_pass.clone()));
+    let error_reporting = match (args.flag_sentry_dsn, args.flag_error_webhook) {
+        (Some(dsn), _) => match reporting::Destination::from_sentry_dsn(&dsn) {
+            Ok(destination) => Some(destination),
+            Err(message) => {
+                println!("{}", message);
+                process::exit(1);
+            }
+        },
+        (None, Some(url)) => Some(reporting::Destination::Webhook(url)),
+        (None, None) => None
+    };
 
-    let mut chain = Chain::new(mount);
-    let cors = CORS::new(vec![
-        (vec![Method::Get], "ping".to_owned()),
-        (vec![Method::Post], "register".to_owned()),
-    ]);
-    chain.link_after(cors);
+    let subnet = subnet::Prefixes {
+        v4: args.flag_subnet_v4,
+        v6: args.flag_subnet_v6,
+    };
+    if subnet.v4.map_or(false, |bits| bits > 32) || subnet.v6.map_or(false, |bits| bits > 128) {
+        println!("Subnet prefixes are at most 32 bits long for IPv4, 128 for IPv6");
+        process::exit(1);
+    }
+    if args.flag_hash_ips.is_some() && (subnet.v4.is_some() || subnet.v6.is_some()) {
+        println!("Subnets can't be matched when the public IPs are hashed");
+        process::exit(1);
+    }
+    if args.flag_hash_ips.is_some() && args.flag_probe_port.is_some() {
+        println!("Boxes can't be probed when the public IPs are hashed");
+        process::exit(1);
+    }
 
-    let iron = Iron::new(chain);
-    info!("Starting server on {}:{}", host, port);
+    let oidc = match (args.flag_oidc_issuer, args.flag_oidc_client_id,
+                      args.flag_oidc_client_secret, args.flag_oidc_redirect_uri) {
+        (Some(issuer), Some(client_id), Some(client_secret), Some(redirect_uri)) => {
+            Some(oidc::Provider {
+                issuer: issuer,
+                client_id: client_id,
+                client_secret: client_secret,
+                redirect_uri: redirect_uri,
+            })
+        },
+        (None, None, None, None) => None,
+        _ => {
+            println!("The OpenID Connect issuer, client id, client secret and redirect URI \
+                      go together");
+            process::exit(1);
+        }
+    };
+    if oidc.is_some() && !args.flag_accounts {
+        println!("Logging in with OpenID Connect requires --accounts");
+        process::exit(1);
+    }
+
+    let retention = retention::Policy {
+        records: args.flag_retain_records,
+        archive: args.flag_retain_archive,
+        usage: args.flag_retain_usage,
+        backups: args.flag_retain_backups,
+    };
+    if let Err(message) = retention.validate() {
+        println!("{}", message);
+        process::exit(1);
+    }
+
+    let disabled = args.flag_disable_features.map_or(Ok(vec![]), |list| {
+        features::parse_list(&list)
+    }).unwrap_or_else(|message| {
+        println!("{}", message);
+        process::exit(1);
+    });
+    if let Err(message) = jwt::check_algorithm(&args.flag_jwt_algorithm) {
+        println!("{}", message);
+        process::exit(1);
+    }
+    let jwt = args.flag_jwt_keys.map(|list| {
+        jwt::Keys::parse(&list, &args.flag_jwt_issuer).unwrap_or_else(|message| {
+            println!("{}", message);
+            process::exit(1);
+        })
+    });
+    let push = push::Settings {
+        fcm_key: args.flag_fcm_key,
+        gateway: args.flag_push_gateway,
+    };
+    let mut available = vec![features::Feature::Pairing];
+    if args.flag_accounts {
+        available.push(features::Feature::Accounts);
+    }
+    if push.is_enabled() {
+        available.push(features::Feature::Push);
+    }
+
+    let config = Config {
+        db_host: db_host,
+        db_port: db_port,
+        db_password: db_pass,
+        db_index: 0,
+        admin_token: args.flag_admin_token,
+        cache_size: args.flag_cache_size,
+        negative_cache_size: args.flag_negative_cache_size,
+        cluster: args.flag_cluster,
+        instance_id: args.flag_instance_id
+                         .unwrap_or(format!("{}:{}", host, port)),
+        backup_dir: PathBuf::from(args.flag_backup_dir),
+        retention: retention,
+        maintenance_interval: args.flag_maintenance_interval,
+        strict: args.flag_strict,
+        error_reporting: error_reporting,
+        zipkin_url: args.flag_zipkin_url,
+        // Requests mostly wait for Redis, so use more threads than CPUs.
+        threads: args.flag_threads.unwrap_or(8 * num_cpus::get()),
+        read_timeout: args.flag_read_timeout.unwrap_or(args.flag_request_timeout),
+        write_timeout: args.flag_write_timeout.unwrap_or(args.flag_request_timeout),
+        keep_alive: args.flag_keep_alive,
+        handler_timeout: args.flag_handler_timeout,
+        slow_query_threshold: args.flag_slow_query_threshold,
+        clock: Arc::new(clock::SystemClock),
+        storage: Arc::new(storage::RedisConnector),
+        metrics: Arc::new(metrics::Metrics::new()),
+        subnet: subnet,
+        ping_interval: args.flag_expected_ping_interval,
+        push: push,
+        accounts: args.flag_accounts,
+        quotas: accounts::Quotas {
+            boxes: args.flag_max_boxes_per_account,
+            registrations_per_hour: args.flag_max_registrations,
+            subdomains: args.flag_max_subdomains,
+        },
+        subdomain_blocklist: args.flag_blocked_subdomains.map_or(vec![], |list| {
+            labels::parse_blocklist(&list)
+        }),
+        oidc: oidc,
+        max_ip_changes: args.flag_max_ip_changes,
+        flapping_auth: args.flag_flapping_auth,
+        usage_aggregates: args.flag_usage_aggregates,
+        ip_hash_key: args.flag_hash_ips,
+        fingerprint_salt: args.flag_hash_fingerprints,
+        read_only: Arc::new(read_only::ReadOnly::new()),
+        features: Arc::new(features::Features::new(&available, &disabled)),
+        jwt: jwt,
+        revocations: Arc::new(revocation::Revocations::new()),
+        events: Arc::new(events::Bus::new().with(Box::new(push::Notifier::new()))
+                                           .with(Box::new(probe::Prober::new()))),
+        discovery_cache: cache::shared(args.flag_cluster, args.flag_cache_size,
+                                       args.flag_negative_cache_size),
+        probe_port: args.flag_probe_port,
+        open_discovery_limit: args.flag_open_discovery_limit,
+        mailbox_limit: args.flag_mailbox_limit,
+        api_keys: vec![],
+    };
+    if args.flag_read_only {
+        config.read_only.enable(read_only::DEFAULT_RETRY_AFTER);
+    }
+
+    let tenants = match args.flag_tenants {
+        Some(path) => tenants::load(Path::new(&path)).unwrap_or_else(|message| {
+            println!("{}", message);
+            process::exit(1);
+        }),
+        None => vec![]
+    };
+    let tenants: Vec<_> = tenants.into_iter().map(|tenant| {
+        let tenant_config = tenant.config(&config);
+        (tenant, tenant_config)
+    }).collect();
+
+    let command = if args.cmd_restore {
+        let path = args.arg_backup_file.unwrap();
+        Some(commands::restore(&config, Path::new(&path), args.flag_dry_run))
+    } else if args.cmd_export {
+        Some(commands::export(&config, &args.flag_format))
+    } else if args.cmd_import {
+        let path = args.arg_file.unwrap();
+        Some(commands::import(&config, Path::new(&path), &args.flag_format,
+                              args.flag_dry_run))
+    } else if args.cmd_seed {
+        Some(commands::seed(&config, args.flag_count, args.flag_networks,
+                            &args.flag_distribution))
+    } else if args.cmd_ctl {
+        let operation = if args.cmd_list {
+            ctl::Operation::List(db::Filter {
+                public_ip: args.flag_public_ip,
+                client: args.flag_fingerprint,
+                since: args.flag_since,
+                until: args.flag_until,
+            })
+        } else if args.cmd_find {
+            ctl::Operation::Find(args.arg_fingerprint.unwrap())
+        } else if args.cmd_delete {
+            ctl::Operation::Delete(args.arg_fingerprint.unwrap())
+        } else if args.cmd_evict {
+            ctl::Operation::Evict
+        } else {
+            ctl::Operation::Stats
+        };
+        Some(commands::ctl(&config, args.flag_admin_url, operation))
+    } else if args.cmd_keys {
+        let key_config = match args.flag_tenant {
+            Some(ref name) => match tenants.iter().find(|&&(ref tenant, _)| tenant.name == *name) {
+                Some(&(_, ref tenant_config)) => tenant_config.clone(),
+                None => {
+                    println!("There is no tenant named {}", name);
+                    process::exit(1);
+                }
+            },
+            None => config.clone()
+        };
+        if args.cmd_create {
+            let kind = if args.cmd_admin { db::ADMIN_CREDENTIAL } else { db::API_CREDENTIAL };
+            Some(commands::keys_create(&key_config, kind, &args.arg_name.unwrap()))
+        } else if args.cmd_list {
+            Some(commands::keys_list(&key_config))
+        } else {
+            Some(commands::keys_revoke(&key_config, &args.arg_name.unwrap()))
+        }
+    } else if args.cmd_e2e {
+        if args.cmd_secret {
+            Some(commands::e2e_secret())
+        } else {
+            let fingerprint = args.arg_fingerprint.unwrap();
+            let secret = args.arg_secret.unwrap();
+            let data = args.arg_data.unwrap();
+            if args.cmd_seal {
+                Some(commands::e2e_seal(&fingerprint, &secret, &data))
+            } else {
+                Some(commands::e2e_open(&fingerprint, &secret, &data))
+            }
+        }
+    } else if args.cmd_loadtest {
+        let settings = loadtest::Settings {
+            boxes: args.flag_boxes,
+            register_interval: args.flag_register_interval,
+            ping_interval: args.flag_ping_interval,
+            duration: args.flag_duration,
+            connections: args.flag_connections,
+        };
+        Some(commands::loadtest(&args.arg_url.unwrap(), &settings))
+    } else if args.flag_check_config {
+        let cert_directory = args.flag_cert_directory.as_ref().map(PathBuf::from);
+        let pidfile = args.flag_pidfile.as_ref().map(PathBuf::from);
+        let process = check::Process {
+            host: &host,
+            port: port,
+            reuse_port: args.flag_reuse_port,
+            cert_directory: cert_directory.as_ref().map(|path| path.as_path()),
+            pidfile: pidfile.as_ref().map(|path| path.as_path()),
+            log: &args.flag_log,
+        };
+        Some(commands::check_config(&config, &tenants, &process))
+    } else if args.flag_backup {
+        Some(commands::backup(&config))
+    } else {
+        None
+    };
+
+    if let Some(result) = command {
+        if let Err(message) = result {
+            println!("{}", message);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Daemonizing has to happen before starting any thread.
+    let daemon = if args.flag_daemonize { daemon::daemonize() } else { Ok(()) };
+    let pidfile = args.flag_pidfile.map(PathBuf::from);
+    let daemon = daemon.and_then(|_| match pidfile {
+        Some(ref path) => daemon::write_pidfile(path),
+        None => Ok(())
+    });
+    // With SO_REUSEPORT, let the requests in progress finish on SIGTERM
+    // while the new instance takes the new connections.
+    let drain = Arc::new(server::Drain::new());
+    let drain_timeout = Duration::from_secs(args.flag_drain_timeout);
+    let daemon = daemon.and_then(|_| {
+        if pidfile.is_none() && !args.flag_reuse_port {
+            return Ok(());
+        }
+        let drain = drain.clone();
+        let reuse_port = args.flag_reuse_port;
+        daemon::handle_signals(pidfile.clone(), move || {
+            if reuse_port {
+                drain.run(drain_timeout);
+            }
+        })
+    });
+    if let Err(message) = daemon {
+        error!("{}", message);
+        println!("{}", message);
+        process::exit(1);
+    }
+
+    // Fail at startup rather than answering every request with a 503.
+    let configs = Some(&config).into_iter()
+                               .chain(tenants.iter().map(|&(_, ref config)| config));
+    for config in configs {
+        let connected = db::Db::wait_for(|| db::Db::from_config(config),
+                                         Duration::from_secs(db::CONNECT_TIMEOUT));
+        let db = match connected {
+            Ok(db) => db,
+            Err(e) => {
+                let message = format!("Can't connect to the database {} at {}:{}: {}",
+                                      config.db_index, config.db_host, config.db_port, e);
+                error!("{}", message);
+                println!("{}", message);
+                process::exit(1);
+            }
+        };
+        // The records left by the first versions would fail the requests.
+        match db.migrate_records() {
+            Ok(0) => {},
+            Ok(count) => info!("Converted {} records of database {} to hashes", count,
+                               config.db_index),
+            Err(e) => error!("Can't convert the records of database {}: {}",
+                             config.db_index, e)
+        }
+    }
+
+    if config.cluster {
+        info!("Running in cluster mode as {}", config.instance_id);
+    }
+    scheduler::start(config.clone(), scheduler::default_jobs(&config));
+    for &(ref tenant, ref tenant_config) in &tenants {
+        info!("Serving tenant {} from database {}", tenant.name, tenant.database);
+        scheduler::start(tenant_config.clone(), scheduler::default_jobs(tenant_config));
+    }
+    if let Some(ref addresses) = args.flag_udp_echo {
+        for addr in addresses.split(',').map(str::trim).filter(|addr| !addr.is_empty()) {
+            if let Err(e) = nat::serve("udp_echo", addr, config.metrics.clone(), nat::echo) {
+                error!("Can't answer UDP echo requests on {}: {}", addr, e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(stun_port) = args.flag_stun_port {
+        let addr = format!("{}:{}", host, stun_port);
+        if let Err(e) = nat::serve("stun", &addr, config.metrics.clone(), stun::answer) {
+            error!("Can't answer STUN requests on {}: {}", addr, e);
+            process::exit(1);
+        }
+    }
+
+    let iron = Iron::new(reporting::CatchPanics {
+        handler: tenants::Tenants::new(&config, &tenants, create_chain),
+    });
+    info!("Starting server on {}:{} with {} threads", host, port, config.threads);
     let addr = format!("{}:{}", host, port);
+    // Each connection holds a thread while it's open, so the timeouts are
+    // what keeps slow or idle clients from taking all of them.
+    let timeouts = Timeouts {
+        keep_alive: if config.keep_alive > 0 {
+            Some(Duration::from_secs(config.keep_alive))
+        } else {
+            None
+        },
+        read: Some(Duration::from_secs(config.read_timeout)),
+        write: Some(Duration::from_secs(config.write_timeout)),
+    };
 
-    if !using_tls {
-        iron.http(addr.as_ref() as &str)
+    if args.flag_reuse_port {
+        if using_tls {
+            error!("--reuse-port doesn't support TLS");
+            process::exit(1);
+        }
+        let listener = server::bind(&addr).unwrap_or_else(|e| {
+            error!("Can't bind {}: {}", addr, e);
+            process::exit(1);
+        });
+        server::serve(iron.handler, listener, &config, drain).unwrap();
+    } else if !using_tls {
+        iron.listen_with(addr.as_ref() as &str, config.threads, Protocol::Http,
+                         Some(timeouts))
             .unwrap();
     } else {
         info!("Starting TLS server");
@@ -110,12 +774,11 @@ fn main() {
             certificate: cert,
             key: private_key,
         };
-        iron.listen_with(addr.as_ref() as &str, 8, protocol, None).unwrap();
+        iron.listen_with(addr.as_ref() as &str, config.threads, protocol,
+                         Some(timeouts)).unwrap();
     }
 }
 
-// TODO: add iron tests.
-
 #[test]
 fn options_are_good() {
     // short form options
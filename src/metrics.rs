@@ -0,0 +1,254 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// In-memory counters of this instance, for the admin dashboard: the
+/// requests and errors of every route, the datagrams of the UDP services,
+/// the eviction runs, the database health checks, the latency of the
+/// database operations, and those which were retried while Redis was busy
+/// or after reconnecting, or were slow.
+
+use iron::prelude::*;
+use iron::Handler;
+use std::collections::BTreeMap;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+/// Upper bounds, in milliseconds, of the buckets of the latency histograms.
+/// A last bucket counts the slower operations.
+pub static LATENCY_BUCKETS_MS: [u64; 10] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+#[derive(RustcEncodable, Debug, Clone, Default, PartialEq)]
+pub struct RouteStats {
+    pub requests: u64,
+    /// Responses with a 4xx status.
+    pub client_errors: u64,
+    /// Responses with a 5xx status.
+    pub server_errors: u64,
+}
+
+#[derive(RustcEncodable, Debug, Clone, Default, PartialEq)]
+pub struct UdpStats {
+    pub datagrams: u64,
+    /// Datagrams dropped without an answer, e.g. malformed.
+    pub dropped: u64,
+}
+
+#[derive(RustcEncodable, Debug, Clone, Default, PartialEq)]
+pub struct EvictionStats {
+    pub runs: u64,
+    /// Total number of clients evicted.
+    pub evicted: u64,
+    /// When the last eviction ran, and how many clients it evicted.
+    pub last_run: Option<u64>,
+    pub last_evicted: usize,
+}
+
+#[derive(RustcEncodable, Debug, Clone, Default, PartialEq)]
+pub struct HealthStats {
+    /// Whether the last health check could reach the database.
+    pub healthy: bool,
+    pub checks: u64,
+    pub failures: u64,
+    pub last_check: Option<u64>,
+    /// Number of broken connections replaced while handling requests.
+    pub reconnections: u64,
+}
+
+/// Histogram of the durations of a storage operation.
+#[derive(RustcEncodable, Debug, Clone, Default, PartialEq)]
+pub struct Latency {
+    pub count: u64,
+    /// Sum of the durations in microseconds, for the mean.
+    pub total_us: u64,
+    /// Number of operations in each bucket of `latency_buckets_ms`, and
+    /// slower ones last.
+    pub buckets: Vec<u64>,
+}
+
+impl Latency {
+    fn record(&mut self, duration: Duration) {
+        let us = duration.as_secs() * 1_000_000 + duration.subsec_nanos() as u64 / 1000;
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS_MS.len() + 1];
+        }
+        let bucket = LATENCY_BUCKETS_MS.iter().position(|&ms| us <= ms * 1000)
+                                       .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total_us += us;
+    }
+}
+
+#[derive(RustcEncodable, Debug, Clone, Default)]
+pub struct Snapshot {
+    /// Per route id, e.g. "ping".
+    pub routes: BTreeMap<String, RouteStats>,
+    /// Per UDP service, e.g. "stun".
+    pub udp: BTreeMap<String, UdpStats>,
+    pub evictions: EvictionStats,
+    pub health: HealthStats,
+    /// Number of retries per storage operation, e.g. "set".
+    pub retries: BTreeMap<String, u64>,
+    /// Number of operations slower than the slow query threshold, per
+    /// storage operation.
+    pub slow_queries: BTreeMap<String, u64>,
+    /// Number of retried requests answered with the response of their first
+    /// attempt, per route id.
+    pub replays: BTreeMap<String, u64>,
+    /// The upper bounds of the buckets of the latency histograms.
+    pub latency_buckets_ms: Vec<u64>,
+    /// Per backend, e.g. "redis", and per storage operation.
+    pub latencies: BTreeMap<String, BTreeMap<String, Latency>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    state: Mutex<Snapshot>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn record_response(&self, route_id: &str, status: u16) {
+        let mut state = self.state.lock().unwrap();
+        let stats = state.routes.entry(route_id.to_owned()).or_insert_with(RouteStats::default);
+        stats.requests += 1;
+        match status {
+            400...499 => stats.client_errors += 1,
+            500...599 => stats.server_errors += 1,
+            _ => {}
+        }
+    }
+
+    pub fn record_datagram(&self, service: &str, answered: bool) {
+        let mut state = self.state.lock().unwrap();
+        let stats = state.udp.entry(service.to_owned()).or_insert_with(UdpStats::default);
+        stats.datagrams += 1;
+        if !answered {
+            stats.dropped += 1;
+        }
+    }
+
+    pub fn record_eviction(&self, evicted: usize, now: u64) {
+        let mut state = self.state.lock().unwrap();
+        let evictions = &mut state.evictions;
+        evictions.runs += 1;
+        evictions.evicted += evicted as u64;
+        evictions.last_run = Some(now);
+        evictions.last_evicted = evicted;
+    }
+
+    pub fn record_retry(&self, operation: &str) {
+        let mut state = self.state.lock().unwrap();
+        *state.retries.entry(operation.to_owned()).or_insert(0) += 1;
+    }
+
+    pub fn record_slow_query(&self, operation: &str) {
+        let mut state = self.state.lock().unwrap();
+        *state.slow_queries.entry(operation.to_owned()).or_insert(0) += 1;
+    }
+
+    pub fn record_replay(&self, route_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        *state.replays.entry(route_id.to_owned()).or_insert(0) += 1;
+    }
+
+    pub fn record_latency(&self, backend: &str, operation: &str, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.latencies.entry(backend.to_owned()).or_insert_with(BTreeMap::new)
+             .entry(operation.to_owned()).or_insert_with(Latency::default)
+             .record(duration);
+    }
+
+    pub fn record_health_check(&self, healthy: bool, now: u64) {
+        let mut state = self.state.lock().unwrap();
+        let health = &mut state.health;
+        health.healthy = healthy;
+        health.checks += 1;
+        if !healthy {
+            health.failures += 1;
+        }
+        health.last_check = Some(now);
+    }
+
+    pub fn record_reconnection(&self) {
+        self.state.lock().unwrap().health.reconnections += 1;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = self.state.lock().unwrap().clone();
+        snapshot.latency_buckets_ms = LATENCY_BUCKETS_MS.to_vec();
+        snapshot
+    }
+}
+
+/// Handler wrapper counting the responses of a route.
+pub struct Counted<H: Handler> {
+    handler: H,
+    route_id: String,
+    metrics: Arc<Metrics>,
+}
+
+impl<H: Handler> Counted<H> {
+    pub fn new(handler: H, route_id: String, metrics: Arc<Metrics>) -> Counted<H> {
+        Counted {
+            handler: handler,
+            route_id: route_id,
+            metrics: metrics,
+        }
+    }
+}
+
+impl<H: Handler> Handler for Counted<H> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let result = self.handler.handle(req);
+        let status = match result {
+            Ok(ref response) => response.status,
+            Err(ref err) => err.response.status,
+        };
+        self.metrics.record_response(&self.route_id,
+                                     status.map_or(200, |status| status.to_u16()));
+        result
+    }
+}
+
+#[test]
+fn test_metrics() {
+    let metrics = Metrics::new();
+    metrics.record_response("ping", 200);
+    metrics.record_response("ping", 404);
+    metrics.record_response("ping", 501);
+    metrics.record_response("register", 200);
+    metrics.record_datagram("stun", true);
+    metrics.record_datagram("stun", false);
+    metrics.record_eviction(3, 1481900000);
+    metrics.record_retry("set");
+    metrics.record_retry("set");
+    metrics.record_slow_query("discover");
+    metrics.record_latency("redis", "discover", Duration::from_millis(3));
+    metrics.record_latency("redis", "discover", Duration::from_millis(2));
+    metrics.record_latency("redis", "discover", Duration::from_secs(2));
+    metrics.record_health_check(false, 1481900000);
+    metrics.record_health_check(true, 1481900010);
+    metrics.record_reconnection();
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.routes["ping"],
+               RouteStats { requests: 3, client_errors: 1, server_errors: 1 });
+    assert_eq!(snapshot.routes["register"].requests, 1);
+    assert_eq!(snapshot.udp["stun"], UdpStats { datagrams: 2, dropped: 1 });
+    assert_eq!(snapshot.evictions,
+               EvictionStats { runs: 1, evicted: 3, last_run: Some(1481900000),
+                               last_evicted: 3 });
+    assert_eq!(snapshot.retries["set"], 2);
+    assert_eq!(snapshot.slow_queries["discover"], 1);
+    assert_eq!(snapshot.latencies["redis"]["discover"],
+               Latency { count: 3, total_us: 2_005_000,
+                         buckets: vec![0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 1] });
+    assert_eq!(snapshot.health,
+               HealthStats { healthy: true, checks: 2, failures: 1,
+                             last_check: Some(1481900010), reconnections: 1 });
+}
@@ -0,0 +1,132 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// NAT type detection: with `--udp-echo <addresses>`, the server answers
+/// every UDP datagram received on these addresses with the public address
+/// and port it came from, as JSON, e.g. `{"ip":"88.22.170.96","port":40123}`.
+///
+/// A box sends a datagram to each of the addresses from the same local
+/// socket and compares the answers: the same public port everywhere means
+/// an endpoint-independent mapping, a port per server port an
+/// address-and-port-dependent one, and so on when the addresses differ.
+/// It then gives its mapping behavior in the `nat` field of its
+/// registration, one of `MAPPINGS`, so that its clients can tell how
+/// likely a direct connection is to work.
+///
+/// To keep the service from amplifying spoofed traffic, datagrams shorter
+/// than `MIN_REQUEST_LENGTH` bytes, which the answers never exceed, are
+/// dropped: boxes pad theirs.
+///
+/// `serve` runs the UDP services, this one and STUN, from a thread of their
+/// own, counting their datagrams in the metrics.
+
+use metrics::Metrics;
+use rustc_serialize::json;
+use std::io;
+use std::net::{ SocketAddr, UdpSocket };
+use std::sync::Arc;
+use std::thread;
+
+/// The mapping behaviors of RFC 4787 a box can report, and "none" for a
+/// box with a public address.
+pub static MAPPINGS: [&'static str; 4] = ["none", "endpoint-independent", "address-dependent",
+                                          "address-and-port-dependent"];
+
+/// Datagrams shorter than this are dropped.
+pub static MIN_REQUEST_LENGTH: usize = 64;
+
+/// Whether clients outside the network of a box with this mapping behavior
+/// can usually connect to it: it has a public address, or a NAT which maps
+/// its ports the same way for every destination.
+pub fn lets_clients_in(mapping: &str) -> bool {
+    mapping == MAPPINGS[0] || mapping == MAPPINGS[1]
+}
+
+#[derive(RustcEncodable)]
+struct Observed {
+    ip: String,
+    port: u16,
+}
+
+/// The answer to a datagram received from `addr`.
+pub fn answer(addr: &SocketAddr) -> String {
+    json::encode(&Observed {
+        ip: format!("{}", addr.ip()),
+        port: addr.port(),
+    }).unwrap()
+}
+
+/// The UDP echo service, for `serve`.
+pub fn echo(request: &[u8], from: &SocketAddr) -> Option<Vec<u8>> {
+    if request.len() < MIN_REQUEST_LENGTH {
+        return None;
+    }
+    Some(answer(from).into_bytes())
+}
+
+/// Answer the datagrams received on `addr` from a dedicated thread with
+/// `respond`, which returns `None` to drop them, returning the address
+/// bound. The datagrams are counted under `service` in the metrics.
+pub fn serve<F>(service: &'static str, addr: &str, metrics: Arc<Metrics>, respond: F)
+    -> io::Result<SocketAddr>
+    where F: Fn(&[u8], &SocketAddr) -> Option<Vec<u8>> + Send + 'static {
+    let socket = try!(UdpSocket::bind(addr));
+    let local_addr = try!(socket.local_addr());
+    info!("Answering {} requests on UDP {}", service, local_addr);
+
+    thread::spawn(move || {
+        let mut buffer = [0; 512];
+        loop {
+            let (length, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Can't receive a {} request: {}", service, e);
+                    continue;
+                }
+            };
+            let response = match respond(&buffer[..length], &from) {
+                Some(response) => response,
+                None => {
+                    debug!("Dropping a {} datagram of {} bytes from {}", service, length, from);
+                    metrics.record_datagram(service, false);
+                    continue;
+                }
+            };
+            metrics.record_datagram(service, true);
+            if let Err(e) = socket.send_to(&response, from) {
+                warn!("Can't answer the {} request of {}: {}", service, from, e);
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+#[test]
+fn test_udp_echo() {
+    use rustc_serialize::json::Json;
+    use std::time::Duration;
+
+    let longest: SocketAddr = "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535".parse().unwrap();
+    assert!(answer(&longest).len() <= MIN_REQUEST_LENGTH);
+
+    let metrics = Arc::new(Metrics::new());
+    let server = serve("udp_echo", "127.0.0.1:0", metrics.clone(), echo).unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let mut buffer = [0; 512];
+
+    socket.send_to(&[0; MIN_REQUEST_LENGTH], server).unwrap();
+    let (length, from) = socket.recv_from(&mut buffer).unwrap();
+    assert_eq!(from, server);
+    let observed = Json::from_str(::std::str::from_utf8(&buffer[..length]).unwrap()).unwrap();
+    let local_addr = socket.local_addr().unwrap();
+    assert_eq!(observed.find("ip").and_then(Json::as_string), Some("127.0.0.1"));
+    assert_eq!(observed.find("port").and_then(Json::as_u64), Some(local_addr.port() as u64));
+
+    // Short datagrams go unanswered.
+    socket.send_to(b"ping", server).unwrap();
+    assert!(socket.recv_from(&mut buffer).is_err());
+    let udp = metrics.snapshot().udp;
+    assert_eq!((udp["udp_echo"].datagrams, udp["udp_echo"].dropped), (2, 1));
+}
@@ -0,0 +1,142 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Log in to accounts through an OpenID Connect provider, e.g. Firefox
+/// Accounts, with the authorization code flow.
+///
+/// The ID token comes straight from the token endpoint of the provider over
+/// TLS, so instead of checking its signature we trust the server and ask
+/// the userinfo endpoint for the email of the user, as OpenID Connect
+/// allows for confidential clients.
+
+use ctl::percent_encode;
+use hyper::Client;
+use hyper::client::Response;
+use hyper::header::Headers;
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{ self, Json };
+use std::io::Read;
+use std::time::Duration;
+
+/// Number of seconds the provider has to answer, so that a stalled
+/// provider fails the login with a 502 instead of holding a worker thread.
+static TIMEOUT: u64 = 10;
+
+fn client() -> Client {
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(TIMEOUT)));
+    client.set_write_timeout(Some(Duration::from_secs(TIMEOUT)));
+    client
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Provider {
+    /// e.g. "https://accounts.firefox.com".
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the provider sends the users back, the URL of
+    /// /v1/account/oidc/callback on this server.
+    pub redirect_uri: String,
+}
+
+/// The endpoints of a provider, from its discovery document.
+#[derive(Debug, RustcDecodable)]
+struct Endpoints {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Debug, RustcDecodable)]
+struct Tokens {
+    access_token: String,
+}
+
+fn read_json(mut response: Response, url: &str) -> Result<Json, String> {
+    let mut body = String::new();
+    try!(response.read_to_string(&mut body).map_err(|e| format!("{}: {}", url, e)));
+    if !response.status.is_success() {
+        return Err(format!("{}: {} {}", url, response.status, body));
+    }
+    Json::from_str(&body).map_err(|e| format!("{}: {}", url, e))
+}
+
+fn decode<T: Decodable>(value: Json, url: &str) -> Result<T, String> {
+    T::decode(&mut json::Decoder::new(value)).map_err(|e| format!("{}: {}", url, e))
+}
+
+impl Provider {
+    /// Fetch the discovery document of the provider. Logins are rare
+    /// enough not to bother caching it.
+    fn endpoints(&self) -> Result<Endpoints, String> {
+        let url = format!("{}/.well-known/openid-configuration",
+                          self.issuer.trim_right_matches('/'));
+        let response = try!(client().get(&url).send()
+                                         .map_err(|e| format!("{}: {}", url, e)));
+        decode(try!(read_json(response, &url)), &url)
+    }
+
+    /// Where to send users to log in. The provider sends them back to the
+    /// redirect URI with the same `state`.
+    pub fn authorization_url(&self, state: &str) -> Result<String, String> {
+        let endpoints = try!(self.endpoints());
+        let separator = if endpoints.authorization_endpoint.contains('?') { '&' } else { '?' };
+        Ok(format!("{}{}response_type=code&scope=openid%20email&client_id={}\
+                    &redirect_uri={}&state={}",
+                   endpoints.authorization_endpoint, separator,
+                   percent_encode(&self.client_id),
+                   percent_encode(&self.redirect_uri),
+                   percent_encode(state)))
+    }
+
+    /// Exchange the authorization code the user came back with for their
+    /// email, if the provider verified it.
+    pub fn email(&self, code: &str) -> Result<Option<String>, String> {
+        let endpoints = try!(self.endpoints());
+
+        let url = &endpoints.token_endpoint;
+        let body = format!("grant_type=authorization_code&code={}&redirect_uri={}\
+                            &client_id={}&client_secret={}",
+                           percent_encode(code),
+                           percent_encode(&self.redirect_uri),
+                           percent_encode(&self.client_id),
+                           percent_encode(&self.client_secret));
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![b"application/x-www-form-urlencoded".to_vec()]);
+        let response = try!(client().post(url).headers(headers).body(&body).send()
+                                         .map_err(|e| format!("{}: {}", url, e)));
+        let tokens: Tokens = try!(decode(try!(read_json(response, url)), url));
+
+        let url = &endpoints.userinfo_endpoint;
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization",
+                        vec![format!("Bearer {}", tokens.access_token).into_bytes()]);
+        let response = try!(client().get(url).headers(headers).send()
+                                         .map_err(|e| format!("{}: {}", url, e)));
+        Ok(verified_email(&try!(read_json(response, url))))
+    }
+}
+
+/// The email of userinfo claims, if the provider says that it is verified:
+/// an unverified email could take over the account of its actual owner.
+/// Emails are matched in lower case, like those of accounts.
+fn verified_email(claims: &Json) -> Option<String> {
+    if claims.find("email_verified").and_then(Json::as_boolean) != Some(true) {
+        return None;
+    }
+    claims.find("email").and_then(Json::as_string).map(|email| email.to_lowercase())
+}
+
+#[test]
+fn test_verified_email() {
+    let claims = |claims: &str| verified_email(&Json::from_str(claims).unwrap());
+    assert_eq!(claims(r#"{"sub": "1", "email": "User@example.com", "email_verified": true}"#),
+               Some("user@example.com".to_owned()));
+    assert_eq!(claims(r#"{"email": "a@b", "email_verified": false}"#), None);
+    assert_eq!(claims(r#"{"email": "a@b", "email_verified": "true"}"#), None);
+    // Without the claim, the email may not be verified.
+    assert_eq!(claims(r#"{"sub": "1", "email": "a@b"}"#), None);
+    assert_eq!(claims(r#"{"sub": "1"}"#), None);
+}
@@ -0,0 +1,52 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Payloads of the QR codes shown by boxes to pair mobile apps: the
+/// discovery information of the box, and a pairing code which the app can
+/// redeem to get it again if it doesn't find the box at these addresses.
+
+use ctl::percent_encode;
+use db::Record;
+
+static PREFIX: &'static str = "fxbox://pair?v=1";
+
+/// Number of bytes a QR code holds at most, in byte mode with the lowest
+/// error correction level.
+pub static MAX_QR_BYTES: usize = 2953;
+
+/// The payload of the QR code of a box, a URI such as
+/// "fxbox://pair?v=1&fingerprint=...&public_ip=...&code=...". The message
+/// is left out when it doesn't fit in a QR code, since the app gets it by
+/// redeeming the code anyway.
+pub fn qr_payload(record: &Record, code: &str) -> String {
+    let mut payload = format!("{}&fingerprint={}&public_ip={}&code={}",
+                              PREFIX,
+                              percent_encode(&record.client),
+                              percent_encode(&record.public_ip),
+                              code);
+    if let Some(ref local_ip) = record.local_ip {
+        payload.push_str(&format!("&local_ip={}", percent_encode(local_ip)));
+    }
+
+    let message = format!("&message={}", percent_encode(&record.message));
+    if payload.len() + message.len() <= MAX_QR_BYTES {
+        payload.push_str(&message);
+    }
+    payload
+}
+
+#[test]
+fn test_qr_payload() {
+    let mut record = Record::new("1.2.3.4".to_owned(), "abcd".to_owned(),
+                                 "https://local.example/".to_owned(), 0);
+    record.local_ip = Some("192.168.1.2".to_owned());
+    assert_eq!(qr_payload(&record, "012345"),
+               "fxbox://pair?v=1&fingerprint=abcd&public_ip=1.2.3.4&code=012345\
+                &local_ip=192.168.1.2&message=https%3A%2F%2Flocal.example%2F");
+
+    record.message = (0..4096).map(|_| '/').collect();
+    let payload = qr_payload(&record, "012345");
+    assert!(!payload.contains("&message="));
+    assert!(payload.len() <= MAX_QR_BYTES);
+}
@@ -0,0 +1,73 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Privacy mode: with `--hash-ips <key>`, the database holds an HMAC-SHA256
+/// of the public IPs, keyed with a secret of the operator, rather than the
+/// addresses themselves. Boxes are still found by the hash of the public IP
+/// discovery is requested from, but a leaked database doesn't tell where
+/// they are without the key.
+///
+/// Likewise with `--hash-fingerprints <salt>` for the fingerprints of the
+/// boxes, which are hashed wherever they come from the requests, so that
+/// looking a box up by its exact fingerprint keeps working.
+
+use config::Config;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use rustc_serialize::hex::ToHex;
+use std::net::IpAddr;
+
+/// The keyed hash of a value, as 64 hex digits.
+fn keyed_hash(key: &str, value: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), key.as_bytes());
+    hmac.input(value.as_bytes());
+    hmac.result().code().to_hex()
+}
+
+/// The keyed hash of an IP.
+pub fn hash_ip(key: &str, ip: &IpAddr) -> String {
+    keyed_hash(key, &format!("{}", ip))
+}
+
+/// The public IP as stored in the database: its hash in privacy mode, the
+/// address itself otherwise.
+pub fn stored_ip(config: &Config, ip: &IpAddr) -> String {
+    match config.ip_hash_key {
+        Some(ref key) => hash_ip(key, ip),
+        None => format!("{}", ip)
+    }
+}
+
+/// The fingerprint of a box as stored in the database: its salted hash
+/// with `--hash-fingerprints`, the fingerprint itself otherwise.
+pub fn stored_fingerprint(config: &Config, fingerprint: &str) -> String {
+    match config.fingerprint_salt {
+        Some(ref salt) => keyed_hash(salt, fingerprint),
+        None => fingerprint.to_owned()
+    }
+}
+
+#[test]
+fn test_hash_ip() {
+    let ip: IpAddr = "88.22.170.96".parse().unwrap();
+    let hash = hash_ip("secret", &ip);
+    assert_eq!(hash.len(), 64);
+    assert!(hash.chars().all(|c| c.is_digit(16)));
+    assert_eq!(hash, hash_ip("secret", &"88.22.170.96".parse().unwrap()));
+    assert!(hash != hash_ip("other", &ip));
+    assert!(hash != hash_ip("secret", &"88.22.170.97".parse().unwrap()));
+}
+
+#[test]
+fn test_stored_fingerprint() {
+    use super::test_server::test_config;
+
+    let mut config = test_config(6379);
+    assert_eq!(stored_fingerprint(&config, "abcd"), "abcd");
+    config.fingerprint_salt = Some("salt".to_owned());
+    let hash = stored_fingerprint(&config, "abcd");
+    assert_eq!(hash, keyed_hash("salt", "abcd"));
+    assert!(hash != stored_fingerprint(&config, "abce"));
+}
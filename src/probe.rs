@@ -0,0 +1,177 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Reachability probes: with `--probe-port <port>`, the server tries to
+/// open a TCP connection back to the public IP a box registered from, on
+/// this port, and records whether it could in the `verified` field of the
+/// registration, so that clients can tell the boxes they can connect to
+/// from outside their network.
+///
+/// A box is probed when it registers while it wasn't registered from this
+/// public IP, or wasn't probed yet, rather than on every registration. The
+/// probes follow the events of the bus, through the `Prober` subscriber,
+/// which queues them for a single prober thread, dropping them when too
+/// many are waiting already. They only ever connect to the address the
+/// registration came from, so they can't be pointed at others.
+///
+/// The probes need the actual public IPs, which is why `--probe-port` and
+/// `--hash-ips` don't go together.
+
+use config::Config;
+use db::Record;
+use events;
+use libc;
+use net2::TcpBuilder;
+use std::net::{ IpAddr, SocketAddr };
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::sync::mpsc::{ self, SyncSender, TrySendError };
+use std::thread;
+use std::time::Duration;
+use storage::Storage;
+
+/// Number of seconds a box has to accept the connection.
+pub static PROBE_TIMEOUT: u64 = 5;
+/// Number of probes waiting to run above which the new ones are dropped.
+static QUEUE_SIZE: usize = 256;
+
+/// Whether a TCP connection to `addr` can be opened within `timeout`. The
+/// connection is closed right away.
+pub fn reachable(addr: SocketAddr, timeout: Duration) -> bool {
+    // `TcpStream::connect` has no timeout, so the socket connects without
+    // blocking, and `poll` waits for it.
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4(),
+        SocketAddr::V6(_) => TcpBuilder::new_v6(),
+    };
+    let builder = match builder {
+        Ok(builder) => builder,
+        Err(_) => return false
+    };
+    let fd = builder.as_raw_fd();
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) } == -1 {
+        return false;
+    }
+    match builder.connect(addr) {
+        Ok(_) => return true,
+        Err(ref e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {},
+        Err(_) => return false
+    }
+    let mut poll = libc::pollfd { fd: fd, events: libc::POLLOUT, revents: 0 };
+    let millis = timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1_000_000) as u64;
+    if unsafe { libc::poll(&mut poll, 1, millis as libc::c_int) } != 1 {
+        return false;
+    }
+    match builder.take_error() {
+        Ok(None) => true,
+        Ok(Some(_)) | Err(_) => false
+    }
+}
+
+/// Whether a box whose registration was `previous` should be probed when
+/// registering as `record`.
+fn needs_probe(previous: Option<&Record>, record: &Record) -> bool {
+    previous.map_or(true, |previous| {
+        previous.public_ip != record.public_ip || previous.verified.is_none()
+    })
+}
+
+struct Job {
+    config: Config,
+    public_ip: String,
+    client: String,
+    addr: SocketAddr,
+}
+
+fn probe(job: Job) {
+    let verified = reachable(job.addr, Duration::from_secs(PROBE_TIMEOUT));
+    info!("Probed {} at {}, reachable: {}", job.client, job.addr, verified);
+    let stored = job.config.storage.connect(&job.config).and_then(|db| {
+        db.set_verified(job.public_ip, job.client, verified)
+    });
+    if let Err(e) = stored {
+        error!("Can't record the probe: {}", e);
+    }
+}
+
+/// Probes the boxes which registered from a new public IP. Failures only
+/// cost probes.
+pub struct Prober {
+    // A `SyncSender` can be sent to another thread, but not shared.
+    queue: Mutex<SyncSender<Job>>,
+}
+
+impl Prober {
+    /// Start the prober thread, which stops along with the prober.
+    pub fn new() -> Prober {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(QUEUE_SIZE);
+        thread::spawn(move || {
+            for job in receiver {
+                probe(job);
+            }
+        });
+        Prober { queue: Mutex::new(sender) }
+    }
+}
+
+impl events::Subscriber for Prober {
+    fn name(&self) -> &'static str {
+        "probe"
+    }
+
+    fn wants_previous(&self, config: &Config) -> bool {
+        config.probe_port.is_some()
+    }
+
+    fn handle(&self, config: &Config, _: &Storage, event: &events::Event) {
+        let (previous, record) = match *event {
+            events::Event::Registered { ref previous, ref record } => (previous, record),
+            events::Event::Updated { .. } | events::Event::Evicted { .. } => return
+        };
+        let port = match config.probe_port {
+            Some(port) => port,
+            None => return
+        };
+        if !needs_probe(previous.as_ref(), record) {
+            return;
+        }
+        let ip: IpAddr = match record.public_ip.parse() {
+            Ok(ip) => ip,
+            Err(_) => return
+        };
+
+        let job = Job {
+            config: config.clone(),
+            public_ip: record.public_ip.clone(),
+            client: record.client.clone(),
+            addr: SocketAddr::new(ip, port),
+        };
+        match self.queue.lock().unwrap().try_send(job) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                warn!("Too many pending probes, dropping the one of {}", record.client)
+            },
+            Err(TrySendError::Disconnected(_)) => error!("The prober thread stopped")
+        }
+    }
+}
+
+#[test]
+fn test_reachable() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert!(reachable(addr, Duration::from_secs(1)));
+    drop(listener);
+    assert!(!reachable(addr, Duration::from_secs(1)));
+
+    let record = Record::new("1.2.3.4".to_owned(), "a".to_owned(), "m".to_owned(), 0);
+    assert!(needs_probe(None, &record));
+    let verified = Record { verified: Some(false), .. record.clone() };
+    assert!(!needs_probe(Some(&verified), &record));
+    assert!(needs_probe(Some(&record), &record));
+    let moved = Record { public_ip: "5.6.7.8".to_owned(), .. verified };
+    assert!(needs_probe(Some(&moved), &record));
+}
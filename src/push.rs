@@ -0,0 +1,340 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Push notifications to the mobile clients which subscribed to a box, sent
+/// when the box comes online or changes address.
+///
+/// Notifications to FCM tokens are sent to FCM directly with the server key
+/// of the project. The APNs provider API requires HTTP/2, which our HTTP
+/// client doesn't speak, so notifications to APNs tokens (and to FCM tokens
+/// without a server key) are POSTed to a push gateway run next to the
+/// server instead.
+///
+/// The notifications follow the events of the bus, through the `Notifier`
+/// subscriber, which queues them for a single sender thread. They tell
+/// which box changed, but not its IPs, which only the discovery hands out.
+
+use config::Config;
+use db::Record;
+use errors::ErrNo;
+use events;
+use features::Feature;
+use hyper::Client;
+use hyper::header::{ ContentType, Headers };
+use rustc_serialize::json::{ self, Json };
+use std::sync::Mutex;
+use std::sync::mpsc::{ self, SyncSender, TrySendError };
+use std::thread;
+use std::time::Duration;
+use storage::Storage;
+use validation::{ self, ValidationError };
+
+static FCM_URL: &'static str = "https://fcm.googleapis.com/fcm/send";
+
+/// Maximum length, in bytes, of a push token.
+pub static MAX_TOKEN_LENGTH: usize = 4096;
+/// Number of notifications waiting to be sent above which the new ones are
+/// dropped.
+static QUEUE_SIZE: usize = 1024;
+/// Number of seconds a push service has to read a notification, and to
+/// answer it.
+static SEND_TIMEOUT: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Service {
+    Fcm,
+    Apns,
+}
+
+impl Service {
+    pub fn from_name(name: &str) -> Option<Service> {
+        match name {
+            "fcm" => Some(Service::Fcm),
+            "apns" => Some(Service::Apns),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Service::Fcm => "fcm",
+            Service::Apns => "apns",
+        }
+    }
+}
+
+/// A push token of a mobile client, stored as "<service>:<token>".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscription {
+    pub service: Service,
+    pub token: String,
+}
+
+impl Subscription {
+    pub fn to_key(&self) -> String {
+        format!("{}:{}", self.service.name(), self.token)
+    }
+
+    pub fn from_key(key: &str) -> Option<Subscription> {
+        let mut parts = key.splitn(2, ':');
+        match (parts.next().and_then(Service::from_name), parts.next()) {
+            (Some(service), Some(token)) => Some(Subscription {
+                service: service,
+                token: token.to_owned(),
+            }),
+            _ => None
+        }
+    }
+}
+
+/// Validate the payload of POST /v1/push/subscribe and
+/// /v1/push/unsubscribe: the fingerprint of a box, and the push service and
+/// token of a mobile client. Kept apart from `validation` so that the fuzz
+/// target builds it without the push notifier.
+pub fn subscription(value: &Json) -> Result<(String, Subscription), ValidationError> {
+    if !value.is_object() {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, "A push subscription must be an object".to_owned()));
+    }
+    let client = try!(validation::string_field(value, "client", validation::MAX_CLIENT_LENGTH,
+                                               ErrNo::MissingClient, ErrNo::InvalidClient));
+    let service = try!(validation::string_field(value, "service", 16,
+                                                ErrNo::BadRequest, ErrNo::BadRequest));
+    let service = match Service::from_name(&service) {
+        Some(service) => service,
+        None => {
+            return Err(ValidationError::new(
+                ErrNo::BadRequest, "`service` must be fcm or apns".to_owned()))
+        }
+    };
+    let token = try!(validation::string_field(value, "token", MAX_TOKEN_LENGTH,
+                                              ErrNo::BadRequest, ErrNo::BadRequest));
+    Ok((client, Subscription {
+        service: service,
+        token: token,
+    }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// The box registered after being offline or unknown.
+    Online,
+    /// The public or local IP of the box changed.
+    AddressChanged,
+}
+
+impl Event {
+    /// What changed between the `previous` registration of a box and its
+    /// new `record`, if anything worth a notification.
+    pub fn between(previous: Option<&Record>, record: &Record, now: u64, ping_interval: u64)
+        -> Option<Event> {
+        match previous {
+            None => Some(Event::Online),
+            Some(previous) if !previous.is_online(now, ping_interval) => Some(Event::Online),
+            // The encrypted data may hold the local addresses as well.
+            Some(previous) if previous.public_ip != record.public_ip ||
+                              previous.local_ip != record.local_ip ||
+                              previous.encrypted != record.encrypted => {
+                Some(Event::AddressChanged)
+            },
+            Some(_) => None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Event::Online => "online",
+            Event::AddressChanged => "address_changed",
+        }
+    }
+}
+
+/// Where the notifications go. The push subsystem is enabled when at least
+/// one of them is set.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub fcm_key: Option<String>,
+    pub gateway: Option<String>,
+}
+
+impl Settings {
+    pub fn is_enabled(&self) -> bool {
+        self.fcm_key.is_some() || self.gateway.is_some()
+    }
+}
+
+#[derive(Debug, RustcEncodable)]
+struct Data {
+    event: &'static str,
+    client: String,
+    encrypted: Option<String>,
+    message: String,
+}
+
+#[derive(Debug, RustcEncodable)]
+struct FcmMessage<'a> {
+    to: &'a str,
+    data: &'a Data,
+}
+
+#[derive(Debug, RustcEncodable)]
+struct GatewayMessage<'a> {
+    service: &'static str,
+    token: &'a str,
+    data: &'a Data,
+}
+
+fn post(client: &Client, url: &str, headers: Headers,
+        body: Result<String, json::EncoderError>) {
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => return error!("Can't encode the push notification: {}", e)
+    };
+    let result = client.post(url).headers(headers).body(&body).send();
+    match result {
+        Ok(ref response) if response.status.is_success() => {},
+        Ok(response) => error!("Push notification rejected by {}: {}", url, response.status),
+        Err(e) => error!("Can't send the push notification to {}: {}", url, e)
+    }
+}
+
+/// The notifications of an event to the subscribers of a box.
+struct Job {
+    settings: Settings,
+    subscriptions: Vec<Subscription>,
+    data: Data,
+}
+
+fn send(client: &Client, job: Job) {
+    for subscription in job.subscriptions {
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+
+        match (subscription.service, &job.settings.fcm_key, &job.settings.gateway) {
+            (Service::Fcm, &Some(ref key), _) => {
+                headers.set_raw("Authorization", vec![format!("key={}", key).into_bytes()]);
+                post(client, FCM_URL, headers, json::encode(&FcmMessage {
+                    to: &subscription.token,
+                    data: &job.data,
+                }));
+            },
+            (_, _, &Some(ref gateway)) => {
+                post(client, gateway, headers, json::encode(&GatewayMessage {
+                    service: subscription.service.name(),
+                    token: &subscription.token,
+                    data: &job.data,
+                }));
+            },
+            (service, _, _) => {
+                warn!("No way to send {} push notifications", service.name());
+            }
+        }
+    }
+}
+
+/// Notifies the subscribers of the boxes which came online or changed
+/// address. Failures only cost notifications.
+pub struct Notifier {
+    // A `SyncSender` can be sent to another thread, but not shared.
+    queue: Mutex<SyncSender<Job>>,
+}
+
+impl Notifier {
+    /// Start the thread sending the notifications, which stops along with
+    /// the notifier.
+    pub fn new() -> Notifier {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(QUEUE_SIZE);
+        thread::spawn(move || {
+            let mut client = Client::new();
+            client.set_read_timeout(Some(Duration::from_secs(SEND_TIMEOUT)));
+            client.set_write_timeout(Some(Duration::from_secs(SEND_TIMEOUT)));
+            for job in receiver {
+                send(&client, job);
+            }
+        });
+        Notifier { queue: Mutex::new(sender) }
+    }
+
+    /// Queue the notifications of `event` about `record` to the
+    /// subscriptions, so that the registration isn't slowed down. They are
+    /// dropped when too many are waiting already.
+    fn notify(&self, settings: &Settings, subscriptions: Vec<Subscription>, event: Event,
+              record: &Record) {
+        if subscriptions.is_empty() {
+            return;
+        }
+        info!("Notifying {} subscribers that {} is {}", subscriptions.len(), record.client,
+              event.name());
+
+        let job = Job {
+            settings: settings.clone(),
+            subscriptions: subscriptions,
+            data: Data {
+                event: event.name(),
+                client: record.client.clone(),
+                encrypted: record.encrypted.clone(),
+                message: record.message.clone(),
+            },
+        };
+        match self.queue.lock().unwrap().try_send(job) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                warn!("Too many pending push notifications, dropping those about {}",
+                      record.client)
+            },
+            Err(TrySendError::Disconnected(_)) => error!("The push notifications sender stopped")
+        }
+    }
+}
+
+impl events::Subscriber for Notifier {
+    fn name(&self) -> &'static str {
+        "push"
+    }
+
+    fn wants_previous(&self, config: &Config) -> bool {
+        config.push.is_enabled() && config.features.is_enabled(Feature::Push)
+    }
+
+    fn handle(&self, config: &Config, db: &Storage, event: &events::Event) {
+        let (previous, record) = match *event {
+            events::Event::Registered { ref previous, ref record } |
+            events::Event::Updated { ref previous, ref record } => (previous, record),
+            events::Event::Evicted { .. } => return
+        };
+        if !self.wants_previous(config) {
+            return;
+        }
+        let event = match Event::between(previous.as_ref(), record, config.clock.now(),
+                                         config.ping_interval) {
+            Some(event) => event,
+            None => return
+        };
+        match db.subscriptions(record.client.clone()) {
+            Ok(subscriptions) => self.notify(&config.push, subscriptions, event, record),
+            Err(e) => error!("{}", e)
+        }
+    }
+}
+
+#[test]
+fn test_events() {
+    let record = |public_ip: &str, last_seen: u64| {
+        Record::new(public_ip.to_owned(), "<fingerprint>".to_owned(),
+                    "<message>".to_owned(), last_seen)
+    };
+    let now = 1000;
+    let current = record("1.2.3.4", now);
+
+    assert_eq!(Event::between(None, &current, now, 30), Some(Event::Online));
+    assert_eq!(Event::between(Some(&record("1.2.3.4", now - 100)), &current, now, 30),
+               Some(Event::Online));
+    assert_eq!(Event::between(Some(&record("5.6.7.8", now - 10)), &current, now, 30),
+               Some(Event::AddressChanged));
+    assert_eq!(Event::between(Some(&record("1.2.3.4", now - 10)), &current, now, 30), None);
+
+    let subscription = Subscription { service: Service::Apns, token: "a:b".to_owned() };
+    assert_eq!(Subscription::from_key(&subscription.to_key()), Some(subscription));
+    assert_eq!(Subscription::from_key("gcm:token"), None);
+}
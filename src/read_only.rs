@@ -0,0 +1,87 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Read-only mode, for database migrations and failovers: discovery keeps
+/// working, but the requests which write are rejected with a 503 and a
+/// Retry-After header, and the background jobs are paused.
+
+use errors::*;
+use iron::BeforeMiddleware;
+use iron::method::Method;
+use iron::prelude::*;
+use iron::status;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+/// Retry-After delay when none is given, in seconds.
+pub static DEFAULT_RETRY_AFTER: u64 = 60;
+
+/// Whether the server is read-only, shared by the handlers and the admin
+/// endpoint toggling it.
+#[derive(Debug, Default)]
+pub struct ReadOnly {
+    /// Seconds after which clients should retry their writes, 0 when the
+    /// server accepts them.
+    retry_after: AtomicUsize,
+}
+
+impl ReadOnly {
+    pub fn new() -> ReadOnly {
+        ReadOnly::default()
+    }
+
+    /// Reject the writes, telling clients to retry after `retry_after`
+    /// seconds, at least 1.
+    pub fn enable(&self, retry_after: u64) {
+        self.retry_after.store(retry_after.max(1) as usize, Ordering::SeqCst);
+    }
+
+    pub fn disable(&self) {
+        self.retry_after.store(0, Ordering::SeqCst);
+    }
+
+    /// The Retry-After delay of the rejected writes, if read-only.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self.retry_after.load(Ordering::SeqCst) {
+            0 => None,
+            retry_after => Some(retry_after as u64)
+        }
+    }
+}
+
+/// Rejects the public requests which may write while read-only. The admin
+/// API stays available, to turn the read-only mode off among other things.
+pub struct Guard {
+    pub state: Arc<ReadOnly>,
+}
+
+impl BeforeMiddleware for Guard {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let retry_after = match self.state.retry_after() {
+            Some(retry_after) => retry_after,
+            None => return Ok(())
+        };
+        match req.method {
+            Method::Get | Method::Head | Method::Options => return Ok(()),
+            _ => {}
+        }
+        if req.url.path().first() == Some(&"admin") {
+            return Ok(());
+        }
+        Err(EndpointError::build(status::ServiceUnavailable, ErrNo::ReadOnly,
+                                 Some(retry_after), None))
+    }
+}
+
+#[test]
+fn test_read_only() {
+    let state = ReadOnly::new();
+    assert_eq!(state.retry_after(), None);
+    state.enable(0);
+    assert_eq!(state.retry_after(), Some(1));
+    state.enable(120);
+    assert_eq!(state.retry_after(), Some(120));
+    state.disable();
+    assert_eq!(state.retry_after(), None);
+}
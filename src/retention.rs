@@ -0,0 +1,106 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// How long each kind of data is kept: the registrations, evicted once the
+/// box stops registering, the archive of the evicted registrations, the
+/// usage aggregates and the backup files.
+///
+/// Redis expires the registrations and aggregates by itself, with the TTLs
+/// of the policy; the backups are pruned by the "retention" job.
+
+use db::{ RECORD_TTL, USAGE_RETENTION };
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Policy {
+    /// Number of seconds after which the registration of a box which
+    /// didn't register again or send a heartbeat is evicted.
+    pub records: u64,
+    /// Number of days the latest registration of a box is archived after
+    /// its eviction, 0 to disable the archive.
+    pub archive: u64,
+    /// Number of days the usage aggregates are kept.
+    pub usage: u64,
+    /// Number of days the backup files are kept, 0 to keep them forever.
+    pub backups: u64,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            records: RECORD_TTL as u64,
+            archive: 0,
+            usage: USAGE_RETENTION,
+            backups: 0,
+        }
+    }
+}
+
+impl Policy {
+    /// Check that the policy keeps the data long enough to be usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.records == 0 {
+            return Err("The registrations must be kept for at least a second".to_owned());
+        }
+        if self.usage == 0 {
+            return Err("The usage aggregates must be kept for at least a day".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Delete the backup files of `directory` created more than `days` days
+/// before `now`, going by the timestamp in their name. Returns the number of
+/// files deleted.
+pub fn prune_backups(directory: &Path, days: u64, now: u64) -> io::Result<usize> {
+    if days == 0 || !directory.is_dir() {
+        return Ok(0);
+    }
+    let mut pruned = 0;
+    for entry in try!(fs::read_dir(directory)) {
+        let path = try!(entry).path();
+        let created = path.file_name().and_then(|name| name.to_str()).and_then(|name| {
+            if name.starts_with("registrations-") && name.ends_with(".json") {
+                name["registrations-".len()..name.len() - ".json".len()].parse::<u64>().ok()
+            } else {
+                None
+            }
+        });
+        if let Some(created) = created {
+            if created + days * 86400 <= now {
+                info!("Deleting the backup {:?}", path);
+                try!(fs::remove_file(&path));
+                pruned += 1;
+            }
+        }
+    }
+    Ok(pruned)
+}
+
+#[test]
+fn test_prune_backups() {
+    use std::env;
+    use std::fs::File;
+    use std::time::{ SystemTime, UNIX_EPOCH };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let directory = env::temp_dir().join(format!("registration_server_retention_{}", now));
+    fs::create_dir_all(&directory).unwrap();
+    for name in &[format!("registrations-{}.json", now - 8 * 86400),
+                  format!("registrations-{}.json", now - 86400),
+                  "notes.json".to_owned()] {
+        File::create(directory.join(name)).unwrap();
+    }
+
+    assert_eq!(prune_backups(&directory, 0, now).unwrap(), 0);
+    assert_eq!(prune_backups(&directory, 7, now).unwrap(), 1);
+    assert!(directory.join(format!("registrations-{}.json", now - 86400)).exists());
+    assert!(directory.join("notes.json").exists());
+    fs::remove_dir_all(&directory).unwrap();
+
+    assert!(Policy::default().validate().is_ok());
+    assert!(Policy { records: 0, .. Policy::default() }.validate().is_err());
+}
@@ -0,0 +1,190 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Revoked credentials: the admin API can revoke box tokens and tenant API
+/// keys, which lock out a compromised box or client right away, whatever
+/// the endpoint, with a 401 and the `errno` 115.
+///
+/// The database holds the SHA-256 of the revoked credentials rather than
+/// the credentials themselves. Each instance keeps the list in memory, so
+/// that checking a request doesn't hit the database: a single instance
+/// loads it once and updates it as it revokes, while the instances of a
+/// cluster fetch it again every `REFRESH_INTERVAL` seconds to see the
+/// revocations of the others. A single request fetches it at a time, without
+/// holding the lock of the list: the others keep checking the current one
+/// meanwhile. A failed fetch is only attempted again `REFRESH_INTERVAL`
+/// seconds later.
+
+use config::Config;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use errors::*;
+use iron::BeforeMiddleware;
+use iron::prelude::*;
+use iron::status;
+use redis::RedisResult;
+use std::collections::HashSet;
+use std::sync::{ Mutex, RwLock };
+use storage::Storage;
+use tokens;
+
+/// Number of seconds the instances of a cluster keep the list.
+pub static REFRESH_INTERVAL: u64 = 10;
+
+/// The hash of a credential, as 64 hex digits.
+pub fn hash(credential: &str) -> String {
+    let mut sha = Sha256::new();
+    sha.input_str(credential);
+    sha.result_str()
+}
+
+/// The hashes of the revoked credentials, shared by the middleware and the
+/// admin endpoints.
+#[derive(Debug, Default)]
+pub struct Revocations {
+    /// The list, once it was fetched.
+    hashes: RwLock<Option<HashSet<String>>>,
+    /// When the list was last fetched, or failed to be.
+    attempted: Mutex<Option<u64>>,
+}
+
+impl Revocations {
+    pub fn new() -> Revocations {
+        Revocations::default()
+    }
+
+    /// Whether one of `credentials` is revoked, fetching the list if needed.
+    /// The previous list is kept when the database can't be reached, and
+    /// nothing is revoked until the list could be fetched.
+    pub fn any_revoked(&self, config: &Config, credentials: &[String]) -> bool {
+        if self.claim_refresh(config) {
+            match config.storage.connect(config).and_then(|db| db.revoked()) {
+                Ok(revoked) => {
+                    let hashes = revoked.into_iter().map(|revocation| revocation.hash);
+                    *self.hashes.write().unwrap() = Some(hashes.collect());
+                },
+                Err(e) => error!("Can't fetch the revoked credentials: {}", e)
+            }
+        }
+        match *self.hashes.read().unwrap() {
+            Some(ref hashes) => {
+                credentials.iter().any(|credential| hashes.contains(&hash(credential)))
+            },
+            None => false
+        }
+    }
+
+    /// Whether the list should be fetched now, in which case the caller
+    /// fetches it and the other requests don't until `REFRESH_INTERVAL`
+    /// seconds passed.
+    fn claim_refresh(&self, config: &Config) -> bool {
+        let now = config.clock.now();
+        let mut attempted = self.attempted.lock().unwrap();
+        let fetched = self.hashes.read().unwrap().is_some();
+        let due = match *attempted {
+            Some(_) if fetched && !config.cluster => false,
+            Some(at) => at + REFRESH_INTERVAL <= now,
+            None => true
+        };
+        if due {
+            *attempted = Some(now);
+        }
+        due
+    }
+
+    /// Revoke the credentials with these hashes.
+    pub fn revoke(&self, db: &Storage, hashes: &[String]) -> RedisResult<()> {
+        for hash in hashes {
+            try!(db.revoke(hash.clone()));
+        }
+        if let Some(ref mut cached) = *self.hashes.write().unwrap() {
+            cached.extend(hashes.iter().cloned());
+        }
+        Ok(())
+    }
+
+    /// Accept the credential with this hash again, returning whether it was
+    /// revoked.
+    pub fn restore(&self, db: &Storage, hash: &str) -> RedisResult<bool> {
+        let restored = try!(db.unrevoke(hash.to_owned()));
+        if let Some(ref mut cached) = *self.hashes.write().unwrap() {
+            cached.remove(hash);
+        }
+        Ok(restored)
+    }
+}
+
+/// Rejects the requests carrying a revoked box token or API key. The admin
+/// API is left out, its token can't be revoked.
+pub struct Guard {
+    pub config: Config,
+}
+
+impl BeforeMiddleware for Guard {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if req.url.path().first() == Some(&"admin") {
+            return Ok(());
+        }
+        let mut credentials = vec![];
+        if let Some(token) = tokens::bearer(req) {
+            credentials.push(token);
+        }
+        let api_key = req.headers.get_raw("X-Api-Key")
+                         .and_then(|values| values.get(0))
+                         .and_then(|value| String::from_utf8(value.clone()).ok());
+        if let Some(api_key) = api_key {
+            credentials.push(api_key);
+        }
+        if credentials.is_empty() ||
+           !self.config.revocations.any_revoked(&self.config, &credentials) {
+            return Ok(());
+        }
+        info!("Rejecting a revoked credential");
+        Err(EndpointError::build(status::Unauthorized, ErrNo::Revoked, None, None))
+    }
+}
+
+#[test]
+fn test_hash() {
+    let hashed = hash("token");
+    assert_eq!(hashed.len(), 64);
+    assert!(hashed.chars().all(|c| c.is_digit(16)));
+    assert_eq!(hashed, hash("token"));
+    assert!(hashed != hash("other"));
+}
+
+#[test]
+fn test_refresh() {
+    use super::clock::ManualClock;
+    use super::storage::{ MockStorage, Storage };
+    use super::test_server::test_config;
+    use redis::ErrorKind;
+    use std::sync::Arc;
+
+    let credentials = vec!["token".to_owned()];
+    let clock = Arc::new(ManualClock::new(1481900000));
+    let storage = MockStorage::new();
+    let mut config = test_config(0);
+    config.cluster = true;
+    config.clock = clock.clone();
+    config.storage = Arc::new(storage.clone());
+
+    storage.revoke(hash("token")).unwrap();
+    let revocations = Revocations::new();
+    assert!(revocations.any_revoked(&config, &credentials));
+    storage.unrevoke(hash("token")).unwrap();
+    // The list is only fetched again once it is stale.
+    assert!(revocations.any_revoked(&config, &credentials));
+    clock.advance(REFRESH_INTERVAL);
+    assert!(!revocations.any_revoked(&config, &credentials));
+    assert_eq!(storage.calls().iter().filter(|call| *call == "revoked ").count(), 2);
+
+    // A failed fetch keeps the list, and isn't attempted again right away.
+    storage.revoke(hash("token")).unwrap();
+    storage.fail("revoked", ErrorKind::IoError, "connection lost");
+    clock.advance(REFRESH_INTERVAL);
+    assert!(!revocations.any_revoked(&config, &credentials));
+    assert!(!revocations.any_revoked(&config, &credentials));
+    assert_eq!(storage.calls().iter().filter(|call| *call == "revoked ").count(), 3);
+}
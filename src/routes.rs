@@ -2,16 +2,34 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use db::{ Db, Record};
+use cache::{ discovery_key, SharedCache };
+use config::Config;
+use db::{ self, Heartbeat, Pairing, Precondition, Record, RecordStatus };
+use discovery::{ self, Options, Summary };
+use events::Event;
+use pairing;
 use errors::*;
-use iron::headers::ContentType;
+use iron::headers::{ ContentType, EntityTag, ETag, IfMatch, IfUnmodifiedSince, Location };
+use iron::method::Method;
 use iron::prelude::*;
+use jwt;
 use iron::status::{ self, Status };
+use params::Params;
+use privacy;
+use push;
+use revocation;
 use router::Router;
+use routing::Routes;
+use rustc_serialize::Encodable;
 use rustc_serialize::json;
+use signaling::{ Candidate, Exchange };
 use std::error::Error;
 use std::fmt::{ self, Debug };
-use std::io::Read;
+use storage::Storage;
+use tenants::RateLimiter;
+use tokens;
+use tracing;
+use validation::{ self, Registration };
 
 #[derive(Debug)]
 struct StringError(String);
@@ -26,116 +44,1995 @@ impl Error for StringError {
     fn description(&self) -> &str { &*self.0 }
 }
 
-fn register(req: &mut Request,
-            db_host: String,
-            db_port: u16,
-            db_password: Option<String>) -> IronResult<Response> {
-   // Get the local IP and optional tunnel url from the body,
-    #[derive(RustcDecodable, Debug)]
-    struct RegisterBody {
-        client:  String,
-        message: String,
+/// Whether discovery lookups are cached, which they aren't in cluster mode.
+fn caches_discovery(config: &Config) -> bool {
+    !config.cluster && config.cache_size > 0
+}
+
+/// Forget the cached discovery results that the registration of these
+/// records changes: the ones of their public IP, and of the public IPs
+/// they moved from.
+fn invalidate_discovery(cache: &SharedCache, config: &Config, previous: &[Option<Record>],
+                        records: &[Record]) {
+    let mut cache = cache.lock().unwrap();
+    for (previous, record) in previous.iter().zip(records) {
+        cache.invalidate(&discovery_key(config, &record.public_ip));
+        if let Some(ref previous) = *previous {
+            if previous.public_ip != record.public_ip {
+                cache.invalidate(&discovery_key(config, &previous.public_ip));
+            }
+        }
     }
+}
 
-    let mut payload = String::new();
-    req.body.read_to_string(&mut payload).unwrap();
-    let body: RegisterBody = match json::decode(&payload) {
-        Ok(body) => body,
-        Err(error) => {
-            error!("{:?}", error);
-            return from_decoder_error(error);
+/// The latest records of the clients about to register, when a subscriber
+/// of the events wants them, or the discovery results of the public IPs
+/// they may move from are cached.
+fn previous_records(db: &Storage, config: &Config, records: &[Record])
+    -> Vec<Option<Record>> {
+    if !config.events.wants_previous(config) && !caches_discovery(config) {
+        return vec![None; records.len()];
+    }
+    records.iter().map(|record| {
+        db.find_by_client(record.client.clone()).unwrap_or_else(|e| {
+            error!("{}", e);
+            None
+        })
+    }).collect()
+}
+
+/// Publish the registration of these records, or their update when
+/// `updated`.
+fn publish_changes(db: &Storage, config: &Config, previous: Vec<Option<Record>>,
+                   records: &[Record], updated: bool) {
+    for (previous, record) in previous.into_iter().zip(records) {
+        let event = if updated {
+            Event::Updated { previous: previous, record: record.clone() }
+        } else {
+            Event::Registered { previous: previous, record: record.clone() }
+        };
+        config.events.publish(config, db, &event);
+    }
+}
+
+/// Empty the mailbox of a box for the response to its registration or
+/// heartbeat, as a `"mailbox"` field to add to the response when it isn't
+/// empty. The messages stay for the next time if the database fails.
+fn mailbox_field(req: &mut Request, db: &Storage, client: &str) -> String {
+    let messages = tracing::span(req, "db.take_messages",
+                                 || db.take_messages(client.to_owned()));
+    match messages {
+        Ok(ref messages) if messages.is_empty() => String::new(),
+        Ok(messages) => {
+            info!("Delivering {} messages to {}", messages.len(), client);
+            format!(", \"mailbox\" : {}", json::encode(&messages).unwrap())
+        },
+        Err(e) => {
+            error!("{}", e);
+            String::new()
         }
+    }
+}
+
+/// With `--flapping-auth`, require the token of the latest registration of
+/// `client` when it is flagged as flapping.
+fn check_flapping(req: &mut Request, db: &Storage, config: &Config, client: &str)
+    -> IronResult<()> {
+    if !config.flapping_auth {
+        return Ok(());
+    }
+    let token = tokens::bearer(req);
+    match tracing::span(req, "db.check_flapping",
+                        || db.check_flapping(client.to_owned(), token)) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            info!("Rejecting the registration of the flapping {}", client);
+            Err(EndpointError::build(status::Unauthorized, ErrNo::Flapping, None, None))
+        },
+        Err(e) => Err(database_error(e))
+    }
+}
+
+/// Count a registration of `client` against the hourly quota of its owner,
+/// if it is linked to an account. Returns the state of the quota, to be
+/// sent with the response.
+fn check_quota(req: &mut Request, db: &Storage, config: &Config, client: &str)
+    -> IronResult<Option<LimitState>> {
+    let max = match config.quotas.registrations_per_hour {
+        Some(max) => max,
+        None => return Ok(None)
     };
+    match tracing::span(req, "db.count_registration",
+                        || db.count_registration(client.to_owned())) {
+        Ok(Some((owner, count))) => {
+            let now = config.clock.now();
+            let state = LimitState::new(max, count, 3600 - now % 3600);
+            if count > max {
+                info!("{} is over its quota of registrations", owner);
+                return Err(EndpointError::with_limit(
+                    ErrNo::TooManyRegistrations, state,
+                    Some(format!("At most {} registrations per hour", max))
+                ));
+            }
+            Ok(Some(state))
+        },
+        Ok(None) => Ok(None),
+        // The quota isn't worth failing registrations.
+        Err(e) => {
+            error!("{}", e);
+            Ok(None)
+        }
+    }
+}
 
-    let message   = body.message;
-    let client_id = body.client;
+/// The condition of an update on the current record of the box, so that
+/// devices sharing a fingerprint don't silently overwrite each other's
+/// registration: the expected revision of the payload if any, else the
+/// `If-Match` or `If-Unmodified-Since` header.
+fn precondition(req: &Request, expected_revision: Option<u64>) -> Option<Precondition> {
+    if let Some(revision) = expected_revision {
+        return Some(Precondition::Revision(revision));
+    }
+    match req.headers.get::<IfMatch>() {
+        Some(&IfMatch::Any) => return Some(Precondition::Exists),
+        Some(&IfMatch::Items(ref etags)) => {
+            // If-Match uses the strong comparison, which weak tags never match.
+            return Some(Precondition::Matches(
+                etags.iter().filter(|etag| !etag.weak)
+                            .map(|etag| etag.tag().to_owned())
+                            .collect()
+            ));
+        },
+        None => {}
+    }
+    req.headers.get::<IfUnmodifiedSince>().map(|date| {
+        Precondition::UnmodifiedSince((date.0).0.to_timespec().sec as u64)
+    })
+}
 
-    // And the public IP from the socket.
-    let public_ip = format!("{}", req.remote_addr.ip());
+/// Longest `Idempotency-Key` header accepted.
+static MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// The key under which the response of a request with an `Idempotency-Key`
+/// header is kept, scoped by route and public IP so that clients can't get
+/// each other's responses.
+fn idempotency_key(req: &Request, config: &Config, route_id: &str)
+                   -> IronResult<Option<String>> {
+    let key = match req.headers.get_raw("Idempotency-Key").and_then(|values| values.get(0)) {
+        Some(key) => key.clone(),
+        None => return Ok(None)
+    };
+    match String::from_utf8(key) {
+        Ok(ref key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH => {
+            let public_ip = privacy::stored_ip(config, &req.remote_addr.ip());
+            Ok(Some(format!("{}:{}:{}", route_id, public_ip, key)))
+        },
+        _ => {
+            Err(EndpointError::build(status::BadRequest, ErrNo::InvalidParameter, None,
+                                     Some(format!("`Idempotency-Key` must have 1 to {} \
+                                                   bytes of UTF-8",
+                                                  MAX_IDEMPOTENCY_KEY_LENGTH))))
+        }
+    }
+}
+
+/// The response of the first attempt of a request retried with the same
+/// idempotency key, if it succeeded.
+fn replay(req: &mut Request, db: &Storage, config: &Config, key: &Option<String>,
+          route_id: &str) -> IronResult<Option<Response>> {
+    let key = match *key {
+        Some(ref key) => key,
+        None => return Ok(None)
+    };
+    match tracing::span(req, "db.find_response", || db.find_response(key.clone())) {
+        Ok(Some(body)) => {
+            info!("Replaying the response of {}", key);
+            config.metrics.record_replay(route_id);
+            let mut response = Response::with((Status::Ok, body));
+            response.headers.set(ContentType::json());
+            response.headers.set_raw("Idempotent-Replayed", vec![b"true".to_vec()]);
+            Ok(Some(response))
+        },
+        Ok(None) => Ok(None),
+        Err(e) => Err(database_error(e))
+    }
+}
+
+/// Keep the response of a request with an idempotency key, for its retries.
+fn keep_response(db: &Storage, key: Option<String>, body: &str) {
+    if let Some(key) = key {
+        // The request succeeded anyway, retries will just be applied again.
+        if let Err(e) = db.keep_response(key, body.to_owned()) {
+            error!("{}", e);
+        }
+    }
+}
+
+fn register(req: &mut Request,
+            config: &Config,
+            cache: &SharedCache) -> IronResult<Response> {
+   // Get the local IP and optional tunnel url from the body,
+    let body = try!(validation::extract(req, |value| {
+        validation::registration(value, config.strict)
+    }));
 
     info!("POST /register public_ip={} client={} message={}",
-          public_ip, client_id, message);
+          req.remote_addr.ip(), body.client, body.message);
+    store_registration(req, config, cache, body, "post_message", false)
+}
+
+/// Store a validated registration, with the public IP of the request. With
+/// `replace`, a 201 tells that the box wasn't registered, as PUT /v1/box
+/// does.
+fn store_registration(req: &mut Request,
+                      config: &Config,
+                      cache: &SharedCache,
+                      body: Registration,
+                      route_id: &str,
+                      replace: bool) -> IronResult<Response> {
+    let message     = body.message;
+    let fingerprint = body.client.clone();
+    let client_id   = privacy::stored_fingerprint(config, &body.client);
+
+    // And the public IP from the socket.
+    let public_ip = privacy::stored_ip(config, &req.remote_addr.ip());
 
     // Save this registration in the database.
     // If we already have the same (local, tunnel, public) match, update it,
     // if not create a new match.
-    let db = Db::new(db_host, db_port, db_password);
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let idempotency_key = try!(idempotency_key(req, config, route_id));
+    if let Some(response) = try!(replay(req, &*db, config, &idempotency_key, route_id)) {
+        return Ok(response);
+    }
+
+    let mut record = Record::new(public_ip.clone(),
+                                 client_id.clone(),
+                                 message.clone(),
+                                 config.clock.now());
+    record.local_ip = body.local_ip;
+    record.encrypted = body.encrypted;
+    record.nat = body.nat;
+    let records = [record];
+    try!(check_flapping(req, &*db, config, &client_id));
+    let quota = try!(check_quota(req, &*db, config, &client_id));
+    let previous = previous_records(&*db, config, &records);
+
+    let precondition = precondition(req, body.expected_revision);
+    let revision = match precondition {
+        Some(precondition) => {
+            match tracing::span(req, "db.set_if",
+                                || db.set_if(records[0].clone(), precondition)) {
+                Ok(Some(revision)) => revision,
+                Ok(None) => {
+                    return EndpointError::with(status::PreconditionFailed,
+                                               ErrNo::PreconditionFailed)
+                },
+                Err(e) => return Err(database_error(e))
+            }
+        },
+        None => match tracing::span(req, "db.set", || db.set(records[0].clone())) {
+            Ok(revision) => revision,
+            Err(e) => return Err(database_error(e))
+        }
+    };
+    invalidate_discovery(cache, config, &previous, &records);
+    publish_changes(&*db, config, previous, &records, false);
+
+    let token = jwt::issue(config.jwt.as_ref(), &client_id, jwt::BOX_SCOPE,
+                           jwt::BOX_TOKEN_LIFETIME, config.clock.now());
+    if let Err(e) = tracing::span(req, "db.set_token",
+                                  || db.set_token(client_id.clone(), token.clone())) {
+        return Err(database_error(e))
+    }
+
+    let mailbox = mailbox_field(req, &*db, &client_id);
+    let body = format!("{{\"status\" : \"registered\", \"revision\" : {}, \
+                        \"token\" : \"{}\"{}}}",
+                       revision, token, mailbox);
+    keep_response(&*db, idempotency_key, &body);
+    let mut response = Response::with(body);
+    response.headers.set(ContentType::json());
+    if replace && revision == 1 {
+        response.status = Some(Status::Created);
+        response.headers.set(Location(format!("/v1/box/{}", fingerprint)));
+    } else {
+        response.status = Some(Status::Ok);
+    }
+    if let Some(quota) = quota {
+        quota.set_headers(&mut response.headers);
+    }
+
+    Ok(response)
+}
+
+/// Replace the registration of a box with the one of the body, creating it
+/// if the box isn't registered.
+fn replace_box(req: &mut Request,
+               config: &Config,
+               cache: &SharedCache) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    let body = try!(validation::extract(req, |value| {
+        validation::replacement(value, &fingerprint, config.strict)
+    }));
+    info!("PUT /v1/box/{} public_ip={} message={}",
+          fingerprint, req.remote_addr.ip(), body.message);
+    store_registration(req, config, cache, body, "replace_box", true)
+}
+
+/// Maximum number of registrations accepted by a single batch.
+static MAX_BATCH_SIZE: usize = 100;
 
-    let record = Record {
-        public_ip: public_ip.clone(),
-        client:  client_id.clone(),
-        message: message.clone()
+#[derive(RustcEncodable)]
+struct BatchRegistered {
+    status: String,
+    count: usize,
+    /// The revision and the token of each registration, in order.
+    revisions: Vec<u64>,
+    tokens: Vec<String>,
+}
+
+fn register_batch(req: &mut Request,
+                  config: &Config,
+                  cache: &SharedCache) -> IronResult<Response> {
+    let bodies = try!(validation::extract(req, |value| {
+        validation::batch(value, MAX_BATCH_SIZE, config.strict)
+    }));
+
+    let public_ip = privacy::stored_ip(config, &req.remote_addr.ip());
+    info!("POST /v1/register/batch public_ip={} count={}",
+          public_ip, bodies.len());
+
+    let now = config.clock.now();
+    let records: Vec<Record> = bodies.into_iter().map(|body| {
+        let client = privacy::stored_fingerprint(config, &body.client);
+        let mut record = Record::new(public_ip.clone(), client, body.message, now);
+        record.local_ip = body.local_ip;
+        record.encrypted = body.encrypted;
+        record.nat = body.nat;
+        record
+    }).collect();
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let idempotency_key = try!(idempotency_key(req, config, "register_batch"));
+    if let Some(response) = try!(replay(req, &*db, config, &idempotency_key,
+                                        "register_batch")) {
+        return Ok(response);
+    }
+    // The boxes may belong to several accounts, the response tells about
+    // the one closest to its quota.
+    let mut quota: Option<LimitState> = None;
+    for record in &records {
+        try!(check_flapping(req, &*db, config, &record.client));
+        if let Some(state) = try!(check_quota(req, &*db, config, &record.client)) {
+            if quota.map_or(true, |quota| state.remaining < quota.remaining) {
+                quota = Some(state);
+            }
+        }
+    }
+    let previous = previous_records(&*db, config, &records);
+    let revisions = match tracing::span(req, "db.add_many", || db.add_many(&records)) {
+        Ok(revisions) => revisions,
+        Err(e) => return Err(database_error(e))
     };
+    invalidate_discovery(cache, config, &previous, &records);
+    publish_changes(&*db, config, previous, &records, false);
 
-    if let Err(e) = db.set(record) {
-        error!("{}", e);
-        return EndpointError::with(status::InternalServerError, 501)
+    let mut box_tokens = Vec::with_capacity(records.len());
+    for record in &records {
+        let token = jwt::issue(config.jwt.as_ref(), &record.client, jwt::BOX_SCOPE,
+                               jwt::BOX_TOKEN_LIFETIME, config.clock.now());
+        if let Err(e) = db.set_token(record.client.clone(), token.clone()) {
+            return Err(database_error(e))
+        }
+        box_tokens.push(token);
     }
 
-    let mut response = Response::with("{\"status\" : \"registered\"}");
+    let body = match json::encode(&BatchRegistered {
+        status: "registered".to_owned(),
+        count: records.len(),
+        revisions: revisions,
+        tokens: box_tokens,
+    }) {
+        Ok(body) => body,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+    keep_response(&*db, idempotency_key, &body);
+    let mut response = Response::with(body);
     response.status = Some(Status::Ok);
     response.headers.set(ContentType::json());
+    if let Some(quota) = quota {
+        quota.set_headers(&mut response.headers);
+    }
 
     Ok(response)
 }
 
 fn ping(req: &mut Request,
-        db_host: String,
-        db_port: u16,
-        db_password: Option<String>) -> IronResult<Response> {
+        config: &Config,
+        cache: &SharedCache) -> IronResult<Response> {
     info!("GET /ping");
-    let public_ip = format!("{}", req.remote_addr.ip());
-
-    let mut serialized = String::from("[");
-
-    let db = Db::new(db_host, db_port, db_password);
-    match db.get(public_ip.clone()) {
-        Ok(rvect) => {
-            info!("Registrations {:?}", rvect);
-            // Serialize the vector.
-            let max = rvect.len();
-            let mut index = 0;
-            for record in rvect {
-                match json::encode(&record) {
-                    Ok(ref record) => serialized.push_str(record),
-                    Err(_) => {
-                        return EndpointError::with(status::InternalServerError, 501)
-                    }
-                }
-
-                index += 1;
-                if index < max {
-                    serialized.push_str(",");
-                }
+    discovery_response(req, config, cache)
+}
+
+/// The full records of the boxes behind the public IP of the request.
+fn discovery_response(req: &mut Request,
+                      config: &Config,
+                      cache: &SharedCache) -> IronResult<Response> {
+    let (public_ip, records) = try!(discover(req, config, cache));
+
+    let now = config.clock.now();
+    let records: Vec<RecordStatus> = records.into_iter().map(|record| {
+        let strategy = discovery::strategy(&record, &public_ip);
+        RecordStatus::new(record, now, config.ping_interval).with_strategy(strategy)
+    }).collect();
+    let serialized = match json::encode(&records) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+/// The open discovery: the boxes behind the public IP of the request, as
+/// `discovery::Summary`, for at most `open_discovery_limit` requests per
+/// minute from each public IP.
+fn open_discovery(req: &mut Request,
+                  config: &Config,
+                  cache: &SharedCache,
+                  limiter: &RateLimiter) -> IronResult<Response> {
+    info!("GET /v1/discovery");
+    let state = match limiter.check(req.remote_addr.ip(), config.clock.now()) {
+        Ok(state) => state,
+        Err(state) => {
+            info!("Rate limiting the open discovery of {}", req.remote_addr.ip());
+            return Err(EndpointError::with_limit(ErrNo::TooManyRequests, state, None));
+        }
+    };
+    let (public_ip, records) = try!(discover(req, config, cache));
+
+    let now = config.clock.now();
+    let summaries: Vec<Summary> = records.iter().map(|record| {
+        Summary::new(record, &public_ip, now, config.ping_interval)
+    }).collect();
+    let serialized = match json::encode(&summaries) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+    state.set_headers(&mut response.headers);
+
+    Ok(response)
+}
+
+/// The full discovery: the records of /ping, for the clients with an API
+/// key of the tenants file or of the database.
+fn full_discovery(req: &mut Request,
+                  config: &Config,
+                  cache: &SharedCache) -> IronResult<Response> {
+    info!("GET /v1/discovery/full");
+    let api_key = req.headers.get_raw("X-Api-Key")
+                     .and_then(|values| values.get(0))
+                     .and_then(|value| String::from_utf8(value.clone()).ok());
+    let api_key = match api_key {
+        Some(api_key) => api_key,
+        None => return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized)
+    };
+    if !config.api_keys.iter().any(|expected| tokens::matches(expected, &api_key)) {
+        let db = try!(config.storage.connect(config).map_err(database_unavailable));
+        match tracing::span(req, "db.find_credential",
+                            || db.find_credential(revocation::hash(&api_key))) {
+            Ok(Some(ref credential)) if credential.kind == db::API_CREDENTIAL => {},
+            Ok(_) => return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized),
+            Err(e) => return Err(database_error(e))
+        }
+    }
+    discovery_response(req, config, cache)
+}
+
+/// The public IP of a discovery request, as stored, and the records behind
+/// it, ranked as its query string asks.
+fn discover(req: &mut Request,
+            config: &Config,
+            cache: &SharedCache) -> IronResult<(String, Vec<Record>)> {
+    let public_ip = privacy::stored_ip(config, &req.remote_addr.ip());
+
+    let options = match req.get_ref::<Params>() {
+        Ok(params) => match Options::from_params(params) {
+            Ok(options) => options,
+            Err(param) => {
+                error!("Invalid discovery parameter {}", param);
+                return Err(EndpointError::build(status::BadRequest,
+                                                ErrNo::InvalidParameter,
+                                                None,
+                                                Some(format!("Invalid `{}` parameter", param))))
+            }
+        },
+        Err(_) => Options::default()
+    };
+
+    let key = discovery_key(config, &public_ip);
+    let cached = cache.lock().unwrap().get(&key, config.clock.now());
+    let rvect = match cached {
+        Some(rvect) => rvect,
+        None => {
+            // Discovery degrades to an empty list, even without a database.
+            let discovered = config.storage.connect(config).and_then(|db| {
+                tracing::span(req, "db.discover", || db.discover(public_ip.clone()))
+            });
+            match discovered {
+                Ok(rvect) => {
+                    cache.lock().unwrap().insert(key,
+                                                 rvect.clone(),
+                                                 config.clock.now());
+                    rvect
+                },
+                Err(_) => vec![]
             }
+        }
+    };
+    info!("Registrations {:?}", rvect);
+    Ok((public_ip, discovery::rank(rvect, &options)))
+}
+
+fn heartbeat(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let client = try!(validation::extract(req, validation::heartbeat));
+    let token = match tokens::bearer(req) {
+        Some(token) => token,
+        None => return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized)
+    };
+    info!("PUT /v1/ping client={}", client);
+    let client = privacy::stored_fingerprint(config, &client);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let record = match tracing::span(req, "db.heartbeat", || db.heartbeat(client, &token)) {
+        Ok(Heartbeat::Alive(record)) => record,
+        Ok(Heartbeat::Unknown) => return EndpointError::with(status::NotFound, ErrNo::NotFound),
+        Ok(Heartbeat::InvalidToken) => {
+            return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized)
+        },
+        Err(e) => return Err(database_error(e))
+    };
+
+    let mailbox = mailbox_field(req, &*db, &record.client);
+    let mut response = Response::with(
+        format!("{{\"status\" : \"alive\", \"last_seen\" : {}{}}}", record.last_seen, mailbox)
+    );
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+/// Change some fields of the latest registration of a box, keeping the
+/// others, so that it doesn't have to send the whole registration again.
+fn patch_box(req: &mut Request,
+             config: &Config,
+             cache: &SharedCache) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    let patch = try!(validation::extract(req, validation::patch));
+    info!("PATCH /v1/box/{} {:?}", fingerprint, patch);
+    let fingerprint = privacy::stored_fingerprint(config, &fingerprint);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let current = try!(authenticate_box(req, &*db, fingerprint.clone()));
+
+    let mut record = Record::new(current.public_ip.clone(), fingerprint,
+                                 patch.message.unwrap_or(current.message.clone()),
+                                 config.clock.now());
+    record.first_seen = current.first_seen;
+    record.local_ip = patch.local_ip.unwrap_or(current.local_ip.clone());
+    record.encrypted = patch.encrypted.unwrap_or(current.encrypted.clone());
+    record.nat = current.nat.clone();
+
+    // Merging is only right if the record didn't change since we read it.
+    let precondition = precondition(req, None)
+        .unwrap_or(Precondition::Matches(vec![current.etag()]));
+    let records = [record];
+    let previous = previous_records(&*db, config, &records);
+    let revision = match tracing::span(req, "db.set_if",
+                                       || db.set_if(records[0].clone(), precondition)) {
+        Ok(Some(revision)) => revision,
+        Ok(None) => {
+            return EndpointError::with(status::PreconditionFailed, ErrNo::PreconditionFailed)
+        },
+        Err(e) => return Err(database_error(e))
+    };
+    cache.lock().unwrap().invalidate(&discovery_key(config, &current.public_ip));
+    publish_changes(&*db, config, previous, &records, true);
+
+    let record = Record { revision: revision, .. records[0].clone() };
+    let etag = EntityTag::strong(record.etag());
+    let record = RecordStatus::new(record, config.clock.now(), config.ping_interval);
+    let serialized = match json::encode(&record) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+    response.headers.set(ETag(etag));
+
+    Ok(response)
+}
+
+/// Check that a request comes from the box `client` itself, with the token
+/// of its latest registration, and return this registration. The request
+/// also proves that the box is alive.
+fn authenticate_box(req: &mut Request, db: &Storage, client: String) -> IronResult<Record> {
+    let token = match tokens::bearer(req) {
+        Some(token) => token,
+        None => {
+            return Err(EndpointError::build(status::Unauthorized, ErrNo::Unauthorized,
+                                            None, None))
+        }
+    };
+    match tracing::span(req, "db.heartbeat", || db.heartbeat(client, &token)) {
+        Ok(Heartbeat::Alive(record)) => Ok(record),
+        Ok(Heartbeat::Unknown) => {
+            Err(EndpointError::build(status::NotFound, ErrNo::NotFound, None, None))
+        },
+        Ok(Heartbeat::InvalidToken) => {
+            Err(EndpointError::build(status::Unauthorized, ErrNo::Unauthorized, None, None))
+        },
+        Err(e) => Err(database_error(e))
+    }
+}
+
+fn new_pairing_code(req: &mut Request, db: &Storage, client: String) -> IronResult<String> {
+    tracing::span(req, "db.create_pairing_code", || db.create_pairing_code(client))
+        .map_err(database_error)
+}
+
+fn create_pairing(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let client = try!(validation::extract(req, validation::pairing));
+    info!("POST /v1/pairing client={}", client);
+    let client = privacy::stored_fingerprint(config, &client);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    try!(authenticate_box(req, &*db, client.clone()));
+    let code = try!(new_pairing_code(req, &*db, client));
+
+    let mut response = Response::with(
+        format!("{{\"code\" : \"{}\", \"expires_in\" : {}}}", code, db::PAIRING_TTL)
+    );
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+#[derive(RustcEncodable)]
+struct RotatedToken {
+    token: String,
+    /// Number of seconds the previous token keeps working.
+    grace_period: u64,
+}
+
+/// Give a box a new token in exchange for its current one, so that
+/// long-lived boxes can refresh their secret without registering again.
+fn rotate_token(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    info!("POST /v1/box/{}/token", fingerprint);
+    let client = privacy::stored_fingerprint(config, &fingerprint);
+    let token = match tokens::bearer(req) {
+        Some(token) => token,
+        None => return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized)
+    };
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let token = match tracing::span(req, "db.rotate_token", || db.rotate_token(client, &token)) {
+        Ok(Some(token)) => token,
+        Ok(None) => return EndpointError::with(status::Unauthorized, ErrNo::Unauthorized),
+        Err(e) => return Err(database_error(e))
+    };
+    let serialized = match json::encode(&RotatedToken {
+        token: token,
+        grace_period: db::TOKEN_GRACE_PERIOD,
+    }) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+#[derive(RustcEncodable)]
+struct PairingQr {
+    payload: String,
+    code: String,
+    expires_in: i32,
+}
+
+/// The payload of a QR code which the box shows to pair apps, along with
+/// the pairing code it contains.
+fn pairing_qr(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    info!("POST /v1/box/{}/qr", fingerprint);
+    let client = privacy::stored_fingerprint(config, &fingerprint);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let record = try!(authenticate_box(req, &*db, client.clone()));
+    let code = try!(new_pairing_code(req, &*db, client));
+
+    // The apps check the fingerprint of the box, not its hash.
+    let record = Record { client: fingerprint, .. record };
+    let serialized = match json::encode(&PairingQr {
+        payload: pairing::qr_payload(&record, &code),
+        code: code,
+        expires_in: db::PAIRING_TTL,
+    }) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+fn redeem_pairing(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let code = req.extensions.get::<Router>().unwrap()
+                  .find("code").unwrap_or("").to_owned();
+    let public_ip = privacy::stored_ip(config, &req.remote_addr.ip());
+    info!("POST /v1/pairing/<code> public_ip={}", public_ip);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let record = match tracing::span(req, "db.redeem_pairing_code",
+                                     || db.redeem_pairing_code(&code, public_ip.clone())) {
+        Ok(Pairing::Paired(record)) => record,
+        Ok(Pairing::Unknown) => return EndpointError::with(status::NotFound, ErrNo::NotFound),
+        Ok(Pairing::Throttled(retry_after)) => {
+            return EndpointError::with_retry_after(status::TooManyRequests,
+                                                   ErrNo::TooManyRequests,
+                                                   retry_after)
         },
-        Err(_) => {}
+        Err(e) => return Err(database_error(e))
+    };
+
+    let record = RecordStatus::new(record, config.clock.now(), config.ping_interval);
+    let serialized = match json::encode(&record) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+/// Check that a request about the push notifications of a box carries the
+/// token of the box, or the session of the user it is linked to: the
+/// notifications tell when the box moves.
+fn authorize_push(req: &mut Request, db: &Storage, client: String) -> IronResult<()> {
+    let unauthorized = || {
+        EndpointError::build(status::Unauthorized, ErrNo::Unauthorized, None, None)
+    };
+    let token = try!(tokens::bearer(req).ok_or_else(&unauthorized));
+    if try!(db.token_matches(client.clone(), &token).map_err(database_error)) {
+        return Ok(());
+    }
+    let email = match try!(db.session_user(&token).map_err(database_error)) {
+        Some(email) => email,
+        None => return Err(unauthorized())
+    };
+    if try!(db.is_owner(&email, client).map_err(database_error)) {
+        Ok(())
+    } else {
+        Err(unauthorized())
+    }
+}
+
+fn push_subscription(req: &mut Request, config: &Config, subscribe: bool)
+    -> IronResult<Response> {
+    let (client, subscription) = try!(validation::extract(req, push::subscription));
+    info!("POST /v1/push/{} client={} service={}",
+          if subscribe { "subscribe" } else { "unsubscribe" },
+          client, subscription.service.name());
+    let client = privacy::stored_fingerprint(config, &client);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    try!(authorize_push(req, &*db, client.clone()));
+    let result = if subscribe {
+        tracing::span(req, "db.subscribe", || db.subscribe(client, &subscription))
+            .map(|_| "subscribed")
+    } else {
+        tracing::span(req, "db.unsubscribe", || db.unsubscribe(client, &subscription))
+            .map(|_| "unsubscribed")
+    };
+    let state = match result {
+        Ok(state) => state,
+        Err(e) => return Err(database_error(e))
+    };
+
+    let mut response = Response::with(format!("{{\"status\" : \"{}\"}}", state));
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+fn exchange_response<T: Encodable>(exchange: Exchange<T>) -> IronResult<Response> {
+    let serialized = match json::encode(&exchange) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+/// Replace the NAT traversal candidates of a box, with the public endpoint
+/// of the request, returning the candidates the clients left for it since.
+fn box_candidates(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    let mut candidates = try!(validation::extract(req, validation::candidates));
+    info!("PUT /v1/box/{}/candidates", fingerprint);
+    let client = privacy::stored_fingerprint(config, &fingerprint);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    try!(authenticate_box(req, &*db, client.clone()));
+    let observed = Candidate::observed(&req.remote_addr);
+    candidates.insert(0, observed.clone());
+    if let Err(e) = tracing::span(req, "db.set_candidates",
+                                  || db.set_candidates(client.clone(), &candidates)) {
+        return Err(database_error(e))
+    }
+    let peers = match tracing::span(req, "db.take_peers", || db.take_peers(client)) {
+        Ok(peers) => peers,
+        Err(e) => return Err(database_error(e))
+    };
+
+    exchange_response(Exchange {
+        observed: observed,
+        candidates: peers,
+    })
+}
+
+/// Leave the NAT traversal candidates of a client, with the public endpoint
+/// of the request, for the box to connect to, returning the box's.
+fn peer_candidates(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    let mut candidates = try!(validation::extract(req, validation::candidates));
+    info!("POST /v1/box/{}/candidates public_ip={}", fingerprint, req.remote_addr.ip());
+    let client = privacy::stored_fingerprint(config, &fingerprint);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let box_candidates = match tracing::span(req, "db.candidates",
+                                             || db.candidates(client.clone())) {
+        Ok(Some(box_candidates)) => box_candidates,
+        Ok(None) => return EndpointError::with(status::NotFound, ErrNo::NotFound),
+        Err(e) => return Err(database_error(e))
+    };
+    let observed = Candidate::observed(&req.remote_addr);
+    candidates.insert(0, observed.clone());
+    if let Err(e) = tracing::span(req, "db.add_peer", || db.add_peer(client, &candidates)) {
+        return Err(database_error(e))
+    }
+
+    exchange_response(Exchange {
+        observed: observed,
+        candidates: box_candidates,
+    })
+}
+
+/// Leave a message for a registered box, which it gets with the response to
+/// its next registration or heartbeat, e.g. to wake it up, for at most
+/// `mailbox_limit` messages per minute from each public IP.
+fn leave_message(req: &mut Request,
+                 config: &Config,
+                 limiter: &RateLimiter) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    info!("POST /v1/box/{}/mailbox", fingerprint);
+    let state = match limiter.check(req.remote_addr.ip(), config.clock.now()) {
+        Ok(state) => state,
+        Err(state) => {
+            info!("Rate limiting the mailbox messages of {}", req.remote_addr.ip());
+            return Err(EndpointError::with_limit(ErrNo::TooManyRequests, state, None));
+        }
+    };
+    let message = try!(validation::extract(req, validation::mailbox_message));
+    let client = privacy::stored_fingerprint(config, &fingerprint);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    match tracing::span(req, "db.find_by_client", || db.find_by_client(client.clone())) {
+        Ok(Some(_)) => {},
+        Ok(None) => return EndpointError::with(status::NotFound, ErrNo::NotFound),
+        Err(e) => return Err(database_error(e))
+    }
+    let waiting = match tracing::span(req, "db.leave_message",
+                                      || db.leave_message(client, message)) {
+        Ok(waiting) => waiting,
+        Err(e) => return Err(database_error(e))
+    };
+
+    let mut response = Response::with(
+        format!("{{\"status\" : \"queued\", \"waiting\" : {}}}", waiting)
+    );
+    response.status = Some(Status::Accepted);
+    response.headers.set(ContentType::json());
+
+    Ok(response)
+}
+
+fn find_box(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let fingerprint = req.extensions.get::<Router>().unwrap()
+                         .find("fingerprint").unwrap_or("").to_owned();
+    info!("GET /v1/box/{}", fingerprint);
+    let fingerprint = privacy::stored_fingerprint(config, &fingerprint);
+
+    let db = try!(config.storage.connect(config).map_err(database_unavailable));
+    let record = match tracing::span(req, "db.find_by_client",
+                                    || db.find_by_client(fingerprint)) {
+        Ok(Some(record)) => record,
+        Ok(None) => return EndpointError::with(status::NotFound, ErrNo::NotFound),
+        Err(e) => return Err(database_error(e))
+    };
+
+    let etag = EntityTag::strong(record.etag());
+    let public_ip = privacy::stored_ip(config, &req.remote_addr.ip());
+    let strategy = discovery::strategy(&record, &public_ip);
+    let record = RecordStatus::new(record, config.clock.now(), config.ping_interval)
+        .with_strategy(strategy);
+    let serialized = match json::encode(&record) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError, ErrNo::InternalError)
+        }
     };
 
-    serialized.push_str("]");
     let mut response = Response::with(serialized);
     response.status = Some(Status::Ok);
     response.headers.set(ContentType::json());
+    response.headers.set(ETag(etag));
 
     Ok(response)
 }
 
-pub fn create(db_host: String,
-              db_port: u16,
-              db_password: Option<String>) -> Router {
-    let mut router = Router::new();
+fn errors(_: &mut Request) -> IronResult<Response> {
+    let serialized = match json::encode(&describe_all()) {
+        Ok(serialized) => serialized,
+        Err(_) => {
+            return EndpointError::with(status::InternalServerError,
+                                       ErrNo::InternalError)
+        }
+    };
+
+    let mut response = Response::with(serialized);
+    response.status = Some(Status::Ok);
+    response.headers.set(ContentType::json());
 
-    let host = db_host.clone();
-    let pass = db_password.clone();
+    Ok(response)
+}
+
+pub fn create(config: Config) -> Routes {
+    let mut router = Routes::with_metrics(config.metrics.clone());
+    let cache = config.discovery_cache.clone();
+
+    let cfg = config.clone();
+    let cch = cache.clone();
     router.post("register", move |req: &mut Request| -> IronResult<Response> {
-        register(req, host.clone(), db_port, pass.clone())
+        register(req, &cfg, &cch)
     }, "post_message");
 
-    let host = db_host.clone();
-    let pass = db_password.clone();
+    let cfg = config.clone();
+    let cch = cache.clone();
+    router.post("v1/register/batch", move |req: &mut Request| -> IronResult<Response> {
+        register_batch(req, &cfg, &cch)
+    }, "register_batch");
+
+    let cfg = config.clone();
+    let cch = cache.clone();
     router.get("ping", move |req: &mut Request| -> IronResult<Response> {
-        ping(req, host.clone(), db_port, pass.clone())
+        ping(req, &cfg, &cch)
     }, "ping");
 
+    // Each route counts its responses under its own id in the metrics.
+    let cfg = config.clone();
+    let cch = cache.clone();
+    let limiter = RateLimiter::new(config.open_discovery_limit);
+    router.get("v1/discovery", move |req: &mut Request| -> IronResult<Response> {
+        open_discovery(req, &cfg, &cch, &limiter)
+    }, "open_discovery");
+
+    let cfg = config.clone();
+    let cch = cache.clone();
+    router.get("v1/discovery/full", move |req: &mut Request| -> IronResult<Response> {
+        full_discovery(req, &cfg, &cch)
+    }, "full_discovery");
+
+    let cfg = config.clone();
+    router.route(Method::Put, "v1/ping", move |req: &mut Request| -> IronResult<Response> {
+        heartbeat(req, &cfg)
+    }, "heartbeat");
+
+    let cfg = config.clone();
+    router.get("v1/box/:fingerprint", move |req: &mut Request| -> IronResult<Response> {
+        find_box(req, &cfg)
+    }, "find_box");
+
+    let cfg = config.clone();
+    let cch = cache.clone();
+    router.route(Method::Patch, "v1/box/:fingerprint",
+                 move |req: &mut Request| -> IronResult<Response> {
+        patch_box(req, &cfg, &cch)
+    }, "patch_box");
+
+    let cfg = config.clone();
+    let cch = cache.clone();
+    router.route(Method::Put, "v1/box/:fingerprint",
+                 move |req: &mut Request| -> IronResult<Response> {
+        replace_box(req, &cfg, &cch)
+    }, "replace_box");
+
+    let cfg = config.clone();
+    router.post("v1/pairing", move |req: &mut Request| -> IronResult<Response> {
+        create_pairing(req, &cfg)
+    }, "create_pairing");
+
+    let cfg = config.clone();
+    router.post("v1/pairing/:code", move |req: &mut Request| -> IronResult<Response> {
+        redeem_pairing(req, &cfg)
+    }, "redeem_pairing");
+
+    let cfg = config.clone();
+    router.post("v1/box/:fingerprint/token", move |req: &mut Request| -> IronResult<Response> {
+        rotate_token(req, &cfg)
+    }, "rotate_token");
+
+    let cfg = config.clone();
+    router.post("v1/box/:fingerprint/qr", move |req: &mut Request| -> IronResult<Response> {
+        pairing_qr(req, &cfg)
+    }, "pairing_qr");
+
+    let cfg = config.clone();
+    let limiter = RateLimiter::new(config.mailbox_limit);
+    router.post("v1/box/:fingerprint/mailbox", move |req: &mut Request| -> IronResult<Response> {
+        leave_message(req, &cfg, &limiter)
+    }, "leave_message");
+
+    let cfg = config.clone();
+    router.route(Method::Put, "v1/box/:fingerprint/candidates",
+                 move |req: &mut Request| -> IronResult<Response> {
+        box_candidates(req, &cfg)
+    }, "box_candidates");
+
+    let cfg = config.clone();
+    router.post("v1/box/:fingerprint/candidates",
+                move |req: &mut Request| -> IronResult<Response> {
+        peer_candidates(req, &cfg)
+    }, "peer_candidates");
+
+    // The push subsystem is opt-in: without anywhere to send the
+    // notifications, subscriptions would be silently useless.
+    if config.push.is_enabled() {
+        let cfg = config.clone();
+        router.post("v1/push/subscribe", move |req: &mut Request| -> IronResult<Response> {
+            push_subscription(req, &cfg, true)
+        }, "push_subscribe");
+
+        let cfg = config.clone();
+        router.post("v1/push/unsubscribe", move |req: &mut Request| -> IronResult<Response> {
+            push_subscription(req, &cfg, false)
+        }, "push_unsubscribe");
+    }
+
+    router.get("errors", errors, "errors");
+
     router
 }
+
+#[test]
+fn test_endpoints() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::status::StatusCode;
+    use rustc_serialize::json::Json;
+
+    let server = TestServer::new();
+
+    let (status, body) = server.get("/ping");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, "[]");
+    assert_eq!(server.get("/ping?all=true").0, StatusCode::Ok);
+    assert_eq!(server.get("/ping?unique=true&all=true").0, StatusCode::BadRequest);
+
+    let (status, body) = server.post("/register",
+                                     r#"{"client": "<fingerprint>", "message": "<message>"}"#);
+    assert_eq!(status, StatusCode::Ok);
+    let registered = Json::from_str(&body).unwrap();
+    assert_eq!(registered.find("revision").and_then(Json::as_u64), Some(1));
+    let token = token_from(&body);
+
+    let heartbeat = |token: &str| {
+        server.request("PUT", "/v1/ping", bearer(token), Some(r#"{"client": "<fingerprint>"}"#)).0
+    };
+    assert_eq!(heartbeat(&token), StatusCode::Ok);
+    assert_eq!(heartbeat("wrong"), StatusCode::Unauthorized);
+
+    let (status, body) = server.get("/ping");
+    assert_eq!(status, StatusCode::Ok);
+    let records: Vec<Record> = json::decode(&body).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].client, "<fingerprint>");
+    assert_eq!(records[0].public_ip, "127.0.0.1");
+    assert!(body.contains(r#""strategy":"local""#));
+
+    let (status, body) = server.get("/v1/box/<fingerprint>");
+    assert_eq!(status, StatusCode::Ok);
+    let record: Record = json::decode(&body).unwrap();
+    assert_eq!(record.message, "<message>");
+    assert!(body.contains(r#""online":true"#));
+    assert!(body.contains(r#""strategy":"local""#));
+
+    let (status, body) = server.post("/register", r#"{"message": "<message>"}"#);
+    assert_eq!(status, StatusCode::BadRequest);
+    let error: ErrorBody = json::decode(&body).unwrap();
+    assert_eq!(error.errno, ErrNo::MissingClient.code());
+
+    let (status, _) = server.get("/v1/box/unknown");
+    assert_eq!(status, StatusCode::NotFound);
+
+    let (status, _, body) = server.request("POST", "/v1/pairing", bearer(&token),
+                                           Some(r#"{"client": "<fingerprint>"}"#));
+    assert_eq!(status, StatusCode::Ok);
+    let pairing = Json::from_str(&body).unwrap();
+    let code = pairing.find("code").and_then(Json::as_string).unwrap().to_owned();
+    let (status, body) = server.post(&format!("/v1/pairing/{}", code), "");
+    assert_eq!(status, StatusCode::Ok);
+    let record: Record = json::decode(&body).unwrap();
+    assert_eq!(record.client, "<fingerprint>");
+    // Codes can only be used once.
+    let (status, _) = server.post(&format!("/v1/pairing/{}", code), "");
+    assert_eq!(status, StatusCode::NotFound);
+
+    let (status, _, body) = server.request("POST", "/v1/box/<fingerprint>/qr",
+                                           bearer(&token), None);
+    assert_eq!(status, StatusCode::Ok);
+    let qr = Json::from_str(&body).unwrap();
+    let payload = qr.find("payload").and_then(Json::as_string).unwrap();
+    assert!(payload.starts_with("fxbox://pair?v=1&fingerprint=%3Cfingerprint%3E"));
+    let (status, _) = server.post("/v1/box/<fingerprint>/qr", "");
+    assert_eq!(status, StatusCode::Unauthorized);
+
+    let (status, body) = server.get("/register");
+    assert_eq!(status, StatusCode::MethodNotAllowed);
+    let error: ErrorBody = json::decode(&body).unwrap();
+    assert_eq!(error.errno, ErrNo::MethodNotAllowed.code());
+
+    let (status, body) = server.get("/unknown");
+    assert_eq!(status, StatusCode::NotFound);
+    let error: ErrorBody = json::decode(&body).unwrap();
+    assert_eq!(error.errno, ErrNo::UnknownPath.code());
+
+    let (status, _) = server.admin_get("/records");
+    assert_eq!(status, StatusCode::Ok);
+
+    let (status, _) = server.admin_get("/stats");
+    assert_eq!(status, StatusCode::Ok);
+
+    let (status, body) = server.admin_get("/metrics");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains("\"admin_stats\""));
+}
+
+#[test]
+fn test_moved_box_discovery() {
+    use super::test_server::test_config;
+
+    let config = test_config(0);
+    let cache = config.discovery_cache.clone();
+    let before = Record::new("1.2.3.4".to_owned(), "a".to_owned(), "m".to_owned(), 0);
+    let after = Record { public_ip: "5.6.7.8".to_owned(), .. before.clone() };
+    {
+        let mut cache = cache.lock().unwrap();
+        cache.insert("1.2.3.4".to_owned(), vec![before.clone()], 0);
+        cache.insert("5.6.7.8".to_owned(), vec![], 0);
+        cache.insert("9.9.9.9".to_owned(), vec![before.clone()], 0);
+    }
+
+    // The box moves: its old public IP must not discover it anymore.
+    invalidate_discovery(&cache, &config, &[Some(before.clone())], &[after.clone()]);
+    let mut cache = cache.lock().unwrap();
+    assert!(cache.get(&"1.2.3.4".to_owned(), 0).is_none());
+    assert!(cache.get(&"5.6.7.8".to_owned(), 0).is_none());
+    assert!(cache.get(&"9.9.9.9".to_owned(), 0).is_some());
+
+    // The previous records are read for this, unless in cluster mode.
+    let storage = super::storage::MockStorage::new();
+    storage.set(before.clone()).unwrap();
+    let previous = previous_records(&storage, &config, &[after.clone()]);
+    assert_eq!(previous[0].as_ref().map(|record| &record.public_ip[..]), Some("1.2.3.4"));
+    let cluster = Config { cluster: true, .. config.clone() };
+    assert!(previous_records(&storage, &cluster, &[after]).pop().unwrap().is_none());
+}
+
+#[test]
+fn test_admin_changes_discovery() {
+    use super::test_server::{ ADMIN_TOKEN, bearer, TestServer };
+    use hyper::status::StatusCode;
+
+    let server = TestServer::new();
+    server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    // Cached by the discovery.
+    assert_eq!(server.get("/ping").1.matches(r#""client":"a""#).count(), 1);
+
+    let (status, _, _) = server.request("DELETE", "/admin/records/a", bearer(ADMIN_TOKEN), None);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(server.get("/ping").1, "[]");
+}
+
+#[test]
+fn test_storage_errors() {
+    use super::test_server::TestServer;
+    use hyper::status::StatusCode;
+    use redis::ErrorKind;
+
+    let (server, storage) = TestServer::with_mock_storage();
+
+    let (status, _) = server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    assert_eq!(status, StatusCode::Ok);
+
+    storage.fail("set", ErrorKind::ResponseError, "database is locked");
+    let (status, body) = server.post("/register", r#"{"client": "a", "message": "c"}"#);
+    assert_eq!(status, StatusCode::InternalServerError);
+    let error: ErrorBody = json::decode(&body).unwrap();
+    assert_eq!(error.errno, ErrNo::InternalError.code());
+
+    storage.fail("find_by_client", ErrorKind::TypeError, "corrupt record");
+    let (status, _) = server.get("/v1/box/a");
+    assert_eq!(status, StatusCode::InternalServerError);
+
+    // Discovery degrades to an empty list.
+    storage.fail("discover", ErrorKind::IoError, "connection lost");
+    let (status, body) = server.get("/ping");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, "[]");
+
+    assert_eq!(storage.calls(), vec!["find_by_client a", "set a", "set_token a",
+                                     "take_messages a", "find_by_client a", "set a",
+                                     "find_by_client a", "discover 127.0.0.1"]);
+}
+
+#[test]
+fn test_push_subscriptions() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    let (server, storage) = TestServer::with_mock_storage_config(|config| {
+        // Nothing listens there, the notifications are only logged as failed.
+        config.push.gateway = Some("http://127.0.0.1:9/push".to_owned());
+    });
+    let push = |path: &str, headers: Headers, body: &str| {
+        let (status, _, body) = server.request("POST", path, headers, Some(body));
+        (status, body)
+    };
+
+    // Only the first registration brings the box online.
+    let (status, body) = server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    assert_eq!(status, StatusCode::Ok);
+    let token = token_from(&body);
+
+    let subscription = r#"{"client": "a", "service": "apns", "token": "<token>"}"#;
+    assert_eq!(push("/v1/push/subscribe", Headers::new(), subscription).0,
+               StatusCode::Unauthorized);
+    assert_eq!(push("/v1/push/subscribe", bearer("wrong"), subscription).0,
+               StatusCode::Unauthorized);
+    let (status, body) = push("/v1/push/subscribe", bearer(&token), subscription);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, r#"{"status" : "subscribed"}"#);
+
+    let (status, _) = push("/v1/push/subscribe", bearer(&token),
+                           r#"{"client": "a", "service": "sms", "token": "<token>"}"#);
+    assert_eq!(status, StatusCode::BadRequest);
+
+    let (status, _) = server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    assert_eq!(status, StatusCode::Ok);
+
+    // The owner of the box can unsubscribe too, other users can't.
+    storage.add_session("owner-session", "owner@example.com");
+    storage.add_session("other-session", "other@example.com");
+    storage.add_owner("owner@example.com", "a");
+    assert_eq!(push("/v1/push/unsubscribe", bearer("other-session"), subscription).0,
+               StatusCode::Unauthorized);
+    assert_eq!(push("/v1/push/unsubscribe", bearer("owner-session"), subscription).0,
+               StatusCode::Ok);
+
+    assert_eq!(storage.calls(), vec!["find_by_client a", "set a", "subscriptions a",
+                                     "set_token a", "take_messages a",
+                                     "token_matches a", "session_user ",
+                                     "token_matches a", "subscribe a",
+                                     "find_by_client a", "set a", "set_token a",
+                                     "take_messages a",
+                                     "token_matches a", "session_user ", "is_owner a",
+                                     "token_matches a", "session_user ", "is_owner a",
+                                     "unsubscribe a"]);
+}
+
+#[test]
+fn test_read_only_mode() {
+    use super::test_server::TestServer;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    let server = TestServer::new();
+    let (status, _) = server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    assert_eq!(status, StatusCode::Ok);
+
+    server.config.read_only.enable(120);
+    let (status, headers, body) = server.request("POST", "/register", Headers::new(),
+                                                 Some(r#"{"client": "a", "message": "c"}"#));
+    assert_eq!(status, StatusCode::ServiceUnavailable);
+    assert_eq!(headers.get_raw("Retry-After"), Some(&[b"120".to_vec()][..]));
+    let error: ErrorBody = json::decode(&body).unwrap();
+    assert_eq!(error.errno, ErrNo::ReadOnly.code());
+
+    // Discovery keeps working.
+    let (status, body) = server.get("/ping");
+    assert_eq!(status, StatusCode::Ok);
+    let records: Vec<Record> = json::decode(&body).unwrap();
+    assert_eq!(records[0].message, "b");
+
+    server.config.read_only.disable();
+    let (status, _) = server.post("/register", r#"{"client": "a", "message": "c"}"#);
+    assert_eq!(status, StatusCode::Ok);
+}
+
+#[test]
+fn test_flapping_auth() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::status::StatusCode;
+
+    let (server, storage) = TestServer::with_mock_storage_config(|config| {
+        config.flapping_auth = true;
+    });
+
+    let registration = r#"{"client": "a", "message": "b"}"#;
+    let (status, body) = server.post("/register", registration);
+    assert_eq!(status, StatusCode::Ok);
+    let token = token_from(&body);
+
+    storage.flag_flapping("a");
+    let (status, body) = server.post("/register", registration);
+    assert_eq!(status, StatusCode::Unauthorized);
+    let error: ErrorBody = json::decode(&body).unwrap();
+    assert_eq!(error.errno, ErrNo::Flapping.code());
+
+    let (status, _, _) = server.request("POST", "/register", bearer(&token), Some(registration));
+    assert_eq!(status, StatusCode::Ok);
+}
+
+#[test]
+fn test_conditional_registration() {
+    use super::clock::ManualClock;
+    use super::test_server::TestServer;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+    use std::sync::Arc;
+
+    let (server, storage) = TestServer::with_mock_storage_config(|config| {
+        config.clock = Arc::new(ManualClock::new(1481900000));
+    });
+
+    let registration = |revision: u64| {
+        format!(r#"{{"client": "a", "message": "b", "expected_revision": {}}}"#, revision)
+    };
+    assert_eq!(server.post("/register", &registration(1)).0, StatusCode::PreconditionFailed);
+    assert_eq!(server.post("/register", &registration(0)).0, StatusCode::Ok);
+    let (status, body) = server.post("/register", &registration(0));
+    assert_eq!(status, StatusCode::PreconditionFailed);
+    let error: ErrorBody = json::decode(&body).unwrap();
+    assert_eq!(error.errno, ErrNo::PreconditionFailed.code());
+    assert_eq!(server.post("/register", &registration(1)).0, StatusCode::Ok);
+
+    // The box last registered at 1481900000.
+    let unmodified_since = |date: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("If-Unmodified-Since", vec![date.as_bytes().to_vec()]);
+        server.request("POST", "/register", headers, Some(r#"{"client": "a", "message": "b"}"#)).0
+    };
+    assert_eq!(unmodified_since("Fri, 16 Dec 2016 14:53:19 GMT"), StatusCode::PreconditionFailed);
+    assert_eq!(unmodified_since("Fri, 16 Dec 2016 14:53:20 GMT"), StatusCode::Ok);
+
+    let (_, headers, _) = server.request("GET", "/v1/box/a", Headers::new(), None);
+    let etag = String::from_utf8(headers.get_raw("ETag").unwrap()[0].clone()).unwrap();
+    assert_eq!(etag, r#""1481900000-3""#);
+    let if_match = |etag: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("If-Match", vec![etag.as_bytes().to_vec()]);
+        server.request("POST", "/register", headers, Some(r#"{"client": "a", "message": "b"}"#)).0
+    };
+    assert_eq!(if_match(r#""1481900000-2""#), StatusCode::PreconditionFailed);
+    assert_eq!(if_match(&format!("W/{}", etag)), StatusCode::PreconditionFailed);
+    assert_eq!(if_match(&etag), StatusCode::Ok);
+    assert_eq!(if_match("*"), StatusCode::Ok);
+}
+
+#[test]
+fn test_idempotency_key() {
+    use super::test_server::TestServer;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+    use rustc_serialize::json::Json;
+
+    let (server, storage) = TestServer::with_mock_storage();
+
+    let register = |key: &str, path: &str, body: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("Idempotency-Key", vec![key.as_bytes().to_vec()]);
+        server.request("POST", path, headers, Some(body))
+    };
+    let registration = r#"{"client": "a", "message": "b"}"#;
+    let (status, headers, first) = register("key", "/register", registration);
+    assert_eq!(status, StatusCode::Ok);
+    assert!(headers.get_raw("Idempotent-Replayed").is_none());
+    let (status, headers, retried) = register("key", "/register", registration);
+    assert_eq!(status, StatusCode::Ok);
+    assert!(headers.get_raw("Idempotent-Replayed").is_some());
+    assert_eq!(retried, first);
+    assert_eq!(storage.calls().iter().filter(|call| *call == "set a").count(), 1);
+
+    // The keys of the routes are separate.
+    let (status, headers, body) = register("key", "/v1/register/batch",
+                                           &format!("[{}]", registration));
+    assert_eq!(status, StatusCode::Ok);
+    assert!(headers.get_raw("Idempotent-Replayed").is_none());
+    let registered = Json::from_str(&body).unwrap();
+    assert_eq!(registered.find("count").and_then(Json::as_u64), Some(1));
+    assert_eq!(registered.find("tokens").and_then(Json::as_array).map(Vec::len), Some(1));
+
+    let (status, _, _) = register("", "/register", registration);
+    assert_eq!(status, StatusCode::BadRequest);
+}
+
+#[test]
+fn test_patch_box() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::status::StatusCode;
+
+    let (server, storage) = TestServer::with_mock_storage();
+
+    let (_, body) = server.post("/register",
+                                r#"{"client": "a", "message": "b", "local_ip": "10.0.0.2"}"#);
+    let token = token_from(&body);
+    let patch = |token: &str, body: &str| {
+        server.request("PATCH", "/v1/box/a", bearer(token), Some(body))
+    };
+
+    let (status, headers, body) = patch(&token, r#"{"message": "c"}"#);
+    assert_eq!(status, StatusCode::Ok);
+    assert!(headers.get_raw("ETag").is_some());
+    let record: Record = json::decode(&body).unwrap();
+    assert_eq!(record.message, "c");
+    assert_eq!(record.local_ip, Some("10.0.0.2".to_owned()));
+    assert_eq!(record.revision, 2);
+
+    let (status, _, body) = patch(&token, r#"{"local_ip": null}"#);
+    assert_eq!(status, StatusCode::Ok);
+    let record: Record = json::decode(&body).unwrap();
+    assert_eq!(record.message, "c");
+    assert_eq!(record.local_ip, None);
+
+    assert_eq!(patch("wrong", r#"{"message": "d"}"#).0, StatusCode::Unauthorized);
+    assert_eq!(patch(&token, r#"{"mesage": "d"}"#).0, StatusCode::BadRequest);
+}
+
+#[test]
+fn test_replace_box() {
+    use super::test_server::TestServer;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    let (server, storage) = TestServer::with_mock_storage();
+
+    let put = |body: &str| server.request("PUT", "/v1/box/a", Headers::new(), Some(body));
+    let (status, headers, _) = put(r#"{"message": "b"}"#);
+    assert_eq!(status, StatusCode::Created);
+    assert_eq!(headers.get_raw("Location"), Some(&[b"/v1/box/a".to_vec()][..]));
+    let (status, _, body) = put(r#"{"client": "a", "message": "c"}"#);
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(r#""revision" : 2"#));
+    assert_eq!(put(r#"{"client": "b", "message": "c"}"#).0, StatusCode::BadRequest);
+
+    let record: Record = json::decode(&server.get("/v1/box/a").1).unwrap();
+    assert_eq!(record.message, "c");
+}
+
+#[test]
+fn test_options() {
+    use super::test_server::TestServer;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    let server = TestServer::new();
+    let header = |headers: &Headers, name: &str| {
+        headers.get_raw(name).map(|values| String::from_utf8(values[0].clone()).unwrap())
+    };
+
+    let (status, headers, _) = server.request("OPTIONS", "/v1/box/a", Headers::new(), None);
+    assert_eq!(status, StatusCode::NoContent);
+    assert_eq!(header(&headers, "Allow"), Some("GET, PUT, PATCH, OPTIONS".to_owned()));
+    assert!(headers.get_raw("Access-Control-Allow-Methods").is_none());
+
+    let mut preflight = Headers::new();
+    preflight.set_raw("Origin", vec![b"https://app.example.com".to_vec()]);
+    preflight.set_raw("Access-Control-Request-Method", vec![b"PATCH".to_vec()]);
+    preflight.set_raw("Access-Control-Request-Headers", vec![b"authorization".to_vec()]);
+    let (status, headers, _) = server.request("OPTIONS", "/v1/box/a", preflight, None);
+    assert_eq!(status, StatusCode::NoContent);
+    assert!(header(&headers, "Access-Control-Allow-Methods").unwrap().contains("PATCH"));
+    assert_eq!(header(&headers, "Access-Control-Allow-Headers"),
+               Some("authorization".to_owned()));
+
+    let (status, _, _) = server.request("OPTIONS", "/unknown", Headers::new(), None);
+    assert_eq!(status, StatusCode::NotFound);
+}
+
+#[test]
+fn test_unsupported_media_type() {
+    use super::test_server::TestServer;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    let server = TestServer::new();
+    let registration = r#"{"client": "a", "message": "b"}"#;
+    let register = |content_type: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+        server.request("POST", "/register", headers, Some(registration))
+    };
+
+    let (status, _, body) = register("text/plain");
+    assert_eq!(status, StatusCode::UnsupportedMediaType);
+    let error: ErrorBody = json::decode(&body).unwrap();
+    assert_eq!(error.errno, ErrNo::UnsupportedMediaType.code());
+    assert_eq!(register("application/json; charset=utf-8").0, StatusCode::Ok);
+    assert_eq!(server.post("/register", registration).0, StatusCode::Ok);
+}
+
+#[test]
+fn test_form_registration() {
+    use super::test_server::TestServer;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    let server = TestServer::new();
+    let register = |body: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![b"application/x-www-form-urlencoded".to_vec()]);
+        server.request("POST", "/register", headers, Some(body))
+    };
+
+    let (status, _, _) = register("client=a&message=hello%20world&local_ip=192.168.1.2");
+    assert_eq!(status, StatusCode::Ok);
+    let record: Record = json::decode(&server.get("/v1/box/a").1).unwrap();
+    assert_eq!(record.message, "hello world");
+    assert_eq!(record.local_ip, Some("192.168.1.2".to_owned()));
+
+    let (status, _, body) = register("message=hello");
+    assert_eq!(status, StatusCode::BadRequest);
+    let error: ErrorBody = json::decode(&body).unwrap();
+    assert_eq!(error.errno, ErrNo::MissingClient.code());
+
+    // Without a body, the parameters are read from the query string.
+    let (status, _) = server.post("/register?client=b&message=hi&expected_revision=0", "");
+    assert_eq!(status, StatusCode::Ok);
+    let record: Record = json::decode(&server.get("/v1/box/b").1).unwrap();
+    assert_eq!(record.message, "hi");
+    let (status, _) = server.post("/register?client=b&message=hi&expected_revision=one", "");
+    assert_eq!(status, StatusCode::BadRequest);
+}
+
+#[test]
+fn test_hashed_public_ips() {
+    use super::privacy;
+    use super::test_server::TestServer;
+    use hyper::status::StatusCode;
+
+    let server = TestServer::with_config(|config| {
+        config.ip_hash_key = Some("secret".to_owned());
+    });
+    let (status, _) = server.post("/register", r#"{"client": "a", "message": "m"}"#);
+    assert_eq!(status, StatusCode::Ok);
+
+    // Discovery from the same public IP still finds the box, which is
+    // stored under the hash of the IP.
+    let (status, body) = server.get("/ping");
+    assert_eq!(status, StatusCode::Ok);
+    let records: Vec<Record> = json::decode(&body).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].public_ip,
+               privacy::hash_ip("secret", &"127.0.0.1".parse().unwrap()));
+}
+
+#[test]
+fn test_hashed_fingerprints() {
+    use super::privacy;
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    let server = TestServer::with_config(|config| {
+        config.fingerprint_salt = Some("salt".to_owned());
+    });
+    let (status, body) = server.post("/register", r#"{"client": "a", "message": "m"}"#);
+    assert_eq!(status, StatusCode::Ok);
+    let token = token_from(&body);
+
+    // The box is still found by its exact fingerprint, which isn't stored.
+    let (status, body) = server.get("/v1/box/a");
+    assert_eq!(status, StatusCode::Ok);
+    let record: Record = json::decode(&body).unwrap();
+    let hash = privacy::stored_fingerprint(&server.config, "a");
+    assert_eq!(record.client, hash);
+    assert_eq!(server.get(&format!("/v1/box/{}", hash)).0, StatusCode::NotFound);
+
+    let (status, _, _) = server.request("PUT", "/v1/ping", bearer(&token),
+                                        Some(r#"{"client": "a"}"#));
+    assert_eq!(status, StatusCode::Ok);
+
+    // New boxes are located by their exact fingerprint too.
+    let (status, headers, _) = server.request("PUT", "/v1/box/b", Headers::new(),
+                                              Some(r#"{"message": "m"}"#));
+    assert_eq!(status, StatusCode::Created);
+    assert_eq!(headers.get_raw("Location"), Some(&[b"/v1/box/b".to_vec()][..]));
+}
+
+#[test]
+fn test_encrypted_data() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::status::StatusCode;
+
+    let server = TestServer::new();
+    let (status, body) = server.post("/register", r#"{"client": "a", "message": "m",
+                                                      "encrypted": "bm90IHJlYWRhYmxl"}"#);
+    assert_eq!(status, StatusCode::Ok);
+    let token = token_from(&body);
+
+    let records: Vec<Record> = json::decode(&server.get("/ping").1).unwrap();
+    assert_eq!(records[0].encrypted, Some("bm90IHJlYWRhYmxl".to_owned()));
+    assert_eq!(records[0].local_ip, None);
+
+    let (status, _, body) = server.request("PATCH", "/v1/box/a", bearer(&token),
+                                           Some(r#"{"encrypted": null}"#));
+    assert_eq!(status, StatusCode::Ok);
+    let record: Record = json::decode(&body).unwrap();
+    assert_eq!(record.encrypted, None);
+
+    let (status, _) = server.post("/register", r#"{"client": "a", "message": "m",
+                                                   "encrypted": "<not base64>"}"#);
+    assert_eq!(status, StatusCode::BadRequest);
+}
+
+#[test]
+fn test_nat_mapping() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::status::StatusCode;
+
+    let server = TestServer::new();
+    let (status, body) = server.post("/register", r#"{"client": "a", "message": "m",
+                                                      "nat": "endpoint-independent"}"#);
+    assert_eq!(status, StatusCode::Ok);
+    let token = token_from(&body);
+    let records: Vec<Record> = json::decode(&server.get("/ping").1).unwrap();
+    assert_eq!(records[0].nat, Some("endpoint-independent".to_owned()));
+
+    // Patches keep it.
+    let (_, _, body) = server.request("PATCH", "/v1/box/a", bearer(&token),
+                                      Some(r#"{"message": "n"}"#));
+    let record: Record = json::decode(&body).unwrap();
+    assert_eq!(record.nat, Some("endpoint-independent".to_owned()));
+
+    let (status, _) = server.post("/register", r#"{"client": "b", "message": "m",
+                                                   "nat": "full-cone"}"#);
+    assert_eq!(status, StatusCode::BadRequest);
+}
+
+#[test]
+fn test_discovery_variants() {
+    use super::test_server::TestServer;
+    use db::Credential;
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    let server = TestServer::with_config(|config| {
+        config.open_discovery_limit = 2;
+        config.api_keys = vec!["tenant-key".to_owned()];
+    });
+    server.post("/register", r#"{"client": "a", "message": "m", "local_ip": "10.0.0.2"}"#);
+
+    let (status, headers, body) = server.request("GET", "/v1/discovery", Headers::new(), None);
+    assert_eq!(status, StatusCode::Ok);
+    let summaries: Vec<Summary> = json::decode(&body).unwrap();
+    assert_eq!(summaries, vec![Summary {
+        client: "a".to_owned(),
+        online: true,
+        strategy: "local".to_owned(),
+    }]);
+    assert_eq!(headers.get_raw("X-RateLimit-Remaining"), Some(&[b"1".to_vec()][..]));
+    assert_eq!(server.get("/v1/discovery").0, StatusCode::Ok);
+    assert_eq!(server.get("/v1/discovery").0, StatusCode::TooManyRequests);
+    assert_eq!(server.config.metrics.snapshot().routes["open_discovery"].requests, 3);
+
+    let full = |api_key: Option<&str>| {
+        let mut headers = Headers::new();
+        if let Some(api_key) = api_key {
+            headers.set_raw("X-Api-Key", vec![api_key.as_bytes().to_vec()]);
+        }
+        let (status, _, body) = server.request("GET", "/v1/discovery/full", headers, None);
+        (status, body)
+    };
+    assert_eq!(full(None).0, StatusCode::Unauthorized);
+    assert_eq!(full(Some("wrong")).0, StatusCode::Unauthorized);
+    let (status, body) = full(Some("tenant-key"));
+    assert_eq!(status, StatusCode::Ok);
+    let records: Vec<Record> = json::decode(&body).unwrap();
+    assert_eq!(records[0].local_ip, Some("10.0.0.2".to_owned()));
+
+    server.db.add_credential(&Credential {
+        name: "app".to_owned(),
+        kind: db::API_CREDENTIAL.to_owned(),
+        hash: revocation::hash("database-key"),
+        created_at: 0,
+    }).unwrap();
+    assert_eq!(full(Some("database-key")).0, StatusCode::Ok);
+}
+
+#[test]
+fn test_probe() {
+    use super::test_server::TestServer;
+    use hyper::status::StatusCode;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = TestServer::with_config(|config| config.probe_port = Some(port));
+    assert_eq!(server.post("/register", r#"{"client": "a", "message": "m"}"#).0,
+               StatusCode::Ok);
+
+    // The probe runs in the background.
+    let verified = || {
+        let record: Record = json::decode(&server.get("/v1/box/a").1).unwrap();
+        record.verified
+    };
+    for _ in 0..50 {
+        if verified().is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(verified(), Some(true));
+
+    // Registering again keeps it, without probing again.
+    drop(listener);
+    assert_eq!(server.post("/register", r#"{"client": "a", "message": "n"}"#).0,
+               StatusCode::Ok);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(verified(), Some(true));
+}
+
+#[test]
+fn test_rotate_token() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+    use rustc_serialize::json::Json;
+
+    let (server, storage) = TestServer::with_mock_storage();
+
+    let (_, body) = server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    let token = token_from(&body);
+    let with_token = |method: &str, path: &str, token: &str, body: Option<&str>| {
+        server.request(method, path, bearer(token), body)
+    };
+
+    let (status, _, body) = with_token("POST", "/v1/box/a/token", &token, None);
+    assert_eq!(status, StatusCode::Ok);
+    let rotated = Json::from_str(&body).unwrap();
+    let new_token = rotated.find("token").and_then(Json::as_string).unwrap().to_owned();
+    assert!(new_token != token);
+    assert_eq!(rotated.find("grace_period").and_then(Json::as_u64),
+               Some(db::TOKEN_GRACE_PERIOD));
+
+    let ping = Some(r#"{"client": "a"}"#);
+    assert_eq!(with_token("PUT", "/v1/ping", &new_token, ping).0, StatusCode::Ok);
+    assert_eq!(with_token("POST", "/v1/box/a/token", "wrong", None).0,
+               StatusCode::Unauthorized);
+    assert_eq!(server.request("POST", "/v1/box/a/token", Headers::new(), None).0,
+               StatusCode::Unauthorized);
+}
+
+#[test]
+fn test_revoked_credentials() {
+    use super::revocation;
+    use super::test_server::{ ADMIN_TOKEN, bearer, TestServer, token_from };
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    let server = TestServer::new();
+    let (_, body) = server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    let token = token_from(&body);
+    let admin = |method: &str, path: &str, body: Option<&str>| {
+        server.request(method, &format!("/admin{}", path), bearer(ADMIN_TOKEN), body)
+    };
+    let ping = |header: &str, value: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw(header.to_owned(), vec![value.as_bytes().to_vec()]);
+        server.request("PUT", "/v1/ping", headers, Some(r#"{"client": "a"}"#))
+    };
+    let bearer = format!("Bearer {}", token);
+    assert_eq!(ping("Authorization", &bearer).0, StatusCode::Ok);
+
+    let (status, _, body) = admin("POST", "/revoked", Some(r#"{"fingerprint": "a"}"#));
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.contains(&revocation::hash(&token)));
+    let (status, _, body) = ping("Authorization", &bearer);
+    assert_eq!(status, StatusCode::Unauthorized);
+    let error: ErrorBody = json::decode(&body).unwrap();
+    assert_eq!(error.errno, ErrNo::Revoked.code());
+    assert!(server.admin_get("/revoked").1.contains(&revocation::hash(&token)));
+
+    assert_eq!(admin("POST", "/revoked", Some(r#"{"credential": "key"}"#)).0, StatusCode::Ok);
+    let (status, _, body) = ping("X-Api-Key", "key");
+    assert_eq!(status, StatusCode::Unauthorized);
+    assert_eq!(json::decode::<ErrorBody>(&body).unwrap().errno, ErrNo::Revoked.code());
+    assert_eq!(admin("POST", "/revoked", Some(r#"{"fingerprint": "z"}"#)).0,
+               StatusCode::NotFound);
+    assert_eq!(admin("POST", "/revoked", Some("{}")).0, StatusCode::BadRequest);
+
+    let path = format!("/revoked/{}", revocation::hash(&token));
+    assert_eq!(admin("DELETE", &path, None).0, StatusCode::Ok);
+    assert_eq!(admin("DELETE", &path, None).0, StatusCode::NotFound);
+    assert_eq!(ping("Authorization", &bearer).0, StatusCode::Ok);
+}
+
+#[test]
+fn test_jwt_tokens() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::status::StatusCode;
+
+    let keys = jwt::Keys::parse("1:secret", "fxbox").unwrap();
+    let server = TestServer::with_config(|config| config.jwt = Some(keys.clone()));
+    let (_, body) = server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    let token = token_from(&body);
+    let claims = keys.verify(&token, server.config.clock.now()).unwrap();
+    assert_eq!(claims.sub, "a");
+    assert_eq!(claims.scope, jwt::BOX_SCOPE);
+
+    let ping = |token: &str| {
+        server.request("PUT", "/v1/ping", bearer(token), Some(r#"{"client": "a"}"#)).0
+    };
+    assert_eq!(ping(&token), StatusCode::Ok);
+    let other = jwt::Keys::parse("1:other", "fxbox").unwrap()
+        .issue("a", jwt::BOX_SCOPE, 60, server.config.clock.now());
+    assert_eq!(ping(&other), StatusCode::Unauthorized);
+}
+
+#[test]
+fn test_mailbox() {
+    use super::test_server::{ bearer, TestServer };
+    use hyper::status::StatusCode;
+    use rustc_serialize::json::Json;
+
+    let server = TestServer::with_config(|config| config.mailbox_limit = 4);
+    // Only the registered boxes have a mailbox.
+    let (status, _) = server.post("/v1/box/a/mailbox", r#"{"message": "wake up"}"#);
+    assert_eq!(status, StatusCode::NotFound);
+    server.post("/register", r#"{"client": "a", "message": "b"}"#);
+
+    let (status, body) = server.post("/v1/box/a/mailbox", r#"{"message": "wake up"}"#);
+    assert_eq!(status, StatusCode::Accepted);
+    assert_eq!(body, r#"{"status" : "queued", "waiting" : 1}"#);
+    let (status, _) = server.post("/v1/box/a/mailbox", r#"{"message": ""}"#);
+    assert_eq!(status, StatusCode::BadRequest);
+
+    // The box gets the message with its next registration, and only once.
+    let (_, body) = server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    assert_eq!(Json::from_str(&body).unwrap().find("mailbox"),
+               Some(&Json::from_str(r#"["wake up"]"#).unwrap()));
+    let (_, body) = server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    let response = Json::from_str(&body).unwrap();
+    assert!(response.find("mailbox").is_none());
+    let token = response.find("token").and_then(Json::as_string).unwrap().to_owned();
+
+    // Or with its next heartbeat.
+    server.post("/v1/box/a/mailbox", r#"{"message": "hello"}"#);
+    let (status, _, body) = server.request("PUT", "/v1/ping", bearer(&token),
+                                           Some(r#"{"client": "a"}"#));
+    assert_eq!(status, StatusCode::Ok);
+    let response = Json::from_str(&body).unwrap();
+    assert_eq!(response.find("mailbox"), Some(&Json::from_str(r#"["hello"]"#).unwrap()));
+
+    // Each public IP can leave a few messages per minute.
+    let (status, _) = server.post("/v1/box/a/mailbox", r#"{"message": "again"}"#);
+    assert_eq!(status, StatusCode::TooManyRequests);
+}
+
+#[test]
+fn test_candidates() {
+    use super::test_server::{ bearer, TestServer, token_from };
+    use hyper::status::StatusCode;
+    use rustc_serialize::json::Json;
+
+    let server = TestServer::new();
+    let candidates = r#"{"candidates": [{"ip": "192.168.1.2", "port": 8443}]}"#;
+    // Clients can't connect to a box which didn't leave its candidates.
+    let (status, _) = server.post("/v1/box/a/candidates", candidates);
+    assert_eq!(status, StatusCode::NotFound);
+
+    let (_, body) = server.post("/register", r#"{"client": "a", "message": "b"}"#);
+    let token = token_from(&body);
+    let put = |token: &str| {
+        let (status, _, body) = server.request("PUT", "/v1/box/a/candidates", bearer(token),
+                                               Some(candidates));
+        (status, body)
+    };
+    assert_eq!(put("other").0, StatusCode::Unauthorized);
+    let (status, body) = put(&token);
+    assert_eq!(status, StatusCode::Ok);
+    let exchange = Json::from_str(&body).unwrap();
+    assert_eq!(exchange.find_path(&["observed", "kind"]).and_then(Json::as_string),
+               Some("srflx"));
+    assert_eq!(exchange.find_path(&["observed", "ip"]).and_then(Json::as_string),
+               Some("127.0.0.1"));
+    assert_eq!(exchange.find("candidates"), Some(&Json::Array(vec![])));
+
+    // The client gets the candidates of the box, and the box the client's.
+    let (status, body) = server.post("/v1/box/a/candidates", "{}");
+    assert_eq!(status, StatusCode::Ok);
+    let exchange = Json::from_str(&body).unwrap();
+    let box_candidates = exchange.find("candidates").and_then(Json::as_array).unwrap();
+    assert_eq!(box_candidates.len(), 2);
+    assert_eq!(box_candidates[1].find("port").and_then(Json::as_u64), Some(8443));
+
+    let (_, body) = put(&token);
+    let exchange = Json::from_str(&body).unwrap();
+    let peers = exchange.find("candidates").and_then(Json::as_array).unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].as_array().unwrap()[0].find("kind").and_then(Json::as_string),
+               Some("srflx"));
+}
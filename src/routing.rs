@@ -0,0 +1,205 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Router wrapper which keeps track of the methods registered for each
+/// route, so that requesting a known route with the wrong method gets a
+/// 405 with an Allow header instead of the router's 404, and OPTIONS gets
+/// the Allow header along with the CORS preflight headers, and a middleware
+/// turning the 404s for unknown paths into JSON errors. The responses of
+/// every route can also be counted in the `Metrics`.
+
+use errors::*;
+use iron::headers::{ AccessControlAllowHeaders, AccessControlAllowMethods,
+                     AccessControlAllowOrigin, AccessControlMaxAge,
+                     AccessControlRequestHeaders, AccessControlRequestMethod, Allow };
+use iron::method::Method;
+use iron::prelude::*;
+use iron::status;
+use iron::{ AfterMiddleware, Handler };
+use metrics::{ Counted, Metrics };
+use mount::NoMatch;
+use route_recognizer::Router as Recognizer;
+use router::{ NoRoute, Router };
+use std::sync::Arc;
+
+/// Number of seconds browsers can cache the answers to CORS preflights.
+static PREFLIGHT_MAX_AGE: u32 = 24 * 3600;
+
+pub struct Routes {
+    router: Router,
+    methods: Vec<(Method, Recognizer<()>)>,
+    /// The methods of each glob, in the order they were registered.
+    globs: Vec<(String, Vec<Method>)>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Routes {
+    pub fn new() -> Routes {
+        Routes {
+            router: Router::new(),
+            methods: Vec::new(),
+            globs: Vec::new(),
+            metrics: None,
+        }
+    }
+
+    /// Routes counting the responses of each route id.
+    pub fn with_metrics(metrics: Arc<Metrics>) -> Routes {
+        Routes {
+            metrics: Some(metrics),
+            .. Routes::new()
+        }
+    }
+
+    pub fn route<H, S, I>(&mut self, method: Method, glob: S, handler: H, route_id: I)
+        -> &mut Routes where H: Handler, S: AsRef<str>, I: AsRef<str> {
+        if !self.methods.iter().any(|&(ref m, _)| *m == method) {
+            self.methods.push((method.clone(), Recognizer::new()));
+        }
+        for &mut (ref m, ref mut recognizer) in self.methods.iter_mut() {
+            if *m == method {
+                recognizer.add(glob.as_ref(), ());
+            }
+        }
+        match self.globs.iter().position(|&(ref g, _)| *g == glob.as_ref()) {
+            Some(index) => self.globs[index].1.push(method.clone()),
+            None => self.globs.push((glob.as_ref().to_owned(), vec![method.clone()]))
+        }
+
+        match self.metrics {
+            Some(ref metrics) => {
+                let handler = Counted::new(handler, route_id.as_ref().to_owned(),
+                                           metrics.clone());
+                self.router.route(method, glob, handler, route_id)
+            },
+            None => self.router.route(method, glob, handler, route_id)
+        };
+        self
+    }
+
+    pub fn get<H, S, I>(&mut self, glob: S, handler: H, route_id: I)
+        -> &mut Routes where H: Handler, S: AsRef<str>, I: AsRef<str> {
+        self.route(Method::Get, glob, handler, route_id)
+    }
+
+    pub fn post<H, S, I>(&mut self, glob: S, handler: H, route_id: I)
+        -> &mut Routes where H: Handler, S: AsRef<str>, I: AsRef<str> {
+        self.route(Method::Post, glob, handler, route_id)
+    }
+
+    /// The methods of each route, with its glob under `prefix`, the path the
+    /// routes are mounted at, e.g. to let the CORS middleware cover every
+    /// route.
+    pub fn globs(&self, prefix: &str) -> Vec<(Vec<Method>, String)> {
+        let prefix = prefix.trim_matches('/');
+        self.globs.iter().map(|&(ref glob, ref methods)| {
+            let glob = glob.trim_matches('/');
+            let path = match (prefix.is_empty(), glob.is_empty()) {
+                (true, _) => glob.to_owned(),
+                (false, true) => prefix.to_owned(),
+                (false, false) => format!("{}/{}", prefix, glob)
+            };
+            (methods.clone(), path)
+        }).collect()
+    }
+
+    /// The methods registered for `path`, empty for unknown paths.
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        self.methods.iter()
+            .filter(|&&(_, ref recognizer)| recognizer.recognize(path).is_ok())
+            .map(|&(ref method, _)| method.clone())
+            .collect()
+    }
+}
+
+impl Handler for Routes {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let err = match self.router.handle(req) {
+            Err(err) => err,
+            result => return result
+        };
+        if !err.error.is::<NoRoute>() {
+            return Err(err);
+        }
+
+        let allowed = self.allowed_methods(&req.url.path().join("/"));
+        if allowed.is_empty() {
+            return Err(err);
+        }
+        if req.method == Method::Options {
+            return Ok(options(req, allowed));
+        }
+
+        let details = allowed.iter().map(|method| method.to_string())
+                             .collect::<Vec<_>>().join(", ");
+        let mut error = EndpointError::build(status::MethodNotAllowed,
+                                             ErrNo::MethodNotAllowed,
+                                             None,
+                                             Some(format!("Allowed methods: {}",
+                                                          details)));
+        error.response.headers.set(Allow(allowed));
+        Err(error)
+    }
+}
+
+/// The answer to an OPTIONS request for a route with the `allowed` methods,
+/// with the CORS preflight headers when a browser asks whether a page from
+/// another origin can use one of them.
+fn options(req: &Request, mut allowed: Vec<Method>) -> Response {
+    allowed.push(Method::Options);
+    let mut response = Response::with(status::NoContent);
+    let requested = req.headers.get::<AccessControlRequestMethod>();
+    if let Some(&AccessControlRequestMethod(ref method)) = requested {
+        if req.headers.get_raw("Origin").is_some() && allowed.contains(method) {
+            response.headers.set(AccessControlAllowOrigin::Any);
+            response.headers.set(AccessControlAllowMethods(allowed.clone()));
+            if let Some(&AccessControlRequestHeaders(ref headers)) = req.headers.get() {
+                response.headers.set(AccessControlAllowHeaders(headers.clone()));
+            }
+            response.headers.set(AccessControlMaxAge(PREFLIGHT_MAX_AGE));
+        }
+    }
+    response.headers.set(Allow(allowed));
+    response
+}
+
+/// Answer requests for unknown paths with the usual JSON error body rather
+/// than Iron's empty 404.
+pub struct JsonNotFound;
+
+impl AfterMiddleware for JsonNotFound {
+    fn catch(&self, _: &mut Request, err: IronError) -> IronResult<Response> {
+        if err.error.is::<NoRoute>() || err.error.is::<NoMatch>() {
+            return EndpointError::with(status::NotFound, ErrNo::UnknownPath);
+        }
+        Err(err)
+    }
+}
+
+#[test]
+fn test_allowed_methods() {
+    let mut routes = Routes::new();
+    routes.get("ping", |_: &mut Request| Ok(Response::new()), "ping");
+    routes.post("register", |_: &mut Request| Ok(Response::new()), "register");
+    routes.get("v1/box/:fingerprint", |_: &mut Request| Ok(Response::new()), "box");
+
+    assert_eq!(routes.allowed_methods("ping"), vec![Method::Get]);
+    assert_eq!(routes.allowed_methods("register"), vec![Method::Post]);
+    assert_eq!(routes.allowed_methods("v1/box/abcd"), vec![Method::Get]);
+    assert!(routes.allowed_methods("unknown").is_empty());
+}
+
+#[test]
+fn test_globs() {
+    let mut routes = Routes::new();
+    routes.post("/", |_: &mut Request| Ok(Response::new()), "create");
+    routes.get("boxes", |_: &mut Request| Ok(Response::new()), "boxes");
+    routes.post("boxes", |_: &mut Request| Ok(Response::new()), "link");
+
+    assert_eq!(routes.globs(""), vec![(vec![Method::Post], "".to_owned()),
+                                      (vec![Method::Get, Method::Post], "boxes".to_owned())]);
+    assert_eq!(routes.globs("/v1/account"),
+               vec![(vec![Method::Post], "v1/account".to_owned()),
+                    (vec![Method::Get, Method::Post], "v1/account/boxes".to_owned())]);
+}
@@ -0,0 +1,168 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Background jobs, run periodically from a dedicated thread.
+///
+/// In cluster mode every instance runs the scheduler, but a job only runs on
+/// the instance which manages to take its lease in the shared database for
+/// the next `interval` seconds.
+///
+/// Jobs are paused while the server is read-only, and run once it isn't
+/// anymore if they were due.
+///
+/// Every instance also checks that it can reach the database every
+/// `HEALTH_CHECK_INTERVAL` seconds, logging when it becomes unreachable and
+/// when it recovers, and recording the outcome in the metrics.
+
+use cache;
+use config::Config;
+use db::Db;
+use redis::RedisResult;
+use retention;
+use std::thread;
+use std::time::Duration;
+
+/// Number of seconds between two database health checks.
+pub static HEALTH_CHECK_INTERVAL: u64 = 10;
+
+pub struct Job {
+    pub name: &'static str,
+    /// Number of seconds between two runs.
+    pub interval: u64,
+    pub run: Box<Fn(&Db) -> RedisResult<()> + Send>,
+}
+
+/// The jobs every instance runs.
+pub fn default_jobs(config: &Config) -> Vec<Job> {
+    let cfg = config.clone();
+    let mut jobs = vec![
+        Job {
+            name: "evict",
+            interval: 60,
+            run: Box::new(move |db: &Db| {
+                let evicted = try!(db.evict_clients());
+                info!("Evicted {} expired clients", evicted.len());
+                cfg.metrics.record_eviction(evicted.len(), db.now());
+                for &(ref public_ip, _) in &evicted {
+                    cache::invalidate(&cfg, public_ip);
+                }
+                cfg.events.publish_evictions(&cfg, db, evicted);
+                Ok(())
+            }),
+        },
+    ];
+
+    if config.maintenance_interval > 0 {
+        jobs.push(Job {
+            name: "maintenance",
+            interval: config.maintenance_interval,
+            run: Box::new(|db: &Db| {
+                if try!(db.maintenance()) {
+                    info!("Started rewriting the Redis append-only file");
+                }
+                Ok(())
+            }),
+        });
+    }
+
+    if config.retention.backups > 0 {
+        let directory = config.backup_dir.clone();
+        let days = config.retention.backups;
+        jobs.push(Job {
+            name: "retention",
+            interval: 3600,
+            run: Box::new(move |db: &Db| {
+                match retention::prune_backups(&directory, days, db.now()) {
+                    Ok(0) => {},
+                    Ok(pruned) => info!("Deleted {} old backups", pruned),
+                    Err(e) => error!("Can't delete the old backups: {}", e)
+                }
+                Ok(())
+            }),
+        });
+    }
+
+    if config.usage_aggregates {
+        jobs.push(Job {
+            name: "usage",
+            interval: 3600,
+            run: Box::new(|db: &Db| {
+                // Complete the aggregates of yesterday with its last hour.
+                let today = db.now() / 86400;
+                try!(db.aggregate_usage(today - 1));
+                try!(db.aggregate_usage(today));
+                Ok(())
+            }),
+        });
+    }
+
+    jobs
+}
+
+fn run_job(job: &Job, config: &Config) -> RedisResult<()> {
+    // Jobs like the eviction can take longer than a request.
+    let db = try!(try!(Db::from_config(config)).with_timeout(None));
+
+    if config.cluster {
+        let acquired = try!(
+            db.acquire_lease(job.name, &config.instance_id, job.interval)
+        );
+        if !acquired {
+            debug!("Job {} is running on another instance", job.name);
+            return Ok(());
+        }
+    }
+
+    (job.run)(&db)
+}
+
+/// Ping the database with a new connection, logging the changes of state.
+fn check_health(config: &Config) {
+    let was_healthy = config.metrics.snapshot().health.healthy;
+    let result = Db::from_config(config).and_then(|db| db.ping());
+    match result {
+        Ok(()) if !was_healthy => info!("The database is reachable"),
+        Err(ref e) if was_healthy => error!("The database is unreachable: {}", e),
+        _ => {}
+    }
+    config.metrics.record_health_check(result.is_ok(), config.clock.now());
+}
+
+pub fn start(config: Config, jobs: Vec<Job>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        // Start with the maintenance and other infrequent jobs after a full
+        // interval, rather than on every restart.
+        let started = config.clock.now();
+        let mut next_runs: Vec<u64> = jobs.iter().map(|job| {
+            if job.interval > 60 { started + job.interval } else { started }
+        }).collect();
+        let mut next_health_check = started;
+
+        loop {
+            if config.clock.now() >= next_health_check {
+                check_health(&config);
+                next_health_check = config.clock.now() + HEALTH_CHECK_INTERVAL;
+            }
+
+            if config.read_only.retry_after().is_some() {
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+
+            let now = config.clock.now();
+            for (job, next_run) in jobs.iter().zip(next_runs.iter_mut()) {
+                if *next_run > now {
+                    continue;
+                }
+                *next_run = now + job.interval;
+
+                if let Err(e) = run_job(job, &config) {
+                    error!("Job {} failed: {}", job.name, e);
+                }
+            }
+
+            thread::sleep(Duration::from_secs(1));
+        }
+    })
+}
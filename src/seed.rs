@@ -0,0 +1,101 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Generation of fake but realistic records, to fill a development
+/// database when working on the admin UI or testing pagination.
+///
+/// The boxes are spread over a number of networks, i.e. public IPs, either
+/// evenly or the way real homes are: most networks have one or two boxes
+/// and a few of them have many.
+
+use db::{ Record, RECORD_TTL };
+use rand::{ self, Rng };
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    /// The same number of boxes in every network.
+    Uniform,
+    /// The number of boxes of a network decreases with its rank, following
+    /// Zipf's law.
+    Skewed,
+}
+
+impl Distribution {
+    pub fn from_name(name: &str) -> Option<Distribution> {
+        match name {
+            "uniform" => Some(Distribution::Uniform),
+            "skewed" => Some(Distribution::Skewed),
+            _ => None
+        }
+    }
+}
+
+/// A random public IPv4 address, outside of the private, loopback and
+/// multicast ranges.
+fn public_ip<R: Rng>(rng: &mut R) -> String {
+    loop {
+        let (a, b): (u8, u8) = (rng.gen_range(1, 224), rng.gen());
+        let private = a == 10 || a == 127 || (a == 172 && b >= 16 && b < 32) ||
+                      (a == 192 && b == 168) || (a == 169 && b == 254);
+        if !private {
+            return format!("{}.{}.{}.{}", a, b, rng.gen::<u8>(), rng.gen_range(1, 255));
+        }
+    }
+}
+
+fn fingerprint<R: Rng>(rng: &mut R) -> String {
+    (0..20).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+}
+
+/// `count` records of boxes spread over `networks` public IPs, registered
+/// during the last `RECORD_TTL` seconds before `now`.
+pub fn records(count: usize, networks: usize, distribution: Distribution, now: u64)
+    -> Vec<Record> {
+    let mut rng = rand::thread_rng();
+    let networks: Vec<String> = (0..networks).map(|_| public_ip(&mut rng)).collect();
+
+    // Cumulative weights of the networks, to pick them at random.
+    let mut total = 0.0;
+    let weights: Vec<f64> = (0..networks.len()).map(|rank| {
+        total += match distribution {
+            Distribution::Uniform => 1.0,
+            Distribution::Skewed => 1.0 / (rank + 1) as f64,
+        };
+        total
+    }).collect();
+
+    (0..count).map(|_| {
+        let target = rng.gen::<f64>() * total;
+        let network = weights.iter().position(|&weight| target < weight)
+                                    .unwrap_or(networks.len() - 1);
+        let client = fingerprint(&mut rng);
+        let local_ip = format!("192.168.1.{}", rng.gen_range(2, 255));
+        let message = format!(r#"{{"local_origin":"https://{}.{}.box.knilxof.org:4443","tunnel_origin":null}}"#,
+                              local_ip.replace('.', "-"), client);
+
+        let mut record = Record::new(networks[network].clone(), client, message,
+                                     now - rng.gen_range(0, RECORD_TTL as u64));
+        // Boxes stay online for hours, re-registering all along.
+        record.first_seen -= rng.gen_range(0, 7 * 24 * 3600);
+        record
+    }).collect()
+}
+
+#[test]
+fn test_records() {
+    use std::collections::HashSet;
+
+    let now = 1481900000;
+    for &distribution in &[Distribution::Uniform, Distribution::Skewed] {
+        let records = records(1000, 50, distribution, now);
+        assert_eq!(records.len(), 1000);
+        let networks: HashSet<&String> = records.iter().map(|r| &r.public_ip).collect();
+        assert!(networks.len() <= 50);
+        for record in &records {
+            assert_eq!(record.client.len(), 40);
+            assert!(record.last_seen <= now && !record.is_expired(now, RECORD_TTL as u64));
+            assert!(record.first_seen <= record.last_seen);
+        }
+    }
+}
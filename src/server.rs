@@ -0,0 +1,138 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// HTTP server bound with SO_REUSEPORT, for zero-downtime restarts: the new
+/// instance binds the same port while the old one is still running, and
+/// the old one is then sent SIGTERM. It stops accepting connections, so
+/// that the kernel sends them all to the new instance, and exits once the
+/// requests in progress are done or the drain timeout expires.
+///
+/// Iron can't use an existing listener, so the requests are passed from
+/// hyper to the Iron handler here.
+
+use config::Config;
+use hyper::net::HttpListener;
+use hyper::server::{ Handler as HyperHandler, Request as HyperRequest,
+                     Response as HyperResponse, Server };
+use hyper::status::StatusCode;
+use iron::{ Handler, Protocol, Request };
+use libc;
+use net2::TcpBuilder;
+use net2::unix::UnixTcpBuilderExt;
+use std::io;
+use std::net::{ SocketAddr, TcpListener, ToSocketAddrs };
+use std::os::unix::io::{ AsRawFd, RawFd };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+static LISTEN_BACKLOG: i32 = 1024;
+
+/// Bind `addr` with SO_REUSEPORT.
+pub fn bind(addr: &str) -> io::Result<TcpListener> {
+    let addr = match try!(addr.to_socket_addrs()).next() {
+        Some(addr) => addr,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "no address to bind"))
+    };
+    let builder = try!(match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4(),
+        SocketAddr::V6(_) => TcpBuilder::new_v6(),
+    });
+    try!(builder.reuse_address(true));
+    try!(builder.reuse_port(true));
+    try!(builder.bind(addr));
+    builder.listen(LISTEN_BACKLOG)
+}
+
+/// Tracks the requests in progress, to let them finish before exiting.
+pub struct Drain {
+    in_flight: AtomicUsize,
+    listener: Mutex<Option<RawFd>>,
+}
+
+impl Drain {
+    pub fn new() -> Drain {
+        Drain {
+            in_flight: AtomicUsize::new(0),
+            listener: Mutex::new(None),
+        }
+    }
+
+    /// Stop accepting connections and wait for the requests in progress,
+    /// for at most `timeout`.
+    pub fn run(&self, timeout: Duration) {
+        if let Some(fd) = *self.listener.lock().unwrap() {
+            unsafe { libc::shutdown(fd, libc::SHUT_RD) };
+        }
+
+        let start = Instant::now();
+        while self.in_flight.load(Ordering::SeqCst) > 0 {
+            if start.elapsed() >= timeout {
+                warn!("Exiting with {} requests in progress",
+                      self.in_flight.load(Ordering::SeqCst));
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+struct IronAdapter<H: Handler> {
+    handler: H,
+    local_addr: SocketAddr,
+    drain: Arc<Drain>,
+}
+
+impl<H: Handler> HyperHandler for IronAdapter<H> {
+    fn handle(&self, http_req: HyperRequest, mut http_res: HyperResponse) {
+        self.drain.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        match Request::from_http(http_req, self.local_addr, &Protocol::Http) {
+            Ok(mut req) => {
+                let response = match self.handler.handle(&mut req) {
+                    Ok(response) => response,
+                    Err(err) => err.response
+                };
+                response.write_back(http_res);
+            },
+            Err(e) => {
+                error!("Invalid request: {}", e);
+                *http_res.status_mut() = StatusCode::BadRequest;
+                let _ = http_res.send(b"Bad Request");
+            }
+        }
+
+        self.drain.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serve `handler` on `listener` until the process exits.
+pub fn serve<H: Handler>(handler: H,
+                         listener: TcpListener,
+                         config: &Config,
+                         drain: Arc<Drain>) -> io::Result<()> {
+    let local_addr = try!(listener.local_addr());
+    *drain.listener.lock().unwrap() = Some(listener.as_raw_fd());
+
+    let mut server = Server::new(HttpListener::from(listener));
+    if config.keep_alive > 0 {
+        server.keep_alive(Some(Duration::from_secs(config.keep_alive)));
+    } else {
+        server.keep_alive(None);
+    }
+    server.set_read_timeout(Some(Duration::from_secs(config.read_timeout)));
+    server.set_write_timeout(Some(Duration::from_secs(config.write_timeout)));
+
+    let adapter = IronAdapter {
+        handler: handler,
+        local_addr: local_addr,
+        drain: drain,
+    };
+    try!(server.handle_threads(adapter, config.threads).map_err(|e| {
+        io::Error::new(io::ErrorKind::Other, e.to_string())
+    }));
+    Ok(())
+}
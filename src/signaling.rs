@@ -0,0 +1,111 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// NAT traversal hints: boxes and clients which want to connect directly,
+/// e.g. from outside the local network of the box, exchange their ICE-style
+/// candidates through the server, which acts as a minimal signaling helper.
+///
+/// Each side sends the local addresses it listens on as "host" candidates,
+/// and the server adds a "srflx" (server reflexive) candidate with the
+/// public address and port it sees the request coming from, which is the
+/// mapping of its NAT. The box keeps its candidates up to date with its
+/// token, getting the candidates the clients left for it in return, while
+/// the clients leave theirs and get the box's in return. Both sides then
+/// try the candidates of the other, hole punching as needed.
+///
+/// Candidates are short-lived, as the NAT mappings are, and hold the real
+/// addresses, even in privacy mode, since they are of no use otherwise.
+
+use std::net::SocketAddr;
+
+/// The local addresses of a box or client.
+pub static HOST: &'static str = "host";
+/// The public address the server sees a request coming from.
+pub static SERVER_REFLEXIVE: &'static str = "srflx";
+/// Number of seconds the candidates are kept.
+pub static CANDIDATES_TTL: usize = 120;
+/// Maximum number of host candidates given at once.
+pub static MAX_CANDIDATES: usize = 8;
+/// Number of sets of candidates of clients kept for a box, the oldest ones
+/// being dropped first.
+pub static MAX_PEERS: isize = 8;
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Candidate {
+    /// `HOST` or `SERVER_REFLEXIVE`.
+    pub kind: String,
+    pub ip: String,
+    pub port: u16,
+}
+
+impl Candidate {
+    pub fn host(ip: String, port: u16) -> Candidate {
+        Candidate {
+            kind: HOST.to_owned(),
+            ip: ip,
+            port: port,
+        }
+    }
+
+    /// The candidate of the public endpoint a request came from.
+    pub fn observed(addr: &SocketAddr) -> Candidate {
+        Candidate {
+            kind: SERVER_REFLEXIVE.to_owned(),
+            ip: format!("{}", addr.ip()),
+            port: addr.port(),
+        }
+    }
+
+    /// The candidate as stored, "<kind>:<port>:<ip>", the IP coming last
+    /// since IPv6 addresses hold colons.
+    pub fn to_key(&self) -> String {
+        format!("{}:{}:{}", self.kind, self.port, self.ip)
+    }
+
+    pub fn from_key(key: &str) -> Option<Candidate> {
+        let mut parts = key.splitn(3, ':');
+        match (parts.next(), parts.next().and_then(|port| port.parse().ok()), parts.next()) {
+            (Some(kind), Some(port), Some(ip)) => Some(Candidate {
+                kind: kind.to_owned(),
+                ip: ip.to_owned(),
+                port: port,
+            }),
+            _ => None
+        }
+    }
+}
+
+/// A set of candidates as stored, their keys separated by commas.
+pub fn to_key(candidates: &[Candidate]) -> String {
+    let keys: Vec<String> = candidates.iter().map(Candidate::to_key).collect();
+    keys.join(",")
+}
+
+pub fn from_key(key: &str) -> Vec<Candidate> {
+    key.split(',').filter_map(Candidate::from_key).collect()
+}
+
+/// The response to the deposit of candidates: the candidate the server
+/// observed, and the candidates of the other side.
+#[derive(Debug, RustcEncodable)]
+pub struct Exchange<T> {
+    pub observed: Candidate,
+    pub candidates: T,
+}
+
+#[test]
+fn test_observed() {
+    let addr: SocketAddr = "88.22.170.96:40123".parse().unwrap();
+    assert_eq!(Candidate::observed(&addr), Candidate {
+        kind: "srflx".to_owned(),
+        ip: "88.22.170.96".to_owned(),
+        port: 40123,
+    });
+
+    let candidates = vec![Candidate::observed(&addr),
+                          Candidate::host("fe80::1".to_owned(), 8443)];
+    assert_eq!(to_key(&candidates), "srflx:40123:88.22.170.96,host:8443:fe80::1");
+    assert_eq!(from_key(&to_key(&candidates)), candidates);
+    assert_eq!(Candidate::from_key("host:port:1.2.3.4"), None);
+}
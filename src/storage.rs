@@ -0,0 +1,1411 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// The storage operations used by the endpoints, so that handler
+/// tests can replace the database with a `MockStorage`.
+
+use config::Config;
+use db::{ Archived, Credential, Db, Filter, Flapping, Heartbeat, Pairing, Precondition,
+          PublicIp, Record, Revocation, Stats, Usage };
+use metrics::Metrics;
+use push::Subscription;
+use redis::{ ErrorKind, RedisError, RedisResult };
+use signaling::Candidate;
+use std::cell::RefCell;
+use std::fmt::Debug;
+#[cfg(test)]
+use std::collections::HashMap;
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+/// Number of times an operation is retried while Redis is busy.
+static MAX_RETRIES: u32 = 4;
+/// Delay before the first retry, doubled for each of the next ones.
+static RETRY_DELAY_MS: u64 = 10;
+/// The operations which only read, and can run again when the connection
+/// dropped before their answer.
+static READ_ONLY: [&'static str; 25] = [
+    "get", "discover", "find_by_client", "token_matches", "session_user", "is_owner",
+    "subscriptions", "candidates", "find_response", "find_credential", "password_hash",
+    "box_count", "user_boxes", "guest_client", "user_subdomains", "find", "archived",
+    "check_integrity", "flapping", "public_ips", "pinned", "stats", "usage", "tokens",
+    "revoked",
+];
+
+pub trait Storage {
+    /// Add or update a record, returning its new revision.
+    fn set(&self, record: Record) -> RedisResult<u64>;
+    /// Add or update a record if the current one satisfies `precondition`,
+    /// returning its new revision, or `None` if it wasn't updated.
+    fn set_if(&self, record: Record, precondition: Precondition) -> RedisResult<Option<u64>>;
+    /// Add or update several records at once, returning their revisions.
+    fn add_many(&self, records: &[Record]) -> RedisResult<Vec<u64>>;
+    /// The records registered from a public IP.
+    fn get(&self, public_ip: String) -> RedisResult<Vec<Record>>;
+    /// The records discovered from a public IP, including those of its
+    /// subnet when subnets are matched.
+    fn discover(&self, public_ip: String) -> RedisResult<Vec<Record>>;
+    /// The latest record of a client.
+    fn find_by_client(&self, client: String) -> RedisResult<Option<Record>>;
+    /// Store the token of the latest registration of a client.
+    fn set_token(&self, client: String, token: String) -> RedisResult<()>;
+    /// Keep the latest registration of a client alive.
+    fn heartbeat(&self, client: String, token: &str) -> RedisResult<Heartbeat>;
+    /// Replace the current `token` of a client by a new one.
+    fn rotate_token(&self, client: String, token: &str) -> RedisResult<Option<String>>;
+    /// Whether `token` is the current token of a client, or its previous
+    /// one during the grace period of a rotation.
+    fn token_matches(&self, client: String, token: &str) -> RedisResult<bool>;
+    /// The user of a session.
+    fn session_user(&self, token: &str) -> RedisResult<Option<String>>;
+    /// Whether a box is linked to the account of `email`.
+    fn is_owner(&self, email: &str, client: String) -> RedisResult<bool>;
+    /// Create an account, false if the email is already taken.
+    fn create_user(&self, email: &str, password_hash: &str) -> RedisResult<bool>;
+    /// Create the account of `email` unless it exists already.
+    fn ensure_user(&self, email: &str) -> RedisResult<()>;
+    /// The password hash of an account.
+    fn password_hash(&self, email: &str) -> RedisResult<Option<String>>;
+    /// Open a session, returning its token.
+    fn create_session(&self, email: &str) -> RedisResult<String>;
+    /// Close a session.
+    fn delete_session(&self, token: &str) -> RedisResult<()>;
+    /// Remember the state of an OpenID Connect log in.
+    fn create_oidc_state(&self) -> RedisResult<String>;
+    /// Forget the state of a log in, returning whether it was known.
+    fn take_oidc_state(&self, state: &str) -> RedisResult<bool>;
+    /// Link a box to the account of `email`.
+    fn link_box(&self, email: &str, client: String) -> RedisResult<()>;
+    /// Unlink a box, returning whether it was linked.
+    fn unlink_box(&self, email: &str, client: String) -> RedisResult<bool>;
+    /// Number of boxes linked to an account.
+    fn box_count(&self, email: &str) -> RedisResult<u64>;
+    /// The latest registrations of the boxes linked to an account.
+    fn user_boxes(&self, email: &str) -> RedisResult<Vec<Record>>;
+    /// Create a guest token giving access to a box for `ttl` seconds.
+    fn create_guest_token(&self, email: &str, client: String, ttl: u64) -> RedisResult<String>;
+    /// The client a guest token gives access to.
+    fn guest_client(&self, token: &str) -> RedisResult<Option<String>>;
+    /// Reserve a subdomain for an account, false if another one has it.
+    fn reserve_subdomain(&self, email: &str, name: &str) -> RedisResult<bool>;
+    /// Release a subdomain, returning whether the account had it.
+    fn release_subdomain(&self, email: &str, name: &str) -> RedisResult<bool>;
+    /// The subdomains reserved by an account, sorted.
+    fn user_subdomains(&self, email: &str) -> RedisResult<Vec<String>>;
+    /// Count a registration against the hourly quota of the owner of a
+    /// client, returning the owner and their count.
+    fn count_registration(&self, client: String) -> RedisResult<Option<(String, u64)>>;
+    /// Create a pairing code for a client.
+    fn create_pairing_code(&self, client: String) -> RedisResult<String>;
+    /// Redeem a pairing code entered by a user from a public IP.
+    fn redeem_pairing_code(&self, code: &str, public_ip: String) -> RedisResult<Pairing>;
+    /// Subscribe a mobile client to the push notifications about a box.
+    fn subscribe(&self, client: String, subscription: &Subscription) -> RedisResult<()>;
+    /// Unsubscribe a mobile client, returning whether it was subscribed.
+    fn unsubscribe(&self, client: String, subscription: &Subscription) -> RedisResult<bool>;
+    /// The mobile clients subscribed to the push notifications about a box.
+    fn subscriptions(&self, client: String) -> RedisResult<Vec<Subscription>>;
+    /// Leave a message for a box, returning the number of messages waiting.
+    fn leave_message(&self, client: String, message: String) -> RedisResult<usize>;
+    /// Empty the mailbox of a box, returning its messages.
+    fn take_messages(&self, client: String) -> RedisResult<Vec<String>>;
+    /// Replace the NAT traversal candidates of a box.
+    fn set_candidates(&self, client: String, candidates: &[Candidate]) -> RedisResult<()>;
+    /// The NAT traversal candidates of a box, if it has any.
+    fn candidates(&self, client: String) -> RedisResult<Option<Vec<Candidate>>>;
+    /// Leave the candidates of a client for a box.
+    fn add_peer(&self, client: String, candidates: &[Candidate]) -> RedisResult<()>;
+    /// Take the candidates the clients left for a box.
+    fn take_peers(&self, client: String) -> RedisResult<Vec<Vec<Candidate>>>;
+    /// Record whether a box could be reached at the public IP of its
+    /// registration, returning whether it is still registered from there.
+    fn set_verified(&self, public_ip: String, client: String, verified: bool)
+        -> RedisResult<bool>;
+    /// Whether an update of a client is allowed: unless it is flagged as
+    /// flapping, it doesn't need the `token` of its latest registration.
+    fn check_flapping(&self, client: String, token: Option<String>) -> RedisResult<bool>;
+    /// The response kept for an idempotency key.
+    fn find_response(&self, key: String) -> RedisResult<Option<String>>;
+    /// Keep the response of a request with an idempotency key.
+    fn keep_response(&self, key: String, response: String) -> RedisResult<()>;
+    /// The admin token or API key with this hash, if there is one.
+    fn find_credential(&self, hash: String) -> RedisResult<Option<Credential>>;
+    /// The records matching a filter.
+    fn find(&self, filter: &Filter) -> RedisResult<Vec<Record>>;
+    /// Delete the latest record of a client, returning whether it existed.
+    fn delete(&self, client: String) -> RedisResult<bool>;
+    /// The latest registration of a box, kept after its eviction.
+    fn archived(&self, client: String) -> RedisResult<Option<Archived>>;
+    /// Drop the expired clients, returning their public IPs and clients.
+    fn evict_clients(&self) -> RedisResult<Vec<(String, String)>>;
+    /// At most `max_problems` inconsistencies of the database.
+    fn check_integrity(&self, max_problems: usize) -> RedisResult<Vec<String>>;
+    /// The boxes flagged as flapping during the last day.
+    fn flapping(&self) -> RedisResult<Vec<Flapping>>;
+    /// The public IPs with at least `min_clients` clients.
+    fn public_ips(&self, min_clients: usize) -> RedisResult<Vec<PublicIp>>;
+    /// Exempt a box from expiration, false if it isn't registered.
+    fn pin(&self, client: String) -> RedisResult<bool>;
+    /// Let a box expire again, false if it wasn't pinned.
+    fn unpin(&self, client: String) -> RedisResult<bool>;
+    /// The pinned clients.
+    fn pinned(&self) -> RedisResult<Vec<String>>;
+    /// The size of the database and the registration intervals.
+    fn stats(&self) -> RedisResult<Stats>;
+    /// The daily usage aggregates.
+    fn usage(&self) -> RedisResult<Vec<Usage>>;
+    /// The current token of a client, and its previous one if any.
+    fn tokens(&self, client: &str) -> RedisResult<Vec<String>>;
+    /// The revoked credentials, the latest first.
+    fn revoked(&self) -> RedisResult<Vec<Revocation>>;
+    /// Revoke a credential by its hash.
+    fn revoke(&self, hash: String) -> RedisResult<()>;
+    /// Accept a revoked credential again, false if it wasn't revoked.
+    fn unrevoke(&self, hash: String) -> RedisResult<bool>;
+}
+
+/// Opens a `Storage` for each request, injected through the `Config`.
+pub trait Connector: Debug + Send + Sync {
+    fn connect(&self, config: &Config) -> RedisResult<Box<Storage>>;
+}
+
+impl Storage for Db {
+    fn set(&self, record: Record) -> RedisResult<u64> {
+        Db::set(self, record)
+    }
+
+    fn set_if(&self, record: Record, precondition: Precondition) -> RedisResult<Option<u64>> {
+        Db::set_if(self, record, precondition)
+    }
+
+    fn add_many(&self, records: &[Record]) -> RedisResult<Vec<u64>> {
+        Db::add_many(self, records)
+    }
+
+    fn get(&self, public_ip: String) -> RedisResult<Vec<Record>> {
+        Db::get(self, public_ip)
+    }
+
+    fn discover(&self, public_ip: String) -> RedisResult<Vec<Record>> {
+        Db::discover(self, public_ip)
+    }
+
+    fn find_by_client(&self, client: String) -> RedisResult<Option<Record>> {
+        Db::find_by_client(self, client)
+    }
+
+    fn set_token(&self, client: String, token: String) -> RedisResult<()> {
+        Db::set_token(self, client, token)
+    }
+
+    fn heartbeat(&self, client: String, token: &str) -> RedisResult<Heartbeat> {
+        Db::heartbeat(self, client, token)
+    }
+
+    fn rotate_token(&self, client: String, token: &str) -> RedisResult<Option<String>> {
+        Db::rotate_token(self, client, token)
+    }
+
+    fn token_matches(&self, client: String, token: &str) -> RedisResult<bool> {
+        Db::token_matches(self, &client, token)
+    }
+
+    fn session_user(&self, token: &str) -> RedisResult<Option<String>> {
+        Db::session_user(self, token)
+    }
+
+    fn is_owner(&self, email: &str, client: String) -> RedisResult<bool> {
+        Db::is_owner(self, email, client)
+    }
+
+    fn create_user(&self, email: &str, password_hash: &str) -> RedisResult<bool> {
+        Db::create_user(self, email, password_hash)
+    }
+
+    fn ensure_user(&self, email: &str) -> RedisResult<()> {
+        Db::ensure_user(self, email)
+    }
+
+    fn password_hash(&self, email: &str) -> RedisResult<Option<String>> {
+        Db::password_hash(self, email)
+    }
+
+    fn create_session(&self, email: &str) -> RedisResult<String> {
+        Db::create_session(self, email)
+    }
+
+    fn delete_session(&self, token: &str) -> RedisResult<()> {
+        Db::delete_session(self, token)
+    }
+
+    fn create_oidc_state(&self) -> RedisResult<String> {
+        Db::create_oidc_state(self)
+    }
+
+    fn take_oidc_state(&self, state: &str) -> RedisResult<bool> {
+        Db::take_oidc_state(self, state)
+    }
+
+    fn link_box(&self, email: &str, client: String) -> RedisResult<()> {
+        Db::link_box(self, email, client)
+    }
+
+    fn unlink_box(&self, email: &str, client: String) -> RedisResult<bool> {
+        Db::unlink_box(self, email, client)
+    }
+
+    fn box_count(&self, email: &str) -> RedisResult<u64> {
+        Db::box_count(self, email)
+    }
+
+    fn user_boxes(&self, email: &str) -> RedisResult<Vec<Record>> {
+        Db::user_boxes(self, email)
+    }
+
+    fn create_guest_token(&self, email: &str, client: String, ttl: u64) -> RedisResult<String> {
+        Db::create_guest_token(self, email, client, ttl)
+    }
+
+    fn guest_client(&self, token: &str) -> RedisResult<Option<String>> {
+        Db::guest_client(self, token)
+    }
+
+    fn reserve_subdomain(&self, email: &str, name: &str) -> RedisResult<bool> {
+        Db::reserve_subdomain(self, email, name)
+    }
+
+    fn release_subdomain(&self, email: &str, name: &str) -> RedisResult<bool> {
+        Db::release_subdomain(self, email, name)
+    }
+
+    fn user_subdomains(&self, email: &str) -> RedisResult<Vec<String>> {
+        Db::user_subdomains(self, email)
+    }
+
+    fn count_registration(&self, client: String) -> RedisResult<Option<(String, u64)>> {
+        Db::count_registration(self, client)
+    }
+
+    fn create_pairing_code(&self, client: String) -> RedisResult<String> {
+        Db::create_pairing_code(self, client)
+    }
+
+    fn redeem_pairing_code(&self, code: &str, public_ip: String) -> RedisResult<Pairing> {
+        Db::redeem_pairing_code(self, code, public_ip)
+    }
+
+    fn subscribe(&self, client: String, subscription: &Subscription) -> RedisResult<()> {
+        Db::subscribe(self, client, subscription)
+    }
+
+    fn unsubscribe(&self, client: String, subscription: &Subscription) -> RedisResult<bool> {
+        Db::unsubscribe(self, client, subscription)
+    }
+
+    fn subscriptions(&self, client: String) -> RedisResult<Vec<Subscription>> {
+        Db::subscriptions(self, client)
+    }
+
+    fn leave_message(&self, client: String, message: String) -> RedisResult<usize> {
+        Db::leave_message(self, client, message)
+    }
+
+    fn take_messages(&self, client: String) -> RedisResult<Vec<String>> {
+        Db::take_messages(self, client)
+    }
+
+    fn set_candidates(&self, client: String, candidates: &[Candidate]) -> RedisResult<()> {
+        Db::set_candidates(self, client, candidates)
+    }
+
+    fn candidates(&self, client: String) -> RedisResult<Option<Vec<Candidate>>> {
+        Db::candidates(self, client)
+    }
+
+    fn add_peer(&self, client: String, candidates: &[Candidate]) -> RedisResult<()> {
+        Db::add_peer(self, client, candidates)
+    }
+
+    fn take_peers(&self, client: String) -> RedisResult<Vec<Vec<Candidate>>> {
+        Db::take_peers(self, client)
+    }
+
+    fn set_verified(&self, public_ip: String, client: String, verified: bool)
+        -> RedisResult<bool> {
+        Db::set_verified(self, public_ip, client, verified)
+    }
+
+    fn check_flapping(&self, client: String, token: Option<String>) -> RedisResult<bool> {
+        Db::check_flapping(self, client, token)
+    }
+
+    fn find_response(&self, key: String) -> RedisResult<Option<String>> {
+        Db::find_response(self, key)
+    }
+
+    fn keep_response(&self, key: String, response: String) -> RedisResult<()> {
+        Db::keep_response(self, key, response)
+    }
+
+    fn find_credential(&self, hash: String) -> RedisResult<Option<Credential>> {
+        Db::find_credential(self, &hash)
+    }
+
+    fn find(&self, filter: &Filter) -> RedisResult<Vec<Record>> {
+        Db::find(self, filter)
+    }
+
+    fn delete(&self, client: String) -> RedisResult<bool> {
+        Db::delete(self, client)
+    }
+
+    fn archived(&self, client: String) -> RedisResult<Option<Archived>> {
+        Db::archived(self, client)
+    }
+
+    fn evict_clients(&self) -> RedisResult<Vec<(String, String)>> {
+        Db::evict_clients(self)
+    }
+
+    fn check_integrity(&self, max_problems: usize) -> RedisResult<Vec<String>> {
+        Db::check_integrity(self, max_problems)
+    }
+
+    fn flapping(&self) -> RedisResult<Vec<Flapping>> {
+        Db::flapping(self)
+    }
+
+    fn public_ips(&self, min_clients: usize) -> RedisResult<Vec<PublicIp>> {
+        Db::public_ips(self, min_clients)
+    }
+
+    fn pin(&self, client: String) -> RedisResult<bool> {
+        Db::pin(self, client)
+    }
+
+    fn unpin(&self, client: String) -> RedisResult<bool> {
+        Db::unpin(self, client)
+    }
+
+    fn pinned(&self) -> RedisResult<Vec<String>> {
+        Db::pinned(self)
+    }
+
+    fn stats(&self) -> RedisResult<Stats> {
+        Db::stats(self)
+    }
+
+    fn usage(&self) -> RedisResult<Vec<Usage>> {
+        Db::usage(self)
+    }
+
+    fn tokens(&self, client: &str) -> RedisResult<Vec<String>> {
+        Db::tokens(self, client)
+    }
+
+    fn revoked(&self) -> RedisResult<Vec<Revocation>> {
+        Db::revoked(self)
+    }
+
+    fn revoke(&self, hash: String) -> RedisResult<()> {
+        Db::revoke(self, hash)
+    }
+
+    fn unrevoke(&self, hash: String) -> RedisResult<bool> {
+        Db::unrevoke(self, hash)
+    }
+}
+
+/// Whether Redis rejected a command because it is temporarily busy.
+fn is_busy(err: &RedisError) -> bool {
+    match err.kind() {
+        ErrorKind::BusyLoadingError => true,
+        ErrorKind::ExtensionError => {
+            err.extension_error_code().map_or(false, |code| code == "BUSY" || code == "TRYAGAIN")
+        },
+        _ => false
+    }
+}
+
+fn is_read_only(operation: &str) -> bool {
+    READ_ONLY.iter().any(|read| *read == operation)
+}
+
+/// Call `func` until Redis isn't busy anymore, at most `MAX_RETRIES` more
+/// times, calling `on_retry` before each retry.
+fn retry<T, F, R>(mut func: F, mut on_retry: R) -> RedisResult<T>
+    where F: FnMut() -> RedisResult<T>, R: FnMut() {
+    let mut delay = RETRY_DELAY_MS;
+    let mut retries = 0;
+    loop {
+        match func() {
+            Err(ref err) if is_busy(err) && retries < MAX_RETRIES => {
+                warn!("Redis is busy: {} (Will retry in {} ms)", err, delay);
+                on_retry();
+                thread::sleep(Duration::from_millis(delay));
+                delay *= 2;
+                retries += 1;
+            },
+            result => return result
+        }
+    }
+}
+
+/// A `Db` retrying the operations rejected while Redis is busy, e.g. loading
+/// its dataset after a restart or running a slow script, with an exponential
+/// backoff rather than failing the request. Retries are counted in the
+/// metrics.
+///
+/// When Redis closed the connection, e.g. after a restart or an idle
+/// timeout, the connection is opened again. Only the reads are retried on
+/// it: Redis may have run the writes before the connection dropped, and
+/// running them twice could e.g. leave a message twice.
+///
+/// The duration of every operation is recorded in the latency histograms of
+/// the metrics, and the operations slower than `slow_query`, retries
+/// included, are logged with the filter they used, e.g. the public IP of a
+/// discovery, and counted in the metrics.
+pub struct Retrying {
+    db: RefCell<Db>,
+    metrics: Arc<Metrics>,
+    /// `None` doesn't log slow operations.
+    slow_query: Option<Duration>,
+}
+
+impl Retrying {
+    fn run<T, F>(&self, operation: &str, filter: &str, mut func: F) -> RedisResult<T>
+        where F: FnMut(&Db) -> RedisResult<T> {
+        let start = Instant::now();
+        let mut reconnected = false;
+        let result = retry(|| {
+            let result = func(&*self.db.borrow());
+            match result {
+                Err(ref err) if err.is_connection_dropped() && !reconnected => {
+                    warn!("Lost the database connection: {} (Will reconnect)", err);
+                    reconnected = true;
+                    try!(self.db.borrow_mut().reconnect());
+                    self.metrics.record_reconnection();
+                },
+                result => return result
+            }
+            if !is_read_only(operation) {
+                return result;
+            }
+            func(&*self.db.borrow())
+        }, || self.metrics.record_retry(operation));
+
+        let elapsed = start.elapsed();
+        self.metrics.record_latency("redis", operation, elapsed);
+        if self.slow_query.map_or(false, |threshold| elapsed >= threshold) {
+            warn!("Slow storage operation {} {}: {} ms", operation, filter,
+                  elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000);
+            self.metrics.record_slow_query(operation);
+        }
+        result
+    }
+}
+
+impl Storage for Retrying {
+    fn set(&self, record: Record) -> RedisResult<u64> {
+        let filter = format!("client={}", record.client);
+        self.run("set", &filter, |db| db.set(record.clone()))
+    }
+
+    fn set_if(&self, record: Record, precondition: Precondition) -> RedisResult<Option<u64>> {
+        let filter = format!("client={}", record.client);
+        self.run("set_if", &filter, |db| db.set_if(record.clone(), precondition.clone()))
+    }
+
+    fn add_many(&self, records: &[Record]) -> RedisResult<Vec<u64>> {
+        let filter = format!("count={}", records.len());
+        self.run("add_many", &filter, |db| db.add_many(records))
+    }
+
+    fn get(&self, public_ip: String) -> RedisResult<Vec<Record>> {
+        let filter = format!("public_ip={}", public_ip);
+        self.run("get", &filter, |db| db.get(public_ip.clone()))
+    }
+
+    fn discover(&self, public_ip: String) -> RedisResult<Vec<Record>> {
+        let filter = format!("public_ip={}", public_ip);
+        self.run("discover", &filter, |db| db.discover(public_ip.clone()))
+    }
+
+    fn find_by_client(&self, client: String) -> RedisResult<Option<Record>> {
+        let filter = format!("client={}", client);
+        self.run("find_by_client", &filter, |db| db.find_by_client(client.clone()))
+    }
+
+    fn set_token(&self, client: String, token: String) -> RedisResult<()> {
+        let filter = format!("client={}", client);
+        self.run("set_token", &filter, |db| db.set_token(client.clone(), token.clone()))
+    }
+
+    fn heartbeat(&self, client: String, token: &str) -> RedisResult<Heartbeat> {
+        let filter = format!("client={}", client);
+        self.run("heartbeat", &filter, |db| db.heartbeat(client.clone(), token))
+    }
+
+    fn rotate_token(&self, client: String, token: &str) -> RedisResult<Option<String>> {
+        let filter = format!("client={}", client);
+        self.run("rotate_token", &filter, |db| db.rotate_token(client.clone(), token))
+    }
+
+    fn token_matches(&self, client: String, token: &str) -> RedisResult<bool> {
+        let filter = format!("client={}", client);
+        self.run("token_matches", &filter, |db| db.token_matches(&client, token))
+    }
+
+    fn session_user(&self, token: &str) -> RedisResult<Option<String>> {
+        self.run("session_user", "", |db| db.session_user(token))
+    }
+
+    fn is_owner(&self, email: &str, client: String) -> RedisResult<bool> {
+        let filter = format!("client={}", client);
+        self.run("is_owner", &filter, |db| db.is_owner(email, client.clone()))
+    }
+
+    fn create_user(&self, email: &str, password_hash: &str) -> RedisResult<bool> {
+        self.run("create_user", "", |db| db.create_user(email, password_hash))
+    }
+
+    fn ensure_user(&self, email: &str) -> RedisResult<()> {
+        self.run("ensure_user", "", |db| db.ensure_user(email))
+    }
+
+    fn password_hash(&self, email: &str) -> RedisResult<Option<String>> {
+        self.run("password_hash", "", |db| db.password_hash(email))
+    }
+
+    fn create_session(&self, email: &str) -> RedisResult<String> {
+        self.run("create_session", "", |db| db.create_session(email))
+    }
+
+    fn delete_session(&self, token: &str) -> RedisResult<()> {
+        self.run("delete_session", "", |db| db.delete_session(token))
+    }
+
+    fn create_oidc_state(&self) -> RedisResult<String> {
+        self.run("create_oidc_state", "", |db| db.create_oidc_state())
+    }
+
+    fn take_oidc_state(&self, state: &str) -> RedisResult<bool> {
+        self.run("take_oidc_state", "", |db| db.take_oidc_state(state))
+    }
+
+    fn link_box(&self, email: &str, client: String) -> RedisResult<()> {
+        let filter = format!("client={}", client);
+        self.run("link_box", &filter, |db| db.link_box(email, client.clone()))
+    }
+
+    fn unlink_box(&self, email: &str, client: String) -> RedisResult<bool> {
+        let filter = format!("client={}", client);
+        self.run("unlink_box", &filter, |db| db.unlink_box(email, client.clone()))
+    }
+
+    fn box_count(&self, email: &str) -> RedisResult<u64> {
+        self.run("box_count", "", |db| db.box_count(email))
+    }
+
+    fn user_boxes(&self, email: &str) -> RedisResult<Vec<Record>> {
+        self.run("user_boxes", "", |db| db.user_boxes(email))
+    }
+
+    fn create_guest_token(&self, email: &str, client: String, ttl: u64) -> RedisResult<String> {
+        let filter = format!("client={}", client);
+        self.run("create_guest_token", &filter,
+                 |db| db.create_guest_token(email, client.clone(), ttl))
+    }
+
+    fn guest_client(&self, token: &str) -> RedisResult<Option<String>> {
+        self.run("guest_client", "", |db| db.guest_client(token))
+    }
+
+    fn reserve_subdomain(&self, email: &str, name: &str) -> RedisResult<bool> {
+        let filter = format!("name={}", name);
+        self.run("reserve_subdomain", &filter, |db| db.reserve_subdomain(email, name))
+    }
+
+    fn release_subdomain(&self, email: &str, name: &str) -> RedisResult<bool> {
+        let filter = format!("name={}", name);
+        self.run("release_subdomain", &filter, |db| db.release_subdomain(email, name))
+    }
+
+    fn user_subdomains(&self, email: &str) -> RedisResult<Vec<String>> {
+        self.run("user_subdomains", "", |db| db.user_subdomains(email))
+    }
+
+    fn count_registration(&self, client: String) -> RedisResult<Option<(String, u64)>> {
+        let filter = format!("client={}", client);
+        self.run("count_registration", &filter, |db| db.count_registration(client.clone()))
+    }
+
+    fn create_pairing_code(&self, client: String) -> RedisResult<String> {
+        let filter = format!("client={}", client);
+        self.run("create_pairing_code", &filter, |db| db.create_pairing_code(client.clone()))
+    }
+
+    fn redeem_pairing_code(&self, code: &str, public_ip: String) -> RedisResult<Pairing> {
+        let filter = format!("public_ip={}", public_ip);
+        self.run("redeem_pairing_code", &filter,
+                 |db| db.redeem_pairing_code(code, public_ip.clone()))
+    }
+
+    fn subscribe(&self, client: String, subscription: &Subscription) -> RedisResult<()> {
+        let filter = format!("client={}", client);
+        self.run("subscribe", &filter, |db| db.subscribe(client.clone(), subscription))
+    }
+
+    fn unsubscribe(&self, client: String, subscription: &Subscription) -> RedisResult<bool> {
+        let filter = format!("client={}", client);
+        self.run("unsubscribe", &filter, |db| db.unsubscribe(client.clone(), subscription))
+    }
+
+    fn subscriptions(&self, client: String) -> RedisResult<Vec<Subscription>> {
+        let filter = format!("client={}", client);
+        self.run("subscriptions", &filter, |db| db.subscriptions(client.clone()))
+    }
+
+    fn leave_message(&self, client: String, message: String) -> RedisResult<usize> {
+        let filter = format!("client={}", client);
+        self.run("leave_message", &filter,
+                 |db| db.leave_message(client.clone(), message.clone()))
+    }
+
+    fn take_messages(&self, client: String) -> RedisResult<Vec<String>> {
+        let filter = format!("client={}", client);
+        self.run("take_messages", &filter, |db| db.take_messages(client.clone()))
+    }
+
+    fn set_candidates(&self, client: String, candidates: &[Candidate]) -> RedisResult<()> {
+        let filter = format!("client={}", client);
+        self.run("set_candidates", &filter, |db| db.set_candidates(client.clone(), candidates))
+    }
+
+    fn candidates(&self, client: String) -> RedisResult<Option<Vec<Candidate>>> {
+        let filter = format!("client={}", client);
+        self.run("candidates", &filter, |db| db.candidates(client.clone()))
+    }
+
+    fn add_peer(&self, client: String, candidates: &[Candidate]) -> RedisResult<()> {
+        let filter = format!("client={}", client);
+        self.run("add_peer", &filter, |db| db.add_peer(client.clone(), candidates))
+    }
+
+    fn take_peers(&self, client: String) -> RedisResult<Vec<Vec<Candidate>>> {
+        let filter = format!("client={}", client);
+        self.run("take_peers", &filter, |db| db.take_peers(client.clone()))
+    }
+
+    fn set_verified(&self, public_ip: String, client: String, verified: bool)
+        -> RedisResult<bool> {
+        let filter = format!("client={}", client);
+        self.run("set_verified", &filter,
+                 |db| db.set_verified(public_ip.clone(), client.clone(), verified))
+    }
+
+    fn check_flapping(&self, client: String, token: Option<String>) -> RedisResult<bool> {
+        let filter = format!("client={}", client);
+        self.run("check_flapping", &filter,
+                 |db| db.check_flapping(client.clone(), token.clone()))
+    }
+
+    fn find_response(&self, key: String) -> RedisResult<Option<String>> {
+        let filter = format!("key={}", key);
+        self.run("find_response", &filter, |db| db.find_response(key.clone()))
+    }
+
+    fn keep_response(&self, key: String, response: String) -> RedisResult<()> {
+        let filter = format!("key={}", key);
+        self.run("keep_response", &filter,
+                 |db| db.keep_response(key.clone(), response.clone()))
+    }
+
+    fn find_credential(&self, hash: String) -> RedisResult<Option<Credential>> {
+        let filter = format!("hash={}", hash);
+        self.run("find_credential", &filter, |db| db.find_credential(&hash))
+    }
+
+    fn find(&self, filter: &Filter) -> RedisResult<Vec<Record>> {
+        let description = format!("{:?}", filter);
+        self.run("find", &description, |db| db.find(filter))
+    }
+
+    fn delete(&self, client: String) -> RedisResult<bool> {
+        let filter = format!("client={}", client);
+        self.run("delete", &filter, |db| db.delete(client.clone()))
+    }
+
+    fn archived(&self, client: String) -> RedisResult<Option<Archived>> {
+        let filter = format!("client={}", client);
+        self.run("archived", &filter, |db| db.archived(client.clone()))
+    }
+
+    fn evict_clients(&self) -> RedisResult<Vec<(String, String)>> {
+        self.run("evict_clients", "", |db| db.evict_clients())
+    }
+
+    fn check_integrity(&self, max_problems: usize) -> RedisResult<Vec<String>> {
+        self.run("check_integrity", "", |db| db.check_integrity(max_problems))
+    }
+
+    fn flapping(&self) -> RedisResult<Vec<Flapping>> {
+        self.run("flapping", "", |db| db.flapping())
+    }
+
+    fn public_ips(&self, min_clients: usize) -> RedisResult<Vec<PublicIp>> {
+        let filter = format!("min_clients={}", min_clients);
+        self.run("public_ips", &filter, |db| db.public_ips(min_clients))
+    }
+
+    fn pin(&self, client: String) -> RedisResult<bool> {
+        let filter = format!("client={}", client);
+        self.run("pin", &filter, |db| db.pin(client.clone()))
+    }
+
+    fn unpin(&self, client: String) -> RedisResult<bool> {
+        let filter = format!("client={}", client);
+        self.run("unpin", &filter, |db| db.unpin(client.clone()))
+    }
+
+    fn pinned(&self) -> RedisResult<Vec<String>> {
+        self.run("pinned", "", |db| db.pinned())
+    }
+
+    fn stats(&self) -> RedisResult<Stats> {
+        self.run("stats", "", |db| db.stats())
+    }
+
+    fn usage(&self) -> RedisResult<Vec<Usage>> {
+        self.run("usage", "", |db| db.usage())
+    }
+
+    fn tokens(&self, client: &str) -> RedisResult<Vec<String>> {
+        let filter = format!("client={}", client);
+        self.run("tokens", &filter, |db| db.tokens(client))
+    }
+
+    fn revoked(&self) -> RedisResult<Vec<Revocation>> {
+        self.run("revoked", "", |db| db.revoked())
+    }
+
+    fn revoke(&self, hash: String) -> RedisResult<()> {
+        let filter = format!("hash={}", hash);
+        self.run("revoke", &filter, |db| db.revoke(hash.clone()))
+    }
+
+    fn unrevoke(&self, hash: String) -> RedisResult<bool> {
+        let filter = format!("hash={}", hash);
+        self.run("unrevoke", &filter, |db| db.unrevoke(hash.clone()))
+    }
+}
+
+/// Connects to the Redis database of the configuration.
+#[derive(Debug)]
+pub struct RedisConnector;
+
+impl Connector for RedisConnector {
+    fn connect(&self, config: &Config) -> RedisResult<Box<Storage>> {
+        Db::from_config(config).map(|db| {
+            Box::new(Retrying {
+                db: RefCell::new(db),
+                metrics: config.metrics.clone(),
+                slow_query: if config.slow_query_threshold > 0 {
+                    Some(Duration::from_millis(config.slow_query_threshold))
+                } else {
+                    None
+                },
+            }) as Box<Storage>
+        })
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct MockState {
+    calls: Vec<String>,
+    failures: HashMap<&'static str, (ErrorKind, &'static str)>,
+    records: Vec<Record>,
+    tokens: HashMap<String, String>,
+    subscriptions: HashMap<String, Vec<Subscription>>,
+    mailboxes: HashMap<String, Vec<String>>,
+    candidates: HashMap<String, Vec<Candidate>>,
+    peers: HashMap<String, Vec<Vec<Candidate>>>,
+    pairing_codes: HashMap<String, String>,
+    flapping: Vec<String>,
+    sessions: HashMap<String, String>,
+    owners: HashMap<String, String>,
+    users: HashMap<String, Option<String>>,
+    oidc_states: Vec<String>,
+    guests: HashMap<String, String>,
+    subdomains: HashMap<String, String>,
+    pinned: Vec<String>,
+    revoked: Vec<String>,
+    responses: HashMap<String, String>,
+    credentials: HashMap<String, Credential>,
+}
+
+/// In-memory storage recording the operations called, which can be told
+/// to fail some of them.
+#[cfg(test)]
+#[derive(Clone, Debug, Default)]
+pub struct MockStorage {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[cfg(test)]
+impl MockStorage {
+    pub fn new() -> MockStorage {
+        MockStorage::default()
+    }
+
+    /// Make every call to `operation` fail with this error.
+    pub fn fail(&self, operation: &'static str, kind: ErrorKind, description: &'static str) {
+        self.state.lock().unwrap().failures.insert(operation, (kind, description));
+    }
+
+    /// Flag a client as flapping.
+    pub fn flag_flapping(&self, client: &str) {
+        self.state.lock().unwrap().flapping.push(client.to_owned());
+    }
+
+    /// Open a session of `email`.
+    pub fn add_session(&self, token: &str, email: &str) {
+        self.state.lock().unwrap().sessions.insert(token.to_owned(), email.to_owned());
+    }
+
+    /// Link a box to the account of `email`.
+    pub fn add_owner(&self, email: &str, client: &str) {
+        self.state.lock().unwrap().owners.insert(client.to_owned(), email.to_owned());
+    }
+
+    /// The operations called so far, e.g. "set <client>".
+    pub fn calls(&self) -> Vec<String> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    fn call(&self, operation: &'static str, argument: &str) -> RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(format!("{} {}", operation, argument));
+        match state.failures.get(operation) {
+            Some(&failure) => Err(failure.into()),
+            None => Ok(())
+        }
+    }
+
+    fn store(&self, record: &Record) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let revision = state.records.iter()
+                            .find(|r| r.client == record.client)
+                            .map_or(1, |r| r.revision + 1);
+        // Like the verification of the box, as long as it doesn't move.
+        let verified = state.records.iter()
+                            .find(|r| r.client == record.client && r.public_ip == record.public_ip)
+                            .and_then(|r| r.verified);
+        state.records.retain(|r| r.client != record.client);
+        let mut record = record.clone();
+        record.revision = revision;
+        record.verified = verified;
+        state.records.push(record);
+        revision
+    }
+}
+
+#[cfg(test)]
+impl Storage for MockStorage {
+    fn set(&self, record: Record) -> RedisResult<u64> {
+        try!(self.call("set", &record.client));
+        Ok(self.store(&record))
+    }
+
+    fn set_if(&self, record: Record, precondition: Precondition) -> RedisResult<Option<u64>> {
+        try!(self.call("set_if", &record.client));
+        let holds = {
+            let state = self.state.lock().unwrap();
+            precondition.holds(state.records.iter().find(|r| r.client == record.client))
+        };
+        Ok(if holds { Some(self.store(&record)) } else { None })
+    }
+
+    fn add_many(&self, records: &[Record]) -> RedisResult<Vec<u64>> {
+        try!(self.call("add_many", &records.len().to_string()));
+        Ok(records.iter().map(|record| self.store(record)).collect())
+    }
+
+    fn get(&self, public_ip: String) -> RedisResult<Vec<Record>> {
+        try!(self.call("get", &public_ip));
+        let state = self.state.lock().unwrap();
+        Ok(state.records.iter().filter(|r| r.public_ip == public_ip).cloned().collect())
+    }
+
+    fn discover(&self, public_ip: String) -> RedisResult<Vec<Record>> {
+        try!(self.call("discover", &public_ip));
+        let state = self.state.lock().unwrap();
+        Ok(state.records.iter().filter(|r| r.public_ip == public_ip).cloned().collect())
+    }
+
+    fn find_by_client(&self, client: String) -> RedisResult<Option<Record>> {
+        try!(self.call("find_by_client", &client));
+        let state = self.state.lock().unwrap();
+        Ok(state.records.iter().find(|r| r.client == client).cloned())
+    }
+
+    fn set_token(&self, client: String, token: String) -> RedisResult<()> {
+        try!(self.call("set_token", &client));
+        self.state.lock().unwrap().tokens.insert(client, token);
+        Ok(())
+    }
+
+    fn heartbeat(&self, client: String, token: &str) -> RedisResult<Heartbeat> {
+        try!(self.call("heartbeat", &client));
+        let state = self.state.lock().unwrap();
+        let record = match state.records.iter().find(|r| r.client == client) {
+            Some(record) => record,
+            None => return Ok(Heartbeat::Unknown)
+        };
+        if state.tokens.get(&client).map(|expected| &expected[..]) != Some(token) {
+            return Ok(Heartbeat::InvalidToken);
+        }
+        Ok(Heartbeat::Alive(record.clone()))
+    }
+
+    fn rotate_token(&self, client: String, token: &str) -> RedisResult<Option<String>> {
+        try!(self.call("rotate_token", &client));
+        let mut state = self.state.lock().unwrap();
+        if state.tokens.get(&client).map(|expected| &expected[..]) != Some(token) {
+            return Ok(None);
+        }
+        let new_token = format!("{}-rotated", token);
+        state.tokens.insert(client, new_token.clone());
+        Ok(Some(new_token))
+    }
+
+    fn token_matches(&self, client: String, token: &str) -> RedisResult<bool> {
+        try!(self.call("token_matches", &client));
+        let state = self.state.lock().unwrap();
+        Ok(state.tokens.get(&client).map(|expected| &expected[..]) == Some(token))
+    }
+
+    fn session_user(&self, token: &str) -> RedisResult<Option<String>> {
+        try!(self.call("session_user", ""));
+        Ok(self.state.lock().unwrap().sessions.get(token).cloned())
+    }
+
+    fn is_owner(&self, email: &str, client: String) -> RedisResult<bool> {
+        try!(self.call("is_owner", &client));
+        let state = self.state.lock().unwrap();
+        Ok(state.owners.get(&client).map(|owner| &owner[..]) == Some(email))
+    }
+
+    fn create_user(&self, email: &str, password_hash: &str) -> RedisResult<bool> {
+        try!(self.call("create_user", email));
+        let mut state = self.state.lock().unwrap();
+        if state.users.contains_key(email) {
+            return Ok(false);
+        }
+        state.users.insert(email.to_owned(), Some(password_hash.to_owned()));
+        Ok(true)
+    }
+
+    fn ensure_user(&self, email: &str) -> RedisResult<()> {
+        try!(self.call("ensure_user", email));
+        self.state.lock().unwrap().users.entry(email.to_owned()).or_insert(None);
+        Ok(())
+    }
+
+    fn password_hash(&self, email: &str) -> RedisResult<Option<String>> {
+        try!(self.call("password_hash", email));
+        Ok(self.state.lock().unwrap().users.get(email).and_then(|hash| hash.clone()))
+    }
+
+    fn create_session(&self, email: &str) -> RedisResult<String> {
+        try!(self.call("create_session", email));
+        let mut state = self.state.lock().unwrap();
+        let token = format!("session-{}", state.sessions.len());
+        state.sessions.insert(token.clone(), email.to_owned());
+        Ok(token)
+    }
+
+    fn delete_session(&self, token: &str) -> RedisResult<()> {
+        try!(self.call("delete_session", ""));
+        self.state.lock().unwrap().sessions.remove(token);
+        Ok(())
+    }
+
+    fn create_oidc_state(&self) -> RedisResult<String> {
+        try!(self.call("create_oidc_state", ""));
+        let mut state = self.state.lock().unwrap();
+        let oidc_state = format!("state-{}", state.oidc_states.len());
+        state.oidc_states.push(oidc_state.clone());
+        Ok(oidc_state)
+    }
+
+    fn take_oidc_state(&self, oidc_state: &str) -> RedisResult<bool> {
+        try!(self.call("take_oidc_state", ""));
+        let mut state = self.state.lock().unwrap();
+        let known = state.oidc_states.iter().any(|s| s == oidc_state);
+        state.oidc_states.retain(|s| s != oidc_state);
+        Ok(known)
+    }
+
+    fn link_box(&self, email: &str, client: String) -> RedisResult<()> {
+        try!(self.call("link_box", &client));
+        self.state.lock().unwrap().owners.insert(client, email.to_owned());
+        Ok(())
+    }
+
+    fn unlink_box(&self, email: &str, client: String) -> RedisResult<bool> {
+        try!(self.call("unlink_box", &client));
+        let mut state = self.state.lock().unwrap();
+        if state.owners.get(&client).map(|owner| &owner[..]) != Some(email) {
+            return Ok(false);
+        }
+        state.owners.remove(&client);
+        Ok(true)
+    }
+
+    fn box_count(&self, email: &str) -> RedisResult<u64> {
+        try!(self.call("box_count", email));
+        let state = self.state.lock().unwrap();
+        Ok(state.owners.values().filter(|owner| *owner == email).count() as u64)
+    }
+
+    fn user_boxes(&self, email: &str) -> RedisResult<Vec<Record>> {
+        try!(self.call("user_boxes", email));
+        let state = self.state.lock().unwrap();
+        Ok(state.records.iter()
+                .filter(|r| state.owners.get(&r.client).map(|o| &o[..]) == Some(email))
+                .cloned()
+                .collect())
+    }
+
+    fn create_guest_token(&self, _: &str, client: String, _: u64) -> RedisResult<String> {
+        try!(self.call("create_guest_token", &client));
+        let mut state = self.state.lock().unwrap();
+        let token = format!("guest-{}", state.guests.len());
+        state.guests.insert(token.clone(), client);
+        Ok(token)
+    }
+
+    fn guest_client(&self, token: &str) -> RedisResult<Option<String>> {
+        try!(self.call("guest_client", ""));
+        Ok(self.state.lock().unwrap().guests.get(token).cloned())
+    }
+
+    fn reserve_subdomain(&self, email: &str, name: &str) -> RedisResult<bool> {
+        try!(self.call("reserve_subdomain", name));
+        let mut state = self.state.lock().unwrap();
+        let owner = state.subdomains.entry(name.to_owned()).or_insert_with(|| email.to_owned());
+        Ok(*owner == email)
+    }
+
+    fn release_subdomain(&self, email: &str, name: &str) -> RedisResult<bool> {
+        try!(self.call("release_subdomain", name));
+        let mut state = self.state.lock().unwrap();
+        if state.subdomains.get(name).map(|owner| &owner[..]) != Some(email) {
+            return Ok(false);
+        }
+        state.subdomains.remove(name);
+        Ok(true)
+    }
+
+    fn user_subdomains(&self, email: &str) -> RedisResult<Vec<String>> {
+        try!(self.call("user_subdomains", email));
+        let state = self.state.lock().unwrap();
+        let mut names: Vec<String> = state.subdomains.iter()
+                                          .filter(|&(_, owner)| owner == email)
+                                          .map(|(name, _)| name.clone())
+                                          .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn count_registration(&self, client: String) -> RedisResult<Option<(String, u64)>> {
+        try!(self.call("count_registration", &client));
+        Ok(None)
+    }
+
+    fn create_pairing_code(&self, client: String) -> RedisResult<String> {
+        try!(self.call("create_pairing_code", &client));
+        let mut state = self.state.lock().unwrap();
+        let code = format!("{:06}", state.pairing_codes.len());
+        state.pairing_codes.insert(code.clone(), client);
+        Ok(code)
+    }
+
+    fn redeem_pairing_code(&self, code: &str, public_ip: String) -> RedisResult<Pairing> {
+        try!(self.call("redeem_pairing_code", &public_ip));
+        let mut state = self.state.lock().unwrap();
+        let client = match state.pairing_codes.remove(code) {
+            Some(client) => client,
+            None => return Ok(Pairing::Unknown)
+        };
+        Ok(match state.records.iter().find(|r| r.client == client) {
+            Some(record) => Pairing::Paired(record.clone()),
+            None => Pairing::Unknown
+        })
+    }
+
+    fn subscribe(&self, client: String, subscription: &Subscription) -> RedisResult<()> {
+        try!(self.call("subscribe", &client));
+        let mut state = self.state.lock().unwrap();
+        let subscriptions = state.subscriptions.entry(client).or_insert(vec![]);
+        if !subscriptions.contains(subscription) {
+            subscriptions.push(subscription.clone());
+        }
+        Ok(())
+    }
+
+    fn unsubscribe(&self, client: String, subscription: &Subscription) -> RedisResult<bool> {
+        try!(self.call("unsubscribe", &client));
+        let mut state = self.state.lock().unwrap();
+        let subscriptions = state.subscriptions.entry(client).or_insert(vec![]);
+        let count = subscriptions.len();
+        subscriptions.retain(|s| s != subscription);
+        Ok(subscriptions.len() != count)
+    }
+
+    fn subscriptions(&self, client: String) -> RedisResult<Vec<Subscription>> {
+        try!(self.call("subscriptions", &client));
+        let state = self.state.lock().unwrap();
+        Ok(state.subscriptions.get(&client).cloned().unwrap_or(vec![]))
+    }
+
+    fn leave_message(&self, client: String, message: String) -> RedisResult<usize> {
+        try!(self.call("leave_message", &client));
+        let mut state = self.state.lock().unwrap();
+        let messages = state.mailboxes.entry(client).or_insert(vec![]);
+        messages.push(message);
+        Ok(messages.len())
+    }
+
+    fn take_messages(&self, client: String) -> RedisResult<Vec<String>> {
+        try!(self.call("take_messages", &client));
+        Ok(self.state.lock().unwrap().mailboxes.remove(&client).unwrap_or(vec![]))
+    }
+
+    fn set_candidates(&self, client: String, candidates: &[Candidate]) -> RedisResult<()> {
+        try!(self.call("set_candidates", &client));
+        self.state.lock().unwrap().candidates.insert(client, candidates.to_vec());
+        Ok(())
+    }
+
+    fn candidates(&self, client: String) -> RedisResult<Option<Vec<Candidate>>> {
+        try!(self.call("candidates", &client));
+        Ok(self.state.lock().unwrap().candidates.get(&client).cloned())
+    }
+
+    fn add_peer(&self, client: String, candidates: &[Candidate]) -> RedisResult<()> {
+        try!(self.call("add_peer", &client));
+        let mut state = self.state.lock().unwrap();
+        state.peers.entry(client).or_insert(vec![]).push(candidates.to_vec());
+        Ok(())
+    }
+
+    fn take_peers(&self, client: String) -> RedisResult<Vec<Vec<Candidate>>> {
+        try!(self.call("take_peers", &client));
+        Ok(self.state.lock().unwrap().peers.remove(&client).unwrap_or(vec![]))
+    }
+
+    fn set_verified(&self, public_ip: String, client: String, verified: bool)
+        -> RedisResult<bool> {
+        try!(self.call("set_verified", &client));
+        let mut state = self.state.lock().unwrap();
+        match state.records.iter_mut().find(|r| r.client == client && r.public_ip == public_ip) {
+            Some(record) => {
+                record.verified = Some(verified);
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    fn check_flapping(&self, client: String, token: Option<String>) -> RedisResult<bool> {
+        try!(self.call("check_flapping", &client));
+        let state = self.state.lock().unwrap();
+        if !state.flapping.contains(&client) {
+            return Ok(true);
+        }
+        Ok(token.is_some() && state.tokens.get(&client) == token.as_ref())
+    }
+
+    fn find_response(&self, key: String) -> RedisResult<Option<String>> {
+        try!(self.call("find_response", &key));
+        Ok(self.state.lock().unwrap().responses.get(&key).cloned())
+    }
+
+    fn keep_response(&self, key: String, response: String) -> RedisResult<()> {
+        try!(self.call("keep_response", &key));
+        self.state.lock().unwrap().responses.entry(key).or_insert(response);
+        Ok(())
+    }
+
+    fn find_credential(&self, hash: String) -> RedisResult<Option<Credential>> {
+        try!(self.call("find_credential", &hash));
+        Ok(self.state.lock().unwrap().credentials.get(&hash).cloned())
+    }
+
+    fn find(&self, filter: &Filter) -> RedisResult<Vec<Record>> {
+        try!(self.call("find", ""));
+        let state = self.state.lock().unwrap();
+        Ok(state.records.iter().filter(|r| filter.matches(r)).cloned().collect())
+    }
+
+    fn delete(&self, client: String) -> RedisResult<bool> {
+        try!(self.call("delete", &client));
+        let mut state = self.state.lock().unwrap();
+        let count = state.records.len();
+        state.records.retain(|r| r.client != client);
+        Ok(state.records.len() < count)
+    }
+
+    fn archived(&self, client: String) -> RedisResult<Option<Archived>> {
+        try!(self.call("archived", &client));
+        Ok(None)
+    }
+
+    fn evict_clients(&self) -> RedisResult<Vec<(String, String)>> {
+        try!(self.call("evict_clients", ""));
+        Ok(vec![])
+    }
+
+    fn check_integrity(&self, _: usize) -> RedisResult<Vec<String>> {
+        try!(self.call("check_integrity", ""));
+        Ok(vec![])
+    }
+
+    fn flapping(&self) -> RedisResult<Vec<Flapping>> {
+        try!(self.call("flapping", ""));
+        let state = self.state.lock().unwrap();
+        Ok(state.flapping.iter().map(|client| Flapping {
+            client: client.clone(),
+            flagged: 0,
+        }).collect())
+    }
+
+    fn public_ips(&self, _: usize) -> RedisResult<Vec<PublicIp>> {
+        try!(self.call("public_ips", ""));
+        Ok(vec![])
+    }
+
+    fn pin(&self, client: String) -> RedisResult<bool> {
+        try!(self.call("pin", &client));
+        let mut state = self.state.lock().unwrap();
+        if !state.records.iter().any(|r| r.client == client) {
+            return Ok(false);
+        }
+        if !state.pinned.contains(&client) {
+            state.pinned.push(client);
+        }
+        Ok(true)
+    }
+
+    fn unpin(&self, client: String) -> RedisResult<bool> {
+        try!(self.call("unpin", &client));
+        let mut state = self.state.lock().unwrap();
+        let count = state.pinned.len();
+        state.pinned.retain(|pinned| *pinned != client);
+        Ok(state.pinned.len() < count)
+    }
+
+    fn pinned(&self) -> RedisResult<Vec<String>> {
+        try!(self.call("pinned", ""));
+        let mut pinned = self.state.lock().unwrap().pinned.clone();
+        pinned.sort();
+        Ok(pinned)
+    }
+
+    fn stats(&self) -> RedisResult<Stats> {
+        try!(self.call("stats", ""));
+        Ok(Stats::default())
+    }
+
+    fn usage(&self) -> RedisResult<Vec<Usage>> {
+        try!(self.call("usage", ""));
+        Ok(vec![])
+    }
+
+    fn tokens(&self, client: &str) -> RedisResult<Vec<String>> {
+        try!(self.call("tokens", client));
+        Ok(self.state.lock().unwrap().tokens.get(client).cloned().into_iter().collect())
+    }
+
+    fn revoked(&self) -> RedisResult<Vec<Revocation>> {
+        try!(self.call("revoked", ""));
+        let state = self.state.lock().unwrap();
+        Ok(state.revoked.iter().rev().map(|hash| Revocation {
+            hash: hash.clone(),
+            revoked_at: 0,
+        }).collect())
+    }
+
+    fn revoke(&self, hash: String) -> RedisResult<()> {
+        try!(self.call("revoke", &hash));
+        let mut state = self.state.lock().unwrap();
+        if !state.revoked.contains(&hash) {
+            state.revoked.push(hash);
+        }
+        Ok(())
+    }
+
+    fn unrevoke(&self, hash: String) -> RedisResult<bool> {
+        try!(self.call("unrevoke", &hash));
+        let mut state = self.state.lock().unwrap();
+        let count = state.revoked.len();
+        state.revoked.retain(|revoked| *revoked != hash);
+        Ok(state.revoked.len() < count)
+    }
+}
+
+#[cfg(test)]
+impl Connector for MockStorage {
+    fn connect(&self, _: &Config) -> RedisResult<Box<Storage>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+#[test]
+fn test_retry() {
+    let busy = || -> RedisError {
+        (ErrorKind::BusyLoadingError, "Redis is loading the dataset in memory").into()
+    };
+
+    let mut calls = 0;
+    let mut retries = 0;
+    let result = retry(|| {
+        calls += 1;
+        if calls < 3 { Err(busy()) } else { Ok(calls) }
+    }, || retries += 1);
+    assert_eq!(result.unwrap(), 3);
+    assert_eq!(retries, 2);
+
+    // Gives up after MAX_RETRIES.
+    let mut calls = 0;
+    let result: RedisResult<()> = retry(|| {
+        calls += 1;
+        Err(busy())
+    }, || {});
+    assert!(result.is_err());
+    assert_eq!(calls, MAX_RETRIES + 1);
+
+    // Other errors aren't retried.
+    let mut calls = 0;
+    let result: RedisResult<()> = retry(|| {
+        calls += 1;
+        Err((ErrorKind::TypeError, "corrupt record").into())
+    }, || {});
+    assert!(result.is_err());
+    assert_eq!(calls, 1);
+}
+
+#[test]
+fn test_read_only() {
+    assert!(is_read_only("discover"));
+    assert!(is_read_only("find_by_client"));
+    assert!(!is_read_only("set"));
+    assert!(!is_read_only("leave_message"));
+}
@@ -0,0 +1,179 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// STUN-lite: with `--stun-port <port>`, the server answers the binding
+/// requests of RFC 5389 on this UDP port with the public address and port
+/// they came from, so that boxes can use off-the-shelf STUN clients and ICE
+/// stacks, rather than the UDP echo service, to learn their server
+/// reflexive candidate.
+///
+/// Only the binding method is supported, without authentication, and the
+/// attributes of the requests are ignored: the response holds a single
+/// XOR-MAPPED-ADDRESS. Anything else, including the classic STUN requests
+/// of RFC 3489 which lack the magic cookie, is dropped. The responses are
+/// at most 24 bytes longer than the 20 bytes requests, which keeps the
+/// service from being of much use to amplify spoofed traffic.
+
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
+
+static HEADER_LENGTH: usize = 20;
+static MAGIC_COOKIE: u32 = 0x2112A442;
+static BINDING_REQUEST: u16 = 0x0001;
+static BINDING_RESPONSE: u16 = 0x0101;
+static XOR_MAPPED_ADDRESS: u16 = 0x0020;
+static FAMILY_IPV4: u8 = 0x01;
+static FAMILY_IPV6: u8 = 0x02;
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (read_u16(bytes) as u32) << 16 | read_u16(&bytes[2..]) as u32
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.push((value >> 8) as u8);
+    bytes.push(value as u8);
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    push_u16(bytes, (value >> 16) as u16);
+    push_u16(bytes, value as u16);
+}
+
+/// The transaction id of a binding request, if `request` is one.
+pub fn binding_request(request: &[u8]) -> Option<&[u8]> {
+    if request.len() < HEADER_LENGTH {
+        return None;
+    }
+    // The two most significant bits of STUN messages are zeroes, and their
+    // attributes are padded to 4 bytes.
+    let length = read_u16(&request[2..]) as usize;
+    if read_u16(request) != BINDING_REQUEST || read_u32(&request[4..]) != MAGIC_COOKIE ||
+       length % 4 != 0 || HEADER_LENGTH + length != request.len() {
+        return None;
+    }
+    Some(&request[8..HEADER_LENGTH])
+}
+
+/// The binding response to the request of `transaction`, received from
+/// `addr`. IPv4 addresses mapped to IPv6, as seen by a socket bound to
+/// `::`, are given as IPv4.
+pub fn binding_response(transaction: &[u8], addr: &SocketAddr) -> Vec<u8> {
+    let ip = match addr.ip() {
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if segments[..5].iter().all(|&segment| segment == 0) && segments[5] == 0xffff {
+                IpAddr::V4(Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8,
+                                         (segments[7] >> 8) as u8, segments[7] as u8))
+            } else {
+                IpAddr::V6(ip)
+            }
+        },
+        ip => ip
+    };
+
+    let mut attribute = vec![0];
+    match ip {
+        IpAddr::V4(ip) => {
+            attribute.push(FAMILY_IPV4);
+            push_u16(&mut attribute, addr.port() ^ (MAGIC_COOKIE >> 16) as u16);
+            push_u32(&mut attribute, read_u32(&ip.octets()) ^ MAGIC_COOKIE);
+        },
+        IpAddr::V6(ip) => {
+            attribute.push(FAMILY_IPV6);
+            push_u16(&mut attribute, addr.port() ^ (MAGIC_COOKIE >> 16) as u16);
+            let mut key = vec![];
+            push_u32(&mut key, MAGIC_COOKIE);
+            key.extend_from_slice(transaction);
+            let octets = ip.octets();
+            attribute.extend(octets.iter().zip(key.iter()).map(|(octet, key)| octet ^ key));
+        }
+    }
+
+    let mut response = Vec::with_capacity(HEADER_LENGTH + 4 + attribute.len());
+    push_u16(&mut response, BINDING_RESPONSE);
+    push_u16(&mut response, 4 + attribute.len() as u16);
+    push_u32(&mut response, MAGIC_COOKIE);
+    response.extend_from_slice(transaction);
+    push_u16(&mut response, XOR_MAPPED_ADDRESS);
+    push_u16(&mut response, attribute.len() as u16);
+    response.extend(attribute);
+    response
+}
+
+/// The STUN service, for `nat::serve`.
+pub fn answer(request: &[u8], from: &SocketAddr) -> Option<Vec<u8>> {
+    binding_request(request).map(|transaction| binding_response(transaction, from))
+}
+
+/// The address of a binding response, as a client would decode it.
+#[cfg(test)]
+fn mapped_address(response: &[u8]) -> Option<SocketAddr> {
+    use std::net::Ipv6Addr;
+
+    if response.len() < HEADER_LENGTH + 8 || read_u16(response) != BINDING_RESPONSE ||
+       read_u16(&response[HEADER_LENGTH..]) != XOR_MAPPED_ADDRESS {
+        return None;
+    }
+    let value = &response[HEADER_LENGTH + 4..];
+    let port = read_u16(&value[2..]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match value[1] {
+        1 => {
+            let ip = read_u32(&value[4..]) ^ MAGIC_COOKIE;
+            IpAddr::V4(Ipv4Addr::new((ip >> 24) as u8, (ip >> 16) as u8, (ip >> 8) as u8,
+                                     ip as u8))
+        },
+        2 => {
+            let key = &response[4..HEADER_LENGTH];
+            let bytes: Vec<u8> = value[4..20].iter().zip(key.iter()).map(|(b, k)| b ^ k)
+                                             .collect();
+            let segments: Vec<u16> = bytes.chunks(2).map(read_u16).collect();
+            IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                     segments[4], segments[5], segments[6], segments[7]))
+        },
+        _ => return None
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[test]
+fn test_binding() {
+    let mut request = vec![0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42];
+    request.extend_from_slice(b"transaction!");
+
+    // The IPv4 example of RFC 5769, 192.0.2.1:32853.
+    let addr: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+    let response = answer(&request, &addr).unwrap();
+    assert_eq!(response.len(), 32);
+    assert_eq!(&response[..4], &[0x01, 0x01, 0x00, 0x0c]);
+    assert_eq!(&response[8..20], b"transaction!");
+    assert_eq!(&response[20..], &[0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47,
+                                  0xe1, 0x12, 0xa6, 0x43]);
+    assert_eq!(mapped_address(&response), Some(addr));
+
+    let addr: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap();
+    let response = answer(&request, &addr).unwrap();
+    assert_eq!(response.len(), 44);
+    assert_eq!(mapped_address(&response), Some(addr));
+
+    let mapped: SocketAddr = "[::ffff:192.0.2.1]:32853".parse().unwrap();
+    let response = answer(&request, &mapped).unwrap();
+    assert_eq!(mapped_address(&response), Some("192.0.2.1:32853".parse().unwrap()));
+
+    // Other methods, classic STUN and truncated requests are dropped.
+    let mut allocate = request.clone();
+    allocate[1] = 0x03;
+    assert_eq!(answer(&allocate, &addr), None);
+    let mut classic = request.clone();
+    classic[4] = 0;
+    assert_eq!(answer(&classic, &addr), None);
+    assert_eq!(answer(&request[..19], &addr), None);
+    let mut attributes = request.clone();
+    attributes[3] = 8;
+    assert_eq!(answer(&attributes, &addr), None);
+    attributes.extend_from_slice(&[0x80, 0x22, 0x00, 0x04, b'f', b'o', b'o', 0]);
+    assert!(answer(&attributes, &addr).is_some());
+}
@@ -0,0 +1,91 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Subnet arithmetic for discovery on large networks, where the boxes and
+/// the clients of the same home can go out through different public IPs of
+/// a carrier-grade NAT.
+
+use std::cmp;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+
+/// Prefix lengths of the subnets whose public IPs are matched together
+/// during discovery, per address family. `None` only matches the exact
+/// public IP.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Prefixes {
+    pub v4: Option<u8>,
+    pub v6: Option<u8>,
+}
+
+impl Prefixes {
+    /// The subnet `ip` belongs to, e.g. "100.64.12.0/24", if subnets are
+    /// matched for its family.
+    pub fn network(&self, ip: &str) -> Option<String> {
+        match ip.parse() {
+            Ok(IpAddr::V4(ip)) => self.v4.map(|prefix| {
+                let mut octets = ip.octets();
+                mask(&mut octets, prefix);
+                format!("{}/{}", Ipv4Addr::from(octets), prefix)
+            }),
+            Ok(IpAddr::V6(ip)) => self.v6.map(|prefix| {
+                let mut octets = ip.octets();
+                mask(&mut octets, prefix);
+                format!("{}/{}", Ipv6Addr::from(octets), prefix)
+            }),
+            Err(_) => None
+        }
+    }
+}
+
+/// Clear the bits of `octets` after the first `prefix` ones.
+fn mask(octets: &mut [u8], prefix: u8) {
+    for (index, octet) in octets.iter_mut().enumerate() {
+        let kept = cmp::min(cmp::max(prefix as isize - index as isize * 8, 0), 8);
+        *octet &= !(0xffu16 >> kept) as u8;
+    }
+}
+
+fn octets(ip: &IpAddr) -> Vec<u8> {
+    match *ip {
+        IpAddr::V4(ref ip) => ip.octets().to_vec(),
+        IpAddr::V6(ref ip) => ip.octets().to_vec(),
+    }
+}
+
+/// Length of the prefix shared by two addresses, 0 if they aren't of the
+/// same family.
+pub fn common_prefix(a: &IpAddr, b: &IpAddr) -> u32 {
+    let (a, b) = (octets(a), octets(b));
+    if a.len() != b.len() {
+        return 0;
+    }
+    let mut bits = 0;
+    for (a, b) in a.iter().zip(b.iter()) {
+        let differing = a ^ b;
+        bits += differing.leading_zeros();
+        if differing != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[test]
+fn test_subnets() {
+    let prefixes = Prefixes { v4: Some(22), v6: Some(48) };
+    assert_eq!(prefixes.network("100.64.13.200"), Some("100.64.12.0/22".to_owned()));
+    assert_eq!(prefixes.network("2001:db8:1:2::1"), Some("2001:db8:1::/48".to_owned()));
+    assert_eq!(prefixes.network("not an ip"), None);
+    assert_eq!(Prefixes { v4: Some(0), v6: None }.network("1.2.3.4"),
+               Some("0.0.0.0/0".to_owned()));
+    assert_eq!(Prefixes { v4: Some(32), v6: None }.network("1.2.3.4"),
+               Some("1.2.3.4/32".to_owned()));
+    assert_eq!(Prefixes::default().network("1.2.3.4"), None);
+
+    let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+    assert_eq!(common_prefix(&ip("192.168.1.10"), &ip("192.168.1.20")), 27);
+    assert_eq!(common_prefix(&ip("192.168.1.10"), &ip("192.168.1.10")), 32);
+    assert_eq!(common_prefix(&ip("10.0.0.1"), &ip("192.168.1.20")), 0);
+    assert_eq!(common_prefix(&ip("10.0.0.1"), &ip("fe80::1")), 0);
+}
@@ -0,0 +1,307 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Several tenants served by the same server, each with the complete set of
+/// endpoints, its own Redis database, admin token and rate limit.
+///
+/// The tenant of a request is the one of its `X-Api-Key` header if any,
+/// else the one whose domain suffix matches its `Host` header. The other
+/// requests go to the default tenant, configured by the command line.
+/// Besides the keys of the file, the API keys created with `keys create api`
+/// lead to the tenant in whose database they are.
+///
+/// Tenants are read from a JSON file such as:
+/// [ { "name": "acme", "domain_suffix": "acme.example.com", "database": 1,
+///     "api_keys": ["..."], "admin_token": "...", "rate_limit": 600 } ]
+
+use cache::{ self, LruCache };
+use config::Config;
+use db::API_CREDENTIAL;
+use errors::*;
+use iron::{ Chain, Handler };
+use iron::headers::Host;
+use iron::prelude::*;
+use iron::status;
+use metrics::Metrics;
+use revocation::Revocations;
+use revocation;
+use rustc_serialize::json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{ Arc, Mutex };
+use tokens;
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable)]
+pub struct Tenant {
+    pub name: String,
+    /// The tenant serves this host and its subdomains, e.g.
+    /// "acme.example.com" and "boxes.acme.example.com".
+    pub domain_suffix: Option<String>,
+    /// Keys selecting the tenant in the `X-Api-Key` header, whatever the
+    /// host, for clients which can't pick the host name.
+    pub api_keys: Option<Vec<String>>,
+    /// Number of the Redis database holding the tenant's records, which
+    /// keeps the tenants apart in the storage.
+    pub database: i64,
+    /// Token of the admin API of the tenant, which is disabled when not set.
+    pub admin_token: Option<String>,
+    /// Number of requests accepted from a public IP per minute, unlimited
+    /// when not set.
+    pub rate_limit: Option<u64>,
+}
+
+impl Tenant {
+    fn serves_host(&self, host: &str) -> bool {
+        match self.domain_suffix {
+            Some(ref suffix) => {
+                let host = host.to_lowercase();
+                let suffix = suffix.to_lowercase();
+                host == suffix || host.ends_with(&format!(".{}", suffix))
+            },
+            None => false
+        }
+    }
+
+    fn has_api_key(&self, key: &str) -> bool {
+        self.api_keys.as_ref().map_or(false, |keys| {
+            keys.iter().any(|expected| tokens::matches(expected, key))
+        })
+    }
+
+    /// The configuration of the server for this tenant.
+    pub fn config(&self, config: &Config) -> Config {
+        Config {
+            db_index: self.database,
+            admin_token: self.admin_token.clone(),
+            instance_id: format!("{}/{}", config.instance_id, self.name),
+            metrics: Arc::new(Metrics::new()),
+            revocations: Arc::new(Revocations::new()),
+            discovery_cache: cache::shared(config.cluster, config.cache_size,
+                                           config.negative_cache_size),
+            api_keys: self.api_keys.clone().unwrap_or(vec![]),
+            .. config.clone()
+        }
+    }
+}
+
+/// Read the tenants from a JSON file, checking that they don't share a
+/// database, a domain suffix or an API key.
+pub fn load(path: &Path) -> Result<Vec<Tenant>, String> {
+    let mut content = String::new();
+    try!(File::open(path).and_then(|mut file| file.read_to_string(&mut content))
+                         .map_err(|e| format!("Can't read {}: {}", path.display(), e)));
+    let tenants: Vec<Tenant> = try!(json::decode(&content).map_err(|e| {
+        format!("Invalid tenants in {}: {}", path.display(), e)
+    }));
+
+    for (index, tenant) in tenants.iter().enumerate() {
+        for other in &tenants[index + 1..] {
+            if tenant.name == other.name || tenant.database == other.database {
+                return Err(format!("The tenants {} and {} share a name or a database",
+                                   tenant.name, other.name));
+            }
+            if tenant.domain_suffix.is_some() && tenant.domain_suffix == other.domain_suffix {
+                return Err(format!("The tenants {} and {} share a domain suffix",
+                                   tenant.name, other.name));
+            }
+            let keys = other.api_keys.clone().unwrap_or(vec![]);
+            if keys.iter().any(|key| tenant.has_api_key(key)) {
+                return Err(format!("The tenants {} and {} share an API key",
+                                   tenant.name, other.name));
+            }
+        }
+    }
+    Ok(tenants)
+}
+
+/// Counts the requests of each public IP over the current minute, for the
+/// tenants and the open discovery.
+pub struct RateLimiter {
+    limit: u64,
+    window: Mutex<(u64, HashMap<IpAddr, u64>)>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u64) -> RateLimiter {
+        RateLimiter {
+            limit: limit,
+            window: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Count a request at time `now`, returning the state of the limit of
+    /// `ip`, as an error if the request goes over it.
+    pub fn check(&self, ip: IpAddr, now: u64) -> Result<LimitState, LimitState> {
+        let mut window = self.window.lock().unwrap();
+        let minute = now / 60;
+        if window.0 != minute {
+            *window = (minute, HashMap::new());
+        }
+        let count = window.1.entry(ip).or_insert(0);
+        *count += 1;
+        let state = LimitState::new(self.limit, *count, 60 - now % 60);
+        if *count > self.limit {
+            Err(state)
+        } else {
+            Ok(state)
+        }
+    }
+}
+
+/// Number of API keys of the databases whose tenant is kept in memory.
+static API_KEY_CACHE_SIZE: usize = 1024;
+/// Number of seconds the tenant of an API key of a database is kept, which
+/// is how long a deleted key keeps working.
+static API_KEY_CACHE_TTL: u64 = 60;
+
+struct TenantHandler {
+    tenant: Tenant,
+    config: Config,
+    limiter: Option<RateLimiter>,
+    chain: Chain,
+}
+
+/// Dispatches the requests to the handler of their tenant.
+pub struct Tenants {
+    tenants: Vec<TenantHandler>,
+    default: Chain,
+    config: Config,
+    /// The tenant of the API keys found in the databases, by hash: the index
+    /// of the tenant, or `None` for the default one.
+    api_keys: Mutex<LruCache<String, Option<usize>>>,
+}
+
+impl Tenants {
+    /// Serve the tenants with their configuration, and the other requests
+    /// with the default one. `create` builds the complete handler of a
+    /// configuration.
+    pub fn new<F>(config: &Config, tenants: &[(Tenant, Config)], create: F) -> Tenants
+        where F: Fn(&Config) -> Chain {
+        Tenants {
+            tenants: tenants.iter().map(|&(ref tenant, ref tenant_config)| TenantHandler {
+                limiter: tenant.rate_limit.map(RateLimiter::new),
+                chain: create(tenant_config),
+                tenant: tenant.clone(),
+                config: tenant_config.clone(),
+            }).collect(),
+            default: create(config),
+            config: config.clone(),
+            api_keys: Mutex::new(LruCache::new(API_KEY_CACHE_SIZE, API_KEY_CACHE_TTL)),
+        }
+    }
+
+    /// The tenant of an API key created from the command line, looked up in
+    /// the database of each tenant: `Some(None)` for the default tenant.
+    fn find_api_key(&self, api_key: &str) -> Option<Option<usize>> {
+        let hash = revocation::hash(api_key);
+        let now = self.config.clock.now();
+        if let Some(index) = self.api_keys.lock().unwrap().get(&hash, now) {
+            return Some(index);
+        }
+        let configs = Some((None, &self.config)).into_iter().chain(
+            self.tenants.iter().enumerate().map(|(index, handler)| (Some(index), &handler.config))
+        );
+        for (index, config) in configs {
+            match config.storage.connect(config).and_then(|db| db.find_credential(hash.clone())) {
+                Ok(Some(ref credential)) if credential.kind == API_CREDENTIAL => {
+                    self.api_keys.lock().unwrap().insert(hash, index, now);
+                    return Some(index);
+                },
+                Ok(_) => {},
+                Err(e) => error!("Can't check the API keys of the database {}: {}",
+                                 config.db_index, e)
+            }
+        }
+        None
+    }
+
+    /// The tenant of a request, `None` for the default one, or an error for
+    /// an unknown API key, which doesn't fall back to the default tenant.
+    fn find(&self, req: &Request) -> IronResult<Option<&TenantHandler>> {
+        let api_key = req.headers.get_raw("X-Api-Key")
+                         .and_then(|values| values.get(0))
+                         .and_then(|value| String::from_utf8(value.clone()).ok());
+        if let Some(api_key) = api_key {
+            let found = self.tenants.iter().find(|handler| handler.tenant.has_api_key(&api_key));
+            if found.is_some() {
+                return Ok(found);
+            }
+            return match self.find_api_key(&api_key) {
+                Some(index) => Ok(index.map(|index| &self.tenants[index])),
+                None => Err(EndpointError::build(status::Unauthorized, ErrNo::Unauthorized,
+                                                 None, None))
+            };
+        }
+        if req.headers.get_raw("X-Api-Key").is_some() {
+            return Err(EndpointError::build(status::Unauthorized, ErrNo::Unauthorized,
+                                            None, None));
+        }
+        Ok(req.headers.get::<Host>().and_then(|host| {
+            self.tenants.iter().find(|handler| handler.tenant.serves_host(&host.hostname))
+        }))
+    }
+}
+
+impl Handler for Tenants {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let handler = match try!(self.find(req)) {
+            Some(handler) => handler,
+            None => return self.default.handle(req)
+        };
+
+        let state = match handler.limiter {
+            Some(ref limiter) => {
+                let now = self.config.clock.now();
+                match limiter.check(req.remote_addr.ip(), now) {
+                    Ok(state) => state,
+                    Err(state) => {
+                        info!("Rate limiting {} for tenant {}", req.remote_addr.ip(),
+                              handler.tenant.name);
+                        return Err(EndpointError::with_limit(ErrNo::TooManyRequests,
+                                                             state, None));
+                    }
+                }
+            },
+            None => return handler.chain.handle(req)
+        };
+        match handler.chain.handle(req) {
+            Ok(mut response) => {
+                state.set_headers(&mut response.headers);
+                Ok(response)
+            },
+            Err(mut error) => {
+                state.set_headers(&mut error.response.headers);
+                Err(error)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_tenants() {
+    let tenant = Tenant {
+        name: "acme".to_owned(),
+        domain_suffix: Some("acme.example.com".to_owned()),
+        api_keys: Some(vec!["key".to_owned()]),
+        database: 1,
+        admin_token: None,
+        rate_limit: Some(2),
+    };
+    assert!(tenant.serves_host("acme.example.com"));
+    assert!(tenant.serves_host("Boxes.Acme.Example.com"));
+    assert!(!tenant.serves_host("notacme.example.com"));
+    assert!(tenant.has_api_key("key"));
+    assert!(!tenant.has_api_key("other"));
+
+    let limiter = RateLimiter::new(2);
+    let ip = "1.2.3.4".parse().unwrap();
+    assert_eq!(limiter.check(ip, 120), Ok(LimitState { limit: 2, remaining: 1, reset: 60 }));
+    assert_eq!(limiter.check(ip, 130), Ok(LimitState { limit: 2, remaining: 0, reset: 50 }));
+    assert_eq!(limiter.check(ip, 135), Err(LimitState { limit: 2, remaining: 0, reset: 45 }));
+    assert!(limiter.check("5.6.7.8".parse().unwrap(), 135).is_ok());
+    assert!(limiter.check(ip, 180).is_ok());
+}
@@ -0,0 +1,207 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Runs the complete server on a random port, against its own Redis server,
+/// so that tests can exercise the real HTTP endpoints.
+
+use super::accounts::Quotas;
+use super::cache;
+use super::clock::SystemClock;
+use super::config::Config;
+use super::create_chain;
+use super::db::Db;
+use super::events::Bus;
+use super::features::{ Feature, Features };
+use super::db_test_context::{ free_port, RedisServer, SERVER_HOST };
+use super::metrics::Metrics;
+use super::probe;
+use super::push;
+use super::read_only::ReadOnly;
+use super::retention::Policy;
+use super::revocation::Revocations;
+use super::storage::{ MockStorage, RedisConnector };
+use super::subnet::Prefixes;
+use hyper::Client;
+use hyper::header::Headers;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use iron::{ Iron, Listening };
+use rustc_serialize::json::Json;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub static ADMIN_TOKEN: &'static str = "test-admin-token";
+
+/// The token of a registration or a login, from its response body.
+pub fn token_from(body: &str) -> String {
+    Json::from_str(body).unwrap().find("token").and_then(Json::as_string).unwrap().to_owned()
+}
+
+/// The headers authenticating a request with `token`.
+pub fn bearer(token: &str) -> Headers {
+    let mut headers = Headers::new();
+    headers.set_raw("Authorization", vec![format!("Bearer {}", token).into_bytes()]);
+    headers
+}
+
+pub fn test_config(db_port: u16) -> Config {
+    Config {
+        db_host: SERVER_HOST.to_owned(),
+        db_port: db_port,
+        db_password: None,
+        db_index: 0,
+        admin_token: Some(ADMIN_TOKEN.to_owned()),
+        cache_size: 16,
+        negative_cache_size: 16,
+        cluster: false,
+        instance_id: "test".to_owned(),
+        backup_dir: PathBuf::from("backups"),
+        retention: Policy::default(),
+        maintenance_interval: 0,
+        strict: false,
+        error_reporting: None,
+        zipkin_url: None,
+        threads: 4,
+        read_timeout: 5,
+        write_timeout: 5,
+        keep_alive: 0,
+        handler_timeout: 5,
+        slow_query_threshold: 0,
+        clock: Arc::new(SystemClock),
+        storage: Arc::new(RedisConnector),
+        metrics: Arc::new(Metrics::new()),
+        subnet: Prefixes::default(),
+        ping_interval: 30,
+        push: push::Settings::default(),
+        accounts: false,
+        quotas: Quotas::default(),
+        subdomain_blocklist: vec![],
+        oidc: None,
+        max_ip_changes: 0,
+        flapping_auth: false,
+        usage_aggregates: false,
+        ip_hash_key: None,
+        fingerprint_salt: None,
+        read_only: Arc::new(ReadOnly::new()),
+        features: Arc::new(Features::new(&Feature::all(), &[])),
+        jwt: None,
+        revocations: Arc::new(Revocations::new()),
+        events: Arc::new(Bus::new().with(Box::new(push::Notifier::new()))
+                                   .with(Box::new(probe::Prober::new()))),
+        discovery_cache: cache::shared(false, 16, 16),
+        probe_port: None,
+        open_discovery_limit: 10,
+        mailbox_limit: 10,
+        api_keys: vec![],
+    }
+}
+
+pub struct TestServer {
+    // Declared first so that the server stops before Redis.
+    listening: Listening,
+    pub url: String,
+    pub config: Config,
+    pub db: Db,
+    _redis: RedisServer,
+}
+
+impl TestServer {
+    pub fn new() -> TestServer {
+        TestServer::with_config(|_| {})
+    }
+
+    /// Start a server with the test configuration, as changed by `customize`.
+    pub fn with_config<F: FnOnce(&mut Config)>(customize: F) -> TestServer {
+        let redis = RedisServer::new();
+        let mut config = test_config(redis.port);
+        customize(&mut config);
+
+        // Waits for Redis to accept connections.
+        let db = Db::wait_for(|| Db::from_config(&config), Duration::from_secs(10)).unwrap();
+        db.flush().unwrap();
+
+        let port = free_port();
+        let listening = Iron::new(create_chain(&config))
+            .http((SERVER_HOST, port))
+            .unwrap();
+
+        TestServer {
+            listening: listening,
+            url: format!("http://{}:{}", SERVER_HOST, port),
+            config: config,
+            db: db,
+            _redis: redis,
+        }
+    }
+
+    /// Start a server whose endpoints use a `MockStorage`, returned
+    /// as well to fail its operations or check the calls.
+    pub fn with_mock_storage() -> (TestServer, MockStorage) {
+        TestServer::with_mock_storage_config(|_| {})
+    }
+
+    /// `with_mock_storage`, with the test configuration changed by
+    /// `customize`.
+    pub fn with_mock_storage_config<F: FnOnce(&mut Config)>(customize: F)
+        -> (TestServer, MockStorage) {
+        let storage = MockStorage::new();
+        let connector = storage.clone();
+        let server = TestServer::with_config(move |config| {
+            config.storage = Arc::new(connector);
+            customize(config);
+        });
+        (server, storage)
+    }
+
+    /// Send any request, and get the response headers as well.
+    pub fn request(&self, method: &str, path: &str, headers: Headers, body: Option<&str>)
+        -> (StatusCode, Headers, String) {
+        let client = Client::new();
+        let url = format!("{}{}", self.url, path);
+        let request = match method {
+            "GET" => client.get(&url),
+            "POST" => client.post(&url),
+            "PUT" => client.put(&url),
+            "DELETE" => client.delete(&url),
+            "PATCH" => client.request(Method::Patch, &url),
+            "OPTIONS" => client.request(Method::Options, &url),
+            _ => panic!("Unsupported method {}", method)
+        };
+        let request = request.headers(headers);
+        let request = match body {
+            Some(body) => request.body(body),
+            None => request
+        };
+
+        let mut response = request.send().unwrap();
+        let mut content = String::new();
+        response.read_to_string(&mut content).unwrap();
+        (response.status, response.headers.clone(), content)
+    }
+
+    pub fn get(&self, path: &str) -> (StatusCode, String) {
+        let (status, _, body) = self.request("GET", path, Headers::new(), None);
+        (status, body)
+    }
+
+    pub fn post(&self, path: &str, body: &str) -> (StatusCode, String) {
+        let (status, _, body) = self.request("POST", path, Headers::new(), Some(body));
+        (status, body)
+    }
+
+    /// GET an admin endpoint, with the admin token.
+    pub fn admin_get(&self, path: &str) -> (StatusCode, String) {
+        let (status, _, body) = self.request("GET", &format!("/admin{}", path),
+                                             bearer(ADMIN_TOKEN), None);
+        (status, body)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.listening.close();
+    }
+}
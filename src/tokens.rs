@@ -0,0 +1,64 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Box tokens: every registration returns a new random token, which the box
+/// presents in an `Authorization: Bearer` header to act on its record
+/// afterwards, since its fingerprint is visible to the other clients.
+
+use iron::prelude::*;
+use rand::{ self, Rng };
+
+/// A new random token, as 32 hex digits.
+pub fn generate() -> String {
+    (0..16).map(|_| format!("{:02x}", rand::random::<u8>())).collect()
+}
+
+/// A new random pairing code, as 6 decimal digits which are easy to type.
+pub fn pairing_code() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0, 1_000_000))
+}
+
+/// Compare tokens in constant time, to not leak how much of a guess was
+/// right.
+pub fn matches(expected: &str, given: &str) -> bool {
+    if expected.len() != given.len() {
+        return false;
+    }
+    expected.bytes().zip(given.bytes())
+            .fold(0, |differences, (a, b)| differences | (a ^ b)) == 0
+}
+
+/// The bearer token of a request.
+pub fn bearer(req: &Request) -> Option<String> {
+    let values = match req.headers.get_raw("Authorization") {
+        Some(values) if values.len() == 1 => values,
+        _ => return None
+    };
+    let value = match String::from_utf8(values[0].clone()) {
+        Ok(value) => value,
+        Err(_) => return None
+    };
+    if value.starts_with("Bearer ") {
+        Some(value[7..].trim().to_owned())
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_tokens() {
+    let token = generate();
+    assert_eq!(token.len(), 32);
+    assert!(token.chars().all(|c| c.is_digit(16)));
+    assert!(token != generate());
+
+    assert!(matches(&token, &token.clone()));
+    assert!(!matches(&token, &generate()));
+    assert!(!matches(&token, &token[1..]));
+    assert!(!matches(&token, ""));
+
+    let code = pairing_code();
+    assert_eq!(code.len(), 6);
+    assert!(code.chars().all(|c| c.is_digit(10)));
+}
@@ -0,0 +1,257 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Distributed tracing, using the Zipkin B3 headers.
+///
+/// Every request gets a server span, continuing the trace of the caller
+/// when the request has X-B3-TraceId and X-B3-SpanId headers, and handlers
+/// wrap their database calls in child spans with `span()`. The trace
+/// headers are echoed in the response, and the spans of sampled traces are
+/// sent to a Zipkin collector (v2 JSON API) when one is configured, or
+/// logged otherwise.
+
+use hyper::Client;
+use hyper::header::{ ContentType, Headers };
+use iron::prelude::*;
+use iron::typemap::Key;
+use iron::Handler;
+use rand;
+use rustc_serialize::json::{ Json, ToJson };
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+static TRACE_ID: &'static str = "X-B3-TraceId";
+static SPAN_ID: &'static str = "X-B3-SpanId";
+static PARENT_SPAN_ID: &'static str = "X-B3-ParentSpanId";
+static SAMPLED: &'static str = "X-B3-Sampled";
+
+static SERVICE_NAME: &'static str = "registration_server";
+
+/// Microseconds since the epoch, the unit Zipkin uses for timestamps.
+fn now_us() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() * 1_000_000 + now.subsec_nanos() as u64 / 1000
+}
+
+pub fn new_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub trace_id: String,
+    pub id: String,
+    pub parent_id: Option<String>,
+    pub name: String,
+    pub server: bool,
+    pub timestamp: u64,
+    pub duration: u64,
+}
+
+impl ToJson for Span {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("traceId".to_owned(), self.trace_id.to_json());
+        object.insert("id".to_owned(), self.id.to_json());
+        if let Some(ref parent_id) = self.parent_id {
+            object.insert("parentId".to_owned(), parent_id.to_json());
+        }
+        object.insert("name".to_owned(), self.name.to_json());
+        if self.server {
+            object.insert("kind".to_owned(), "SERVER".to_json());
+        }
+        object.insert("timestamp".to_owned(), self.timestamp.to_json());
+        object.insert("duration".to_owned(), self.duration.to_json());
+
+        let mut endpoint = BTreeMap::new();
+        endpoint.insert("serviceName".to_owned(), SERVICE_NAME.to_json());
+        object.insert("localEndpoint".to_owned(), Json::Object(endpoint));
+
+        Json::Object(object)
+    }
+}
+
+/// The trace of the request being handled, available from the request
+/// extensions.
+#[derive(Debug)]
+pub struct Trace {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_id: Option<String>,
+    pub sampled: bool,
+    start: u64,
+    spans: Vec<Span>,
+}
+
+impl Key for Trace {
+    type Value = Trace;
+}
+
+impl Trace {
+    fn from_headers(headers: &Headers) -> Trace {
+        let header = |name: &str| {
+            headers.get_raw(name)
+                   .and_then(|values| values.get(0))
+                   .and_then(|value| String::from_utf8(value.clone()).ok())
+        };
+
+        // Only continue the caller's trace if it gave us both ids.
+        let (trace_id, parent_id) = match (header(TRACE_ID), header(SPAN_ID)) {
+            (Some(trace_id), Some(span_id)) => (trace_id, Some(span_id)),
+            _ => (new_id(), None)
+        };
+
+        Trace {
+            trace_id: trace_id,
+            span_id: new_id(),
+            parent_id: parent_id,
+            sampled: header(SAMPLED).map_or(true, |value| value != "0"),
+            start: now_us(),
+            spans: Vec::new(),
+        }
+    }
+
+    fn set_headers(&self, headers: &mut Headers) {
+        headers.set_raw(TRACE_ID, vec![self.trace_id.clone().into_bytes()]);
+        headers.set_raw(SPAN_ID, vec![self.span_id.clone().into_bytes()]);
+        if let Some(ref parent_id) = self.parent_id {
+            headers.set_raw(PARENT_SPAN_ID, vec![parent_id.clone().into_bytes()]);
+        }
+        let sampled = if self.sampled { "1" } else { "0" };
+        headers.set_raw(SAMPLED, vec![sampled.as_bytes().to_vec()]);
+    }
+
+    fn child(&mut self, name: &str, timestamp: u64, duration: u64) {
+        let span = Span {
+            trace_id: self.trace_id.clone(),
+            id: new_id(),
+            parent_id: Some(self.span_id.clone()),
+            name: name.to_owned(),
+            server: false,
+            timestamp: timestamp,
+            duration: duration,
+        };
+        self.spans.push(span);
+    }
+
+    /// Close the server span and return all the spans of the request.
+    fn finish(mut self, name: String) -> Vec<Span> {
+        let server = Span {
+            trace_id: self.trace_id.clone(),
+            id: self.span_id.clone(),
+            parent_id: self.parent_id.clone(),
+            name: name,
+            server: true,
+            timestamp: self.start,
+            duration: now_us() - self.start,
+        };
+        self.spans.push(server);
+        self.spans
+    }
+}
+
+/// The id of the trace of a request, which also identifies the request.
+pub fn request_id(req: &Request) -> Option<String> {
+    req.extensions.get::<Trace>().map(|trace| trace.trace_id.clone())
+}
+
+/// Run `func`, recording it as a child span of the request's trace.
+pub fn span<T, F>(req: &mut Request, name: &str, func: F) -> T
+    where F: FnOnce() -> T {
+    let start = now_us();
+    let result = func();
+    if let Some(trace) = req.extensions.get_mut::<Trace>() {
+        trace.child(name, start, now_us() - start);
+    }
+    result
+}
+
+fn send(collector: &str, spans: Vec<Span>) {
+    let body = spans.to_json().to_string();
+    let url = collector.to_owned();
+    thread::spawn(move || {
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        let result = Client::new().post(&url)
+                                  .headers(headers)
+                                  .body(&body)
+                                  .send();
+        match result {
+            Ok(ref response) if response.status.is_success() => {},
+            Ok(response) => error!("Spans rejected by the collector: {}", response.status),
+            Err(e) => error!("Can't send spans to the collector: {}", e)
+        }
+    });
+}
+
+/// Handler wrapper tracing every request handled by `handler`.
+pub struct Tracing<H: Handler> {
+    handler: H,
+    /// URL of the Zipkin collector, e.g. http://zipkin:9411/api/v2/spans.
+    collector: Option<String>,
+}
+
+impl<H: Handler> Tracing<H> {
+    pub fn new(handler: H, collector: Option<String>) -> Tracing<H> {
+        Tracing {
+            handler: handler,
+            collector: collector,
+        }
+    }
+}
+
+impl<H: Handler> Handler for Tracing<H> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let trace = Trace::from_headers(&req.headers);
+        req.extensions.insert::<Trace>(trace);
+
+        let mut result = self.handler.handle(req);
+
+        let trace = match req.extensions.remove::<Trace>() {
+            Some(trace) => trace,
+            None => return result
+        };
+        match result {
+            Ok(ref mut response) => trace.set_headers(&mut response.headers),
+            Err(ref mut err) => trace.set_headers(&mut err.response.headers),
+        }
+
+        if trace.sampled {
+            let spans = trace.finish(format!("{} /{}", req.method,
+                                             req.url.path().join("/")));
+            match self.collector {
+                Some(ref collector) => send(collector, spans),
+                None => debug!("Spans {}", spans.to_json())
+            }
+        }
+
+        result
+    }
+}
+
+#[test]
+fn test_trace_headers() {
+    let mut headers = Headers::new();
+    let trace = Trace::from_headers(&headers);
+    assert_eq!(trace.trace_id.len(), 16);
+    assert!(trace.parent_id.is_none());
+    assert!(trace.sampled);
+
+    headers.set_raw(TRACE_ID, vec![b"463ac35c9f6413ad".to_vec()]);
+    headers.set_raw(SPAN_ID, vec![b"a2fb4a1d1a96d312".to_vec()]);
+    headers.set_raw(SAMPLED, vec![b"0".to_vec()]);
+    let mut trace = Trace::from_headers(&headers);
+    assert_eq!(trace.trace_id, "463ac35c9f6413ad");
+    assert_eq!(trace.parent_id, Some("a2fb4a1d1a96d312".to_owned()));
+    assert!(!trace.sampled);
+
+    trace.child("db.get", 10, 5);
+    let span_id = trace.span_id.clone();
+    let spans = trace.finish("GET /ping".to_owned());
+    assert_eq!(spans.len(), 2);
+    assert_eq!(spans[0].parent_id, Some(span_id.clone()));
+    assert_eq!(spans[1].id, span_id);
+    assert!(spans[1].server);
+}
@@ -0,0 +1,737 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Validation of the registration payloads, checking the type and size of
+/// every field before anything reaches the database.
+///
+/// Endpoints `extract` their payload from a JSON body, a form-encoded body
+/// or the query string of a request without a body, all turned into JSON
+/// and validated the same way. Form and query string parameters are
+/// strings, so numbers can be given as strings.
+
+use errors::*;
+use nat;
+use iron::headers::ContentType;
+use iron::mime::{ Mime, SubLevel, TopLevel };
+use iron::prelude::*;
+use iron::status;
+use params::{ Map, Params, Value };
+use rustc_serialize::json::Json;
+use signaling::{ self, Candidate };
+use std::io::Read;
+use std::net::IpAddr;
+#[cfg(test)]
+use std::iter;
+
+/// Maximum length, in bytes, of a box fingerprint.
+pub static MAX_CLIENT_LENGTH: usize = 256;
+/// Maximum length, in bytes, of a registration message.
+pub static MAX_MESSAGE_LENGTH: usize = 4096;
+/// Maximum length, in bytes, of the base64 encrypted data of a box.
+pub static MAX_ENCRYPTED_LENGTH: usize = 8192;
+/// Maximum length, in bytes, of a message left in the mailbox of a box.
+pub static MAX_MAILBOX_MESSAGE_LENGTH: usize = 256;
+
+/// Maximum length, in bytes, of the email of an account.
+pub static MAX_EMAIL_LENGTH: usize = 256;
+/// Maximum length, in bytes, of a password. Hashing isn't free.
+pub static MAX_PASSWORD_LENGTH: usize = 1024;
+/// Minimum length, in bytes, of the password of a new account.
+pub static MIN_PASSWORD_LENGTH: usize = 8;
+/// Guest tokens expire after an hour, unless asked otherwise.
+pub static DEFAULT_GUEST_TTL: u64 = 60 * 60;
+/// Guest tokens expire after a week at most.
+pub static MAX_GUEST_TTL: u64 = 7 * 24 * 60 * 60;
+/// Maximum length, in bytes, of a subdomain, as for any DNS label.
+pub static MAX_SUBDOMAIN_LENGTH: usize = 63;
+
+/// The characters of standard and URL-safe base64, with the padding.
+static BASE64_ALPHABET: &'static [u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/-_=";
+
+/// The fields of a registration, the only ones accepted in strict mode.
+static FIELDS: [&'static str; 6] = ["client", "message", "local_ip", "encrypted", "nat",
+                                    "expected_revision"];
+
+#[derive(Debug, PartialEq)]
+pub struct Registration {
+    pub client: String,
+    pub message: String,
+    /// Address of the box on its local network.
+    pub local_ip: Option<String>,
+    /// Data encrypted by the box for its clients, e.g. its local addresses,
+    /// which the server stores and returns without reading it.
+    pub encrypted: Option<String>,
+    /// The mapping behavior of the NAT of the box, one of `nat::MAPPINGS`.
+    pub nat: Option<String>,
+    /// The registration only applies if the current record of the box has
+    /// this revision, 0 for a box which isn't registered.
+    pub expected_revision: Option<u64>,
+}
+
+/// The fields of a registration to change with PATCH /v1/box, the others
+/// being kept.
+#[derive(Debug, PartialEq)]
+pub struct Patch {
+    pub message: Option<String>,
+    /// `Some(None)` removes the local IP.
+    pub local_ip: Option<Option<String>>,
+    /// `Some(None)` removes the encrypted data.
+    pub encrypted: Option<Option<String>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ValidationError {
+    pub errno: ErrNo,
+    pub details: String,
+}
+
+impl ValidationError {
+    pub fn new(errno: ErrNo, details: String) -> ValidationError {
+        ValidationError {
+            errno: errno,
+            details: details,
+        }
+    }
+
+    /// Prefix the details with the position of the registration in a batch.
+    fn at(self, index: usize) -> ValidationError {
+        ValidationError::new(self.errno,
+                             format!("Registration {}: {}", index, self.details))
+    }
+
+    pub fn into_response(self) -> IronResult<Response> {
+        Err(self.into_error())
+    }
+
+    pub fn into_error(self) -> IronError {
+        EndpointError::build(status::BadRequest, self.errno, None, Some(self.details))
+    }
+}
+
+pub fn string_field(value: &Json, name: &str, max_length: usize,
+                    missing: ErrNo, invalid: ErrNo)
+    -> Result<String, ValidationError> {
+    let field = match value.find(name) {
+        Some(&Json::String(ref field)) => field,
+        Some(_) => {
+            return Err(ValidationError::new(
+                invalid, format!("`{}` must be a string", name)))
+        },
+        None => {
+            return Err(ValidationError::new(
+                missing, format!("Missing field `{}`", name)))
+        }
+    };
+
+    if field.is_empty() {
+        return Err(ValidationError::new(
+            invalid, format!("`{}` can't be empty", name)));
+    }
+    if field.len() > max_length {
+        return Err(ValidationError::new(
+            invalid, format!("`{}` is longer than {} bytes", name, max_length)));
+    }
+
+    Ok(field.clone())
+}
+
+fn ip_field(value: &Json, name: &str) -> Result<Option<String>, ValidationError> {
+    match value.find(name) {
+        Some(&Json::String(ref field)) if field.parse::<IpAddr>().is_ok() => {
+            Ok(Some(field.clone()))
+        },
+        Some(&Json::Null) | None => Ok(None),
+        Some(_) => {
+            Err(ValidationError::new(
+                ErrNo::BadRequest, format!("`{}` must be an IP address", name)))
+        }
+    }
+}
+
+/// An optional base64 string, standard or URL-safe.
+fn base64_field(value: &Json, name: &str, max_length: usize)
+    -> Result<Option<String>, ValidationError> {
+    let field = match value.find(name) {
+        Some(&Json::Null) | None => return Ok(None),
+        Some(&Json::String(ref field)) => field,
+        Some(_) => {
+            return Err(ValidationError::new(
+                ErrNo::BadRequest, format!("`{}` must be a base64 string", name)))
+        }
+    };
+    if field.is_empty() || field.len() > max_length {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, format!("`{}` must be 1 to {} bytes long", name, max_length)));
+    }
+    if !field.bytes().all(|byte| BASE64_ALPHABET.contains(&byte)) {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, format!("`{}` must be a base64 string", name)));
+    }
+    Ok(Some(field.clone()))
+}
+
+/// An optional NAT mapping behavior, one of `nat::MAPPINGS`.
+fn nat_field(value: &Json, name: &str) -> Result<Option<String>, ValidationError> {
+    match value.find(name) {
+        Some(&Json::Null) | None => Ok(None),
+        Some(&Json::String(ref field)) if nat::MAPPINGS.contains(&field.as_ref()) => {
+            Ok(Some(field.clone()))
+        },
+        Some(_) => {
+            Err(ValidationError::new(
+                ErrNo::BadRequest,
+                format!("`{}` must be one of {}", name, nat::MAPPINGS.join(", "))))
+        }
+    }
+}
+
+fn u64_field(value: &Json, name: &str) -> Result<Option<u64>, ValidationError> {
+    let number = match value.find(name) {
+        Some(&Json::Null) | None => return Ok(None),
+        Some(&Json::String(ref field)) => field.parse().ok(),
+        Some(field) => field.as_u64()
+    };
+    match number {
+        Some(number) => Ok(Some(number)),
+        None => {
+            Err(ValidationError::new(
+                ErrNo::BadRequest, format!("`{}` must be a positive integer", name)))
+        }
+    }
+}
+
+/// Validate the payload of POST /register. In `strict` mode, unknown fields
+/// are rejected rather than ignored.
+pub fn registration(value: &Json, strict: bool) -> Result<Registration, ValidationError> {
+    let object = match value.as_object() {
+        Some(object) => object,
+        None => {
+            return Err(ValidationError::new(
+                ErrNo::BadRequest, "A registration must be an object".to_owned()))
+        }
+    };
+
+    if strict {
+        if let Some(field) = object.keys().find(|key| !FIELDS.contains(&key.as_ref())) {
+            return Err(ValidationError::new(
+                ErrNo::UnknownField, format!("Unknown field `{}`", field)));
+        }
+    }
+
+    Ok(Registration {
+        client: try!(string_field(value, "client", MAX_CLIENT_LENGTH,
+                                  ErrNo::MissingClient, ErrNo::InvalidClient)),
+        message: try!(string_field(value, "message", MAX_MESSAGE_LENGTH,
+                                   ErrNo::MissingMessage, ErrNo::InvalidMessage)),
+        local_ip: try!(ip_field(value, "local_ip")),
+        encrypted: try!(base64_field(value, "encrypted", MAX_ENCRYPTED_LENGTH)),
+        nat: try!(nat_field(value, "nat")),
+        expected_revision: try!(u64_field(value, "expected_revision")),
+    })
+}
+
+/// Whether a Content-Type is JSON, including the `+json` types.
+fn is_json(mime: &Mime) -> bool {
+    match *mime {
+        Mime(TopLevel::Application, SubLevel::Json, _) => true,
+        Mime(TopLevel::Application, SubLevel::Ext(ref sub_level), _) => {
+            sub_level.ends_with("+json")
+        },
+        _ => false
+    }
+}
+
+/// Read the body of a request to a JSON endpoint. Bodies declared as
+/// something else get a 415 rather than a confusing decoding error, but
+/// those without a Content-Type are accepted, since minimal clients often
+/// leave it out.
+pub fn json_body(req: &mut Request) -> IronResult<String> {
+    if let Some(&ContentType(ref mime)) = req.headers.get::<ContentType>() {
+        if !is_json(mime) {
+            return Err(EndpointError::build(status::UnsupportedMediaType,
+                                            ErrNo::UnsupportedMediaType, None,
+                                            Some(format!("Expected application/json, \
+                                                          got {}", mime))));
+        }
+    }
+    let mut payload = String::new();
+    match req.body.read_to_string(&mut payload) {
+        Ok(_) => Ok(payload),
+        Err(_) => Err(EndpointError::build(status::BadRequest, ErrNo::BadRequest, None, None))
+    }
+}
+
+fn parse(payload: &str) -> Result<Json, ValidationError> {
+    Json::from_str(payload).map_err(|e| {
+        ValidationError::new(ErrNo::BadRequest, format!("Invalid JSON: {}", e))
+    })
+}
+
+/// Whether the body of a request is form-encoded.
+fn is_form(req: &Request) -> bool {
+    match req.headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, _))) => true,
+        _ => false
+    }
+}
+
+/// The JSON object of form-encoded or query string parameters.
+fn params_object(params: &Map) -> Json {
+    Json::Object(params.iter().map(|(name, value)| {
+        let value = match *value {
+            Value::String(ref value) => Json::String(value.clone()),
+            // Nested fields are invalid, whatever they hold.
+            _ => Json::Array(vec![])
+        };
+        (name.clone(), value)
+    }).collect())
+}
+
+/// The payload of a request as JSON: its JSON body, its form-encoded body
+/// for minimal firmwares which can't easily produce JSON, or its query
+/// string when it has no body.
+pub fn payload(req: &mut Request) -> Result<Json, IronError> {
+    if !is_form(req) {
+        let payload = try!(json_body(req));
+        if !payload.trim().is_empty() {
+            return parse(&payload).map_err(ValidationError::into_error);
+        }
+    }
+    match req.get_ref::<Params>() {
+        Ok(params) => Ok(params_object(params)),
+        Err(_) => Err(EndpointError::build(status::BadRequest, ErrNo::BadRequest, None, None))
+    }
+}
+
+/// The payload of a request, see `payload`, validated by `validate`.
+pub fn extract<T, F>(req: &mut Request, validate: F) -> IronResult<T>
+    where F: FnOnce(&Json) -> Result<T, ValidationError> {
+    let payload = try!(payload(req));
+    validate(&payload).map_err(|error| {
+        error!("{:?}", error);
+        error.into_error()
+    })
+}
+
+/// Validate the payload of PUT /v1/box/<fingerprint>, a registration whose
+/// `client` is the `fingerprint` of the path, and may be left out.
+pub fn replacement(value: &Json, fingerprint: &str, strict: bool)
+    -> Result<Registration, ValidationError> {
+    let mut value = value.clone();
+    if let Some(object) = value.as_object_mut() {
+        match object.get("client") {
+            Some(&Json::String(ref client)) if client == fingerprint => {},
+            Some(_) => {
+                return Err(ValidationError::new(
+                    ErrNo::InvalidClient,
+                    "`client` must be the fingerprint of the path".to_owned()))
+            },
+            None => {}
+        }
+        object.insert("client".to_owned(), Json::String(fingerprint.to_owned()));
+    }
+    registration(&value, strict)
+}
+
+/// Validate the payload of PATCH /v1/box/<fingerprint>. Unknown fields are
+/// always rejected, since ignoring them would turn typos into updates
+/// doing nothing.
+pub fn patch(value: &Json) -> Result<Patch, ValidationError> {
+    let object = match value.as_object() {
+        Some(object) => object,
+        None => {
+            return Err(ValidationError::new(
+                ErrNo::BadRequest, "A patch must be an object".to_owned()))
+        }
+    };
+    let fields = ["message", "local_ip", "encrypted"];
+    if let Some(field) = object.keys().find(|key| !fields.contains(&key.as_ref())) {
+        return Err(ValidationError::new(
+            ErrNo::UnknownField, format!("Unknown field `{}`", field)));
+    }
+
+    Ok(Patch {
+        message: match value.find("message") {
+            Some(_) => Some(try!(string_field(value, "message", MAX_MESSAGE_LENGTH,
+                                              ErrNo::MissingMessage, ErrNo::InvalidMessage))),
+            None => None
+        },
+        local_ip: match value.find("local_ip") {
+            Some(_) => Some(try!(ip_field(value, "local_ip"))),
+            None => None
+        },
+        encrypted: match value.find("encrypted") {
+            Some(_) => Some(try!(base64_field(value, "encrypted", MAX_ENCRYPTED_LENGTH))),
+            None => None
+        },
+    })
+}
+
+/// Validate the payload of PUT /v1/ping, returning the fingerprint of the
+/// box.
+pub fn heartbeat(value: &Json) -> Result<String, ValidationError> {
+    client(value, "A heartbeat")
+}
+
+/// Validate the payload of POST /v1/pairing, the fingerprint of the box
+/// requesting a pairing code.
+pub fn pairing(value: &Json) -> Result<String, ValidationError> {
+    client(value, "A pairing request")
+}
+
+fn client(value: &Json, kind: &str) -> Result<String, ValidationError> {
+    if !value.is_object() {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, format!("{} must be an object", kind)));
+    }
+    string_field(value, "client", MAX_CLIENT_LENGTH,
+                 ErrNo::MissingClient, ErrNo::InvalidClient)
+}
+
+/// Validate the payload of POST /v1/account and /v1/account/session,
+/// returning the email, in lower case, and the password.
+pub fn credentials(value: &Json) -> Result<(String, String), ValidationError> {
+    if !value.is_object() {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, "Credentials must be an object".to_owned()));
+    }
+    let email = try!(string_field(value, "email", MAX_EMAIL_LENGTH,
+                                  ErrNo::BadRequest, ErrNo::BadRequest));
+    if !email.contains('@') {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, "`email` must be an email address".to_owned()));
+    }
+    let password = try!(string_field(value, "password", MAX_PASSWORD_LENGTH,
+                                     ErrNo::BadRequest, ErrNo::BadRequest));
+    Ok((email.to_lowercase(), password))
+}
+
+/// Validate the payload of POST /v1/account/boxes, returning the pairing
+/// code.
+pub fn link(value: &Json) -> Result<String, ValidationError> {
+    if !value.is_object() {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, "A link request must be an object".to_owned()));
+    }
+    string_field(value, "code", 16, ErrNo::BadRequest, ErrNo::BadRequest)
+}
+
+/// Validate the payload of POST /v1/account/boxes/<fingerprint>/guests,
+/// returning the lifetime of the guest token in seconds.
+pub fn guest_token(value: &Json) -> Result<u64, ValidationError> {
+    if !value.is_object() {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, "A guest token request must be an object".to_owned()));
+    }
+    match try!(u64_field(value, "expires_in")) {
+        None => Ok(DEFAULT_GUEST_TTL),
+        Some(ttl) if ttl > 0 && ttl <= MAX_GUEST_TTL => Ok(ttl),
+        Some(_) => {
+            Err(ValidationError::new(
+                ErrNo::BadRequest,
+                format!("`expires_in` must be between 1 and {} seconds", MAX_GUEST_TTL)))
+        }
+    }
+}
+
+/// Validate the payload of POST /v1/account/subdomains, returning the
+/// subdomain in lower case. Subdomains are DNS labels of letters, digits
+/// and hyphens, which can't start or end with a hyphen.
+pub fn subdomain(value: &Json) -> Result<String, ValidationError> {
+    if !value.is_object() {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, "A subdomain reservation must be an object".to_owned()));
+    }
+    let name = try!(string_field(value, "name", MAX_SUBDOMAIN_LENGTH,
+                                 ErrNo::BadRequest, ErrNo::BadRequest)).to_lowercase();
+    let is_ldh = name.bytes().all(|b| match b {
+        b'a'...b'z' | b'0'...b'9' | b'-' => true,
+        _ => false
+    });
+    if !is_ldh || name.starts_with('-') || name.ends_with('-') {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest,
+            "`name` must be letters, digits and hyphens, not starting or ending with one"
+                .to_owned()));
+    }
+    Ok(name)
+}
+
+/// Validate the payload of POST /v1/box/<fingerprint>/mailbox, returning
+/// the message to leave.
+pub fn mailbox_message(value: &Json) -> Result<String, ValidationError> {
+    if !value.is_object() {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, "A mailbox message must be an object".to_owned()));
+    }
+    string_field(value, "message", MAX_MAILBOX_MESSAGE_LENGTH,
+                 ErrNo::MissingMessage, ErrNo::BadRequest)
+}
+
+/// Validate the payload of PUT and POST /v1/box/<fingerprint>/candidates,
+/// returning the host candidates, which can be left out.
+pub fn candidates(value: &Json) -> Result<Vec<Candidate>, ValidationError> {
+    if !value.is_object() {
+        return Err(ValidationError::new(
+            ErrNo::BadRequest, "Candidates must be an object".to_owned()));
+    }
+    let entries = match value.find("candidates") {
+        Some(&Json::Null) | None => return Ok(vec![]),
+        Some(&Json::Array(ref entries)) => entries,
+        Some(_) => {
+            return Err(ValidationError::new(
+                ErrNo::BadRequest, "`candidates` must be an array".to_owned()))
+        }
+    };
+    if entries.len() > signaling::MAX_CANDIDATES {
+        return Err(ValidationError::new(
+            ErrNo::TooManyEntries,
+            format!("At most {} candidates are accepted", signaling::MAX_CANDIDATES)));
+    }
+
+    let candidate = |entry: &Json| -> Result<Candidate, ValidationError> {
+        match (try!(ip_field(entry, "ip")), try!(u64_field(entry, "port"))) {
+            (Some(ip), Some(port)) if port > 0 && port <= 65535 => {
+                Ok(Candidate::host(ip, port as u16))
+            },
+            _ => {
+                Err(ValidationError::new(
+                    ErrNo::BadRequest, "`ip` and `port` are required".to_owned()))
+            }
+        }
+    };
+    entries.iter().enumerate().map(|(index, entry)| {
+        candidate(entry).map_err(|e| {
+            ValidationError::new(e.errno, format!("Candidate {}: {}", index, e.details))
+        })
+    }).collect()
+}
+
+/// Validate the payload of POST /v1/register/batch, an array of
+/// registrations of at most `max_size` entries.
+pub fn batch(value: &Json, max_size: usize, strict: bool)
+    -> Result<Vec<Registration>, ValidationError> {
+    let entries = match *value {
+        Json::Array(ref entries) => entries,
+        _ => {
+            return Err(ValidationError::new(
+                ErrNo::BadRequest, "A batch must be an array".to_owned()))
+        }
+    };
+
+    if entries.len() > max_size {
+        return Err(ValidationError::new(
+            ErrNo::TooManyEntries,
+            format!("At most {} registrations are accepted", max_size)));
+    }
+
+    let registrations: Vec<Registration> = try!(entries.iter().enumerate().map(|(index, entry)| {
+        registration(entry, strict).map_err(|e| e.at(index))
+    }).collect());
+
+    // The registrations of a client would overwrite each other.
+    for (index, registration) in registrations.iter().enumerate() {
+        // Batch registrations are unconditional, rather than silently
+        // ignoring the condition.
+        if registration.expected_revision.is_some() {
+            return Err(ValidationError::new(
+                ErrNo::BadRequest,
+                "`expected_revision` isn't supported by batch registrations".to_owned())
+                .at(index));
+        }
+        if registrations[..index].iter().any(|other| other.client == registration.client) {
+            return Err(ValidationError::new(
+                ErrNo::BadRequest,
+                format!("Duplicate client `{}`", registration.client)).at(index));
+        }
+    }
+    Ok(registrations)
+}
+
+#[test]
+fn test_registration_payload() {
+    let registration_payload = |payload: &str, strict: bool| {
+        parse(payload).and_then(|value| registration(&value, strict))
+    };
+    let registration = registration_payload(
+        r#"{"client": "abcd", "message": "hello"}"#, false).unwrap();
+    assert_eq!(registration.client, "abcd");
+    assert_eq!(registration.message, "hello");
+    assert_eq!(registration.expected_revision, None);
+    let registration = registration_payload(
+        r#"{"client": "abcd", "message": "hello", "expected_revision": 3}"#, true).unwrap();
+    assert_eq!(registration.expected_revision, Some(3));
+    let registration = registration_payload(
+        r#"{"client": "abcd", "message": "hello", "nat": "address-dependent"}"#, true).unwrap();
+    assert_eq!(registration.nat, Some("address-dependent".to_owned()));
+
+    let errno = |payload: &str| registration_payload(payload, false).unwrap_err().errno;
+    assert_eq!(errno(r#"{"client": "abcd", "message": "m", "nat": "symmetric"}"#),
+               ErrNo::BadRequest);
+    assert_eq!(errno(r#"{"message": "hello"}"#), ErrNo::MissingClient);
+    assert_eq!(errno(r#"{"client": "abcd"}"#), ErrNo::MissingMessage);
+    assert_eq!(errno(r#"{"client": 42, "message": "hello"}"#), ErrNo::InvalidClient);
+    assert_eq!(errno(r#"{"client": "", "message": "hello"}"#), ErrNo::InvalidClient);
+    assert_eq!(errno(r#"{"client": "abcd", "message": null}"#), ErrNo::InvalidMessage);
+    assert_eq!(errno(r#"{"client": "abcd", "message": "m", "expected_revision": -1}"#),
+               ErrNo::BadRequest);
+    assert_eq!(errno(&format!(r#"{{"client": "abcd", "message": "{}"}}"#,
+                              iter::repeat('a').take(MAX_MESSAGE_LENGTH + 1)
+                                              .collect::<String>())),
+               ErrNo::InvalidMessage);
+    assert_eq!(errno(r#"{"client": "a", "message": "b", "local_ip": "192.168.1"}"#),
+               ErrNo::BadRequest);
+    let registration = registration_payload(
+        r#"{"client": "a", "message": "b", "local_ip": "192.168.1.2"}"#, true).unwrap();
+    assert_eq!(registration.local_ip, Some("192.168.1.2".to_owned()));
+    let registration = registration_payload(
+        r#"{"client": "a", "message": "b", "encrypted": "c2VjcmV0+/-_=="}"#, true).unwrap();
+    assert_eq!(registration.encrypted, Some("c2VjcmV0+/-_==".to_owned()));
+    assert_eq!(errno(r#"{"client": "a", "message": "b", "encrypted": "{}"}"#),
+               ErrNo::BadRequest);
+    assert_eq!(errno(r#"{"client": "a", "message": "b", "encrypted": ""}"#),
+               ErrNo::BadRequest);
+    assert_eq!(errno("[]"), ErrNo::BadRequest);
+    assert_eq!(errno("{"), ErrNo::BadRequest);
+
+    let batch_payload = |payload: &str, max_size: usize| {
+        batch(&parse(payload).unwrap(), max_size, false)
+    };
+    let error = batch_payload(r#"[{"client": "a", "message": "b"}, {"client": "c"}]"#, 10)
+        .unwrap_err();
+    assert_eq!(error.errno, ErrNo::MissingMessage);
+    assert_eq!(error.details, "Registration 1: Missing field `message`");
+    assert_eq!(batch_payload("[{}, {}]", 1).unwrap_err().errno, ErrNo::TooManyEntries);
+    let duplicate = r#"[{"client": "a", "message": "b"}, {"client": "a", "message": "c"}]"#;
+    let error = batch_payload(duplicate, 10).unwrap_err();
+    assert_eq!(error.errno, ErrNo::BadRequest);
+    assert_eq!(error.details, "Registration 1: Duplicate client `a`");
+    let conditional = r#"[{"client": "a", "message": "b", "expected_revision": 1}]"#;
+    let error = batch_payload(conditional, 10).unwrap_err();
+    assert_eq!(error.errno, ErrNo::BadRequest);
+    assert_eq!(error.details,
+               "Registration 0: `expected_revision` isn't supported by batch registrations");
+
+    let extra = r#"{"client": "abcd", "message": "hello", "mesage": "typo"}"#;
+    assert!(registration_payload(extra, false).is_ok());
+    let error = registration_payload(extra, true).unwrap_err();
+    assert_eq!(error.errno, ErrNo::UnknownField);
+    assert_eq!(error.details, "Unknown field `mesage`");
+}
+
+#[test]
+fn test_patch_payload() {
+    let patch_payload = |payload: &str| patch(&parse(payload).unwrap());
+    assert_eq!(patch_payload(r#"{"message": "hello"}"#).unwrap(),
+               Patch { message: Some("hello".to_owned()), local_ip: None, encrypted: None });
+    assert_eq!(patch_payload(r#"{"local_ip": null}"#).unwrap(),
+               Patch { message: None, local_ip: Some(None), encrypted: None });
+    assert_eq!(patch_payload(r#"{"local_ip": "10.0.0.2"}"#).unwrap().local_ip,
+               Some(Some("10.0.0.2".to_owned())));
+    assert_eq!(patch_payload(r#"{"encrypted": null}"#).unwrap().encrypted, Some(None));
+    assert_eq!(patch_payload("{}").unwrap(),
+               Patch { message: None, local_ip: None, encrypted: None });
+
+    let errno = |payload: &str| patch_payload(payload).unwrap_err().errno;
+    assert_eq!(errno(r#"{"message": ""}"#), ErrNo::InvalidMessage);
+    assert_eq!(errno(r#"{"client": "other"}"#), ErrNo::UnknownField);
+    assert_eq!(errno(r#"{"local_ip": 42}"#), ErrNo::BadRequest);
+    assert_eq!(errno(r#"{"encrypted": "not base64"}"#), ErrNo::BadRequest);
+    assert_eq!(errno("[]"), ErrNo::BadRequest);
+}
+
+#[test]
+fn test_replacement_payload() {
+    let replacement_payload = |payload: &str, fingerprint: &str, strict: bool| {
+        replacement(&parse(payload).unwrap(), fingerprint, strict)
+    };
+    let registration = replacement_payload(r#"{"message": "hello"}"#, "abcd", true).unwrap();
+    assert_eq!(registration.client, "abcd");
+    assert_eq!(registration.message, "hello");
+    assert!(replacement_payload(r#"{"client": "abcd", "message": "hello"}"#, "abcd", true)
+                .is_ok());
+
+    let errno = |payload: &str| replacement_payload(payload, "abcd", false).unwrap_err().errno;
+    assert_eq!(errno(r#"{"client": "other", "message": "hello"}"#), ErrNo::InvalidClient);
+    assert_eq!(errno("{}"), ErrNo::MissingMessage);
+    assert_eq!(errno("[]"), ErrNo::BadRequest);
+}
+
+#[test]
+fn test_candidates_payload() {
+    let candidates_payload = |payload: &str| candidates(&parse(payload).unwrap());
+    assert_eq!(candidates_payload("{}").unwrap(), vec![]);
+    assert_eq!(candidates_payload(r#"{"candidates": [{"ip": "192.168.1.2", "port": 8443}]}"#)
+                   .unwrap(),
+               vec![Candidate::host("192.168.1.2".to_owned(), 8443)]);
+
+    let error = candidates_payload(r#"{"candidates": [{"ip": "::1", "port": 0}]}"#).unwrap_err();
+    assert_eq!(error.errno, ErrNo::BadRequest);
+    assert_eq!(error.details, "Candidate 0: `ip` and `port` are required");
+    assert!(candidates_payload(r#"{"candidates": [{"ip": "box", "port": 1}]}"#).is_err());
+    assert!(candidates_payload(r#"{"candidates": "192.168.1.2"}"#).is_err());
+    let many = vec![r#"{"ip": "::1", "port": 1}"#; signaling::MAX_CANDIDATES + 1].join(",");
+    assert_eq!(candidates_payload(&format!(r#"{{"candidates": [{}]}}"#, many)).unwrap_err().errno,
+               ErrNo::TooManyEntries);
+}
+
+#[test]
+fn test_subdomain_payload() {
+    let subdomain_payload = |payload: &str| subdomain(&parse(payload).unwrap());
+    assert_eq!(subdomain_payload(r#"{"name": "Kitchen-2"}"#).unwrap(), "kitchen-2");
+
+    let errno = |payload: &str| subdomain_payload(payload).unwrap_err().errno;
+    assert_eq!(errno(r#"{"name": "-kitchen"}"#), ErrNo::BadRequest);
+    assert_eq!(errno(r#"{"name": "kitchen-"}"#), ErrNo::BadRequest);
+    assert_eq!(errno(r#"{"name": "my.kitchen"}"#), ErrNo::BadRequest);
+    assert_eq!(errno(r#"{"name": "küche"}"#), ErrNo::BadRequest);
+    let long: String = iter::repeat('a').take(MAX_SUBDOMAIN_LENGTH + 1).collect();
+    assert_eq!(errno(&format!(r#"{{"name": "{}"}}"#, long)), ErrNo::BadRequest);
+    assert_eq!(errno("{}"), ErrNo::BadRequest);
+    assert_eq!(errno("[]"), ErrNo::BadRequest);
+}
+
+#[test]
+fn test_is_json() {
+    assert!(is_json(&"application/json".parse().unwrap()));
+    assert!(is_json(&"application/json; charset=utf-8".parse().unwrap()));
+    assert!(is_json(&"application/merge-patch+json".parse().unwrap()));
+    assert!(!is_json(&"text/plain".parse().unwrap()));
+    assert!(!is_json(&"application/x-www-form-urlencoded".parse().unwrap()));
+}
+
+#[test]
+fn test_params_object() {
+    let form = |fields: &[(&str, &str)]| {
+        let mut params = Map::new();
+        for &(name, value) in fields {
+            params.assign(name, Value::String(value.to_owned())).unwrap();
+        }
+        params
+    };
+
+    let registered = registration(
+        &params_object(&form(&[("client", "abcd"), ("message", "hello"),
+                               ("local_ip", "10.0.0.2"), ("expected_revision", "2")])),
+        true).unwrap();
+    assert_eq!(registered, Registration {
+        client: "abcd".to_owned(),
+        message: "hello".to_owned(),
+        local_ip: Some("10.0.0.2".to_owned()),
+        encrypted: None,
+        nat: None,
+        expected_revision: Some(2),
+    });
+
+    let errno = |fields: &[(&str, &str)]| {
+        registration(&params_object(&form(fields)), true).unwrap_err().errno
+    };
+    assert_eq!(errno(&[("message", "hello")]), ErrNo::MissingClient);
+    assert_eq!(errno(&[("client", "abcd"), ("message", "")]), ErrNo::InvalidMessage);
+    assert_eq!(errno(&[("client", "abcd"), ("message", "m"), ("expected_revision", "x")]),
+               ErrNo::BadRequest);
+    assert_eq!(errno(&[("client", "abcd"), ("message", "m"), ("mesage", "typo")]),
+               ErrNo::UnknownField);
+}